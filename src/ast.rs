@@ -1,8 +1,11 @@
-use crate::tokens::{Token, TokenType};
-use crate::types::{FloatType, IntType};
+pub mod scopes;
+
+use crate::tokens::{Token, TokenType, UNARY_PRIORITY};
+use crate::types::{FloatType, IntType, Source};
 use rslua_traits::Comments;
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum UnOp {
     Minus(Token),
     BNot(Token),
@@ -34,11 +37,12 @@ impl UnOp {
         }
     }
     pub fn priority(&self) -> u8 {
-        12
+        UNARY_PRIORITY
     }
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum BinOp {
     Add(Token),
     Minus(Token),
@@ -98,34 +102,32 @@ impl BinOp {
     }
 
     pub fn priority(&self) -> BinOpPriority {
-        match self {
-            BinOp::Or(_) => BinOpPriority { left: 1, right: 1 },
-            BinOp::And(_) => BinOpPriority { left: 2, right: 2 },
-            BinOp::Eq(_)
-            | BinOp::Ne(_)
-            | BinOp::Lt(_)
-            | BinOp::Gt(_)
-            | BinOp::Le(_)
-            | BinOp::Ge(_) => BinOpPriority { left: 3, right: 3 },
-            BinOp::BOr(_) => BinOpPriority { left: 4, right: 4 },
-            BinOp::BXor(_) => BinOpPriority { left: 5, right: 5 },
-            BinOp::BAnd(_) => BinOpPriority { left: 6, right: 6 },
-            BinOp::Shl(_) | BinOp::Shr(_) => BinOpPriority { left: 7, right: 7 },
-            BinOp::Concat(_) => BinOpPriority { left: 9, right: 8 },
-            BinOp::Add(_) | BinOp::Minus(_) => BinOpPriority {
-                left: 10,
-                right: 10,
-            },
-            BinOp::Mul(_) | BinOp::Mod(_) | BinOp::Div(_) | BinOp::IDiv(_) => BinOpPriority {
-                left: 11,
-                right: 11,
-            },
-            BinOp::Pow(_) => BinOpPriority {
-                left: 14,
-                right: 13,
-            },
-            _ => unreachable!(),
-        }
+        let token = match self {
+            BinOp::Add(token)
+            | BinOp::Minus(token)
+            | BinOp::Mul(token)
+            | BinOp::Mod(token)
+            | BinOp::Pow(token)
+            | BinOp::Div(token)
+            | BinOp::IDiv(token)
+            | BinOp::BAnd(token)
+            | BinOp::BOr(token)
+            | BinOp::BXor(token)
+            | BinOp::Shl(token)
+            | BinOp::Shr(token)
+            | BinOp::Concat(token)
+            | BinOp::Ne(token)
+            | BinOp::Eq(token)
+            | BinOp::Lt(token)
+            | BinOp::Le(token)
+            | BinOp::Gt(token)
+            | BinOp::Ge(token)
+            | BinOp::And(token)
+            | BinOp::Or(token) => token,
+            BinOp::None => unreachable!(),
+        };
+        let (left, right) = token.t.binop_priority().unwrap();
+        BinOpPriority { left, right }
     }
 
     pub fn is_comp(&self) -> bool {
@@ -167,6 +169,7 @@ impl Comments for BinOp {
 }
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Expr {
     Nil(Token),
     True(Token),
@@ -185,10 +188,34 @@ pub enum Expr {
 }
 
 impl Expr {
+    // source of the first token of the expr
+    pub fn source(&self) -> Source {
+        match &self {
+            Expr::Nil(token) | Expr::True(token) | Expr::False(token) | Expr::VarArg(token) => {
+                token.source
+            }
+            Expr::Float(expr) => expr.token.source,
+            Expr::Int(expr) => expr.token.source,
+            Expr::String(expr) | Expr::Name(expr) => expr.token.source,
+            Expr::ParenExpr(expr) => expr.source(),
+            Expr::FuncBody(body) => body.lp.source,
+            Expr::Table(table) => table.lb.source,
+            Expr::BinExpr(expr) => expr.left.source(),
+            Expr::UnExpr(expr) => match &expr.op {
+                UnOp::Minus(token) | UnOp::BNot(token) | UnOp::Not(token) | UnOp::Len(token) => {
+                    token.source
+                }
+                UnOp::None => expr.expr.source(),
+            },
+            Expr::SuffixedExpr(expr) => expr.primary.source(),
+        }
+    }
+
     pub fn to_assignable(self) -> Assignable {
         match self {
             Expr::Name(s) => Assignable::Name(s),
             Expr::SuffixedExpr(s) => Assignable::SuffixedExpr(s),
+            Expr::ParenExpr(e) => Assignable::ParenExpr(e),
             _ => unreachable!(),
         }
     }
@@ -244,12 +271,23 @@ impl Comments for Expr {
 }
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Assignable {
     Name(StringExpr),
     SuffixedExpr(SuffixedExpr),
+    // not a valid target, rejected by compiler
+    ParenExpr(Box<Expr>),
 }
 
 impl Assignable {
+    pub fn source(&self) -> Source {
+        match &self {
+            Assignable::Name(name) => name.token.source,
+            Assignable::SuffixedExpr(suffix) => suffix.primary.source(),
+            Assignable::ParenExpr(expr) => expr.source(),
+        }
+    }
+
     pub fn unwrap_as_name(&self) -> &StringExpr {
         match &self {
             Assignable::Name(name) => name,
@@ -269,11 +307,13 @@ impl Comments for Assignable {
         match &self {
             Assignable::Name(name) => name.get_comments(),
             Assignable::SuffixedExpr(suffix) => suffix.get_comments(),
+            Assignable::ParenExpr(expr) => expr.get_comments(),
         }
     }
 }
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FloatExpr {
     pub token: Token,
 }
@@ -282,6 +322,11 @@ impl FloatExpr {
     pub fn value(&self) -> FloatType {
         self.token.get_float()
     }
+
+    // origin literal text, only available with `preserve_number_text`
+    pub fn text(&self) -> Option<&str> {
+        self.token.get_number_text()
+    }
 }
 
 impl Comments for FloatExpr {
@@ -291,6 +336,7 @@ impl Comments for FloatExpr {
 }
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IntExpr {
     pub token: Token,
 }
@@ -299,6 +345,11 @@ impl IntExpr {
     pub fn value(&self) -> IntType {
         self.token.get_int()
     }
+
+    // origin literal text, only available with `preserve_number_text`
+    pub fn text(&self) -> Option<&str> {
+        self.token.get_number_text()
+    }
 }
 
 impl Comments for IntExpr {
@@ -308,6 +359,7 @@ impl Comments for IntExpr {
 }
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct StringExpr {
     pub token: Token,
 }
@@ -325,6 +377,7 @@ impl Comments for StringExpr {
 }
 
 #[derive(PartialEq, Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SuffixedExpr {
     pub primary: Box<Expr>,
     pub suffixes: Vec<Suffix>,
@@ -337,6 +390,8 @@ impl Comments for SuffixedExpr {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum Suffix {
     // '.' NAME
     Attr(Token, StringExpr),
@@ -368,6 +423,7 @@ impl Comments for Suffix {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FuncArgs {
     // '(' [ exprlist ] ')'
     Exprs(Token, ExprList, Token),
@@ -386,6 +442,7 @@ impl Comments for FuncArgs {
 }
 
 #[derive(Default, Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ExprList {
     pub exprs: Vec<Expr>,
     pub commas: Vec<Token>,
@@ -405,6 +462,7 @@ impl Comments for ExprList {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Table {
     pub lb: Token,
     pub fields: Vec<Field>,
@@ -418,6 +476,8 @@ impl Comments for Table {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum Field {
     RecField(RecField),
     ListField(ListField),
@@ -448,6 +508,7 @@ impl Comments for Field {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RecField {
     pub key: FieldKey,
     pub equal: Token,
@@ -462,6 +523,7 @@ impl Comments for RecField {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ListField {
     pub value: Expr,
     pub sep: Option<Token>,
@@ -474,6 +536,8 @@ impl Comments for ListField {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum FieldKey {
     Name(StringExpr),
     // '[' expr ']'
@@ -505,6 +569,7 @@ impl Comments for FieldKey {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct UnExpr {
     pub op: UnOp,
     pub expr: Box<Expr>,
@@ -517,6 +582,7 @@ impl Comments for UnExpr {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BinExpr {
     pub left: Box<Expr>,
     pub op: BinOp,
@@ -530,6 +596,7 @@ impl Comments for BinExpr {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct IfStat {
     pub cond_blocks: Vec<CondBlock>,
     pub else_: Option<Token>,
@@ -548,6 +615,7 @@ impl Comments for IfStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CondBlock {
     pub if_: Token,
     pub cond: Expr,
@@ -562,6 +630,7 @@ impl Comments for CondBlock {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct WhileStat {
     pub while_: Token,
     pub cond: Expr,
@@ -577,6 +646,7 @@ impl Comments for WhileStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DoBlock {
     pub do_: Token,
     pub block: Block,
@@ -590,6 +660,8 @@ impl Comments for DoBlock {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum ForStat {
     ForNum(ForNum),
     ForList(ForList),
@@ -605,6 +677,7 @@ impl Comments for ForStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ForNum {
     pub for_: Token,
     pub var: StringExpr,
@@ -626,6 +699,7 @@ impl Comments for ForNum {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ForList {
     pub for_: Token,
     pub vars: VarList,
@@ -643,6 +717,7 @@ impl Comments for ForList {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct VarList {
     pub vars: Vec<StringExpr>,
     pub delimiters: Vec<Token>,
@@ -659,6 +734,7 @@ impl Comments for VarList {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RepeatStat {
     pub repeat: Token,
     pub block: Block,
@@ -673,12 +749,14 @@ impl Comments for RepeatStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FuncType {
     Global,
     Local(Token),
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncStat {
     pub func_type: FuncType,
     pub function: Token,
@@ -696,6 +774,7 @@ impl Comments for FuncStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncName {
     // NAME {'.' NAME}
     pub fields: VarList,
@@ -714,6 +793,7 @@ impl Comments for FuncName {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct FuncBody {
     pub lp: Token,
     pub params: ParamList,
@@ -729,12 +809,14 @@ impl Comments for FuncBody {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParamList {
     pub params: Vec<Param>,
     pub commas: Vec<Token>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Param {
     VarArg(Token),
     Name(StringExpr),
@@ -759,13 +841,31 @@ impl Comments for Param {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LocalStat {
     pub local: Token,
     pub names: VarList,
+    // attribute of each name
+    pub attribs: Vec<Option<Attrib>>,
     pub equal: Option<Token>,
     pub exprs: Option<ExprList>,
 }
 
+// `<const>` or `<close>` after the name of a local, since lua 5.4
+#[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Attrib {
+    pub lt: Token,
+    pub name: StringExpr,
+    pub gt: Token,
+}
+
+impl Attrib {
+    pub fn value(&self) -> String {
+        self.name.value()
+    }
+}
+
 impl Comments for LocalStat {
     fn get_comments(&self) -> Vec<&str> {
         self.local.get_comments()
@@ -773,6 +873,7 @@ impl Comments for LocalStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LabelStat {
     pub ldc: Token,
     pub label: StringExpr,
@@ -786,6 +887,7 @@ impl Comments for LabelStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct RetStat {
     pub return_: Token,
     pub exprs: Option<ExprList>,
@@ -799,6 +901,7 @@ impl Comments for RetStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct BreakStat {
     pub token: Token,
 }
@@ -810,6 +913,7 @@ impl Comments for BreakStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct GotoStat {
     pub goto: Token,
     pub label: StringExpr,
@@ -822,6 +926,7 @@ impl Comments for GotoStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AssignStat {
     pub left: AssignableList,
     pub equal: Token,
@@ -839,12 +944,14 @@ impl Comments for AssignStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct AssignableList {
     pub assignables: Vec<Assignable>,
     pub commas: Vec<Token>,
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CallStat {
     pub call: Assignable,
 }
@@ -856,6 +963,8 @@ impl Comments for CallStat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[allow(clippy::large_enum_variant)]
 pub enum Stat {
     IfStat(IfStat),
     WhileStat(WhileStat),
@@ -872,6 +981,31 @@ pub enum Stat {
     CallStat(CallStat),
 }
 
+impl Stat {
+    // source of the first token of the stat
+    pub fn source(&self) -> Source {
+        match self {
+            Stat::IfStat(stat) => stat.cond_blocks[0].if_.source,
+            Stat::WhileStat(stat) => stat.while_.source,
+            Stat::DoBlock(stat) => stat.do_.source,
+            Stat::ForStat(ForStat::ForNum(stat)) => stat.for_.source,
+            Stat::ForStat(ForStat::ForList(stat)) => stat.for_.source,
+            Stat::RepeatStat(stat) => stat.repeat.source,
+            Stat::FuncStat(stat) => match &stat.func_type {
+                FuncType::Global => stat.function.source,
+                FuncType::Local(token) => token.source,
+            },
+            Stat::LocalStat(stat) => stat.local.source,
+            Stat::LabelStat(stat) => stat.ldc.source,
+            Stat::RetStat(stat) => stat.return_.source,
+            Stat::BreakStat(stat) => stat.token.source,
+            Stat::GotoStat(stat) => stat.goto.source,
+            Stat::AssignStat(stat) => stat.left.assignables[0].source(),
+            Stat::CallStat(stat) => stat.call.source(),
+        }
+    }
+}
+
 impl Comments for Stat {
     fn get_comments(&self) -> Vec<&str> {
         match self {
@@ -893,6 +1027,7 @@ impl Comments for Stat {
 }
 
 #[derive(Clone, PartialEq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Block {
     pub stats: Vec<Stat>,
 }
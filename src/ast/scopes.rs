@@ -0,0 +1,145 @@
+use crate::ast::*;
+use crate::ast_walker::{self, AstVisitor, VisitAction, VisitResult};
+use crate::types::Source;
+
+// the span where a local variable is visible.
+// `start` is the declaring name, `end` is the token which closes the enclosing block,
+// `end` is none if the local lives until the end of the chunk.
+// for `repeat ... until cond`, the scope ends at `until` but also covers `cond`.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ScopeSpan {
+    pub start: Source,
+    pub end: Option<Source>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalScope {
+    pub name: String,
+    pub decl_source: Source,
+    pub scope_span: ScopeSpan,
+}
+
+// collect every local declaration (locals, local functions, parameters and loop variables) in declaration order,
+// a shadowing declaration produces a distinct entry.
+pub fn local_scopes(block: &Block) -> Vec<LocalScope> {
+    let mut collector = ScopeCollector::default();
+    // the collector never fails
+    let _ = ast_walker::walk_block(block, &mut collector);
+    collector.scopes
+}
+
+// locals declared at the start of a block, e.g. parameters or loop variables
+type BlockLocals = Vec<(String, Source)>;
+
+#[derive(Default)]
+struct ScopeCollector {
+    scopes: Vec<LocalScope>,
+    // end of the scope and locals of the blocks which will be entered next, the last one is entered first
+    pending: Vec<(Option<Source>, BlockLocals)>,
+    // end of the scope of each entered block
+    ends: Vec<Option<Source>>,
+    // the implicit `self` of the method whose body is entered next
+    method: Option<Source>,
+}
+
+impl ScopeCollector {
+    fn add_local(&mut self, name: String, source: Source) {
+        let end = self.ends.last().copied().flatten();
+        self.scopes.push(LocalScope {
+            name,
+            decl_source: source,
+            scope_span: ScopeSpan { start: source, end },
+        });
+    }
+
+    fn push_block(&mut self, end: &Token, locals: BlockLocals) {
+        self.pending.push((Some(end.source), locals));
+    }
+}
+
+fn names(vars: &VarList) -> BlockLocals {
+    vars.vars
+        .iter()
+        .map(|name| (name.value(), name.token.source))
+        .collect()
+}
+
+impl AstVisitor for ScopeCollector {
+    fn begin_stat(&mut self, stat: &Stat) -> VisitResult<()> {
+        match stat {
+            Stat::IfStat(stat) => {
+                if stat.else_block.is_some() {
+                    self.push_block(&stat.end, vec![]);
+                }
+                let mut end = stat.else_.as_ref().unwrap_or(&stat.end);
+                for cond_block in stat.cond_blocks.iter().rev() {
+                    self.push_block(end, vec![]);
+                    end = &cond_block.if_;
+                }
+            }
+            Stat::WhileStat(stat) => self.push_block(&stat.end, vec![]),
+            Stat::DoBlock(stat) => self.push_block(&stat.end, vec![]),
+            Stat::ForStat(ForStat::ForNum(stat)) => {
+                let var = (stat.var.value(), stat.var.token.source);
+                self.push_block(&stat.end, vec![var]);
+            }
+            Stat::ForStat(ForStat::ForList(stat)) => self.push_block(&stat.end, names(&stat.vars)),
+            Stat::RepeatStat(stat) => self.push_block(&stat.until, vec![]),
+            _ => (),
+        }
+        Ok(VisitAction::Continue)
+    }
+
+    fn enter_block(&mut self, _block: &Block) {
+        // only the main chunk has no pending scope
+        let (end, locals) = self.pending.pop().unwrap_or_default();
+        self.ends.push(end);
+        for (name, source) in locals {
+            self.add_local(name, source);
+        }
+    }
+
+    fn leave_block(&mut self) {
+        self.ends.pop();
+    }
+
+    fn func(&mut self, stat: &FuncStat) {
+        if let FuncType::Local(_) = stat.func_type {
+            if let Some(name) = stat.func_name.fields.vars.first() {
+                self.add_local(name.value(), name.token.source);
+            }
+        }
+        // `self` has no token, it is declared at the method name
+        self.method = stat
+            .func_name
+            .method
+            .as_ref()
+            .map(|(_, method)| method.token.source);
+    }
+
+    fn enter_function(&mut self, body: &FuncBody) {
+        let mut locals = BlockLocals::new();
+        if let Some(source) = self.method.take() {
+            locals.push(("self".to_string(), source));
+        }
+        for param in body.params.params.iter() {
+            if let Param::Name(name) = param {
+                locals.push((name.value(), name.token.source));
+            }
+        }
+        self.push_block(&body.end, locals);
+    }
+
+    fn local_stat(&mut self, stat: &LocalStat) -> VisitResult<()> {
+        // locals are visible after the stat, so locals of functions in exprs come first
+        if let Some(exprs) = &stat.exprs {
+            if let VisitAction::Stop = self.exprlist(exprs)? {
+                return Ok(VisitAction::Stop);
+            }
+        }
+        for name in stat.names.vars.iter() {
+            self.add_local(name.value(), name.token.source);
+        }
+        Ok(VisitAction::Continue)
+    }
+}
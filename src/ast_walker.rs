@@ -3,91 +3,167 @@ use rslua_traits::Comments;
 use crate::ast::*;
 use crate::types::Source;
 
-// if visitor return `Ok(true)`, walker will not travel its children
-// if visitor return `Ok(false)`, walker will travel its children recursively.
+// returns from the current walker if the traversal is stopped
+macro_rules! walk {
+    ($walk:expr) => {
+        if let VisitAction::Stop = $walk? {
+            return Ok(VisitAction::Stop);
+        }
+    };
+}
+
+// travels children only if the hook returns `VisitAction::Continue`
+macro_rules! visit {
+    ($hook:expr, $children:block) => {
+        match $hook? {
+            VisitAction::Continue => $children,
+            VisitAction::SkipChildren => (),
+            VisitAction::Stop => return Ok(VisitAction::Stop),
+        }
+    };
+}
+
+// result of hooks of `AstVisitor`, walkers return `Stop` if the traversal is stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VisitAction {
+    // travel children of the node recursively
+    Continue,
+    // don't travel children of the node, but continue with its siblings
+    SkipChildren,
+    // stop traveling the whole tree
+    Stop,
+}
+
+pub type VisitResult<E> = Result<VisitAction, E>;
+
+// `begin_*` hooks are called before children of a node are traveled (pre-order),
+// and `end_*` hooks after (post-order), `end_*` hooks are not called if the traversal is stopped.
 // if visitor return `Err(E)`, walker will stop traveling.
 pub trait AstVisitor<E = ()> {
+    fn begin_stat(&mut self, _stat: &Stat) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
+    }
     fn stat_sep(&mut self) {}
 
-    fn begin_if(&mut self, _cond: &Expr) -> Result<bool, E> {
-        Ok(false)
+    // called around every block walked by `walk_block`, including the main chunk and bodies of functions,
+    // for scope-aware visitors which track locals. `leave_block` is not called if the traversal is stopped.
+    fn enter_block(&mut self, _block: &Block) {}
+    fn leave_block(&mut self) {}
+
+    fn begin_if(&mut self, _cond: &Expr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn then(&mut self, _block: &Block) -> Result<bool, E> {
-        Ok(false)
+    fn then(&mut self, _block: &Block) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn begin_else_if(&mut self, _cond: &Expr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_else_if(&mut self, _cond: &Expr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn begin_else(&mut self, _block: &Block) -> Result<bool, E> {
-        Ok(false)
+    fn begin_else(&mut self, _block: &Block) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_if(&mut self) {}
 
-    fn begin_while(&mut self, _cond: &Expr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_while(&mut self, _cond: &Expr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn begin_while_block(&mut self, _block: &Block) -> Result<bool, E> {
-        Ok(false)
+    fn begin_while_block(&mut self, _block: &Block) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_while(&mut self) {}
 
-    fn begin_do_block(&mut self, _block: &Block) -> Result<bool, E> {
-        Ok(false)
+    fn begin_do_block(&mut self, _block: &Block) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_do_block(&mut self) {}
 
-    fn begin_for_num(&mut self, _for_enum: &ForNum) -> Result<bool, E> {
-        Ok(false)
+    fn begin_for_num(&mut self, _for_enum: &ForNum) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn for_enum_equal(&mut self) {}
 
-    fn begin_for_list(&mut self, _forlist: &ForList) -> Result<bool, E> {
-        Ok(false)
+    fn begin_for_list(&mut self, _forlist: &ForList) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
 
     fn for_list_in(&mut self) {}
 
-    fn begin_for_block(&mut self, _block: &Block) -> Result<bool, E> {
-        Ok(false)
+    fn begin_for_block(&mut self, _block: &Block) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_for(&mut self) {}
 
-    fn begin_repeat(&mut self, _block: &Block) -> Result<bool, E> {
-        Ok(false)
+    fn begin_repeat(&mut self, _block: &Block) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn until(&mut self) {}
     fn end_repeat(&mut self) {}
 
     fn func(&mut self, _funcstat: &FuncStat) {}
 
-    fn local_stat(&mut self, _stat: &LocalStat) -> Result<(), E> {
-        Ok(())
+    // by default, children of these stats are traveled too,
+    // so a visitor could only override hooks of exprs it cares about
+    fn local_stat(&mut self, stat: &LocalStat) -> VisitResult<E>
+    where
+        Self: Sized,
+    {
+        for name in stat.names.vars.iter() {
+            self.name(name);
+        }
+        if let Some(exprs) = &stat.exprs {
+            return self.exprlist(exprs);
+        }
+        Ok(VisitAction::Continue)
     }
-    fn label_stat(&mut self, _stat: &LabelStat) -> Result<(), E> {
-        Ok(())
+    fn label_stat(&mut self, _stat: &LabelStat) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn ret_stat(&mut self, _stat: &RetStat) -> Result<(), E> {
-        Ok(())
+    fn ret_stat(&mut self, stat: &RetStat) -> VisitResult<E>
+    where
+        Self: Sized,
+    {
+        if let Some(exprs) = &stat.exprs {
+            return self.exprlist(exprs);
+        }
+        Ok(VisitAction::Continue)
     }
-    fn break_stat(&mut self, _stat: &BreakStat) -> Result<(), E> {
-        Ok(())
+    fn break_stat(&mut self, _stat: &BreakStat) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn goto_stat(&mut self, _stat: &GotoStat) -> Result<(), E> {
-        Ok(())
+    fn goto_stat(&mut self, _stat: &GotoStat) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
-    fn assign_stat(&mut self, _stat: &AssignStat) -> Result<(), E> {
-        Ok(())
+    fn assign_stat(&mut self, stat: &AssignStat) -> VisitResult<E>
+    where
+        Self: Sized,
+    {
+        for assignable in stat.left.assignables.iter() {
+            walk!(walk_assinable(assignable, self));
+        }
+        self.exprlist(&stat.right)
     }
-    fn call_stat(&mut self, _stat: &CallStat) -> Result<(), E> {
-        Ok(())
+    fn call_stat(&mut self, stat: &CallStat) -> VisitResult<E>
+    where
+        Self: Sized,
+    {
+        walk_assinable(&stat.call, self)
     }
 
-    fn exprlist(&mut self, _stat: &ExprList) -> Result<(), E> {
-        Ok(())
+    fn exprlist(&mut self, exprs: &ExprList) -> VisitResult<E>
+    where
+        Self: Sized,
+    {
+        for expr in exprs.exprs.iter() {
+            walk!(walk_expr(expr, self));
+        }
+        Ok(VisitAction::Continue)
     }
 
-    fn expr(&mut self, _stat: &Expr) -> Result<bool, E> {
-        Ok(false)
+    // called for every expr before `expr`, sub exprs are visited after their parent,
+    // from left to right as they appear in source
+    fn visit_expr(&mut self, _expr: &Expr, _source: &Source) {}
+    fn expr(&mut self, _stat: &Expr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn expr_sep(&mut self) {}
 
@@ -100,42 +176,45 @@ pub trait AstVisitor<E = ()> {
     fn vararg(&mut self) {}
 
     fn anonymous_func(&mut self) {}
-    fn begin_func_body(&mut self, _body: &FuncBody) -> Result<bool, E> {
-        Ok(false)
+    fn begin_func_body(&mut self, _body: &FuncBody) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_func_body(&mut self) {}
+    // called around the block of a function body if its children are traveled, outside of its `enter_block`
+    fn enter_function(&mut self, _body: &FuncBody) {}
+    fn leave_function(&mut self) {}
 
-    fn begin_table(&mut self, _t: &Table) -> Result<bool, E> {
-        Ok(false)
+    fn begin_table(&mut self, _t: &Table) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_table(&mut self, _t: &Table) {}
 
     fn field_sep(&mut self) {}
 
-    fn begin_rec_field(&mut self, _field: &RecField) -> Result<bool, E> {
-        Ok(false)
+    fn begin_rec_field(&mut self, _field: &RecField) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn field_kv_sep(&mut self) {}
-    fn begin_field_key(&mut self, _key: &FieldKey) -> Result<bool, E> {
-        Ok(false)
+    fn begin_field_key(&mut self, _key: &FieldKey) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_field_key(&mut self, _key: &FieldKey) {}
     fn end_rec_field(&mut self) {}
 
-    fn begin_bin_expr(&mut self, _expr: &BinExpr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_bin_expr(&mut self, _expr: &BinExpr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn binop(&mut self, _op: &BinOp) {}
     fn end_bin_expr(&mut self) {}
 
-    fn begin_un_expr(&mut self, _expr: &UnExpr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_un_expr(&mut self, _expr: &UnExpr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn unop(&mut self, _op: &UnOp) {}
     fn end_un_expr(&mut self) {}
 
-    fn begin_suffixed_expr(&mut self, _expr: &SuffixedExpr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_suffixed_expr(&mut self, _expr: &SuffixedExpr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_suffixed_expr(&mut self) {}
 
@@ -143,23 +222,23 @@ pub trait AstVisitor<E = ()> {
     fn attr(&mut self, _attr: &StringExpr) {}
     fn method(&mut self, _method: &StringExpr) {}
 
-    fn begin_index(&mut self, _expr: &Expr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_index(&mut self, _expr: &Expr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_index(&mut self) {}
 
-    fn begin_func_args(&mut self, _args: &FuncArgs) -> Result<bool, E> {
-        Ok(false)
+    fn begin_func_args(&mut self, _args: &FuncArgs) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_func_args(&mut self) {}
 
-    fn begin_paren_expr(&mut self, _expr: &Expr) -> Result<bool, E> {
-        Ok(false)
+    fn begin_paren_expr(&mut self, _expr: &Expr) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
     fn end_paren_expr(&mut self) {}
 
-    fn suffix(&mut self, _suf: &Suffix) -> Result<bool, E> {
-        Ok(false)
+    fn suffix(&mut self, _suf: &Suffix) -> VisitResult<E> {
+        Ok(VisitAction::Continue)
     }
 
     fn error(&mut self, e: E, _source: &Source) -> Result<(), E> {
@@ -169,25 +248,29 @@ pub trait AstVisitor<E = ()> {
     fn comments(&mut self, _comments: &impl Comments) {}
 }
 
-pub fn walk_block<T: AstVisitor<E>, E>(block: &Block, visitor: &mut T) -> Result<(), E> {
+pub fn walk_block<T: AstVisitor<E>, E>(block: &Block, visitor: &mut T) -> VisitResult<E> {
+    visitor.enter_block(block);
     for stat in block.stats.iter() {
-        if let Err(e) = walk_stat(stat, visitor) {
-            return visitor.error(
-                e,
-                &Source {
-                    line: 0,
-                    col: 0,
-                    length: 0,
-                },
-            );
+        match walk_stat(stat, visitor) {
+            Ok(VisitAction::Stop) => return Ok(VisitAction::Stop),
+            Ok(_) => (),
+            Err(e) => {
+                visitor.error(e, &stat.source())?;
+                break;
+            }
         }
         visitor.stat_sep();
     }
-    Ok(())
+    visitor.leave_block();
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_stat<T: AstVisitor<E>, E>(stat: &Stat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_stat<T: AstVisitor<E>, E>(stat: &Stat, visitor: &mut T) -> VisitResult<E> {
     visitor.comments(stat);
+    match visitor.begin_stat(stat)? {
+        VisitAction::Continue => (),
+        action => return Ok(action),
+    }
     match stat {
         Stat::IfStat(ifstat) => walk_ifstat(ifstat, visitor),
         Stat::WhileStat(whilestat) => walk_whilestat(whilestat, visitor),
@@ -205,91 +288,91 @@ pub fn walk_stat<T: AstVisitor<E>, E>(stat: &Stat, visitor: &mut T) -> Result<()
     }
 }
 
-pub fn walk_ifstat<T: AstVisitor<E>, E>(stat: &IfStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_ifstat<T: AstVisitor<E>, E>(stat: &IfStat, visitor: &mut T) -> VisitResult<E> {
     let mut if_blocks = stat.cond_blocks.iter();
     if let Some(if_block) = if_blocks.next() {
-        if !visitor.begin_if(&if_block.cond)? {
-            walk_expr(&if_block.cond, visitor)?;
-        }
+        visit!(visitor.begin_if(&if_block.cond), {
+            walk!(walk_expr(&if_block.cond, visitor));
+        });
         visitor.comments(&if_block.then);
-        if !visitor.then(&if_block.block)? {
-            walk_block(&if_block.block, visitor)?;
-        }
+        visit!(visitor.then(&if_block.block), {
+            walk!(walk_block(&if_block.block, visitor));
+        });
         for else_if_block in if_blocks {
-            if !visitor.begin_else_if(&else_if_block.cond)? {
-                walk_expr(&else_if_block.cond, visitor)?;
-            }
+            visit!(visitor.begin_else_if(&else_if_block.cond), {
+                walk!(walk_expr(&else_if_block.cond, visitor));
+            });
             visitor.comments(&else_if_block.then);
-            if !visitor.then(&else_if_block.block)? {
-                walk_block(&else_if_block.block, visitor)?;
-            }
+            visit!(visitor.then(&else_if_block.block), {
+                walk!(walk_block(&else_if_block.block, visitor));
+            });
         }
         if let Some(else_block) = &stat.else_block {
-            if !visitor.begin_else(else_block)? {
-                walk_block(else_block, visitor)?;
-            }
+            visit!(visitor.begin_else(else_block), {
+                walk!(walk_block(else_block, visitor));
+            });
         }
         visitor.comments(&stat.end);
         visitor.end_if();
     }
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_whilestat<T: AstVisitor<E>, E>(stat: &WhileStat, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_while(&stat.cond)? {
-        walk_expr(&stat.cond, visitor)?;
-    }
+pub fn walk_whilestat<T: AstVisitor<E>, E>(stat: &WhileStat, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_while(&stat.cond), {
+        walk!(walk_expr(&stat.cond, visitor));
+    });
     visitor.comments(&stat.do_);
-    if !visitor.begin_while_block(&stat.block)? {
-        walk_block(&stat.block, visitor)?;
-    }
+    visit!(visitor.begin_while_block(&stat.block), {
+        walk!(walk_block(&stat.block, visitor));
+    });
     visitor.comments(&stat.end);
     visitor.end_while();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_doblockstat<T: AstVisitor<E>, E>(stat: &DoBlock, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_do_block(&stat.block)? {
-        walk_block(&stat.block, visitor)?;
-    }
+pub fn walk_doblockstat<T: AstVisitor<E>, E>(stat: &DoBlock, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_do_block(&stat.block), {
+        walk!(walk_block(&stat.block, visitor));
+    });
     visitor.comments(&stat.end);
     visitor.end_do_block();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_forstat<T: AstVisitor<E>, E>(stat: &ForStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_forstat<T: AstVisitor<E>, E>(stat: &ForStat, visitor: &mut T) -> VisitResult<E> {
     match stat {
         ForStat::ForNum(fornum) => walk_forenum(fornum, visitor),
         ForStat::ForList(forlist) => walk_forlist(forlist, visitor),
     }
 }
 
-pub fn walk_forenum<T: AstVisitor<E>, E>(stat: &ForNum, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_for_num(stat)? {
+pub fn walk_forenum<T: AstVisitor<E>, E>(stat: &ForNum, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_for_num(stat), {
         visitor.name(&stat.var);
         visitor.comments(&stat.equal);
         visitor.for_enum_equal();
-        walk_expr(&stat.init, visitor)?;
+        walk!(walk_expr(&stat.init, visitor));
         visitor.comments(&stat.init_comma);
         visitor.expr_sep();
-        walk_expr(&stat.limit, visitor)?;
+        walk!(walk_expr(&stat.limit, visitor));
         if let Some(expr) = &stat.step {
             visitor.comments(stat.limit_comma.as_ref().unwrap());
             visitor.expr_sep();
-            walk_expr(expr, visitor)?;
+            walk!(walk_expr(expr, visitor));
         }
-    }
+    });
     visitor.comments(&stat.do_);
-    if !visitor.begin_for_block(&stat.body)? {
-        walk_block(&stat.body, visitor)?;
-    }
+    visit!(visitor.begin_for_block(&stat.body), {
+        walk!(walk_block(&stat.body, visitor));
+    });
     visitor.comments(&stat.end);
     visitor.end_for();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_forlist<T: AstVisitor<E>, E>(stat: &ForList, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_for_list(stat)? {
+pub fn walk_forlist<T: AstVisitor<E>, E>(stat: &ForList, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_for_list(stat), {
         stat.vars.vars.iter().enumerate().for_each(|(i, var)| {
             visitor.comments(var);
             visitor.name(var);
@@ -300,64 +383,65 @@ pub fn walk_forlist<T: AstVisitor<E>, E>(stat: &ForList, visitor: &mut T) -> Res
         });
         visitor.comments(&stat.in_);
         visitor.for_list_in();
-        visitor.exprlist(&stat.exprs)?;
-    }
+        walk!(visitor.exprlist(&stat.exprs));
+    });
     visitor.comments(&stat.do_);
-    if !visitor.begin_for_block(&stat.body)? {
-        walk_block(&stat.body, visitor)?;
-    }
+    visit!(visitor.begin_for_block(&stat.body), {
+        walk!(walk_block(&stat.body, visitor));
+    });
     visitor.comments(&stat.end);
     visitor.end_for();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_repeatstat<T: AstVisitor<E>, E>(stat: &RepeatStat, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_repeat(&stat.block)? {
-        walk_block(&stat.block, visitor)?;
+pub fn walk_repeatstat<T: AstVisitor<E>, E>(stat: &RepeatStat, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_repeat(&stat.block), {
+        walk!(walk_block(&stat.block, visitor));
         visitor.comments(&stat.until);
         visitor.until();
-        walk_expr(&stat.cond, visitor)?;
-    }
+        walk!(walk_expr(&stat.cond, visitor));
+    });
     visitor.end_repeat();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_funcstat<T: AstVisitor<E>, E>(stat: &FuncStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_funcstat<T: AstVisitor<E>, E>(stat: &FuncStat, visitor: &mut T) -> VisitResult<E> {
     visitor.func(stat);
     walk_funcbody(&stat.body, visitor)
 }
 
-pub fn walk_localstat<T: AstVisitor<E>, E>(stat: &LocalStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_localstat<T: AstVisitor<E>, E>(stat: &LocalStat, visitor: &mut T) -> VisitResult<E> {
     visitor.local_stat(stat)
 }
 
-pub fn walk_labelstat<T: AstVisitor<E>, E>(stat: &LabelStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_labelstat<T: AstVisitor<E>, E>(stat: &LabelStat, visitor: &mut T) -> VisitResult<E> {
     visitor.label_stat(stat)
 }
 
-pub fn walk_retstat<T: AstVisitor<E>, E>(stat: &RetStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_retstat<T: AstVisitor<E>, E>(stat: &RetStat, visitor: &mut T) -> VisitResult<E> {
     visitor.ret_stat(stat)
 }
 
-pub fn walk_breakstat<T: AstVisitor<E>, E>(stat: &BreakStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_breakstat<T: AstVisitor<E>, E>(stat: &BreakStat, visitor: &mut T) -> VisitResult<E> {
     visitor.break_stat(stat)
 }
 
-pub fn walk_gotostat<T: AstVisitor<E>, E>(stat: &GotoStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_gotostat<T: AstVisitor<E>, E>(stat: &GotoStat, visitor: &mut T) -> VisitResult<E> {
     visitor.goto_stat(stat)
 }
 
-pub fn walk_assignstat<T: AstVisitor<E>, E>(stat: &AssignStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_assignstat<T: AstVisitor<E>, E>(stat: &AssignStat, visitor: &mut T) -> VisitResult<E> {
     visitor.assign_stat(stat)
 }
 
-pub fn walk_callstat<T: AstVisitor<E>, E>(stat: &CallStat, visitor: &mut T) -> Result<(), E> {
+pub fn walk_callstat<T: AstVisitor<E>, E>(stat: &CallStat, visitor: &mut T) -> VisitResult<E> {
     visitor.call_stat(stat)
 }
 
-pub fn walk_expr<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> Result<(), E> {
+pub fn walk_expr<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> VisitResult<E> {
     visitor.comments(expr);
-    if !visitor.expr(expr)? {
+    visitor.visit_expr(expr, &expr.source());
+    visit!(visitor.expr(expr), {
         match expr {
             Expr::Nil(_) => visitor.nil(),
             Expr::True(_) => visitor.true_(),
@@ -367,157 +451,341 @@ pub fn walk_expr<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> Result<()
             Expr::String(string) => visitor.string(string),
             Expr::VarArg(_) => visitor.vararg(),
             Expr::Name(s) => visitor.name(s),
-            Expr::ParenExpr(expr) => walk_parenexpr(expr, visitor)?,
+            Expr::ParenExpr(expr) => walk!(walk_parenexpr(expr, visitor)),
             Expr::FuncBody(body) => {
                 visitor.anonymous_func();
-                walk_funcbody(body, visitor)?
+                walk!(walk_funcbody(body, visitor))
             }
-            Expr::Table(t) => walk_table(t, visitor)?,
-            Expr::BinExpr(expr) => walk_binexpr(expr, visitor)?,
-            Expr::UnExpr(expr) => walk_unexpr(expr, visitor)?,
-            Expr::SuffixedExpr(expr) => walk_suffixedexpr(expr, visitor)?,
+            Expr::Table(t) => walk!(walk_table(t, visitor)),
+            Expr::BinExpr(expr) => walk!(walk_binexpr(expr, visitor)),
+            Expr::UnExpr(expr) => walk!(walk_unexpr(expr, visitor)),
+            Expr::SuffixedExpr(expr) => walk!(walk_suffixedexpr(expr, visitor)),
         };
-    }
-    Ok(())
+    });
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_funcbody<T: AstVisitor<E>, E>(body: &FuncBody, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_func_body(body)? {
-        walk_block(&body.block, visitor)?;
-    }
+pub fn walk_funcbody<T: AstVisitor<E>, E>(body: &FuncBody, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_func_body(body), {
+        visitor.enter_function(body);
+        walk!(walk_block(&body.block, visitor));
+        visitor.leave_function();
+    });
     visitor.comments(&body.end);
     visitor.end_func_body();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_binexpr<T: AstVisitor<E>, E>(expr: &BinExpr, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_bin_expr(expr)? {
-        walk_expr(&expr.left, visitor)?;
+pub fn walk_binexpr<T: AstVisitor<E>, E>(expr: &BinExpr, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_bin_expr(expr), {
+        walk!(walk_expr(&expr.left, visitor));
         visitor.binop(&expr.op);
-        walk_expr(&expr.right, visitor)?;
-    }
+        walk!(walk_expr(&expr.right, visitor));
+    });
     visitor.end_bin_expr();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_unexpr<T: AstVisitor<E>, E>(expr: &UnExpr, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_un_expr(expr)? {
+pub fn walk_unexpr<T: AstVisitor<E>, E>(expr: &UnExpr, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_un_expr(expr), {
         visitor.unop(&expr.op);
-        walk_expr(&expr.expr, visitor)?;
-    }
+        walk!(walk_expr(&expr.expr, visitor));
+    });
     visitor.end_un_expr();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
 pub fn walk_suffixedexpr<T: AstVisitor<E>, E>(
     expr: &SuffixedExpr,
     visitor: &mut T,
-) -> Result<(), E> {
-    if !visitor.begin_suffixed_expr(expr)? {
-        walk_expr(&expr.primary, visitor)?;
+) -> VisitResult<E> {
+    visit!(visitor.begin_suffixed_expr(expr), {
+        walk!(walk_expr(&expr.primary, visitor));
         for suf in expr.suffixes.iter() {
-            if !visitor.suffix(suf)? {
+            visit!(visitor.suffix(suf), {
                 match suf {
                     Suffix::Attr(_, attr) => visitor.attr(attr),
                     Suffix::Method(_, method) => visitor.method(method),
-                    Suffix::Index(_, index, _) => walk_index(index, visitor)?,
-                    Suffix::FuncArgs(args) => walk_funcargs(args, visitor)?,
+                    Suffix::Index(_, index, _) => walk!(walk_index(index, visitor)),
+                    Suffix::FuncArgs(args) => walk!(walk_funcargs(args, visitor)),
                 }
-            }
+            });
         }
-    }
+    });
     visitor.end_suffixed_expr();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
 pub fn walk_assinable<T: AstVisitor<E>, E>(
     assignable: &Assignable,
     visitor: &mut T,
-) -> Result<(), E> {
+) -> VisitResult<E> {
     match assignable {
-        Assignable::SuffixedExpr(s) => walk_suffixedexpr(s, visitor)?,
-        Assignable::Name(s) => visitor.name(s),
-    };
-    Ok(())
+        Assignable::SuffixedExpr(s) => walk_suffixedexpr(s, visitor),
+        Assignable::Name(s) => {
+            visitor.name(s);
+            Ok(VisitAction::Continue)
+        }
+        Assignable::ParenExpr(e) => walk_parenexpr(e, visitor),
+    }
 }
 
-pub fn walk_index<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_index(expr)? {
-        walk_expr(expr, visitor)?;
-    }
+pub fn walk_index<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_index(expr), {
+        walk!(walk_expr(expr, visitor));
+    });
     visitor.end_index();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_funcargs<T: AstVisitor<E>, E>(args: &FuncArgs, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_func_args(args)? {
+pub fn walk_funcargs<T: AstVisitor<E>, E>(args: &FuncArgs, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_func_args(args), {
         match args {
             FuncArgs::String(s) => visitor.string(s),
-            FuncArgs::Table(t) => walk_table(t, visitor)?,
+            FuncArgs::Table(t) => walk!(walk_table(t, visitor)),
             FuncArgs::Exprs(_, exprs, rp) => {
-                visitor.exprlist(exprs)?;
+                walk!(visitor.exprlist(exprs));
                 visitor.comments(rp);
             }
         }
-    }
+    });
     visitor.end_func_args();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_parenexpr<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_paren_expr(expr)? {
-        walk_expr(expr, visitor)?;
-    }
+pub fn walk_parenexpr<T: AstVisitor<E>, E>(expr: &Expr, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_paren_expr(expr), {
+        walk!(walk_expr(expr, visitor));
+    });
     visitor.end_paren_expr();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_table<T: AstVisitor<E>, E>(table: &Table, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_table(table)? {
-        walk_fields(&table.fields, visitor)?;
-    }
+pub fn walk_table<T: AstVisitor<E>, E>(table: &Table, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_table(table), {
+        walk!(walk_fields(&table.fields, visitor));
+    });
     visitor.end_table(table);
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_fields<T: AstVisitor<E>, E>(fields: &[Field], visitor: &mut T) -> Result<(), E> {
+pub fn walk_fields<T: AstVisitor<E>, E>(fields: &[Field], visitor: &mut T) -> VisitResult<E> {
     for field in fields.iter() {
         visitor.comments(field);
-        walk_field(field, visitor)?;
+        walk!(walk_field(field, visitor));
         visitor.field_sep();
     }
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_field<T: AstVisitor<E>, E>(field: &Field, visitor: &mut T) -> Result<(), E> {
+pub fn walk_field<T: AstVisitor<E>, E>(field: &Field, visitor: &mut T) -> VisitResult<E> {
     match field {
         Field::RecField(field) => walk_recfield(field, visitor),
         Field::ListField(field) => walk_listfield(field, visitor),
     }
 }
 
-pub fn walk_recfield<T: AstVisitor<E>, E>(field: &RecField, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_rec_field(field)? {
-        walk_fieldkey(&field.key, visitor)?;
+pub fn walk_recfield<T: AstVisitor<E>, E>(field: &RecField, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_rec_field(field), {
+        walk!(walk_fieldkey(&field.key, visitor));
         visitor.field_kv_sep();
-        walk_expr(&field.value, visitor)?;
-    }
+        walk!(walk_expr(&field.value, visitor));
+    });
     visitor.end_rec_field();
-    Ok(())
+    Ok(VisitAction::Continue)
 }
 
-pub fn walk_listfield<T: AstVisitor<E>, E>(field: &ListField, visitor: &mut T) -> Result<(), E> {
-    walk_expr(&field.value, visitor)?;
-    Ok(())
+pub fn walk_listfield<T: AstVisitor<E>, E>(field: &ListField, visitor: &mut T) -> VisitResult<E> {
+    walk_expr(&field.value, visitor)
 }
 
-pub fn walk_fieldkey<T: AstVisitor<E>, E>(key: &FieldKey, visitor: &mut T) -> Result<(), E> {
-    if !visitor.begin_field_key(key)? {
+pub fn walk_fieldkey<T: AstVisitor<E>, E>(key: &FieldKey, visitor: &mut T) -> VisitResult<E> {
+    visit!(visitor.begin_field_key(key), {
         match key {
             FieldKey::Name(s) => visitor.name(s),
-            FieldKey::Expr(_, expr, _) => walk_expr(expr, visitor)?,
+            FieldKey::Expr(_, expr, _) => walk!(walk_expr(expr, visitor)),
         };
-    }
+    });
     visitor.end_field_key(key);
+    Ok(VisitAction::Continue)
+}
+
+// result of hooks of `AstVisitorMut`
+pub enum VisitFlow<T> {
+    // travel children of the node
+    Continue,
+    // keep the node but don't travel its children
+    SkipChildren,
+    // replace the node, children of the new node are not traveled
+    Replace(T),
+}
+
+// visitor which is able to rewrite the tree, `begin_*` hooks are called before
+// children are traveled, and `end_*` hooks after, `SkipChildren` is the same as
+// `Continue` for `end_*` hooks
+pub trait AstVisitorMut<E = ()> {
+    fn begin_stat(&mut self, _stat: &mut Stat) -> Result<VisitFlow<Stat>, E> {
+        Ok(VisitFlow::Continue)
+    }
+    fn end_stat(&mut self, _stat: &mut Stat) -> Result<VisitFlow<Stat>, E> {
+        Ok(VisitFlow::Continue)
+    }
+    fn begin_expr(&mut self, _expr: &mut Expr) -> Result<VisitFlow<Expr>, E> {
+        Ok(VisitFlow::Continue)
+    }
+    fn end_expr(&mut self, _expr: &mut Expr) -> Result<VisitFlow<Expr>, E> {
+        Ok(VisitFlow::Continue)
+    }
+}
+
+pub fn walk_block_mut<T: AstVisitorMut<E>, E>(block: &mut Block, visitor: &mut T) -> Result<(), E> {
+    for stat in block.stats.iter_mut() {
+        walk_stat_mut(stat, visitor)?;
+    }
+    Ok(())
+}
+
+pub fn walk_stat_mut<T: AstVisitorMut<E>, E>(stat: &mut Stat, visitor: &mut T) -> Result<(), E> {
+    match visitor.begin_stat(stat)? {
+        VisitFlow::Replace(new_stat) => {
+            *stat = new_stat;
+            return Ok(());
+        }
+        VisitFlow::SkipChildren => return Ok(()),
+        VisitFlow::Continue => (),
+    }
+    match stat {
+        Stat::IfStat(stat) => {
+            for cond_block in stat.cond_blocks.iter_mut() {
+                walk_expr_mut(&mut cond_block.cond, visitor)?;
+                walk_block_mut(&mut cond_block.block, visitor)?;
+            }
+            if let Some(block) = &mut stat.else_block {
+                walk_block_mut(block, visitor)?;
+            }
+        }
+        Stat::WhileStat(stat) => {
+            walk_expr_mut(&mut stat.cond, visitor)?;
+            walk_block_mut(&mut stat.block, visitor)?;
+        }
+        Stat::DoBlock(stat) => walk_block_mut(&mut stat.block, visitor)?,
+        Stat::ForStat(ForStat::ForNum(stat)) => {
+            walk_expr_mut(&mut stat.init, visitor)?;
+            walk_expr_mut(&mut stat.limit, visitor)?;
+            if let Some(step) = &mut stat.step {
+                walk_expr_mut(step, visitor)?;
+            }
+            walk_block_mut(&mut stat.body, visitor)?;
+        }
+        Stat::ForStat(ForStat::ForList(stat)) => {
+            walk_exprlist_mut(&mut stat.exprs, visitor)?;
+            walk_block_mut(&mut stat.body, visitor)?;
+        }
+        Stat::RepeatStat(stat) => {
+            walk_block_mut(&mut stat.block, visitor)?;
+            walk_expr_mut(&mut stat.cond, visitor)?;
+        }
+        Stat::FuncStat(stat) => walk_block_mut(&mut stat.body.block, visitor)?,
+        Stat::LocalStat(stat) => {
+            if let Some(exprs) = &mut stat.exprs {
+                walk_exprlist_mut(exprs, visitor)?;
+            }
+        }
+        Stat::RetStat(stat) => {
+            if let Some(exprs) = &mut stat.exprs {
+                walk_exprlist_mut(exprs, visitor)?;
+            }
+        }
+        Stat::AssignStat(stat) => {
+            for assignable in stat.left.assignables.iter_mut() {
+                walk_assignable_mut(assignable, visitor)?;
+            }
+            walk_exprlist_mut(&mut stat.right, visitor)?;
+        }
+        Stat::CallStat(stat) => walk_assignable_mut(&mut stat.call, visitor)?,
+        Stat::LabelStat(_) | Stat::BreakStat(_) | Stat::GotoStat(_) => (),
+    }
+    if let VisitFlow::Replace(new_stat) = visitor.end_stat(stat)? {
+        *stat = new_stat;
+    }
+    Ok(())
+}
+
+pub fn walk_expr_mut<T: AstVisitorMut<E>, E>(expr: &mut Expr, visitor: &mut T) -> Result<(), E> {
+    match visitor.begin_expr(expr)? {
+        VisitFlow::Replace(new_expr) => {
+            *expr = new_expr;
+            return Ok(());
+        }
+        VisitFlow::SkipChildren => return Ok(()),
+        VisitFlow::Continue => (),
+    }
+    match expr {
+        Expr::ParenExpr(expr) => walk_expr_mut(expr, visitor)?,
+        Expr::FuncBody(body) => walk_block_mut(&mut body.block, visitor)?,
+        Expr::Table(table) => walk_table_mut(table, visitor)?,
+        Expr::BinExpr(expr) => {
+            walk_expr_mut(&mut expr.left, visitor)?;
+            walk_expr_mut(&mut expr.right, visitor)?;
+        }
+        Expr::UnExpr(expr) => walk_expr_mut(&mut expr.expr, visitor)?,
+        Expr::SuffixedExpr(expr) => walk_suffixedexpr_mut(expr, visitor)?,
+        _ => (),
+    }
+    if let VisitFlow::Replace(new_expr) = visitor.end_expr(expr)? {
+        *expr = new_expr;
+    }
+    Ok(())
+}
+
+fn walk_exprlist_mut<T: AstVisitorMut<E>, E>(
+    exprs: &mut ExprList,
+    visitor: &mut T,
+) -> Result<(), E> {
+    for expr in exprs.exprs.iter_mut() {
+        walk_expr_mut(expr, visitor)?;
+    }
+    Ok(())
+}
+
+fn walk_assignable_mut<T: AstVisitorMut<E>, E>(
+    assignable: &mut Assignable,
+    visitor: &mut T,
+) -> Result<(), E> {
+    match assignable {
+        Assignable::SuffixedExpr(expr) => walk_suffixedexpr_mut(expr, visitor),
+        Assignable::ParenExpr(expr) => walk_expr_mut(expr, visitor),
+        Assignable::Name(_) => Ok(()),
+    }
+}
+
+fn walk_suffixedexpr_mut<T: AstVisitorMut<E>, E>(
+    expr: &mut SuffixedExpr,
+    visitor: &mut T,
+) -> Result<(), E> {
+    walk_expr_mut(&mut expr.primary, visitor)?;
+    for suffix in expr.suffixes.iter_mut() {
+        match suffix {
+            Suffix::Index(_, key, _) => walk_expr_mut(key, visitor)?,
+            Suffix::FuncArgs(FuncArgs::Exprs(_, exprs, _)) => walk_exprlist_mut(exprs, visitor)?,
+            Suffix::FuncArgs(FuncArgs::Table(table)) => walk_table_mut(table, visitor)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn walk_table_mut<T: AstVisitorMut<E>, E>(table: &mut Table, visitor: &mut T) -> Result<(), E> {
+    for field in table.fields.iter_mut() {
+        match field {
+            Field::RecField(field) => {
+                if let FieldKey::Expr(_, key, _) = &mut field.key {
+                    walk_expr_mut(key, visitor)?;
+                }
+                walk_expr_mut(&mut field.value, visitor)?;
+            }
+            Field::ListField(field) => walk_expr_mut(&mut field.value, visitor)?,
+        }
+    }
     Ok(())
 }
@@ -1,30 +1,115 @@
 use crate::ast::*;
 use crate::ast_walker;
-use crate::ast_walker::AstVisitor;
+use crate::ast_walker::{AstVisitor, VisitAction, VisitResult};
 use crate::consts::Const;
 use crate::opcodes::*;
-use crate::proto::{Proto, ProtoContext};
+use crate::proto::{BlockContext, ContextSnapshot, Label, Proto, ProtoContext};
 use crate::types::Source;
 use crate::utils::success;
 use rslua_derive::Traceable;
 use rslua_traits::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+// categories of constant folding
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct FoldFlags(u8);
+
+impl FoldFlags {
+    pub const NONE: FoldFlags = FoldFlags(0);
+    pub const ARITH: FoldFlags = FoldFlags(1);
+    pub const BITWISE: FoldFlags = FoldFlags(1 << 1);
+    pub const CONCAT: FoldFlags = FoldFlags(1 << 2);
+    pub const COMPARE: FoldFlags = FoldFlags(1 << 3);
+    pub const LEN: FoldFlags = FoldFlags(1 << 4);
+    pub const ALL: FoldFlags = FoldFlags(0b11111);
+
+    pub fn contains(self, other: FoldFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: FoldFlags) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: FoldFlags) {
+        self.0 &= !other.0;
+    }
+}
+
+// same as luac, only arithmetic and bitwise operations are folded by default
+impl Default for FoldFlags {
+    fn default() -> Self {
+        FoldFlags::ARITH | FoldFlags::BITWISE
+    }
+}
+
+impl std::ops::BitOr for FoldFlags {
+    type Output = FoldFlags;
+    fn bitor(self, other: FoldFlags) -> FoldFlags {
+        FoldFlags(self.0 | other.0)
+    }
+}
 
 #[derive(Default, Traceable)]
 pub struct Compiler {
     proto_contexts: Vec<ProtoContext>,
+    // map lines without any instruction (e.g. blank or comment lines) to a no-op jump
+    pub dense_line_info: bool,
+    pub fold_flags: FoldFlags,
+    // max depth of nested expressions of a stat, deeper ones are not folded and fail to compile
+    // with "expression too complex" instead of overflowing the stack, no limit if not set
+    pub max_fold_depth: Option<usize>,
+    // print listing of each function when it's closed
+    debug: bool,
+    // where debug listings go, stderr if not set
+    debug_output: Option<Box<dyn Write + Send>>,
+    // state of the main proto before the current `run_append`
+    append_snapshot: Option<ContextSnapshot>,
+    // nesting depth of exprs being compiled, bounded by `max_fold_depth`
+    expr_depth: usize,
 }
 
-pub struct CompileError(pub String);
+#[derive(Debug)]
+pub struct CompileError {
+    pub message: String,
+    // where the error happens, known once the error leaves the expr or stat causing it
+    pub source: Option<Source>,
+    // message with its location
+    what: String,
+}
 
 impl CompileError {
     pub fn new(str: &str) -> Self {
-        CompileError(str.to_string())
+        CompileError {
+            message: str.to_string(),
+            source: None,
+            what: str.to_string(),
+        }
+    }
+
+    // the innermost source is kept
+    pub fn with_source(mut self, source: &Source) -> Self {
+        if self.source.is_none() {
+            self.what = format!(
+                "[compile error] {} at line [{}].",
+                self.message, source.line
+            );
+            self.source = Some(*source);
+        }
+        self
     }
 }
 
 impl Error for CompileError {
     fn what(&self) -> &str {
-        &self.0
+        &self.what
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.what)
     }
 }
 
@@ -91,14 +176,18 @@ impl Jump {
     }
 
     pub fn free(&self, context: &mut ProtoContext) {
+        self.free_to(context, self.reg.reg);
+    }
+
+    // materialize the boolean result to `target` instead of its own register
+    pub fn free_to(&self, context: &mut ProtoContext, target: u32) {
         let proto = &mut context.proto;
-        let target = self.reg.reg;
         if let Some(from) = self.reg_should_move {
             proto.code_move(target, from);
         }
         let false_pos = proto.code_bool(target, false, 1);
         let true_pos = proto.code_bool(target, true, 0);
-        self.fix(true_pos, false_pos, proto);
+        self.fix(target, true_pos, false_pos, proto);
         self.reg.free(context);
     }
 
@@ -129,21 +218,70 @@ impl Jump {
         self.reg_should_move = Some(from)
     }
 
-    fn fix(&self, true_pos: usize, false_pos: usize, proto: &mut Proto) {
+    fn fix(&self, reg: u32, true_pos: usize, false_pos: usize, proto: &mut Proto) {
         proto.fix_cond_jump_pos(true_pos, false_pos, self.pc);
-        for pc in self.true_jumps.iter() {
-            proto.fix_jump_pos(true_pos, *pc)
-        }
-        for pc in self.false_jumps.iter() {
-            proto.fix_jump_pos(false_pos, *pc)
-        }
+        // jumps of `and`/`or` operands carry their own values to the end
+        let end = true_pos + 1;
+        proto.patch_list(&self.true_jumps, reg, end, true_pos);
+        proto.patch_list(&self.false_jumps, reg, end, false_pos);
     }
 }
 
+// where a name is resolved to
+#[derive(Debug)]
+pub enum Var {
+    Local(u32),
+    UpVal(u32),
+    Global,
+}
+
+// where an assignable is stored to
+#[derive(Debug)]
+pub enum Target {
+    Local(u32),
+    UpVal(u32),
+    // rk of the name, which is added to consts when the target is resolved like luac
+    Global(u32),
+    // register of table and rk of key
+    Index(u32, u32),
+}
+
+// result of `and`/`or`, whose operands may leave early by jumps
 #[derive(Debug)]
 pub struct Test {
-    pub pending_tests: Vec<usize>,
-    pub jump: Jump,
+    // register to store the result
+    pub reg: Reg,
+    // local holding value of last operand, which is not moved to reg yet
+    pub value: Option<u32>,
+    pub true_jumps: Vec<usize>,
+    pub false_jumps: Vec<usize>,
+}
+
+impl Test {
+    pub fn free(&self, context: &mut ProtoContext) {
+        let proto = &mut context.proto;
+        let reg = self.reg.reg;
+        if let Some(from) = self.value {
+            if from != reg {
+                proto.code_move(reg, from);
+            }
+        }
+        // comparison results are converted to booleans
+        let (true_pos, false_pos) =
+            if proto.need_value(&self.true_jumps) || proto.need_value(&self.false_jumps) {
+                let skip = proto.code_jmp(NO_JUMP, 0);
+                let false_pos = proto.code_bool(reg, false, 1);
+                let true_pos = proto.code_bool(reg, true, 0);
+                proto.fix_jump_pos(true_pos + 1, skip);
+                (true_pos, false_pos)
+            } else {
+                (NO_JUMP as usize, NO_JUMP as usize)
+            };
+        let end = proto.code.len();
+        proto.patch_list(&self.true_jumps, reg, end, true_pos);
+        proto.patch_list(&self.false_jumps, reg, end, false_pos);
+        self.reg.free(context);
+    }
 }
 
 #[derive(Debug)]
@@ -153,8 +291,6 @@ pub enum ExprResult {
     Jump(Jump),
     Test(Test),
     Nil,
-    True,
-    False,
 }
 
 impl ExprResult {
@@ -174,15 +310,14 @@ impl ExprResult {
         ExprResult::Jump(Jump::new(reg, pc))
     }
 
-    pub fn get_rk(&self, context: &mut ProtoContext) -> u32 {
+    pub fn get_rk(&self, context: &mut ProtoContext) -> Result<u32, CompileError> {
         match self {
-            ExprResult::Const(k) => {
-                let index = context.proto.add_const(k.clone());
-                MASK_K | index
-            }
-            ExprResult::Reg(i) => i.reg,
-            ExprResult::Jump(j) => j.reg.reg,
-            _ => unreachable!(),
+            ExprResult::Const(k) => context.const_to_rk(k.clone()),
+            ExprResult::Reg(i) => Ok(i.reg),
+            ExprResult::Jump(j) => Ok(j.reg.reg),
+            ExprResult::Test(t) => Ok(t.reg.reg),
+            // nil is a const like luac, e.g. `EQ 1 0 k(nil)`
+            ExprResult::Nil => context.const_to_rk(Const::Nil),
         }
     }
 
@@ -190,21 +325,87 @@ impl ExprResult {
         match self {
             ExprResult::Reg(r) => r.free(context),
             ExprResult::Jump(j) => j.free(context),
+            ExprResult::Test(t) => t.free(context),
             _ => (),
         };
     }
 }
 
 impl Compiler {
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    pub fn set_debug_output(&mut self, output: Box<dyn Write + Send>) {
+        self.debug_output = Some(output);
+    }
+
     pub fn run(&mut self, block: &Block) -> CompileResult {
         self.main_func(block)
     }
 
+    // compile block and append it to the main proto, which is kept across calls.
+    // if it fails, the main proto is restored as before the call by the next `run_append` or `finish`
+    pub fn run_append(&mut self, block: &Block) -> Result<(), CompileError> {
+        self.open_main_proto();
+        self.restore_failed_append();
+        self.append_snapshot = Some(self.context().snapshot());
+        self.append_block(block)?;
+        self.append_snapshot = None;
+        Ok(())
+    }
+
+    fn append_block(&mut self, block: &Block) -> Result<(), CompileError> {
+        self.enter_block(Compiler::tail_labels(block));
+        ast_walker::walk_block(block, self)?;
+        // locals stay visible to following chunks, only labels are dropped
+        let context = self.context();
+        if let Some(block) = context.blocks.pop() {
+            context.labels.truncate(block.first_label);
+        }
+        self.check_pending_gotos()
+    }
+
+    // close the main proto built by `run_append` and take it out
+    pub fn finish(&mut self) -> CompileResult {
+        self.open_main_proto();
+        self.restore_failed_append();
+        let context = self.context();
+        context.proto.close();
+        context.remove_vars(0);
+        context.reg_top = 0;
+        Ok(self.pop_proto())
+    }
+
+    // drop everything generated by an append which didn't finish, including contexts of its functions
+    fn restore_failed_append(&mut self) {
+        if let Some(snapshot) = self.append_snapshot.take() {
+            self.expr_depth = 0;
+            self.proto_contexts.truncate(1);
+            self.context().restore(&snapshot);
+        }
+    }
+
+    fn open_main_proto(&mut self) {
+        if self.proto_contexts.is_empty() {
+            self.push_proto();
+            let proto = self.proto();
+            proto.is_vararg = true;
+            proto.open();
+        }
+    }
+
     fn main_func(&mut self, block: &Block) -> CompileResult {
         self.push_proto();
-        self.proto().open();
-        ast_walker::walk_block(block, self)?;
-        self.proto().close();
+        let proto = self.proto();
+        // main chunk receives command line arguments as varargs
+        proto.is_vararg = true;
+        proto.open();
+        // the implicit return of an empty chunk is on the first line, like luac
+        if block.stats.is_empty() {
+            self.context().set_line(1);
+        }
+        self.func_block(block, None)?;
         Ok(self.pop_proto())
     }
 
@@ -213,12 +414,26 @@ impl Compiler {
     }
 
     fn pop_proto(&mut self) -> Proto {
-        if let Some(context) = self.proto_contexts.pop() {
+        if let Some(mut context) = self.proto_contexts.pop() {
+            context.check_reg_balance();
+            context.fix_line_info();
+            if self.debug {
+                self.debug_proto(&context.proto);
+            }
             return context.proto;
         }
         unreachable!()
     }
 
+    // listing is only for inspection, failures of writing it are ignored
+    fn debug_proto(&mut self, proto: &Proto) {
+        let listing = format!("{:?}", proto);
+        let _ = match &mut self.debug_output {
+            Some(output) => output.write_all(listing.as_bytes()),
+            None => io::stderr().write_all(listing.as_bytes()),
+        };
+    }
+
     // get current proto ref from stack
     fn proto(&mut self) -> &mut Proto {
         &mut self.context().proto
@@ -232,24 +447,57 @@ impl Compiler {
         unreachable!()
     }
 
-    fn adjust_assign(&mut self, num_left: usize, right_exprs: Option<&ExprList>) -> i32 {
+    fn adjust_assign(
+        &mut self,
+        num_left: usize,
+        right_exprs: Option<&ExprList>,
+    ) -> Result<i32, CompileError> {
         let extra = num_left as i32 - right_exprs.map_or(0, |v| v.exprs.len()) as i32;
-        if right_exprs.and_then(|v| v.exprs.last()).is_some() {
-            // todo!("process multi return value")
+        if let Some(last) = right_exprs.and_then(|v| v.exprs.last()) {
+            if Compiler::is_multret(last) {
+                // last expr fills the rest of left values
+                let nret = (extra + 1).max(0) as u32;
+                self.proto().set_returns(nret);
+                if nret > 1 {
+                    self.context().reserve_regs(nret - 1)?;
+                }
+                return Ok(extra);
+            }
         }
 
         if extra > 0 {
             let context = self.context();
             let from = context.get_reg_top();
-            context.reserve_regs(extra as u32);
+            context.reserve_regs(extra as u32)?;
             context.proto.code_nil(from, extra as u32);
         }
 
-        extra
+        Ok(extra)
     }
 
     // process expr and return const index or register index
+    // instructions of an expr are attributed to the line where it starts,
+    // the enclosing expr or stat continues with its own line afterwards
     fn expr(&mut self, expr: &Expr, reg: Option<u32>) -> Result<ExprResult, CompileError> {
+        if self.max_fold_depth.is_some_and(|max| self.expr_depth > max) {
+            return Err(CompileError::new("expression too complex").with_source(&expr.source()));
+        }
+        let context = self.context();
+        let parent_line = context.current_line;
+        context.set_line(expr.source().line as u32);
+        self.expr_depth += 1;
+        let result = self.expr_without_line(expr, reg);
+        self.expr_depth -= 1;
+        let result = result.map_err(|e| e.with_source(&expr.source()))?;
+        self.context().set_line(parent_line);
+        Ok(result)
+    }
+
+    fn expr_without_line(
+        &mut self,
+        expr: &Expr,
+        reg: Option<u32>,
+    ) -> Result<ExprResult, CompileError> {
         let proto = self.proto();
         let result = match expr {
             Expr::Int(i) => ExprResult::new_const(Const::Int(i.value())),
@@ -261,18 +509,34 @@ impl Compiler {
                 ExprResult::new_const(k)
             }
             Expr::Nil(_) => ExprResult::Nil,
-            Expr::True(_) => ExprResult::True,
-            Expr::False(_) => ExprResult::False,
-            Expr::Name(name) => {
-                if let Some(src) = proto.get_local_var(&name.value()) {
-                    return Ok(ExprResult::new_const_reg(src));
+            Expr::True(_) => ExprResult::new_const(Const::Bool(true)),
+            Expr::False(_) => ExprResult::new_const(Const::Bool(false)),
+            Expr::Name(name) => match self.resolve_var(&name.value()) {
+                Var::Local(src) => ExprResult::new_const_reg(src),
+                Var::UpVal(up_var) => {
+                    let alloc_reg = self.alloc_reg(&reg)?;
+                    self.proto().code_get_up_val(alloc_reg.reg, up_var);
+                    ExprResult::Reg(alloc_reg)
                 }
-                // TODO : process upval and globals
-                todo!()
+                Var::Global => self.code_get_global(&name.value(), reg)?,
+            },
+            Expr::VarArg(_) => {
+                if !proto.is_vararg {
+                    return Err(CompileError::new(
+                        "cannot use '...' outside a vararg function",
+                    ));
+                }
+                // one value by default
+                let alloc_reg = self.alloc_reg(&reg)?;
+                self.proto().code_vararg(alloc_reg.reg, 1);
+                ExprResult::Reg(alloc_reg)
             }
+            Expr::FuncBody(body) => self.code_func_body(body, false, reg)?,
+            Expr::Table(table) => self.code_table(table, reg)?,
+            Expr::SuffixedExpr(expr) => self.code_suffixed_expr(expr, reg)?,
             Expr::BinExpr(_) | Expr::UnExpr(_) => self.folding_or_code(expr, reg)?,
-            Expr::ParenExpr(expr) => self.folding_or_code(expr, reg)?,
-            _ => todo!(),
+            // multiple results are adjusted to one, which is the default of calls
+            Expr::ParenExpr(expr) => self.expr(Compiler::strip_parens(expr), reg)?,
         };
         Ok(result)
     }
@@ -285,52 +549,117 @@ impl Compiler {
     ) -> Result<ExprResult, CompileError> {
         if let Some(k) = self.try_const_folding(expr)? {
             Ok(ExprResult::new_const(k))
+        } else if let Some(b) = self.try_comp_folding(expr)? {
+            Ok(ExprResult::new_const(Const::Bool(b)))
         } else {
             self.code_expr(expr, reg)
         }
     }
 
-    // try constant folding expr
+    // nested parentheses are the same as one
+    fn strip_parens(mut expr: &Expr) -> &Expr {
+        while let Expr::ParenExpr(inner) = expr {
+            expr = inner;
+        }
+        expr
+    }
+
+    // try constant folding expr, which is nested in the expr being compiled,
+    // so folding and codegen together don't go deeper than `max_fold_depth`
     fn try_const_folding(&self, expr: &Expr) -> Result<Option<Const>, CompileError> {
+        self.const_folding_at(expr, self.expr_depth.saturating_sub(1))
+    }
+
+    fn const_folding_at(&self, expr: &Expr, depth: usize) -> Result<Option<Const>, CompileError> {
+        if self.max_fold_depth.is_some_and(|max| depth > max) {
+            return Ok(None);
+        }
         match expr {
             Expr::Int(i) => return success(Const::Int(i.value())),
             Expr::Float(f) => return success(Const::Float(f.value())),
             Expr::String(s) => return success(Const::Str(s.value())),
-            Expr::BinExpr(bin) => match bin.op {
-                BinOp::Add(_)
-                | BinOp::Minus(_)
-                | BinOp::Mul(_)
-                | BinOp::Div(_)
-                | BinOp::IDiv(_)
-                | BinOp::Mod(_)
-                | BinOp::Pow(_)
-                | BinOp::BAnd(_)
-                | BinOp::BOr(_)
-                | BinOp::BXor(_)
-                | BinOp::Shl(_)
-                | BinOp::Shr(_) => {
+            Expr::True(_) => return success(Const::Bool(true)),
+            Expr::False(_) => return success(Const::Bool(false)),
+            Expr::BinExpr(bin) => {
+                let flag = match bin.op {
+                    BinOp::Add(_)
+                    | BinOp::Minus(_)
+                    | BinOp::Mul(_)
+                    | BinOp::Div(_)
+                    | BinOp::IDiv(_)
+                    | BinOp::Mod(_)
+                    | BinOp::Pow(_) => FoldFlags::ARITH,
+                    BinOp::BAnd(_)
+                    | BinOp::BOr(_)
+                    | BinOp::BXor(_)
+                    | BinOp::Shl(_)
+                    | BinOp::Shr(_) => FoldFlags::BITWISE,
+                    BinOp::Concat(_) => FoldFlags::CONCAT,
+                    _ => FoldFlags::NONE,
+                };
+                if flag != FoldFlags::NONE && self.fold_flags.contains(flag) {
                     if let (Some(l), Some(r)) = (
-                        self.try_const_folding(&bin.left)?,
-                        self.try_const_folding(&bin.right)?,
+                        self.const_folding_at(&bin.left, depth + 1)?,
+                        self.const_folding_at(&bin.right, depth + 1)?,
                     ) {
-                        if let Some(k) = self.const_folding_bin_op(&bin.op, l, r)? {
+                        let k = self
+                            .const_folding_bin_op(&bin.op, l, r)
+                            .map_err(|e| e.with_source(&expr.source()))?;
+                        if let Some(k) = k {
                             return success(k);
                         }
                     }
                 }
-                _ => (),
-            },
-            Expr::UnExpr(un) => match un.op {
-                UnOp::BNot(_) | UnOp::Minus(_) => {
-                    if let Some(k) = self.try_const_folding(&un.expr)? {
-                        if let Some(k) = self.const_folding_un_op(&un.op, k)? {
+            }
+            // `not` is always folded, like luac does
+            Expr::UnExpr(UnExpr {
+                op: UnOp::Not(_),
+                expr,
+            }) => {
+                if let Some(k) = self.const_folding_at(expr, depth + 1)? {
+                    return success(Const::Bool(!k.is_truthy()));
+                }
+            }
+            Expr::UnExpr(un) => {
+                let flag = match un.op {
+                    UnOp::Minus(_) => FoldFlags::ARITH,
+                    UnOp::BNot(_) => FoldFlags::BITWISE,
+                    UnOp::Len(_) => FoldFlags::LEN,
+                    _ => FoldFlags::NONE,
+                };
+                if flag != FoldFlags::NONE && self.fold_flags.contains(flag) {
+                    if let Some(k) = self.const_folding_at(&un.expr, depth + 1)? {
+                        let k = self
+                            .const_folding_un_op(&un.op, k)
+                            .map_err(|e| e.with_source(&expr.source()))?;
+                        if let Some(k) = k {
                             return success(k);
                         }
                     }
                 }
-                _ => (),
-            },
-            Expr::ParenExpr(expr) => return self.try_const_folding(expr),
+            }
+            Expr::ParenExpr(expr) => {
+                return self.const_folding_at(Compiler::strip_parens(expr), depth + 1)
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    // try folding comparison of constants to a boolean
+    fn try_comp_folding(&self, expr: &Expr) -> Result<Option<bool>, CompileError> {
+        match expr {
+            Expr::BinExpr(bin)
+                if bin.op.is_comp() && self.fold_flags.contains(FoldFlags::COMPARE) =>
+            {
+                if let (Some(l), Some(r)) = (
+                    self.try_const_folding(&bin.left)?,
+                    self.try_const_folding(&bin.right)?,
+                ) {
+                    return Ok(l.compare(&r, &bin.op));
+                }
+            }
+            Expr::ParenExpr(expr) => return self.try_comp_folding(Compiler::strip_parens(expr)),
             _ => (),
         }
         Ok(None)
@@ -339,7 +668,9 @@ impl Compiler {
     fn code_expr(&mut self, expr: &Expr, reg: Option<u32>) -> Result<ExprResult, CompileError> {
         match expr {
             Expr::BinExpr(bin) => match bin.op {
-                BinOp::And(_) => self.code_and(reg, &bin.left, &bin.right),
+                BinOp::And(_) => self.code_and_or(true, reg, &bin.left, &bin.right),
+                BinOp::Or(_) => self.code_and_or(false, reg, &bin.left, &bin.right),
+                BinOp::Concat(_) => self.code_concat(reg, &bin.left, &bin.right),
                 _ => self.code_bin_op(&bin.op, reg, &bin.left, &bin.right),
             },
             Expr::UnExpr(un) => {
@@ -373,6 +704,7 @@ impl Compiler {
             BinOp::BXor(_) => (l ^ r)?,
             BinOp::Shl(_) => (l << r)?,
             BinOp::Shr(_) => (l >> r)?,
+            BinOp::Concat(_) => l.concat(r)?,
             _ => None,
         };
         Ok(result)
@@ -382,6 +714,7 @@ impl Compiler {
         let result = match op {
             UnOp::Minus(_) => k.minus()?,
             UnOp::BNot(_) => k.bnot()?,
+            UnOp::Len(_) => k.str_len()?,
             _ => None,
         };
         Ok(result)
@@ -394,14 +727,18 @@ impl Compiler {
             right_input = match &left {
                 ExprResult::Reg(r) if !is_input_reusable(r.reg, input_reg) => None,
                 ExprResult::Jump(j) if !is_input_reusable(j.reg.reg, input_reg) => None,
+                ExprResult::Test(t) if !is_input_reusable(t.reg.reg, input_reg) => None,
                 _ => input,
             };
         };
         right_input
     }
 
-    fn alloc_reg(&mut self, input: &Option<u32>) -> Reg {
-        input.map_or_else(|| Reg::new_temp(self.context().reserve_regs(1)), Reg::new)
+    fn alloc_reg(&mut self, input: &Option<u32>) -> Result<Reg, CompileError> {
+        match input {
+            Some(reg) => Ok(Reg::new(*reg)),
+            None => Ok(Reg::new_temp(self.context().reserve_regs(1)?)),
+        }
     }
 
     fn code_bin_op(
@@ -425,29 +762,28 @@ impl Compiler {
         // resolve previous expr result
         right.resolve(self.context());
 
-        let alloc_reg = self.alloc_reg(&input);
+        let alloc_reg = self.alloc_reg(&input)?;
         let reg = alloc_reg.reg;
         let mut result = ExprResult::Reg(alloc_reg);
 
         // get rk of left and right expr
-        let mut get_rk = || {
-            let left_rk = left.get_rk(self.context());
-            let right_rk = right.get_rk(self.context());
-            (left_rk, right_rk)
-        };
+        let reg_top = self.context().get_reg_top();
+        let left_rk = left.get_rk(self.context())?;
+        let right_rk = right.get_rk(self.context())?;
 
         // gennerate opcode of binop
         match op {
             _ if op.is_comp() => {
-                let (left_rk, right_rk) = get_rk();
                 result = self.code_comp(op, result, left_rk, right_rk);
             }
             _ => {
-                let (left_rk, right_rk) = get_rk();
                 self.proto().code_bin_op(op, reg, left_rk, right_rk);
             }
         };
 
+        // free consts loaded to registers
+        let context = self.context();
+        context.free_reg(context.get_reg_top() - reg_top);
         Ok(result)
     }
 
@@ -462,60 +798,217 @@ impl Compiler {
 
                 let proto = self.proto();
                 proto.code_comp(op, left, right);
-                let jump = proto.code_jmp(NO_JUMP, 0);
+                let jump = proto.code_cond_jmp();
                 ExprResult::new_jump(reg, jump)
             }
             _ => unreachable!(),
         }
     }
 
-    fn code_and(
+    // concat is right associative, so `a .. b .. c` is flattened from right side
+    // and compiled to a single CONCAT over consecutive registers
+    fn code_concat(
         &mut self,
         input: Option<u32>,
         left_expr: &Expr,
         right_expr: &Expr,
     ) -> Result<ExprResult, CompileError> {
-        // get left expr result
-        let left = self.expr(left_expr, input)?;
-        match left {
-            // do const folding if left is const value
-            ExprResult::True | ExprResult::Const(_) => self.expr(right_expr, input),
-            ExprResult::Jump(j) => self.go_if_true(j, right_expr),
-            ExprResult::Reg(reg) => {
-                let jump = self.code_test_with_jump(input, &reg);
-                self.go_if_true(jump, right_expr)
+        let mut operands = vec![left_expr];
+        let mut right_expr = right_expr;
+        while let Expr::BinExpr(bin) = right_expr {
+            match bin.op {
+                BinOp::Concat(_) => {
+                    operands.push(&bin.left);
+                    right_expr = &bin.right;
+                }
+                _ => break,
+            }
+        }
+        operands.push(right_expr);
+
+        // fold adjacent constants
+        let mut items: Vec<(&Expr, Option<Const>)> = Vec::new();
+        for operand in operands {
+            let k = if self.fold_flags.contains(FoldFlags::CONCAT) {
+                self.try_const_folding(operand)?
+            } else {
+                None
+            };
+            if let (Some(k), Some((_, Some(last)))) = (&k, items.last_mut()) {
+                if let Some(folded) = last.clone().concat(k.clone())? {
+                    *last = folded;
+                    continue;
+                }
+            }
+            items.push((operand, k));
+        }
+
+        let context = self.context();
+        let (base, result) = match input {
+            Some(reg) if reg + 1 == context.get_reg_top() => (reg, ExprResult::new_const_reg(reg)),
+            _ => {
+                let reg = context.reserve_regs(1)?;
+                (reg, ExprResult::Reg(Reg::new_temp(reg)))
             }
-            _ => todo!(),
+        };
+        for (i, (operand, k)) in items.iter().enumerate() {
+            let reg = if i == 0 {
+                base
+            } else {
+                self.context().reserve_regs(1)?
+            };
+            let operand = match k {
+                Some(k) => ExprResult::new_const(k.clone()),
+                None => self.expr(operand, Some(reg))?,
+            };
+            self.save_result(operand, reg);
         }
+        let last = base + items.len() as u32 - 1;
+        let context = self.context();
+        context.free_reg(context.get_reg_top() - base - 1);
+        context.proto.code_concat(base, base, last);
+        Ok(result)
     }
 
-    // Short circuit, jump when it is false
-    fn go_if_true(
+    // short circuit `and` if `is_and` is true, or `or` otherwise
+    fn code_and_or(
         &mut self,
-        mut jump: Jump,
+        is_and: bool,
+        input: Option<u32>,
+        left_expr: &Expr,
         right_expr: &Expr,
     ) -> Result<ExprResult, CompileError> {
-        jump.inverse_falsy_cond(self.context());
-        let right = self.expr(right_expr, Some(jump.reg.reg))?;
-        println!("{:?}", right_expr);
-        match right {
-            ExprResult::Jump(mut rj) => {
-                rj.concat_false_jumps(&mut jump);
-                Ok(ExprResult::Jump(rj))
-            }
-            ExprResult::Reg(r) => Ok(ExprResult::Reg(r)),
-            _ => {
-                println!("{:?}", right);
-                unreachable!()
+        // get left expr result
+        let left = self.expr(left_expr, input)?;
+        let left_is_true = match &left {
+            ExprResult::Const(k) => Some(k.is_truthy()),
+            ExprResult::Nil => Some(false),
+            _ => None,
+        };
+        // do const folding if left is const value
+        match left_is_true {
+            Some(b) if b == is_and => return self.expr(right_expr, input),
+            Some(_) => return Ok(left),
+            None => (),
+        }
+
+        // jumps leaving with the value of left expr
+        let jumps = if is_and {
+            self.go_if_true(left)
+        } else {
+            self.go_if_false(left)
+        };
+
+        let reg = self.alloc_reg(&input)?;
+        let right = self.expr(right_expr, Some(reg.reg))?;
+        let mut result = match right {
+            ExprResult::Jump(j) => ExprResult::Jump(Jump { reg, ..j }),
+            ExprResult::Test(t) => ExprResult::Test(Test { reg, ..t }),
+            ExprResult::Reg(r) if r.is_const() => ExprResult::Test(Test {
+                reg,
+                value: Some(r.reg),
+                true_jumps: Vec::new(),
+                false_jumps: Vec::new(),
+            }),
+            right => {
+                self.save_result(right, reg.reg);
+                ExprResult::Test(Test {
+                    reg,
+                    value: None,
+                    true_jumps: Vec::new(),
+                    false_jumps: Vec::new(),
+                })
             }
+        };
+        let (true_jumps, false_jumps) = match &mut result {
+            ExprResult::Jump(j) => (&mut j.true_jumps, &mut j.false_jumps),
+            ExprResult::Test(t) => (&mut t.true_jumps, &mut t.false_jumps),
+            _ => unreachable!(),
+        };
+        if is_and {
+            false_jumps.extend(jumps);
+        } else {
+            true_jumps.extend(jumps);
         }
+        Ok(result)
     }
 
-    fn code_test_with_jump(&mut self, input: Option<u32>, reg: &Reg) -> Jump {
+    // emit jumps which are taken if result is falsy, go ahead if it is truthy
+    fn go_if_true(&mut self, result: ExprResult) -> Vec<usize> {
+        match result {
+            ExprResult::Const(k) if k.is_truthy() => Vec::new(),
+            ExprResult::Const(_) | ExprResult::Nil => vec![self.proto().code_jmp(NO_JUMP, 0)],
+            ExprResult::Reg(r) => vec![self.jump_on_cond(&r, false)],
+            ExprResult::Jump(j) => {
+                j.free_reg(self.context());
+                j.inverse_falsy_cond(self.context());
+                self.patch_to_here(&j.true_jumps);
+                let mut false_jumps = j.false_jumps;
+                false_jumps.push(j.pc);
+                false_jumps
+            }
+            ExprResult::Test(t) => {
+                let jump = self.jump_on_test(&t, false);
+                self.patch_to_here(&t.true_jumps);
+                let mut false_jumps = t.false_jumps;
+                false_jumps.push(jump);
+                false_jumps
+            }
+        }
+    }
+
+    // emit jumps which are taken if result is truthy, go ahead if it is falsy
+    fn go_if_false(&mut self, result: ExprResult) -> Vec<usize> {
+        match result {
+            ExprResult::Const(k) if k.is_truthy() => vec![self.proto().code_jmp(NO_JUMP, 0)],
+            ExprResult::Const(_) | ExprResult::Nil => Vec::new(),
+            ExprResult::Reg(r) => vec![self.jump_on_cond(&r, true)],
+            ExprResult::Jump(j) => {
+                j.free_reg(self.context());
+                self.patch_to_here(&j.false_jumps);
+                let mut true_jumps = j.true_jumps;
+                true_jumps.push(j.pc);
+                true_jumps
+            }
+            ExprResult::Test(t) => {
+                let jump = self.jump_on_test(&t, true);
+                self.patch_to_here(&t.false_jumps);
+                let mut true_jumps = t.true_jumps;
+                true_jumps.push(jump);
+                true_jumps
+            }
+        }
+    }
+
+    // jump if the truthiness of register equals to `cond`,
+    // the value is copied by TESTSET whose target is set when jump is patched
+    fn jump_on_cond(&mut self, r: &Reg, cond: bool) -> usize {
+        r.free(self.context());
+        self.code_cond_jump(r.reg, !r.is_const(), cond)
+    }
+
+    fn jump_on_test(&mut self, t: &Test, cond: bool) -> usize {
+        t.reg.free(self.context());
+        match t.value {
+            Some(reg) => self.code_cond_jump(reg, false, cond),
+            None => self.code_cond_jump(t.reg.reg, true, cond),
+        }
+    }
+
+    // `reg` is computed by last instruction if `relocatable` is true
+    fn code_cond_jump(&mut self, reg: u32, relocatable: bool, cond: bool) -> usize {
         let proto = self.proto();
-        proto.code_test_set(NO_REG, reg.reg, 0);
-        let jump = proto.code_jmp(NO_JUMP, 0);
-        Jump::new(self.alloc_reg(&input), jump)
+        if let Some(last) = proto.code.last() {
+            // `not a` is tested on `a` directly
+            if relocatable && last.get_op() == OpCode::Not && last.get_arg_A() == reg {
+                let src = last.get_arg_B();
+                proto.code.pop();
+                proto.code_test(src, if cond { 0 } else { 1 });
+                return proto.code_cond_jmp();
+            }
+        }
+        proto.code_test_set(NO_REG, reg, if cond { 1 } else { 0 });
+        proto.code_cond_jmp()
     }
 
     fn code_un_op(
@@ -524,12 +1017,21 @@ impl Compiler {
         input: Option<u32>,
         expr: ExprResult,
     ) -> Result<ExprResult, CompileError> {
-        let src = expr.get_rk(self.context());
+        // operand of unary ops must be a register, consts not folded are loaded first
+        if let ExprResult::Const(_) | ExprResult::Nil = expr {
+            let alloc_reg = self.alloc_reg(&input)?;
+            let reg = alloc_reg.reg;
+            self.save_result(expr, reg);
+            self.proto().code_un_op(op, reg, reg);
+            return Ok(ExprResult::Reg(alloc_reg));
+        }
+
+        let src = expr.get_rk(self.context())?;
 
         // resolve previous result
         expr.resolve(self.context());
 
-        let alloc_reg = self.alloc_reg(&input);
+        let alloc_reg = self.alloc_reg(&input)?;
         let reg = alloc_reg.reg;
         let result = ExprResult::Reg(alloc_reg);
 
@@ -546,69 +1048,923 @@ impl Compiler {
         input: Option<u32>,
         expr: &Expr,
     ) -> Result<ExprResult, CompileError> {
-        if self.try_const_folding(expr)?.is_some() {
-            Ok(ExprResult::False)
-        } else {
-            let result = self.expr(expr, input)?;
-            match &result {
-                ExprResult::Jump(j) => {
-                    j.inverse_falsy_cond(self.context());
-                    Ok(result)
-                }
-                ExprResult::Nil | ExprResult::False => Ok(ExprResult::True),
-                ExprResult::Const(_) | ExprResult::True => Ok(ExprResult::False),
-                _ => self.code_un_op(op, input, result),
+        let result = self.expr(expr, input)?;
+        match &result {
+            ExprResult::Jump(j) => {
+                j.inverse_falsy_cond(self.context());
+                Ok(result)
             }
+            ExprResult::Nil => Ok(ExprResult::new_const(Const::Bool(true))),
+            ExprResult::Const(k) => Ok(ExprResult::new_const(Const::Bool(!k.is_truthy()))),
+            _ => self.code_un_op(op, input, result),
         }
     }
 
     // process expr and save to register
     fn expr_and_save(&mut self, expr: &Expr, save_reg: Option<u32>) -> Result<u32, CompileError> {
-        let reg = save_reg.unwrap_or_else(|| self.context().reserve_regs(1));
+        let reg = match save_reg {
+            Some(reg) => reg,
+            None => self.context().reserve_regs(1)?,
+        };
 
         // use a register to store temp result
         let temp_reg = if Some(reg) != save_reg {
             reg
         } else {
-            self.context().reserve_regs(1)
+            self.context().reserve_regs(1)?
         };
 
         let result = self.expr(expr, Some(temp_reg))?;
+        self.save_result(result, reg);
+
+        if temp_reg != reg {
+            self.context().free_reg(1);
+        }
+
+        Ok(reg)
+    }
+
+    // save expr result to register
+    fn save_result(&mut self, result: ExprResult, reg: u32) {
         let proto = self.proto();
         match result {
+            ExprResult::Const(Const::Bool(b)) => proto.code_bool(reg, b, 0),
             ExprResult::Const(k) => {
                 let index = proto.add_const(k);
                 proto.code_const(reg, index)
             }
+            ExprResult::Reg(src) if src.is_const() && src.reg == reg => 0,
             ExprResult::Reg(src) if src.is_const() => proto.code_move(reg, src.reg),
             ExprResult::Reg(_) => proto.save(reg),
-            ExprResult::True => proto.code_bool(reg, true, 0),
-            ExprResult::False => proto.code_bool(reg, false, 0),
             ExprResult::Nil => proto.code_nil(reg, 1),
             ExprResult::Jump(j) => {
-                j.free(self.context());
+                j.free_to(self.context(), reg);
+                0
+            }
+            ExprResult::Test(t) => {
+                t.free(self.context());
+                if t.reg.reg != reg {
+                    self.proto().code_move(reg, t.reg.reg);
+                }
                 0
             }
-            ExprResult::Test(_) => todo!(),
         };
+    }
 
-        if temp_reg != reg {
-            self.context().free_reg(1);
+    // find a var from current function to the outermost one,
+    // upvalues are created along the way if it is a local of an enclosing function
+    fn resolve_var(&mut self, name: &str) -> Var {
+        let level = self.proto_contexts.len() - 1;
+        self.resolve_var_at(level, name)
+    }
+
+    fn resolve_var_at(&mut self, level: usize, name: &str) -> Var {
+        let context = &mut self.proto_contexts[level];
+        if let Some(reg) = context.get_local_var(name) {
+            return Var::Local(reg);
+        }
+        let proto = &mut context.proto;
+        if let Some(up_var) = proto.get_up_var(name) {
+            return Var::UpVal(up_var);
+        }
+        if level == 0 {
+            // _ENV is always the first upvalue of main function
+            if name == "_ENV" {
+                return Var::UpVal(proto.add_up_var(name, true, 0));
+            }
+            return Var::Global;
+        }
+        let var = self.resolve_var_at(level - 1, name);
+        if let Var::Local(reg) = var {
+            // the local must be closed when its block ends
+            self.proto_contexts[level - 1].mark_upval(reg);
         }
+        let proto = &mut self.proto_contexts[level].proto;
+        match var {
+            Var::Local(reg) => Var::UpVal(proto.add_up_var(name, true, reg)),
+            Var::UpVal(up_var) => Var::UpVal(proto.add_up_var(name, false, up_var)),
+            Var::Global => Var::Global,
+        }
+    }
 
-        Ok(reg)
+    // upvalues refer to the innermost local with the name in enclosing functions
+    fn check_read_only(&self, name: &str) -> Result<(), CompileError> {
+        for context in self.proto_contexts.iter().rev() {
+            if let Some(reg) = context.get_local_var(name) {
+                if context.is_read_only(reg) {
+                    return Err(CompileError::new(&format!(
+                        "attempt to assign to const variable '{}'",
+                        name
+                    )));
+                }
+                return Ok(());
+            }
+        }
+        Ok(())
+    }
+
+    // get index of _ENV upvalue, globals are fields of it
+    fn get_env(&mut self) -> u32 {
+        match self.resolve_var("_ENV") {
+            Var::UpVal(up_var) => up_var,
+            // TODO : support local _ENV
+            _ => unreachable!(),
+        }
+    }
+
+    // methods have an implicit `self` parameter before the others
+    fn code_func_body(
+        &mut self,
+        body: &FuncBody,
+        is_method: bool,
+        input: Option<u32>,
+    ) -> Result<ExprResult, CompileError> {
+        self.push_proto();
+        self.proto().open();
+        if is_method {
+            let context = self.context();
+            context.add_local_var("self");
+            context.proto.param_count += 1;
+            context.reserve_regs(1)?;
+        }
+        for param in body.params.params.iter() {
+            let context = self.context();
+            match param {
+                Param::Name(name) => {
+                    context.add_local_var(&name.value());
+                    context.proto.param_count += 1;
+                    context.reserve_regs(1)?;
+                }
+                Param::VarArg(_) => context.proto.is_vararg = true,
+            }
+        }
+        // implicit return is at the line of `end`
+        self.func_block(&body.block, Some(body.end.source.line as u32))?;
+        let proto = self.pop_proto();
+
+        let parent = self.proto();
+        parent.protos.push(proto);
+        let index = (parent.protos.len() - 1) as u32;
+        let alloc_reg = self.alloc_reg(&input)?;
+        self.proto().code_closure(alloc_reg.reg, index);
+        Ok(ExprResult::Reg(alloc_reg))
+    }
+
+    // get rk of a global or field name
+    fn name_key(&mut self, name: &str) -> Result<u32, CompileError> {
+        self.context().const_to_rk(Const::Str(name.to_string()))
+    }
+
+    fn code_get_global(
+        &mut self,
+        name: &str,
+        input: Option<u32>,
+    ) -> Result<ExprResult, CompileError> {
+        let env = self.get_env();
+        let alloc_reg = self.alloc_reg(&input)?;
+        let reg_top = self.context().get_reg_top();
+        let key = self.name_key(name)?;
+        let context = self.context();
+        context.proto.code_get_tab_up(alloc_reg.reg, env, key);
+        context.free_reg(context.get_reg_top() - reg_top);
+        Ok(ExprResult::Reg(alloc_reg))
     }
 
-    fn get_assinable_reg(&mut self, assignable: &Assignable) -> u32 {
-        match assignable {
-            Assignable::Name(name) => self.proto().get_local_var(&name.value()).unwrap(),
-            Assignable::SuffixedExpr(_) => todo!(),
+    fn code_set_global(&mut self, key: u32, value: u32) {
+        let env = self.get_env();
+        self.proto().code_set_tab_up(env, key, value);
+    }
+
+    // process expr and return its rk, locals and consts are used directly
+    // otherwise the result is saved to a new register, which should be freed by caller
+    fn expr_to_rk(&mut self, expr: &Expr) -> Result<u32, CompileError> {
+        if let Expr::Name(name) = expr {
+            if let Some(reg) = self.context().get_local_var(&name.value()) {
+                return Ok(reg);
+            }
+        }
+        if let Some(k) = self.try_const_folding(expr)? {
+            return self.context().const_to_rk(k);
+        }
+        self.expr_and_save(expr, None)
+    }
+
+    fn code_table(
+        &mut self,
+        table: &Table,
+        input: Option<u32>,
+    ) -> Result<ExprResult, CompileError> {
+        // array items are stored in registers right after the table
+        let context = self.context();
+        let (reg, result) = match input {
+            Some(reg) if reg + 1 == context.get_reg_top() => (reg, ExprResult::new_const_reg(reg)),
+            _ => {
+                let reg = context.reserve_regs(1)?;
+                (reg, ExprResult::Reg(Reg::new_temp(reg)))
+            }
+        };
+        let pc = context.proto.code_new_table(reg);
+
+        let mut array_size = 0;
+        let mut hash_size = 0;
+        let mut pending = 0;
+        for (i, field) in table.fields.iter().enumerate() {
+            match field {
+                Field::ListField(field) => {
+                    self.expr_and_save(&field.value, None)?;
+                    array_size += 1;
+                    pending += 1;
+                    let batch = (array_size - 1) / LFIELDS_PER_FLUSH + 1;
+                    if i == table.fields.len() - 1 && Compiler::is_multret(&field.value) {
+                        // store all values returned by last item
+                        let proto = self.proto();
+                        proto.set_multret();
+                        proto.code_set_list(reg, 0, batch);
+                        array_size -= 1;
+                    } else if pending == LFIELDS_PER_FLUSH {
+                        self.proto().code_set_list(reg, pending, batch);
+                    } else {
+                        continue;
+                    }
+                    self.context().free_reg(pending);
+                    pending = 0;
+                }
+                Field::RecField(field) => {
+                    let reg_top = self.context().get_reg_top();
+                    let key = match &field.key {
+                        FieldKey::Name(name) => self.name_key(&name.value())?,
+                        FieldKey::Expr(_, expr, _) => self.expr_to_rk(expr)?,
+                    };
+                    let value = self.expr_to_rk(&field.value)?;
+                    self.proto().code_set_table(reg, key, value);
+                    let context = self.context();
+                    context.free_reg(context.get_reg_top() - reg_top);
+                    hash_size += 1;
+                }
+            }
+        }
+        if pending > 0 {
+            self.proto()
+                .code_set_list(reg, pending, (array_size - 1) / LFIELDS_PER_FLUSH + 1);
+            self.context().free_reg(pending);
+        }
+        self.proto().fix_table_size(pc, array_size, hash_size);
+        Ok(result)
+    }
+
+    fn code_suffixed_expr(
+        &mut self,
+        expr: &SuffixedExpr,
+        input: Option<u32>,
+    ) -> Result<ExprResult, CompileError> {
+        // function and args of a call should be in consecutive registers
+        let context = self.context();
+        let (base, result) = match input {
+            Some(reg) if reg + 1 == context.get_reg_top() => (reg, ExprResult::new_const_reg(reg)),
+            _ => {
+                let reg = context.reserve_regs(1)?;
+                (reg, ExprResult::Reg(Reg::new_temp(reg)))
+            }
+        };
+        let reg = self.code_suffixes(&expr.primary, &expr.suffixes, base)?;
+        if reg != base {
+            self.proto().code_move(base, reg);
+        }
+        Ok(result)
+    }
+
+    // compile primary expr and its suffixes, `base` is a free register to store temp values,
+    // returns the register holding the value, which is a local if there is no suffix
+    fn code_suffixes(
+        &mut self,
+        primary: &Expr,
+        suffixes: &[Suffix],
+        base: u32,
+    ) -> Result<u32, CompileError> {
+        let mut obj = match self.expr(primary, Some(base))? {
+            // locals are indexed directly
+            ExprResult::Reg(r) if r.is_const() => r.reg,
+            result => {
+                self.save_result(result, base);
+                base
+            }
+        };
+        for suffix in suffixes.iter() {
+            let reg_top = self.context().get_reg_top();
+            match suffix {
+                Suffix::Attr(_, name) => {
+                    let key = self.name_key(&name.value())?;
+                    self.proto().code_get_table(base, obj, key);
+                }
+                Suffix::Index(_, key, _) => {
+                    let key = self.expr_to_rk(key)?;
+                    self.proto().code_get_table(base, obj, key);
+                }
+                Suffix::Method(_, name) => {
+                    // object is passed as the first argument, its register is reserved
+                    // before the key, so a key loaded to register is not overwritten
+                    self.context().reserve_regs(1)?;
+                    let key = self.name_key(&name.value())?;
+                    let context = self.context();
+                    context.proto.code_self(base, obj, key);
+                    context.free_reg(context.get_reg_top() - reg_top - 1);
+                    obj = base;
+                    continue;
+                }
+                Suffix::FuncArgs(args) => {
+                    if obj != base {
+                        self.proto().code_move(base, obj);
+                    }
+                    self.code_call(base, args)?;
+                    obj = base;
+                    continue;
+                }
+            }
+            obj = base;
+            let context = self.context();
+            context.free_reg(context.get_reg_top() - reg_top);
+        }
+        Ok(obj)
+    }
+
+    // call function in register `base`, the result is saved to `base`,
+    // registers already reserved after `base` are arguments too
+    fn code_call(&mut self, base: u32, args: &FuncArgs) -> Result<(), CompileError> {
+        let mut nargs = self.context().get_reg_top() - base - 1;
+        match args {
+            FuncArgs::Exprs(_, exprs, _) => {
+                for expr in exprs.exprs.iter() {
+                    self.expr_and_save(expr, None)?;
+                    nargs += 1;
+                }
+                if exprs.exprs.last().is_some_and(Compiler::is_multret) {
+                    self.proto().set_multret();
+                    nargs = LUA_MULTRET;
+                }
+            }
+            FuncArgs::Table(table) => {
+                let reg = self.context().reserve_regs(1)?;
+                self.code_table(table, Some(reg))?;
+                nargs += 1;
+            }
+            FuncArgs::String(s) => {
+                let reg = self.context().reserve_regs(1)?;
+                let index = self.proto().add_const(Const::Str(s.value()));
+                self.proto().code_const(reg, index);
+                nargs += 1;
+            }
+        }
+        let context = self.context();
+        // one result by default
+        context.proto.code_call(base, nargs, 1);
+        context.free_reg(context.get_reg_top() - base - 1);
+        Ok(())
+    }
+
+    // compile a block
+    fn block(&mut self, block: &Block) -> Result<(), CompileError> {
+        self.enter_block(Compiler::tail_labels(block));
+        ast_walker::walk_block(block, self)?;
+        self.leave_block();
+        Ok(())
+    }
+
+    // outermost block of a function, its locals are visible till the implicit return
+    fn func_block(&mut self, block: &Block, end_line: Option<u32>) -> Result<(), CompileError> {
+        self.enter_block(Compiler::tail_labels(block));
+        ast_walker::walk_block(block, self)?;
+        if let Some(line) = end_line {
+            self.context().set_line(line);
+        }
+        self.proto().close();
+        self.leave_block();
+        self.check_pending_gotos()
+    }
+
+    // labels at the end of block, locals of the block are considered out of scope there
+    fn tail_labels(block: &Block) -> Vec<String> {
+        block
+            .stats
+            .iter()
+            .rev()
+            .map_while(|stat| match stat {
+                Stat::LabelStat(stat) => Some(stat.label.value()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn enter_block(&mut self, tail_labels: Vec<String>) {
+        let context = self.context();
+        context.blocks.push(BlockContext {
+            nactvar: context.active_vars.len(),
+            first_label: context.labels.len(),
+            tail_labels,
+            upval: false,
+        });
+    }
+
+    fn leave_block(&mut self) {
+        let context = self.context();
+        if let Some(block) = context.blocks.pop() {
+            // captured locals are closed by a jump to the next instruction, like luac,
+            // the outermost block of a function is closed by its return
+            if block.upval && !context.blocks.is_empty() {
+                let proto = &mut context.proto;
+                let pc = proto.code_jmp(0, 0);
+                proto.patch_close(&[pc], block.nactvar as u32);
+                proto.get_label();
+            }
+            context.remove_vars(block.nactvar);
+            // registers of active locals are always at the bottom of stack
+            context.reg_top = context.active_vars.len() as u32;
+            // labels of the block are not visible outside
+            context.labels.truncate(block.first_label);
+            // pending gotos and breaks leave the block, and so do its locals,
+            // which are closed by the jump if captured
+            let jumps = context.pending_gotos.iter_mut();
+            for goto in jumps.chain(context.loops.iter_mut().flatten()) {
+                if goto.nactvar > block.nactvar {
+                    if block.upval {
+                        context.proto.patch_close(&[goto.pc], block.nactvar as u32);
+                    }
+                    goto.nactvar = block.nactvar;
+                }
+            }
+        }
+    }
+
+    fn enter_loop(&mut self) {
+        self.context().loops.push(Vec::new());
+    }
+
+    // patch breaks of the loop to current pc
+    fn leave_loop(&mut self) {
+        if let Some(breaks) = self.context().loops.pop() {
+            let breaks: Vec<usize> = breaks.iter().map(|jump| jump.pc).collect();
+            self.patch_to_here(&breaks);
+        }
+    }
+
+    // every goto should be resolved at the end of function
+    fn check_pending_gotos(&mut self) -> Result<(), CompileError> {
+        if let Some(goto) = self.context().pending_gotos.first() {
+            let source = goto.source;
+            let e = CompileError::new(&format!("no visible label '{}' for goto", goto.name));
+            return self.compile_error(e, &source);
+        }
+        Ok(())
+    }
+
+    // compile cond and return jumps to be patched when cond is false,
+    // cond is true if no jump happens
+    fn cond(&mut self, cond: &Expr) -> Result<Vec<usize>, CompileError> {
+        let result = self.expr(cond, None)?;
+        let false_jumps = self.go_if_true(result);
+        Ok(false_jumps)
+    }
+
+    // patch jumps to current pc
+    fn patch_to_here(&mut self, jumps: &[usize]) {
+        let proto = self.proto();
+        let pc = proto.code.len();
+        proto.patch_list(jumps, NO_REG, pc, pc);
+    }
+
+    // the local is visible in function body, so it could be called recursively
+    fn local_function_stat(&mut self, stat: &FuncStat) -> Result<(), CompileError> {
+        let context = self.context();
+        for name in stat.func_name.fields.vars.iter() {
+            context.add_local_var(&name.value());
+        }
+        let reg = context.reserve_regs(1)?;
+        self.code_func_body(&stat.body, false, Some(reg))?;
+        // debug info only sees the local after the closure is created
+        let proto = self.proto();
+        let pc = proto.code.len() as u32;
+        if let Some(local) = proto.local_vars.last_mut() {
+            local.start_pc = pc;
+        }
+        Ok(())
+    }
+
+    // `function a.b:c() end` is `a.b.c = function(self) end`
+    fn function_stat(&mut self, stat: &FuncStat) -> Result<(), CompileError> {
+        let func_name = &stat.func_name;
+        let (first, fields) = match func_name.fields.vars.split_first() {
+            Some(vars) => vars,
+            None => unreachable!(),
+        };
+        let mut suffixes: Vec<Suffix> = func_name
+            .fields
+            .delimiters
+            .iter()
+            .zip(fields)
+            .map(|(dot, name)| Suffix::Attr(dot.clone(), name.clone()))
+            .collect();
+        if let Some((colon, method)) = &func_name.method {
+            suffixes.push(Suffix::Attr(colon.clone(), method.clone()));
+        }
+        let assignable = if suffixes.is_empty() {
+            self.check_read_only(&first.value())?;
+            Assignable::Name(first.clone())
+        } else {
+            Assignable::SuffixedExpr(SuffixedExpr {
+                primary: Box::new(Expr::Name(first.clone())),
+                suffixes,
+            })
+        };
+
+        let is_method = func_name.method.is_some();
+        let reg_top = self.context().get_reg_top();
+        match self.resolve_assignable(&assignable)? {
+            Target::Local(reg) => {
+                self.code_func_body(&stat.body, is_method, Some(reg))?;
+            }
+            Target::UpVal(up_var) => {
+                let closure = self.code_closure_reg(&stat.body, is_method)?;
+                self.proto().code_set_up_val(up_var, closure);
+            }
+            Target::Global(key) => {
+                let closure = self.code_closure_reg(&stat.body, is_method)?;
+                self.code_set_global(key, closure);
+            }
+            Target::Index(table, key) => {
+                let closure = self.code_closure_reg(&stat.body, is_method)?;
+                self.proto().code_set_table(table, key, closure);
+            }
+        }
+        let context = self.context();
+        context.free_reg(context.get_reg_top() - reg_top);
+        Ok(())
+    }
+
+    // register of a new closure
+    fn code_closure_reg(&mut self, body: &FuncBody, is_method: bool) -> Result<u32, CompileError> {
+        match self.code_func_body(body, is_method, None)? {
+            ExprResult::Reg(reg) => Ok(reg.reg),
+            _ => unreachable!(),
+        }
+    }
+
+    fn if_stat(&mut self, stat: &IfStat) -> Result<(), CompileError> {
+        // jumps to the end of if stat
+        let mut escapes = Vec::new();
+        for (i, cond_block) in stat.cond_blocks.iter().enumerate() {
+            let false_jumps = match cond_block.block.stats.first() {
+                Some(Stat::GotoStat(_)) | Some(Stat::BreakStat(_)) => {
+                    match self.then_jump_block(&cond_block.cond, &cond_block.block)? {
+                        Some(false_jumps) => false_jumps,
+                        // the jump is the whole block, the next cond follows directly
+                        None => continue,
+                    }
+                }
+                _ => {
+                    let false_jumps = self.cond(&cond_block.cond)?;
+                    self.block(&cond_block.block)?;
+                    false_jumps
+                }
+            };
+            let is_last = i == stat.cond_blocks.len() - 1 && stat.else_block.is_none();
+            if !is_last {
+                escapes.push(self.proto().code_jmp(NO_JUMP, 0));
+            }
+            self.patch_to_here(&false_jumps);
+        }
+        if let Some(else_block) = &stat.else_block {
+            self.block(else_block)?;
+        }
+        self.patch_to_here(&escapes);
+        Ok(())
+    }
+
+    // `if cond then goto label` or `break`, jumps of cond are taken by the goto directly
+    // instead of jumping over it, same as `test_then_block` of luac. return jumps to be
+    // patched when cond is false, or None if the goto is the whole block.
+    fn then_jump_block(
+        &mut self,
+        cond: &Expr,
+        block: &Block,
+    ) -> Result<Option<Vec<usize>>, CompileError> {
+        let result = self.expr(cond, None)?;
+        let true_jumps = self.go_if_false(result);
+        self.enter_block(Compiler::tail_labels(block));
+        let (first, rest) = match block.stats.split_first() {
+            Some(stats) => stats,
+            None => unreachable!(),
+        };
+        let result = match first {
+            Stat::GotoStat(stat) => self.code_goto(stat, true_jumps),
+            Stat::BreakStat(_) => self.code_break(first.source(), true_jumps),
+            _ => unreachable!(),
+        };
+        if let Err(e) = result {
+            return self.compile_error(e, &first.source());
+        }
+        if rest.is_empty() {
+            self.leave_block();
+            return Ok(None);
+        }
+        // skip the rest of block if cond is false
+        let false_jump = self.proto().code_jmp(NO_JUMP, 0);
+        for stat in rest.iter() {
+            if let Err(e) = ast_walker::walk_stat(stat, self) {
+                return self.compile_error(e, &stat.source());
+            }
+        }
+        self.leave_block();
+        Ok(Some(vec![false_jump]))
+    }
+
+    fn while_stat(&mut self, stat: &WhileStat) -> Result<(), CompileError> {
+        let start = self.proto().get_label();
+        let exits = self.cond(&stat.cond)?;
+        self.enter_loop();
+        self.block(&stat.block)?;
+        let proto = self.proto();
+        let pc = proto.code_jmp(NO_JUMP, 0);
+        proto.fix_jump_pos(start, pc);
+        self.patch_to_here(&exits);
+        self.leave_loop();
+        Ok(())
+    }
+
+    fn repeat_stat(&mut self, stat: &RepeatStat) -> Result<(), CompileError> {
+        let start = self.proto().get_label();
+        self.enter_loop();
+        // locals of the body are visible in until condition, so they share a scope,
+        // and labels before `until` are not at the end of the scope
+        self.enter_block(Vec::new());
+        ast_walker::walk_block(&stat.block, self)?;
+        let context = self.context();
+        context.set_line(stat.until.source.line as u32);
+        let loops = self.cond(&stat.cond)?;
+        // locals of the body are closed before looping back too
+        let context = self.context();
+        if let Some(block) = context.blocks.last().filter(|block| block.upval) {
+            let level = block.nactvar as u32;
+            context.proto.patch_close(&loops, level);
+        }
+        self.proto().patch_list(&loops, NO_REG, start, start);
+        self.leave_block();
+        self.leave_loop();
+        Ok(())
+    }
+
+    fn for_num_stat(&mut self, stat: &ForNum) -> Result<(), CompileError> {
+        let base = self.context().get_reg_top();
+
+        // scope of control vars
+        self.enter_block(Vec::new());
+        self.expr_and_save(&stat.init, None)?;
+        self.expr_and_save(&stat.limit, None)?;
+        match &stat.step {
+            Some(step) => {
+                self.expr_and_save(step, None)?;
+            }
+            None => {
+                let reg = self.context().reserve_regs(1)?;
+                let proto = self.proto();
+                let index = proto.add_const(Const::Int(1));
+                proto.code_const(reg, index);
+            }
+        }
+
+        // internal control vars
+        let context = self.context();
+        context.add_local_var("(for index)");
+        context.add_local_var("(for limit)");
+        context.add_local_var("(for step)");
+        let prep = self.proto().code_for_prep(base, NO_JUMP);
+
+        // visible loop var
+        self.enter_block(Vec::new());
+        let context = self.context();
+        context.add_local_var(&stat.var.value());
+        context.reserve_regs(1)?;
+        self.enter_loop();
+        self.block(&stat.body)?;
+        self.leave_block();
+
+        // loop back to the body
+        let context = self.context();
+        context.set_line(stat.for_.source.line as u32);
+        let proto = &mut context.proto;
+        let pc = proto.code_for_loop(base, NO_JUMP);
+        proto.fix_jump_pos(pc, prep);
+        proto.fix_jump_pos(prep + 1, pc);
+        self.leave_loop();
+        self.leave_block();
+        Ok(())
+    }
+
+    fn for_in_stat(&mut self, stat: &ForList) -> Result<(), CompileError> {
+        let base = self.context().get_reg_top();
+
+        // scope of control vars
+        self.enter_block(Vec::new());
+        let exprs = &stat.exprs.exprs;
+        for expr in exprs.iter() {
+            self.expr_and_save(expr, None)?;
+        }
+        let extra = self.adjust_assign(3, Some(&stat.exprs))?;
+        if extra < 0 {
+            self.context().free_reg(-extra as u32);
+        }
+        // extra space to call generator
+        self.context().check_stack(3)?;
+
+        // internal control vars
+        let context = self.context();
+        context.add_local_var("(for generator)");
+        context.add_local_var("(for state)");
+        context.add_local_var("(for control)");
+        let prep = self.proto().code_jmp(NO_JUMP, 0);
+
+        // visible loop vars
+        self.enter_block(Vec::new());
+        let nvars = stat.vars.vars.len() as u32;
+        let context = self.context();
+        for var in stat.vars.vars.iter() {
+            context.add_local_var(&var.value());
+        }
+        context.reserve_regs(nvars)?;
+        self.enter_loop();
+        self.block(&stat.body)?;
+        self.leave_block();
+
+        // call generator and loop back to the body
+        let context = self.context();
+        context.set_line(stat.for_.source.line as u32);
+        let proto = &mut context.proto;
+        proto.fix_jump_pos(proto.code.len(), prep);
+        proto.code_tfor_call(base, nvars);
+        let pc = proto.code_tfor_loop(base + 2, NO_JUMP);
+        proto.fix_jump_pos(prep + 1, pc);
+        self.leave_loop();
+        self.leave_block();
+        Ok(())
+    }
+
+    // whether expr may produce multiple values
+    fn is_multret(expr: &Expr) -> bool {
+        match expr {
+            Expr::VarArg(_) => true,
+            Expr::SuffixedExpr(expr) => matches!(
+                expr.suffixes.last(),
+                Some(Suffix::FuncArgs(_)) | Some(Suffix::Method(_, _))
+            ),
+            _ => false,
+        }
+    }
+
+    fn resolve_assignable(&mut self, assignable: &Assignable) -> Result<Target, CompileError> {
+        let target = match assignable {
+            Assignable::Name(name) => match self.resolve_var(&name.value()) {
+                Var::Local(reg) => Target::Local(reg),
+                Var::UpVal(up_var) => Target::UpVal(up_var),
+                Var::Global => Target::Global(self.name_key(&name.value())?),
+            },
+            Assignable::SuffixedExpr(expr) => {
+                let (last, prefix) = match expr.suffixes.split_last() {
+                    Some(suffixes) => suffixes,
+                    None => unreachable!(),
+                };
+                let base = self.context().reserve_regs(1)?;
+                let table = self.code_suffixes(&expr.primary, prefix, base)?;
+                if table != base {
+                    self.context().free_reg(1);
+                }
+                let key = match last {
+                    Suffix::Attr(_, name) => self.name_key(&name.value())?,
+                    Suffix::Index(_, key, _) => self.expr_to_rk(key)?,
+                    _ => unreachable!(),
+                };
+                Target::Index(table, key)
+            }
+            Assignable::ParenExpr(_) => {
+                return Err(CompileError::new(
+                    "cannot assign to a parenthesized expression",
+                ))
+            }
+        };
+        Ok(target)
+    }
+
+    // a local assigned by a multiple assignment may be the table or key of a previous target,
+    // which uses a copy of it instead, same as `check_conflict` of luac
+    fn check_conflict(&mut self, targets: &mut [Target], reg: u32) -> Result<(), CompileError> {
+        let extra = self.context().get_reg_top();
+        let mut conflict = false;
+        for target in targets.iter_mut() {
+            if let Target::Index(table, key) = target {
+                if *table == reg {
+                    *table = extra;
+                    conflict = true;
+                }
+                if *key == reg {
+                    *key = extra;
+                    conflict = true;
+                }
+            }
+        }
+        if conflict {
+            self.context().reserve_regs(1)?;
+            self.proto().code_move(extra, reg);
+        }
+        Ok(())
+    }
+
+    // store value in register `src` to target
+    fn store_target(&mut self, target: &Target, src: u32) -> Result<(), CompileError> {
+        match target {
+            Target::Local(reg) => {
+                self.proto().code_move(*reg, src);
+            }
+            Target::UpVal(up_var) => {
+                self.proto().code_set_up_val(*up_var, src);
+            }
+            Target::Global(key) => {
+                self.code_set_global(*key, src);
+            }
+            Target::Index(table, key) => {
+                self.proto().code_set_table(*table, *key, src);
+            }
+        }
+        Ok(())
+    }
+
+    // compile `target = expr`
+    fn assign_target(&mut self, target: &Target, expr: &Expr) -> Result<(), CompileError> {
+        match target {
+            Target::Local(reg) => {
+                self.expr_and_save(expr, Some(*reg))?;
+            }
+            Target::UpVal(up_var) => {
+                let value = self.expr_and_save(expr, None)?;
+                self.proto().code_set_up_val(*up_var, value);
+            }
+            Target::Global(key) => {
+                let value = self.expr_to_rk(expr)?;
+                self.code_set_global(*key, value);
+            }
+            Target::Index(table, key) => {
+                let value = self.expr_to_rk(expr)?;
+                self.proto().code_set_table(*table, *key, value);
+            }
+        }
+        Ok(())
+    }
+
+    // resolve jumps of a goto to its label, forward ones are pending till the label is seen
+    fn code_goto(&mut self, stat: &GotoStat, jumps: Vec<usize>) -> Result<(), CompileError> {
+        let name = stat.label.value();
+        let context = self.context();
+        let nactvar = context.active_vars.len();
+        match context.labels.iter().rev().find(|label| label.name == name) {
+            // backward jump, locals declared after the label are closed like luac
+            Some(label) => {
+                let (target, label_nactvar) = (label.pc, label.nactvar);
+                let proto = &mut context.proto;
+                if nactvar > label_nactvar {
+                    proto.patch_close(&jumps, label_nactvar as u32);
+                }
+                proto.patch_list(&jumps, NO_REG, target, target);
+            }
+            None => {
+                for pc in jumps {
+                    context.pending_gotos.push(Label {
+                        name: name.clone(),
+                        pc,
+                        nactvar,
+                        source: stat.goto.source,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // jumps of a break are patched when the loop ends
+    fn code_break(&mut self, source: Source, jumps: Vec<usize>) -> Result<(), CompileError> {
+        let context = self.context();
+        let nactvar = context.active_vars.len();
+        match context.loops.last_mut() {
+            Some(breaks) => {
+                breaks.extend(jumps.into_iter().map(|pc| Label {
+                    name: "break".to_string(),
+                    pc,
+                    nactvar,
+                    source,
+                }));
+                Ok(())
+            }
+            None => Err(CompileError::new("break not inside a loop")),
         }
     }
 
     fn compile_error<T>(&self, e: CompileError, source: &Source) -> Result<T, CompileError> {
-        let error_msg = format!("[compile error] {} at line [{}].", e.0, source.line);
-        Compiler::trace_error(CompileError(error_msg))
+        Compiler::trace_error(e.with_source(source))
     }
 }
 
@@ -618,71 +1974,230 @@ impl AstVisitor<CompileError> for Compiler {
         self.compile_error(e, source)
     }
 
+    // update line info before compiling each stat
+    fn begin_stat(&mut self, stat: &Stat) -> VisitResult<CompileError> {
+        let line = stat.source().line as u32;
+        let dense_line_info = self.dense_line_info;
+        let context = self.context();
+        context.check_reg_balance();
+        if dense_line_info {
+            context.fill_empty_lines(line);
+        }
+        context.set_line(line);
+
+        // control structures are compiled here instead of walker's hooks,
+        // since their blocks and jumps need to be patched together
+        match stat {
+            Stat::IfStat(stat) => self.if_stat(stat)?,
+            Stat::ForStat(ForStat::ForNum(stat)) => self.for_num_stat(stat)?,
+            Stat::ForStat(ForStat::ForList(stat)) => self.for_in_stat(stat)?,
+            Stat::WhileStat(stat) => self.while_stat(stat)?,
+            Stat::RepeatStat(stat) => self.repeat_stat(stat)?,
+            Stat::DoBlock(stat) => self.block(&stat.block)?,
+            Stat::FuncStat(stat) => match stat.func_type {
+                FuncType::Local(_) => self.local_function_stat(stat)?,
+                FuncType::Global => self.function_stat(stat)?,
+            },
+            _ => return Ok(VisitAction::Continue),
+        }
+        Ok(VisitAction::SkipChildren)
+    }
+
     // compile local stat
-    fn local_stat(&mut self, stat: &LocalStat) -> Result<(), CompileError> {
-        let proto = self.proto();
-        for name in stat.names.vars.iter() {
-            proto.add_local_var(&name.value());
+    fn local_stat(&mut self, stat: &LocalStat) -> VisitResult<CompileError> {
+        // closing locals at the end of their scope needs `OP_TBC` of lua 5.4
+        if stat
+            .attribs
+            .iter()
+            .flatten()
+            .any(|attrib| attrib.value() == "close")
+        {
+            return Err(CompileError::new(
+                "to-be-closed variables are not supported by Lua 5.3 bytecode",
+            ));
         }
         if let Some(expr_list) = &stat.exprs {
             for expr in expr_list.exprs.iter() {
                 self.expr_and_save(expr, None)?;
             }
         }
-        self.adjust_assign(stat.names.vars.len(), stat.exprs.as_ref());
-        Ok(())
+        let extra = self.adjust_assign(stat.names.vars.len(), stat.exprs.as_ref())?;
+        if extra < 0 {
+            self.context().free_reg(-extra as u32);
+        }
+
+        // new locals are visible after the stat
+        let context = self.context();
+        for (name, attrib) in stat.names.vars.iter().zip(&stat.attribs) {
+            context.add_local_var(&name.value());
+            if attrib.is_some() {
+                if let Some(var) = context.proto.local_vars.last_mut() {
+                    var.read_only = true;
+                }
+            }
+        }
+        Ok(VisitAction::Continue)
     }
 
-    // compile assign stat
-    fn assign_stat(&mut self, stat: &AssignStat) -> Result<(), CompileError> {
-        let use_temp_reg = stat.right.exprs.len() != stat.left.assignables.len();
-        let mut to_move: Vec<(u32, u32)> = Vec::new();
-
-        // move rules:
-        // if num of left != num of right:
-        //      MOVE temp[1..n] right[1..n]
-        //      MOVE left[1..n] temp[1..n]
-        // if num of left == num of right:
-        //      MOVE temp[1..(n-1)] right[1..(n-1)]
-        //      MOVE left[n] right[n]
-        //      MOVE left[1..(n-1)] temp[1..(n-1)]
-        for (i, expr) in stat.right.exprs.iter().enumerate() {
-            if i != stat.right.exprs.len() - 1 || use_temp_reg {
-                let reg = self.expr_and_save(expr, None)?;
-                if i < stat.left.assignables.len() {
-                    let target = self.get_assinable_reg(&stat.left.assignables[i]);
-                    to_move.push((target, reg));
+    fn label_stat(&mut self, stat: &LabelStat) -> VisitResult<CompileError> {
+        let name = stat.label.value();
+        let context = self.context();
+        // like lua 5.3, labels of enclosing blocks can be shadowed, but not ones of the same block
+        let first_label = context.blocks.last().map_or(0, |block| block.first_label);
+        let block_labels = &context.labels[first_label..];
+        if let Some(label) = block_labels.iter().find(|label| label.name == name) {
+            return Err(CompileError::new(&format!(
+                "label '{}' already defined on line {}",
+                name, label.source.line
+            )));
+        }
+        let nactvar = match context.blocks.last() {
+            Some(block) if block.tail_labels.contains(&name) => block.nactvar,
+            _ => context.active_vars.len(),
+        };
+        let pc = context.proto.get_label();
+
+        // resolve pending gotos to this label
+        let mut i = 0;
+        while i < context.pending_gotos.len() {
+            if context.pending_gotos[i].name != name {
+                i += 1;
+                continue;
+            }
+            let goto = context.pending_gotos.remove(i);
+            if goto.nactvar < nactvar {
+                let local = context.active_vars[goto.nactvar];
+                return Err(CompileError::new(&format!(
+                    "<goto {}> at line {} jumps into the scope of local '{}'",
+                    name,
+                    goto.source.line,
+                    context.local_var_name(local)
+                )));
+            }
+            context.proto.patch_list(&[goto.pc], NO_REG, pc, pc);
+        }
+
+        context.labels.push(Label {
+            name,
+            pc,
+            nactvar,
+            source: stat.ldc.source,
+        });
+        Ok(VisitAction::Continue)
+    }
+
+    fn goto_stat(&mut self, stat: &GotoStat) -> VisitResult<CompileError> {
+        let pc = self.proto().code_jmp(NO_JUMP, 0);
+        self.code_goto(stat, vec![pc])?;
+        Ok(VisitAction::Continue)
+    }
+
+    fn break_stat(&mut self, stat: &BreakStat) -> VisitResult<CompileError> {
+        let pc = self.proto().code_jmp(NO_JUMP, 0);
+        self.code_break(stat.token.source, vec![pc])?;
+        Ok(VisitAction::Continue)
+    }
+
+    fn ret_stat(&mut self, stat: &RetStat) -> VisitResult<CompileError> {
+        let reg_top = self.context().get_reg_top();
+        let mut first = reg_top;
+        let exprs = stat.exprs.as_ref().map_or(&[][..], |list| &list.exprs[..]);
+        let nret = match exprs {
+            [] => 0,
+            // return a local directly
+            [Expr::Name(name)] if self.context().get_local_var(&name.value()).is_some() => {
+                first = self.context().get_local_var(&name.value()).unwrap();
+                1
+            }
+            [.., last] if Compiler::is_multret(last) => {
+                for expr in exprs.iter() {
+                    self.expr_and_save(expr, None)?;
                 }
-            } else {
-                let reg = self.get_assinable_reg(&stat.left.assignables[i]);
-                self.expr_and_save(expr, Some(reg))?;
-            };
+                let proto = self.proto();
+                proto.set_multret();
+                // `return f(...)` reuses the frame of current function
+                if let [Expr::SuffixedExpr(_)] = exprs {
+                    if let Some(call) = proto.code.last_mut() {
+                        call.set_op(OpCode::TailCall);
+                    }
+                }
+                LUA_MULTRET
+            }
+            _ => {
+                for expr in exprs.iter() {
+                    self.expr_and_save(expr, None)?;
+                }
+                exprs.len() as u32
+            }
+        };
+        self.proto().code_return(first, nret);
+        let context = self.context();
+        context.free_reg(context.get_reg_top() - reg_top);
+        Ok(VisitAction::Continue)
+    }
+
+    // compile call stat
+    fn call_stat(&mut self, stat: &CallStat) -> VisitResult<CompileError> {
+        if let Assignable::SuffixedExpr(expr) = &stat.call {
+            let result = self.code_suffixed_expr(expr, None)?;
+            // discard results
+            self.proto().set_returns(0);
+            result.resolve(self.context());
         }
+        Ok(VisitAction::Continue)
+    }
 
-        // nil move
-        let reg = self.context().get_reg_top();
-        let extra = self.adjust_assign(stat.left.assignables.len(), Some(&stat.right));
-        if extra > 0 {
-            let left_start = stat.left.assignables.len() as i32 - extra;
-            for i in 0..extra {
-                let target =
-                    self.get_assinable_reg(&stat.left.assignables[(left_start + i) as usize]);
-                let src = (reg as i32 + i) as u32;
-                to_move.push((target, src));
+    // compile assign stat
+    fn assign_stat(&mut self, stat: &AssignStat) -> VisitResult<CompileError> {
+        for assignable in stat.left.assignables.iter() {
+            if let Assignable::Name(name) = assignable {
+                self.check_read_only(&name.value())?;
             }
         }
+        let reg_top = self.context().get_reg_top();
+
+        // tables and keys of targets are evaluated from left to right before values, like luac
+        let mut targets: Vec<Target> = Vec::new();
+        for assignable in stat.left.assignables.iter() {
+            let target = self.resolve_assignable(assignable)?;
+            if let Target::Local(reg) = target {
+                self.check_conflict(&mut targets, reg)?;
+            }
+            targets.push(target);
+        }
 
-        // apply moves
-        for (target, src) in to_move.iter().rev() {
-            self.proto().code_move(*target, *src);
-            self.context().free_reg(1);
+        // values are saved to registers from `first`, except the last one which is
+        // assigned directly if nums of left and right are equal
+        let exprs = &stat.right.exprs;
+        let first = self.context().get_reg_top();
+        if exprs.len() == targets.len() {
+            let (last, exprs) = match exprs.split_last() {
+                Some(exprs) => exprs,
+                None => unreachable!(),
+            };
+            for expr in exprs.iter() {
+                self.expr_and_save(expr, None)?;
+            }
+            if let Some(target) = targets.pop() {
+                self.assign_target(&target, last)?;
+            }
+        } else {
+            for expr in exprs.iter() {
+                self.expr_and_save(expr, None)?;
+            }
+            let extra = self.adjust_assign(targets.len(), Some(&stat.right))?;
+            if extra < 0 {
+                self.context().free_reg(-extra as u32);
+            }
         }
 
-        // free extra regs
-        if extra < 0 {
-            self.context().free_reg(-extra as u32);
+        // apply moves
+        for (i, target) in targets.iter().enumerate().rev() {
+            self.store_target(target, first + i as u32)?;
         }
 
-        Ok(())
+        let context = self.context();
+        context.free_reg(context.get_reg_top() - reg_top);
+        Ok(VisitAction::Continue)
     }
 }
@@ -1,32 +1,118 @@
+use crate::ast::BinOp;
 use crate::compiler::CompileError;
-use crate::types::{FloatType, IntType};
+use crate::lexer::Lexer;
+use crate::types::{FloatType, IntType, Number};
 use crate::utils::success;
+use std::cmp::Ordering;
 use std::hash::{Hash, Hasher};
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Const {
     Int(IntType),
     Float(FloatType),
     Str(String),
+    Bool(bool),
+    // only used as rk operand, e.g. of `x == nil`, never folded
+    Nil,
+}
+
+// floats are compared by bit patterns so that `Eq` agrees with `Hash`,
+// `-0.0` is the same as `0.0`, and all NaNs are the same
+fn float_bits(f: FloatType) -> u64 {
+    if f == 0.0 {
+        0
+    } else if f.is_nan() {
+        FloatType::NAN.to_bits()
+    } else {
+        f.to_bits()
+    }
+}
+
+impl PartialEq for Const {
+    fn eq(&self, other: &Const) -> bool {
+        match (self, other) {
+            (Const::Int(a), Const::Int(b)) => a == b,
+            (Const::Float(a), Const::Float(b)) => float_bits(*a) == float_bits(*b),
+            (Const::Str(a), Const::Str(b)) => a == b,
+            (Const::Bool(a), Const::Bool(b)) => a == b,
+            (Const::Nil, Const::Nil) => true,
+            // `0` and `0.0` are different consts
+            _ => false,
+        }
+    }
 }
 
 impl Eq for Const {}
 
+// same as constants in luac listings
+impl std::fmt::Display for Const {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Const::Int(i) => write!(f, "{}", i),
+            Const::Float(n) => write!(f, "{}", Number::Float(*n)),
+            Const::Str(s) => write!(f, "\"{}\"", s),
+            Const::Bool(b) => write!(f, "{}", b),
+            Const::Nil => write!(f, "nil"),
+        }
+    }
+}
+
 impl Hash for Const {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        // `true` and `1` are different consts
+        std::mem::discriminant(self).hash(state);
         match self {
             Const::Int(i) => i.hash(state),
-            Const::Float(f) => f.to_bits().hash(state),
+            Const::Float(f) => float_bits(*f).hash(state),
             Const::Str(s) => s.hash(state),
+            Const::Bool(b) => b.hash(state),
+            Const::Nil => (),
         }
     }
 }
 
-fn float_to_int(f: FloatType) -> Option<IntType> {
-    if f.floor() == f {
-        Some(f as IntType)
+// floats with integral values in [-2^63, 2^63) are converted to ints in bitwise operations
+fn float_to_int(f: FloatType) -> Result<IntType, CompileError> {
+    if f.floor() == f && f >= -(2.0 as FloatType).powi(63) && f < (2.0 as FloatType).powi(63) {
+        Ok(f as IntType)
+    } else {
+        Err(CompileError::new("number has no integer representation"))
+    }
+}
+
+// shifts are logical, and shifting by 64 bits or more results 0
+fn shift_left(a: IntType, b: IntType) -> IntType {
+    if b <= -64 || b >= 64 {
+        0
+    } else if b >= 0 {
+        ((a as u64) << b) as IntType
     } else {
+        ((a as u64) >> -b) as IntType
+    }
+}
+
+fn shift_right(a: IntType, b: IntType) -> IntType {
+    shift_left(a, b.wrapping_neg())
+}
+
+// order of an int and a float without converting the int to a float, which may round it,
+// ints are compared with the floor of floats in int range like `LTnum` and `LEnum` of lua
+fn cmp_int_float(i: IntType, f: FloatType) -> Option<Ordering> {
+    let bound = (2.0 as FloatType).powi(63);
+    if f.is_nan() {
         None
+    } else if f >= bound {
+        Some(Ordering::Less)
+    } else if f < -bound {
+        Some(Ordering::Greater)
+    } else {
+        let floor = f.floor();
+        match i.cmp(&(floor as IntType)) {
+            // `i == floor(f) < f` if f has no integer value
+            Ordering::Equal if floor != f => Some(Ordering::Less),
+            ordering => Some(ordering),
+        }
     }
 }
 
@@ -41,6 +127,33 @@ fn ignore_unhashable_float(
     }
 }
 
+// numeric strings are converted to numbers in bitwise operations
+fn str_to_number(k: Const) -> Const {
+    if let Const::Str(s) = &k {
+        let s = s.trim_matches(|c: char| " \t\n\r\x0B\x0C".contains(c));
+        // `inf` and `nan` are not numerals in lua
+        if !s.contains(['n', 'N']) {
+            match Lexer::str_to_num(s) {
+                Number::Int(i) => return Const::Int(i),
+                Number::Float(f) => return Const::Float(f),
+                Number::None => (),
+            }
+        }
+    }
+    k
+}
+
+// lua 5.3 converts strings to floats in arithmetic operations, so `"3" + 4` is `7.0`
+fn str_to_float(k: Const) -> Const {
+    match k {
+        Const::Str(_) => match str_to_number(k) {
+            Const::Int(i) => Const::Float(i as FloatType),
+            k => k,
+        },
+        k => k,
+    }
+}
+
 fn bin_op<IntInt, IntFloat, FloatInt, FloatFloat>(
     a: Const,
     b: Const,
@@ -55,24 +168,26 @@ where
     FloatInt: FnOnce(FloatType, IntType) -> Result<Option<Const>, CompileError>,
     FloatFloat: FnOnce(FloatType, FloatType) -> Result<Option<Const>, CompileError>,
 {
-    let result = match (a, b) {
+    let result = match (str_to_float(a), str_to_float(b)) {
         (Const::Int(a), Const::Int(b)) => int_int(a, b),
         (Const::Int(a), Const::Float(b)) => int_float(a, b),
         (Const::Float(a), Const::Int(b)) => float_int(a, b),
         (Const::Float(a), Const::Float(b)) => float_float(a, b),
-        _ => unreachable!(),
+        // non-numeric operands raise errors at runtime
+        _ => Ok(None),
     };
 
     ignore_unhashable_float(result)
 }
 
+// ints wrap around on overflow, mixed operands are converted to floats
 macro_rules! bin_op_normal {
-    ($name:ident, $op:tt) => {
+    ($name:ident, $int_op:ident, $op:tt) => {
         fn $name(self, other: Const) -> Result<Option<Const>, CompileError> {
             bin_op(
                 self,
                 other,
-                |a, b| success(Const::Int(a $op b)),
+                |a, b| success(Const::Int(a.$int_op(b))),
                 |a, b| success(Const::Float(a as FloatType $op b)),
                 |a, b| success(Const::Float(a $op b as FloatType)),
                 |a, b| success(Const::Float(a $op b))
@@ -81,36 +196,47 @@ macro_rules! bin_op_normal {
     }
 }
 
+// operands of bitwise operations must have integer representations
 macro_rules! bin_op_int {
-    ($name:ident, $op:tt) => {
+    ($name:ident, $op:expr) => {
         fn $name(self, other: Const) -> Result<Option<Const>, CompileError> {
             bin_op(
-                self,
-                other,
-                |a, b| success(Const::Int(a $op b)),
-                |a, b| Ok(float_to_int(b).map(|b| Const::Int(a $op b))),
-                |a, b| Ok(float_to_int(a).map(|a| Const::Int(a $op b))),
-                |a, b| Ok(float_to_int(a).and_then(|a| float_to_int(b).and_then(|b| Some(Const::Int(a $op b)))))
+                str_to_number(self),
+                str_to_number(other),
+                |a, b| success(Const::Int($op(a, b))),
+                |a, b| success(Const::Int($op(a, float_to_int(b)?))),
+                |a, b| success(Const::Int($op(float_to_int(a)?, b))),
+                |a, b| success(Const::Int($op(float_to_int(a)?, float_to_int(b)?))),
             )
         }
-    }
+    };
 }
 
 impl Const {
+    // only `false` and `nil` are falsy
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Const::Bool(false) | Const::Nil)
+    }
+
     pub fn idiv(self, other: Const) -> Result<Option<Const>, CompileError> {
         bin_op(
             self,
             other,
             |a, b| {
                 if b == 0 {
-                    Err(CompileError::new("divide by zero"))
+                    return Err(CompileError::new("attempt to perform 'n//0'"));
+                }
+                // rounds towards minus infinity
+                let q = a.wrapping_div(b);
+                if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                    success(Const::Int(q - 1))
                 } else {
-                    success(Const::Int(a / b))
+                    success(Const::Int(q))
                 }
             },
-            |_, _| Ok(None),
-            |_, _| Ok(None),
-            |_, _| Ok(None),
+            |a, b| success(Const::Float((a as FloatType / b).floor())),
+            |a, b| success(Const::Float((a / b as FloatType).floor())),
+            |a: FloatType, b| success(Const::Float((a / b).floor())),
         )
     }
 
@@ -126,8 +252,8 @@ impl Const {
     }
 
     pub fn minus(&self) -> Result<Option<Const>, CompileError> {
-        let result = match self {
-            Const::Int(i) => success(Const::Int(-i)),
+        let result = match str_to_float(self.clone()) {
+            Const::Int(i) => success(Const::Int(i.wrapping_neg())),
             Const::Float(f) => success(Const::Float(-f)),
             _ => return Ok(None),
         };
@@ -135,26 +261,89 @@ impl Const {
     }
 
     pub fn bnot(&self) -> Result<Option<Const>, CompileError> {
-        match self {
+        match str_to_number(self.clone()) {
             Const::Int(i) => success(Const::Int(!i)),
+            Const::Float(f) => success(Const::Int(!float_to_int(f)?)),
+            _ => Ok(None),
+        }
+    }
+
+    // float to string conversion depends on runtime format, so only strings and ints are folded
+    pub fn concat(self, other: Const) -> Result<Option<Const>, CompileError> {
+        // numbers are converted like `tostring`, non-finite floats are left to runtime
+        // whose output depends on platform
+        let to_string = |k: Const| match k {
+            Const::Str(s) => Some(s),
+            Const::Int(i) => Some(Number::Int(i).to_string()),
+            Const::Float(f) if f.is_finite() => Some(Number::Float(f).to_string()),
+            Const::Float(_) | Const::Bool(_) | Const::Nil => None,
+        };
+        match (to_string(self), to_string(other)) {
+            (Some(a), Some(b)) => success(Const::Str(a + &b)),
             _ => Ok(None),
         }
     }
+
+    pub fn str_len(&self) -> Result<Option<Const>, CompileError> {
+        match self {
+            Const::Str(s) => success(Const::Int(s.len() as IntType)),
+            _ => Ok(None),
+        }
+    }
+
+    // compare two consts, return None if the result can only be known at runtime
+    pub fn compare(&self, other: &Const, op: &BinOp) -> Option<bool> {
+        let ordering = match (self, other) {
+            (Const::Int(a), Const::Int(b)) => a.partial_cmp(b),
+            (Const::Int(a), Const::Float(b)) => cmp_int_float(*a, *b),
+            (Const::Float(a), Const::Int(b)) => cmp_int_float(*b, *a).map(Ordering::reverse),
+            (Const::Float(a), Const::Float(b)) => a.partial_cmp(b),
+            (Const::Str(a), Const::Str(b)) => a.partial_cmp(b),
+            // booleans have no order
+            (Const::Bool(a), Const::Bool(b)) => {
+                return match op {
+                    BinOp::Eq(_) => Some(a == b),
+                    BinOp::Ne(_) => Some(a != b),
+                    _ => None,
+                };
+            }
+            _ => {
+                // consts of different types are never equal, comparing their order raises an error
+                return match op {
+                    BinOp::Eq(_) => Some(false),
+                    BinOp::Ne(_) => Some(true),
+                    _ => None,
+                };
+            }
+        };
+        let result = match (op, ordering) {
+            (BinOp::Eq(_), o) => o == Some(Ordering::Equal),
+            (BinOp::Ne(_), o) => o != Some(Ordering::Equal),
+            // NaN is not folded
+            (_, None) => return None,
+            (BinOp::Lt(_), Some(o)) => o == Ordering::Less,
+            (BinOp::Le(_), Some(o)) => o != Ordering::Greater,
+            (BinOp::Gt(_), Some(o)) => o == Ordering::Greater,
+            (BinOp::Ge(_), Some(o)) => o != Ordering::Less,
+            _ => return None,
+        };
+        Some(result)
+    }
 }
 
 impl std::ops::Add for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_normal! {add, +}
+    bin_op_normal! {add, wrapping_add, +}
 }
 
 impl std::ops::Sub for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_normal! {sub, -}
+    bin_op_normal! {sub, wrapping_sub, -}
 }
 
 impl std::ops::Mul for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_normal! {mul, *}
+    bin_op_normal! {mul, wrapping_mul, *}
 }
 
 impl std::ops::Div for Const {
@@ -171,41 +360,61 @@ impl std::ops::Div for Const {
     }
 }
 
+fn float_mod(a: FloatType, b: FloatType) -> Result<Option<Const>, CompileError> {
+    let m = a % b;
+    if m * b < 0.0 {
+        success(Const::Float(m + b))
+    } else {
+        success(Const::Float(m))
+    }
+}
+
 impl std::ops::Rem for Const {
     type Output = Result<Option<Const>, CompileError>;
     fn rem(self, other: Const) -> Self::Output {
         bin_op(
             self,
             other,
-            |a, b| success(Const::Int(a % b)),
-            |a, b| success(Const::Float(a as FloatType % b)),
-            |a, b| success(Const::Float(a % b as FloatType)),
-            |a, b| success(Const::Float(a % b)),
+            |a, b| {
+                if b == 0 {
+                    return Err(CompileError::new("attempt to perform 'n%%0'"));
+                }
+                // result has the same sign as divisor
+                let m = a.wrapping_rem(b);
+                if m != 0 && (m ^ b) < 0 {
+                    success(Const::Int(m + b))
+                } else {
+                    success(Const::Int(m))
+                }
+            },
+            |a, b| float_mod(a as FloatType, b),
+            |a, b| float_mod(a, b as FloatType),
+            float_mod,
         )
     }
 }
 
 impl std::ops::BitXor for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_int! {bitxor, ^}
+    bin_op_int! {bitxor, |a: IntType, b| a ^ b}
 }
 
 impl std::ops::BitAnd for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_int! {bitand, &}
+    bin_op_int! {bitand, |a: IntType, b| a & b}
 }
 
 impl std::ops::BitOr for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_int! {bitor, |}
+    bin_op_int! {bitor, |a: IntType, b| a | b}
 }
 
 impl std::ops::Shl for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_int! {shl, <<}
+    bin_op_int! {shl, shift_left}
 }
 
 impl std::ops::Shr for Const {
     type Output = Result<Option<Const>, CompileError>;
-    bin_op_int! {shr, >>}
+    bin_op_int! {shr, shift_right}
 }
@@ -0,0 +1,453 @@
+use crate::ast::*;
+use crate::tokens::TokenType;
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum QuoteStyle {
+    #[default]
+    Double,
+    Single,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FormatConfig {
+    // count of spaces of each indent level
+    pub indent: usize,
+    // keep `;` after return stats and between table fields, otherwise they are dropped or become `,`
+    pub keep_semicolons: bool,
+    // quotes of string literals
+    pub quote_style: QuoteStyle,
+}
+
+impl Default for FormatConfig {
+    fn default() -> Self {
+        FormatConfig {
+            indent: 4,
+            keep_semicolons: true,
+            quote_style: QuoteStyle::Double,
+        }
+    }
+}
+
+// re-emit canonical lua source of the block, comments are dropped.
+// string values are expected to be escaped, which means the lexer must not use `use_origin_string`.
+pub fn format_block(block: &Block, config: &FormatConfig) -> String {
+    let mut formatter = Formatter {
+        config,
+        output: String::new(),
+        depth: 0,
+    };
+    formatter.stats(block);
+    formatter.output
+}
+
+struct Formatter<'a> {
+    config: &'a FormatConfig,
+    output: String,
+    depth: usize,
+}
+
+impl<'a> Formatter<'a> {
+    fn append(&mut self, content: &str) {
+        self.output.push_str(content);
+    }
+
+    fn begin_line(&mut self) {
+        let indent = self.depth * self.config.indent;
+        self.output.extend(std::iter::repeat(' ').take(indent));
+    }
+
+    fn end_line(&mut self) {
+        self.output.push('\n');
+    }
+
+    fn stats(&mut self, block: &Block) {
+        for stat in block.stats.iter() {
+            self.begin_line();
+            // `a = b` followed by `(f)()` would be parsed as `a = b(f)()`
+            if Formatter::starts_with_paren(stat) {
+                self.append(";");
+            }
+            self.stat(stat);
+            self.end_line();
+        }
+    }
+
+    fn indented(&mut self, block: &Block) {
+        self.depth += 1;
+        self.stats(block);
+        self.depth -= 1;
+    }
+
+    // indented block on new lines, followed by the closing keyword
+    fn block(&mut self, block: &Block, end: &str) {
+        self.end_line();
+        self.indented(block);
+        self.begin_line();
+        self.append(end);
+    }
+
+    fn starts_with_paren(stat: &Stat) -> bool {
+        let assignable = match stat {
+            Stat::AssignStat(stat) => &stat.left.assignables[0],
+            Stat::CallStat(stat) => &stat.call,
+            _ => return false,
+        };
+        match assignable {
+            Assignable::ParenExpr(_) => true,
+            Assignable::SuffixedExpr(expr) => matches!(*expr.primary, Expr::ParenExpr(_)),
+            Assignable::Name(_) => false,
+        }
+    }
+
+    fn stat(&mut self, stat: &Stat) {
+        match stat {
+            Stat::IfStat(stat) => {
+                for (i, cond_block) in stat.cond_blocks.iter().enumerate() {
+                    if i > 0 {
+                        self.begin_line();
+                        self.append("elseif ");
+                    } else {
+                        self.append("if ");
+                    }
+                    self.expr(&cond_block.cond);
+                    self.append(" then");
+                    self.end_line();
+                    self.indented(&cond_block.block);
+                }
+                if let Some(block) = &stat.else_block {
+                    self.begin_line();
+                    self.append("else");
+                    self.end_line();
+                    self.indented(block);
+                }
+                self.begin_line();
+                self.append("end");
+            }
+            Stat::WhileStat(stat) => {
+                self.append("while ");
+                self.expr(&stat.cond);
+                self.append(" do");
+                self.block(&stat.block, "end");
+            }
+            Stat::DoBlock(stat) => {
+                self.append("do");
+                self.block(&stat.block, "end");
+            }
+            Stat::ForStat(ForStat::ForNum(stat)) => {
+                self.append(&format!("for {} = ", stat.var.value()));
+                self.expr(&stat.init);
+                self.append(", ");
+                self.expr(&stat.limit);
+                if let Some(step) = &stat.step {
+                    self.append(", ");
+                    self.expr(step);
+                }
+                self.append(" do");
+                self.block(&stat.body, "end");
+            }
+            Stat::ForStat(ForStat::ForList(stat)) => {
+                self.append("for ");
+                self.names(&stat.vars.vars);
+                self.append(" in ");
+                self.exprlist(&stat.exprs);
+                self.append(" do");
+                self.block(&stat.body, "end");
+            }
+            Stat::RepeatStat(stat) => {
+                self.append("repeat");
+                self.block(&stat.block, "until ");
+                self.expr(&stat.cond);
+            }
+            Stat::FuncStat(stat) => {
+                if let FuncType::Local(_) = stat.func_type {
+                    self.append("local ");
+                }
+                self.append("function ");
+                let fields: Vec<String> = stat
+                    .func_name
+                    .fields
+                    .vars
+                    .iter()
+                    .map(|f| f.value())
+                    .collect();
+                self.append(&fields.join("."));
+                if let Some((_, method)) = &stat.func_name.method {
+                    self.append(&format!(":{}", method.value()));
+                }
+                self.func_body(&stat.body);
+            }
+            Stat::LocalStat(stat) => {
+                self.append("local ");
+                for (i, (name, attrib)) in stat.names.vars.iter().zip(&stat.attribs).enumerate() {
+                    if i > 0 {
+                        self.append(", ");
+                    }
+                    self.append(&name.value());
+                    if let Some(attrib) = attrib {
+                        self.append(&format!(" <{}>", attrib.value()));
+                    }
+                }
+                if let Some(exprs) = &stat.exprs {
+                    self.append(" = ");
+                    self.exprlist(exprs);
+                }
+            }
+            Stat::LabelStat(stat) => self.append(&format!("::{}::", stat.label.value())),
+            Stat::RetStat(stat) => {
+                self.append("return");
+                if let Some(exprs) = &stat.exprs {
+                    self.append(" ");
+                    self.exprlist(exprs);
+                }
+                if stat.semi.is_some() && self.config.keep_semicolons {
+                    self.append(";");
+                }
+            }
+            Stat::BreakStat(_) => self.append("break"),
+            Stat::GotoStat(stat) => self.append(&format!("goto {}", stat.label.value())),
+            Stat::AssignStat(stat) => {
+                for (i, assignable) in stat.left.assignables.iter().enumerate() {
+                    if i > 0 {
+                        self.append(", ");
+                    }
+                    self.assignable(assignable);
+                }
+                self.append(" = ");
+                self.exprlist(&stat.right);
+            }
+            Stat::CallStat(stat) => self.assignable(&stat.call),
+        }
+    }
+
+    fn names(&mut self, names: &[StringExpr]) {
+        let names: Vec<String> = names.iter().map(|name| name.value()).collect();
+        self.append(&names.join(", "));
+    }
+
+    fn func_body(&mut self, body: &FuncBody) {
+        self.append("(");
+        for (i, param) in body.params.params.iter().enumerate() {
+            if i > 0 {
+                self.append(", ");
+            }
+            match param {
+                Param::VarArg(_) => self.append("..."),
+                Param::Name(name) => self.append(&name.value()),
+            }
+        }
+        self.append(")");
+        if body.block.stats.is_empty() {
+            self.append(" end");
+        } else {
+            self.block(&body.block, "end");
+        }
+    }
+
+    fn exprlist(&mut self, exprs: &ExprList) {
+        for (i, expr) in exprs.exprs.iter().enumerate() {
+            if i > 0 {
+                self.append(", ");
+            }
+            self.expr(expr);
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Expr::Nil(_) => self.append("nil"),
+            Expr::True(_) => self.append("true"),
+            Expr::False(_) => self.append("false"),
+            Expr::VarArg(_) => self.append("..."),
+            Expr::Float(f) => match f.text() {
+                Some(text) => self.append(text),
+                None => self.float(f.value()),
+            },
+            Expr::Int(i) => match i.text() {
+                Some(text) => self.append(text),
+                // hex literals may wrap around to negative values
+                None if i.value() < 0 => self.append(&format!("0x{:x}", i.value())),
+                None => self.append(&i.value().to_string()),
+            },
+            Expr::String(s) => self.string(&s.value()),
+            Expr::Name(name) => self.append(&name.value()),
+            Expr::ParenExpr(expr) => {
+                self.append("(");
+                self.expr(expr);
+                self.append(")");
+            }
+            Expr::FuncBody(body) => {
+                self.append("function");
+                self.func_body(body);
+            }
+            Expr::Table(table) => self.table(table),
+            Expr::BinExpr(expr) => {
+                self.expr(&expr.left);
+                self.append(&format!(" {} ", Formatter::binop(&expr.op)));
+                self.expr(&expr.right);
+            }
+            Expr::UnExpr(expr) => {
+                let op = match expr.op {
+                    UnOp::Minus(_) => "-",
+                    UnOp::BNot(_) => "~",
+                    UnOp::Not(_) => "not ",
+                    UnOp::Len(_) => "#",
+                    UnOp::None => "",
+                };
+                self.append(op);
+                // `- -a` is not a comment
+                if let (
+                    UnOp::Minus(_),
+                    Expr::UnExpr(UnExpr {
+                        op: UnOp::Minus(_), ..
+                    }),
+                ) = (&expr.op, expr.expr.as_ref())
+                {
+                    self.append(" ");
+                }
+                self.expr(&expr.expr);
+            }
+            Expr::SuffixedExpr(expr) => self.suffixed_expr(expr),
+        }
+    }
+
+    fn float(&mut self, f: f64) {
+        // debug format is the shortest text which reads back to the same value
+        if f.is_infinite() {
+            self.append("1e999");
+        } else {
+            self.append(&format!("{:?}", f));
+        }
+    }
+
+    fn string(&mut self, s: &str) {
+        let quote = match self.config.quote_style {
+            QuoteStyle::Double => '"',
+            QuoteStyle::Single => '\'',
+        };
+        self.output.push(quote);
+        for c in s.chars() {
+            match c {
+                '\\' => self.append("\\\\"),
+                '\n' => self.append("\\n"),
+                '\r' => self.append("\\r"),
+                '\t' => self.append("\\t"),
+                _ if c == quote => {
+                    self.output.push('\\');
+                    self.output.push(c);
+                }
+                // 3 digits so that following digits are not part of the escape
+                _ if c.is_ascii_control() => self.append(&format!("\\{:03}", c as u32)),
+                _ => self.output.push(c),
+            }
+        }
+        self.output.push(quote);
+    }
+
+    fn binop(op: &BinOp) -> &'static str {
+        match op {
+            BinOp::Add(_) => "+",
+            BinOp::Minus(_) => "-",
+            BinOp::Mul(_) => "*",
+            BinOp::Mod(_) => "%",
+            BinOp::Pow(_) => "^",
+            BinOp::Div(_) => "/",
+            BinOp::IDiv(_) => "//",
+            BinOp::BAnd(_) => "&",
+            BinOp::BOr(_) => "|",
+            BinOp::BXor(_) => "~",
+            BinOp::Shl(_) => "<<",
+            BinOp::Shr(_) => ">>",
+            BinOp::Concat(_) => "..",
+            BinOp::Ne(_) => "~=",
+            BinOp::Eq(_) => "==",
+            BinOp::Lt(_) => "<",
+            BinOp::Le(_) => "<=",
+            BinOp::Gt(_) => ">",
+            BinOp::Ge(_) => ">=",
+            BinOp::And(_) => "and",
+            BinOp::Or(_) => "or",
+            BinOp::None => unreachable!(),
+        }
+    }
+
+    fn assignable(&mut self, assignable: &Assignable) {
+        match assignable {
+            Assignable::Name(name) => self.append(&name.value()),
+            Assignable::SuffixedExpr(expr) => self.suffixed_expr(expr),
+            Assignable::ParenExpr(expr) => {
+                self.append("(");
+                self.expr(expr);
+                self.append(")");
+            }
+        }
+    }
+
+    fn suffixed_expr(&mut self, expr: &SuffixedExpr) {
+        self.expr(&expr.primary);
+        for suffix in expr.suffixes.iter() {
+            match suffix {
+                Suffix::Attr(_, attr) => self.append(&format!(".{}", attr.value())),
+                Suffix::Method(_, method) => self.append(&format!(":{}", method.value())),
+                Suffix::Index(_, key, _) => {
+                    self.append("[");
+                    self.expr(key);
+                    self.append("]");
+                }
+                Suffix::FuncArgs(FuncArgs::Exprs(_, exprs, _)) => {
+                    self.append("(");
+                    self.exprlist(exprs);
+                    self.append(")");
+                }
+                Suffix::FuncArgs(FuncArgs::Table(table)) => {
+                    self.append(" ");
+                    self.table(table);
+                }
+                Suffix::FuncArgs(FuncArgs::String(s)) => {
+                    self.append(" ");
+                    self.string(&s.value());
+                }
+            }
+        }
+    }
+
+    fn table(&mut self, table: &Table) {
+        if table.fields.is_empty() {
+            self.append("{}");
+            return;
+        }
+        self.append("{ ");
+        for field in table.fields.iter() {
+            let sep = match field {
+                Field::RecField(field) => {
+                    match &field.key {
+                        FieldKey::Name(name) => self.append(&name.value()),
+                        FieldKey::Expr(_, key, _) => {
+                            self.append("[");
+                            self.expr(key);
+                            self.append("]");
+                        }
+                    }
+                    self.append(" = ");
+                    self.expr(&field.value);
+                    &field.sep
+                }
+                Field::ListField(field) => {
+                    self.expr(&field.value);
+                    &field.sep
+                }
+            };
+            if let Some(sep) = sep {
+                if sep.t == TokenType::Semi && self.config.keep_semicolons {
+                    self.append("; ");
+                } else {
+                    self.append(", ");
+                }
+            }
+        }
+        // no trailing space after the last separator
+        if self.output.ends_with(' ') {
+            self.output.pop();
+        }
+        self.append(" }");
+    }
+}
@@ -1,25 +1,27 @@
-use crate::tokens::{Token, TokenType, TokenValue};
-use crate::types::{FloatType, IntType, Number, Source};
+use crate::tokens::{BorrowedToken, InternTable, Token, TokenType, TokenValue};
+use crate::types::{FloatType, IntType, LuaVersion, Number, Source};
 use crate::utils::success;
 use rslua_derive::Traceable;
 use rslua_traits::Error;
+use std::borrow::Cow;
+use std::ops::Range;
 use std::str;
 
 // context for lexer
 struct Context<'a> {
-    buffer: &'a str,
+    buffer: &'a [u8],
     current: usize,
     line: usize,
     col: usize,
     old_pos: usize,
     old_line: usize,
     old_col: usize,
-    offset: usize,
+    // index of the first comment token not attached to a token yet
     comment_offset: usize,
 }
 
 impl<'a> Context<'a> {
-    pub fn new(buffer: &'a str) -> Self {
+    pub fn new(buffer: &'a [u8]) -> Self {
         Context::<'a> {
             buffer,
             current: 0,
@@ -28,7 +30,6 @@ impl<'a> Context<'a> {
             old_pos: 0,
             old_line: 0,
             old_col: 0,
-            offset: 0,
             comment_offset: 0,
         }
     }
@@ -44,6 +45,7 @@ impl<'a> Context<'a> {
             line: self.old_line,
             col: self.old_col,
             length: self.current - self.old_pos,
+            offset: self.old_pos,
         }
     }
 
@@ -58,7 +60,7 @@ impl<'a> Context<'a> {
 
     // eat n chars, and write these chars to output
     pub fn write_into(&mut self, n: usize, output: &mut Vec<u8>) {
-        if let Some(slice) = self.buffer.as_bytes().get(self.current..(self.current + n)) {
+        if let Some(slice) = self.buffer.get(self.current..(self.current + n)) {
             output.extend_from_slice(slice);
             self.skip(n);
         }
@@ -78,21 +80,202 @@ impl<'a> Context<'a> {
     }
 
     pub fn get_ahead(&self, index: usize) -> Option<u8> {
-        self.buffer.as_bytes().get(self.current + index).copied()
+        self.buffer.get(self.current + index).copied()
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct LexerConfig {
     // if use origin string, lexer won't escape special chars and keep the quotes or string boundaries.
     pub use_origin_string: bool,
     // reserve comments or not
     pub reserve_comments: bool,
+    // max count of '=' in long brackets, no limit if not set.
+    pub max_long_bracket_level: Option<usize>,
+    // if preserve number text, number tokens will keep their origin literal text along with the value.
+    pub preserve_number_text: bool,
+    // target lua version, affects version specific lexing rules.
+    pub lua_version: LuaVersion,
+    // attach a single line comment to the token before it on the same line,
+    // instead of the token after it.
+    pub attach_trailing_comments: bool,
+    // max length of names, strings and long comments in bytes, no limit if not set.
+    pub max_token_length: Option<usize>,
+    // values of names and strings are `TokenValue::Interned`, shared in the intern table of
+    // the lexer, which is kept across runs.
+    pub intern_strings: bool,
 }
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+
+// moves tokens after an edit, columns only change on the line of the edit
+struct Shift {
+    line: usize,
+    lines: isize,
+    cols: isize,
+    offset: isize,
+}
+
+impl Shift {
+    fn apply(&self, token: &mut Token) {
+        let source = &mut token.source;
+        if source.line == self.line {
+            source.col = (source.col as isize + self.cols) as usize;
+        }
+        source.line = (source.line as isize + self.lines) as usize;
+        source.offset = (source.offset as isize + self.offset) as usize;
+        for comment in token
+            .comments
+            .iter_mut()
+            .chain(token.trailing_comments.iter_mut())
+        {
+            self.apply(comment);
+        }
+    }
+}
+
+// level of the long bracket starting at `i`, e.g. 2 for `[==[`
+fn long_bracket_level(input: &[u8], i: usize) -> Option<usize> {
+    let level = input[i + 1..].iter().take_while(|c| **c == b'=').count();
+    match (input[i], input.get(i + 1 + level)) {
+        (b'[', Some(b'[')) => Some(level),
+        _ => None,
+    }
+}
+
+// offset after the closing long bracket of `level`, or the end of input
+fn skip_long_bracket(input: &[u8], i: usize, level: usize) -> usize {
+    let mut close = vec![b'='; level + 2];
+    close[0] = b']';
+    close[level + 1] = b']';
+    match input[i..].windows(close.len()).position(|w| w == close) {
+        Some(pos) => i + pos + close.len(),
+        None => input.len(),
+    }
+}
+
+// offsets after line breaks which are not in strings or comments, where lexing can start over,
+// about `chunk_size` bytes apart
+fn split_points(input: &[u8], chunk_size: usize) -> Vec<usize> {
+    let mut points = Vec::new();
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            c @ (b'\r' | b'\n') => {
+                i += 1;
+                // \r\n or \n\r is a single line break
+                if i < input.len() && input[i] != c && matches!(input[i], b'\r' | b'\n') {
+                    i += 1;
+                }
+                // utf8 bom is only skipped at the start of input
+                if i >= chunk_size * (points.len() + 1)
+                    && i < input.len()
+                    && !input[i..].starts_with(UTF8_BOM)
+                {
+                    points.push(i);
+                }
+            }
+            b'-' if input.get(i + 1) == Some(&b'-') => {
+                i += 2;
+                if i < input.len() {
+                    if let Some(level) = long_bracket_level(input, i) {
+                        i = skip_long_bracket(input, i, level);
+                        continue;
+                    }
+                }
+                while i < input.len() && !matches!(input[i], b'\r' | b'\n') {
+                    i += 1;
+                }
+            }
+            b'[' => match long_bracket_level(input, i) {
+                Some(level) => i = skip_long_bracket(input, i, level),
+                None => i += 1,
+            },
+            // escaped and `\z` skipped line breaks are in the string too
+            quote @ (b'"' | b'\'' | b'`') => {
+                i += 1;
+                while i < input.len() && input[i] != quote {
+                    i += if input[i] == b'\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            _ => i += 1,
+        }
+    }
+    points
+}
+
+// convert a token of a borrowed run, names and strings without a value are sliced from the input
+fn borrow_token(input: &str, token: Token, origin: bool) -> BorrowedToken<'_> {
+    let Token {
+        t,
+        value,
+        raw,
+        source,
+        comments,
+        trailing_comments,
+    } = token;
+    let (value, text) = match value {
+        TokenValue::Str(s) => (TokenValue::None, Some(Cow::Owned(s))),
+        TokenValue::Interned(_, s) => (TokenValue::None, Some(Cow::Owned(s.to_string()))),
+        TokenValue::None if t == TokenType::Name => (
+            TokenValue::None,
+            Some(Cow::Borrowed(&input[source.offset..source.end()])),
+        ),
+        TokenValue::None if t == TokenType::String => (
+            TokenValue::None,
+            Some(Cow::Borrowed(
+                &input[string_content(input, &source, origin)],
+            )),
+        ),
+        value => (value, None),
+    };
+    let borrow_all = |tokens: Vec<Token>| {
+        tokens
+            .into_iter()
+            .map(|token| borrow_token(input, token, origin))
+            .collect()
+    };
+    BorrowedToken {
+        t,
+        value,
+        text,
+        raw: raw.map(|_| &input[source.offset..source.end()]),
+        source,
+        comments: borrow_all(comments),
+        trailing_comments: borrow_all(trailing_comments),
+    }
+}
+
+// range of the content of a short or long string token without quotes or brackets,
+// the whole token if `origin` like the value of `use_origin_string`
+fn string_content(input: &str, source: &Source, origin: bool) -> Range<usize> {
+    let bytes = input.as_bytes();
+    let (start, end) = (source.offset, source.end());
+    if origin {
+        return start..end;
+    } else if bytes[start] != b'[' {
+        return start + 1..end - 1;
+    }
+    let level = long_bracket_level(bytes, start).unwrap_or(0);
+    let mut content = start + level + 2;
+    // the first line break is skipped, `\r\n` or `\n\r` counts as one
+    if let Some(&c) = bytes.get(content).filter(|&&c| c == b'\r' || c == b'\n') {
+        content += 1;
+        if matches!(bytes.get(content), Some(&next) if next != c && (next == b'\r' || next == b'\n'))
+        {
+            content += 1;
+        }
+    }
+    content..end - level - 2
+}
+
 #[derive(Traceable, Default)]
 pub struct Lexer {
     config: LexerConfig,
     tokens: Vec<Token>,
+    interner: InternTable,
+    // values of names and escape-free strings are left empty, see `run_borrowed`
+    borrow: bool,
 }
 
 #[derive(Debug)]
@@ -107,42 +290,257 @@ impl Error for LexError {
 type LexResult = Result<Option<(TokenType, TokenValue)>, LexError>;
 
 impl<'a> Lexer {
+    // reserve room for `capacity` tokens for the next run
+    pub fn with_capacity(capacity: usize) -> Self {
+        Lexer {
+            config: LexerConfig::default(),
+            tokens: Vec::with_capacity(capacity),
+            interner: InternTable::default(),
+            borrow: false,
+        }
+    }
+
+    // give back tokens returned by `run`, so that their allocation is
+    // reused by the next run instead of allocating a new vec
+    pub fn recycle(&mut self, mut tokens: Vec<Token>) {
+        if tokens.capacity() > self.tokens.capacity() {
+            tokens.clear();
+            self.tokens = tokens;
+        }
+    }
+
     pub fn set_config(&mut self, config: LexerConfig) {
         self.config = config;
     }
 
+    pub fn intern_table(&self) -> &InternTable {
+        &self.interner
+    }
+
     pub fn run(&mut self, input: &'a str) -> Result<Vec<Token>, LexError> {
+        self.run_bytes(input.as_bytes())
+    }
+
+    // lex raw bytes, utf8 is only required for the value of string and comment tokens
+    pub fn run_bytes(&mut self, input: &'a [u8]) -> Result<Vec<Token>, LexError> {
+        self.lex_all(input)?;
+        Ok(std::mem::take(&mut self.tokens))
+    }
+
+    // like `run`, but values of names and strings borrow from the input if they have no
+    // escapes, which saves an allocation for each of them. `intern_strings` is ignored.
+    pub fn run_borrowed<'s>(&mut self, input: &'s str) -> Result<Vec<BorrowedToken<'s>>, LexError> {
+        self.borrow = true;
+        let result = self.lex_all(input.as_bytes());
+        self.borrow = false;
+        result?;
+        Ok(self
+            .tokens
+            .drain(..)
+            .map(|token| borrow_token(input, token, self.config.use_origin_string))
+            .collect())
+    }
+
+    // lex chunks of input on `threads` threads, tokens are the same as `run`. chunks are split at
+    // line breaks which are not in strings or comments, the whole input is lexed again if
+    // any chunk fails, so that errors are the same too.
+    pub fn run_parallel(&mut self, input: &'a str, threads: usize) -> Result<Vec<Token>, LexError> {
+        let input = input.as_bytes();
+        let mut bounds = vec![0];
+        bounds.extend(split_points(input, input.len() / threads.max(1) + 1));
+        bounds.push(input.len());
+        if bounds.len() <= 2 {
+            return self.run_bytes(input);
+        }
+        let chunks: Vec<Option<Vec<Token>>> = std::thread::scope(|scope| {
+            let handles: Vec<_> = bounds
+                .windows(2)
+                .map(|range| {
+                    let config = self.config.clone();
+                    let chunk = &input[range[0]..range[1]];
+                    scope.spawn(move || {
+                        let mut lexer = Lexer::default();
+                        lexer.set_config(config);
+                        lexer.run_bytes(chunk)
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().ok().and_then(|result| result.ok()))
+                .collect()
+        });
+        if chunks.iter().any(|chunk| chunk.is_none()) {
+            return self.run_bytes(input);
+        }
+
+        let mut tokens = Vec::new();
+        let mut lines = 0;
+        // comments at the end of a chunk belong to the first token of the next chunk
+        let mut comments: Vec<Token> = Vec::new();
+        let count = bounds.len() - 1;
+        for (i, (chunk, start)) in chunks.into_iter().flatten().zip(bounds).enumerate() {
+            // chunks start at column 1 of a new line
+            let shift = Shift {
+                line: 0,
+                lines: lines as isize,
+                cols: 0,
+                offset: start as isize,
+            };
+            for mut token in chunk {
+                shift.apply(&mut token);
+                // symbols of chunks are given by their own lexers
+                if let TokenValue::Interned(_, s) = &token.value {
+                    token.value = self.str_value(s.as_ref());
+                }
+                if !token.is_comment() && !comments.is_empty() {
+                    token.comments.splice(0..0, comments.drain(..));
+                }
+                tokens.push(token);
+            }
+            if i + 1 < count {
+                let eos = tokens.pop().unwrap();
+                lines = eos.source.line - 1;
+                comments = eos.comments;
+            }
+        }
+        Ok(tokens)
+    }
+
+    // relex `input` after `edit`, a range of the last input, is replaced. tokens are kept in
+    // the lexer (see `tokens`), the whole input is lexed if there are none, e.g. after `run`.
+    // lexing restarts at the first token which may be changed by the edit, and stops at a token
+    // starting where an old token after the edit did, the rest of old tokens are moved after it.
+    pub fn relex(&mut self, input: &'a str, edit: Range<usize>) -> Result<(), LexError> {
+        let input = input.as_bytes();
+        let result = match self.tokens.last() {
+            Some(eos)
+                if eos.t == TokenType::Eos
+                    && edit.start <= edit.end
+                    && edit.end <= eos.source.offset =>
+            {
+                let delta = input.len() as isize - eos.source.offset as isize;
+                self.relex_from(input, edit, delta)
+            }
+            _ => self.lex_all(input),
+        };
+        // tokens of a failed run are incomplete, the next relex starts over
+        if result.is_err() {
+            self.reset();
+        }
+        result
+    }
+
+    fn lex_all(&mut self, input: &'a [u8]) -> Result<(), LexError> {
         self.reset();
         let mut ctx = Context::new(input);
+        // utf8 bom at the start of the input is skipped, the first token is still at column 1
+        if input.starts_with(UTF8_BOM) {
+            ctx.current = UTF8_BOM.len();
+        }
+        while self.lex_next(&mut ctx)? {}
+        Ok(())
+    }
+
+    fn relex_from(
+        &mut self,
+        input: &'a [u8],
+        edit: Range<usize>,
+        delta: isize,
+    ) -> Result<(), LexError> {
+        // the lexer looks at most 2 chars after a token, eos is always affected
+        let mut start = self
+            .tokens
+            .iter()
+            .position(|t| t.source.end() + 2 > edit.start)
+            .unwrap_or(self.tokens.len() - 1);
+        // restart after a token which is not a comment, so that comments are attached again,
+        // and before the edit, which may be in spaces before the token
+        while start > 0
+            && (self.tokens[start - 1].is_comment()
+                || self.tokens[start].source.offset > edit.start)
+        {
+            start -= 1;
+        }
+        if start == 0 {
+            return self.lex_all(input);
+        }
+        let old = self.tokens.split_off(start);
+        self.tokens[start - 1].trailing_comments.clear();
+
+        let mut ctx = Context::new(input);
+        ctx.current = old[0].source.offset;
+        ctx.line = old[0].source.line;
+        ctx.col = old[0].source.col;
+        ctx.comment_offset = start;
+        // old tokens after the edit are not changed, only moved
+        let unchanged = &old[old.partition_point(|t| t.source.offset < edit.end)..];
         loop {
-            ctx.save();
-            if let Some(c) = ctx.get() {
-                if let Some((token_type, token_value)) = match c {
-                    _ if Lexer::is_line_break(c) => self.read_line_break(&mut ctx)?,
-                    _ if Lexer::is_space(c) => self.read_space(&mut ctx)?,
-                    _ if Lexer::is_digit(c) => self.read_number(&mut ctx)?,
-                    b'-' if self.check_next(&ctx, '-') => self.read_comment(&mut ctx)?,
-                    b'=' => self.read_eq_assign(&mut ctx)?,
-                    b'<' => self.read_le_shl_lt(&mut ctx)?,
-                    b'>' => self.read_ge_shr_gt(&mut ctx)?,
-                    b'/' if self.check_next(&ctx, '/') => self.read_idiv(&mut ctx)?,
-                    b'~' => self.read_ne_xor(&mut ctx)?,
-                    b':' => self.read_colon(&mut ctx)?,
-                    b'.' => self.read_attr_concat_dots_numbers(&mut ctx)?,
-                    b'"' | b'\'' | b'`' => self.read_short_string(&mut ctx)?,
-                    b'[' if self.check_next2(&ctx, '[', '=') => self.read_long_string(&mut ctx)?,
-                    _ => self.read_other_tokens(&mut ctx)?,
-                } {
-                    self.add_token(&mut ctx, token_type, token_value);
+            let count = self.tokens.len();
+            if !self.lex_next(&mut ctx)? {
+                return Ok(());
+            }
+            if self.tokens.len() == count {
+                continue;
+            }
+            let token = self.tokens.last_mut().unwrap();
+            let offset = token.source.offset as isize - delta;
+            if token.is_comment() || offset < edit.end as isize {
+                continue;
+            }
+            if let Ok(i) = unchanged.binary_search_by_key(&(offset as usize), |t| t.source.offset) {
+                let old_token = &unchanged[i];
+                let shift = Shift {
+                    line: old_token.source.line,
+                    lines: token.source.line as isize - old_token.source.line as isize,
+                    cols: token.source.col as isize - old_token.source.col as isize,
+                    offset: delta,
+                };
+                token.trailing_comments = old_token.trailing_comments.clone();
+                token
+                    .trailing_comments
+                    .iter_mut()
+                    .for_each(|t| shift.apply(t));
+                for old_token in unchanged[i + 1..].iter() {
+                    let mut token = old_token.clone();
+                    shift.apply(&mut token);
+                    self.tokens.push(token);
                 }
-            } else {
-                // append eos and return tokens
-                self.add_token(&mut ctx, TokenType::Eos, TokenValue::None);
-                return Ok(std::mem::take(&mut self.tokens));
+                return Ok(());
             }
         }
     }
 
+    // lex a token at the current position, return false after eos is added
+    fn lex_next(&mut self, ctx: &mut Context) -> Result<bool, LexError> {
+        ctx.save();
+        if let Some(c) = ctx.get() {
+            if let Some((token_type, token_value)) = match c {
+                _ if Lexer::is_line_break(c) => self.read_line_break(ctx)?,
+                _ if Lexer::is_space(c) => self.read_space(ctx)?,
+                _ if Lexer::is_digit(c) => self.read_number(ctx)?,
+                b'-' if self.check_next(ctx, '-') => self.read_comment(ctx)?,
+                b'=' => self.read_eq_assign(ctx)?,
+                b'<' => self.read_le_shl_lt(ctx)?,
+                b'>' => self.read_ge_shr_gt(ctx)?,
+                b'/' if self.check_next(ctx, '/') => self.read_idiv(ctx)?,
+                b'~' => self.read_ne_xor(ctx)?,
+                b':' => self.read_colon(ctx)?,
+                b'.' => self.read_attr_concat_dots_numbers(ctx)?,
+                b'"' | b'\'' | b'`' => self.read_short_string(ctx)?,
+                b'[' if self.check_next2(ctx, '[', '=') => self.read_long_string(ctx)?,
+                _ => self.read_other_tokens(ctx)?,
+            } {
+                self.check_version(ctx, token_type)?;
+                self.add_token(ctx, token_type, token_value);
+            }
+            Ok(true)
+        } else {
+            self.add_token(ctx, TokenType::Eos, TokenValue::None);
+            Ok(false)
+        }
+    }
+
     pub fn tokens(&self) -> &Vec<Token> {
         &self.tokens
     }
@@ -167,11 +565,13 @@ impl<'a> Lexer {
 
     fn read_comment(&mut self, ctx: &mut Context) -> LexResult {
         ctx.skip(2);
-        let sep_count = self.try_read_long_string_boundary(ctx, b'[');
-        if sep_count >= 0 {
-            let comment = self.read_long_string_impl(ctx, sep_count as usize, "comment")?;
+        if let Some(sep_count) = self.try_read_long_string_boundary(ctx)? {
+            let comment = self.read_long_string_impl(ctx, sep_count, "comment")?;
             if self.config.reserve_comments {
-                success((TokenType::MComment, TokenValue::Str(comment)))
+                match str::from_utf8(&ctx.buffer[comment]) {
+                    Ok(comment) => success((TokenType::MComment, TokenValue::Str(comment.into()))),
+                    Err(_) => self.lex_error(ctx, "invalid multi line comment"),
+                }
             } else {
                 Ok(None)
             }
@@ -188,12 +588,10 @@ impl<'a> Lexer {
             }
             ctx.write_into(1, &mut bytes);
         }
-        if let Ok(comment) = str::from_utf8(&bytes) {
-            if self.config.reserve_comments {
-                success((TokenType::SComment, TokenValue::Str(comment.to_string())))
-            } else {
-                Ok(None)
-            }
+        if !self.config.reserve_comments {
+            Ok(None)
+        } else if let Ok(comment) = String::from_utf8(bytes) {
+            success((TokenType::SComment, TokenValue::Str(comment)))
         } else {
             self.lex_error(ctx, "invalid single line comment")
         }
@@ -325,13 +723,25 @@ impl<'a> Lexer {
         })
     }
 
+    // error at the current char, which should be a hex digit
+    fn hex_digit_expected<T>(&self, ctx: &Context) -> Result<T, LexError> {
+        let near = match ctx.get() {
+            Some(c) => format!("'{}'", c as char),
+            None => "<eof>".to_string(),
+        };
+        self.lex_error(ctx, &format!("hexadecimal digit expected near {}", near))
+    }
+
+    // exactly two hex digits after `\x`, the error points at the first char which is not
     fn try_read_hex_esc(&mut self, ctx: &mut Context) -> Result<u8, LexError> {
-        if let Some(p1) = self.try_read_hexa(ctx) {
-            if let Some(p2) = self.try_read_hexa(ctx) {
-                return Ok((p1 << 4) + p2);
+        let mut r = 0;
+        for _ in 0..2 {
+            match self.try_read_hexa(ctx) {
+                Some(c) => r = (r << 4) + c,
+                None => return self.hex_digit_expected(ctx),
             }
         }
-        self.lex_error(ctx, "hexadecimal digit expected")
+        Ok(r)
     }
 
     fn try_read_utf8_esc(
@@ -339,38 +749,59 @@ impl<'a> Lexer {
         ctx: &mut Context,
         bytes: &mut Vec<u8>,
     ) -> Result<(), LexError> {
-        if let Some(c) = ctx.get() {
-            if c != b'{' {
-                return self.lex_error(ctx, "missing '{'");
-            }
-            ctx.next();
-            if let Some(c) = self.try_read_hexa(ctx) {
-                let mut r = c as u32;
-                while let Some(c) = self.try_read_hexa(ctx) {
-                    r = (r << 4) + (c as u32);
-                    if r > 0x10FFFF {
-                        return self.lex_error(ctx, "UTF-8 value too large");
-                    }
-                }
-                if self.check_current(ctx, '}') {
-                    if let Some(c) = std::char::from_u32(r) {
-                        let mut string = String::new();
-                        string.push(c);
-                        bytes.append(&mut string.into_bytes());
-                        ctx.next();
-                    } else {
-                        return self.lex_error(ctx, "invalid utf8 codepoint");
-                    }
-                } else {
-                    return self.lex_error(ctx, "missing '}'");
-                }
-            } else {
-                return self.lex_error(ctx, "hexadecimal digit expected");
+        // lua 5.4 accepts the extended utf8 form up to 2^31
+        let max: u64 = match self.config.lua_version {
+            LuaVersion::Lua51 | LuaVersion::Lua53 => 0x10FFFF,
+            LuaVersion::Lua54 => 0x7FFFFFFF,
+        };
+        if ctx.get() != Some(b'{') {
+            return self.lex_error(ctx, "missing '{'");
+        }
+        ctx.next();
+        let mut r = match self.try_read_hexa(ctx) {
+            Some(c) => c as u64,
+            None => return self.hex_digit_expected(ctx),
+        };
+        while let Some(c) = self.try_read_hexa(ctx) {
+            r = (r << 4) + (c as u64);
+            if r > max {
+                return self.lex_error(ctx, "UTF-8 value too large");
             }
         }
+        if !self.check_current(ctx, '}') {
+            return self.lex_error(ctx, "missing '}'");
+        }
+        // surrogates are not valid scalar values, `char::from_u32` refuses them as well
+        if (0xD800..=0xDFFF).contains(&r) {
+            return self.lex_error(ctx, &format!("invalid utf8 codepoint {:#X}", r));
+        }
+        Lexer::utf8_esc(r as u32, bytes);
+        ctx.next();
         Ok(())
     }
 
+    // encode x as utf8 into bytes, using lua's extended form (up to 6 bytes) for values beyond 0x10FFFF
+    fn utf8_esc(x: u32, bytes: &mut Vec<u8>) {
+        if x < 0x80 {
+            bytes.push(x as u8);
+            return;
+        }
+        let mut x = x;
+        let mut buffer: Vec<u8> = Vec::new();
+        // maximum that fits in first byte
+        let mut mfb = 0x3f;
+        loop {
+            buffer.push(0x80 | (x & 0x3f) as u8);
+            x >>= 6;
+            mfb >>= 1;
+            if x <= mfb {
+                break;
+            }
+        }
+        buffer.push(((!mfb << 1) | x) as u8);
+        bytes.extend(buffer.iter().rev());
+    }
+
     fn try_read_dec_esc(
         &mut self,
         ctx: &mut Context,
@@ -399,6 +830,12 @@ impl<'a> Lexer {
     fn try_read_esc(&mut self, ctx: &mut Context, bytes: &mut Vec<u8>) -> Result<(), LexError> {
         ctx.next();
         if let Some(next) = ctx.get() {
+            if Lexer::is_line_break(next) {
+                // \r, \n, \r\n and \n\r are all a single line break
+                self.read_line_break(ctx)?;
+                bytes.push(b'\n');
+                return Ok(());
+            }
             ctx.next();
             match next {
                 b'n' => bytes.push(b'\n'),
@@ -413,10 +850,6 @@ impl<'a> Lexer {
                     bytes.push(v);
                 }
                 b'u' => self.try_read_utf8_esc(ctx, bytes)?,
-                b'\r' | b'\n' => {
-                    bytes.push(b'\n');
-                    ctx.inc_line();
-                }
                 b'\\' | b'"' | b'\'' => bytes.push(next),
                 b'z' => {
                     // skip the following span of white-space characters, including line breaks
@@ -424,8 +857,7 @@ impl<'a> Lexer {
                         match c {
                             _ if Lexer::is_space(c) => ctx.next(),
                             _ if Lexer::is_line_break(c) => {
-                                ctx.next();
-                                ctx.inc_line();
+                                self.read_line_break(ctx)?;
                             }
                             _ => break,
                         }
@@ -441,57 +873,96 @@ impl<'a> Lexer {
     }
 
     fn read_short_string(&mut self, ctx: &mut Context) -> LexResult {
+        // content is only copied into `bytes` after the first escape, origin strings are
+        // taken from the input as they are
         let mut bytes: Vec<u8> = Vec::new();
+        let mut escaped = false;
         let start = ctx.get();
-        if self.config.use_origin_string {
-            bytes.push(start.unwrap());
-        }
+        let line = ctx.line;
         ctx.next();
-        let unfinished_error: &'static str = "unfinished string";
+        let content_start = ctx.current;
+        let unfinished_error = || format!("unfinished string (starting at line {})", line);
         while ctx.get() != start {
+            self.check_token_length(ctx, ctx.current - content_start)?;
             match ctx.get() {
-                Some(b'\\') if self.config.use_origin_string => ctx.write_into(2, &mut bytes),
-                Some(b'\\') => self.try_read_esc(ctx, &mut bytes)?,
+                Some(b'\\') if self.config.use_origin_string => {
+                    if self.check_next_if(ctx, Lexer::is_line_break) {
+                        ctx.next();
+                        self.read_line_break(ctx)?;
+                    } else {
+                        ctx.skip(2)
+                    }
+                }
+                Some(b'\\') => {
+                    if !escaped {
+                        bytes.extend_from_slice(&ctx.buffer[content_start..ctx.current]);
+                        escaped = true;
+                    }
+                    self.try_read_esc(ctx, &mut bytes)?
+                }
                 Some(c) => {
                     if Lexer::is_line_break(c) {
-                        return self.lex_error(ctx, unfinished_error);
+                        return self.lex_error(ctx, &unfinished_error());
                     } else {
-                        bytes.push(c);
+                        if escaped {
+                            bytes.push(c);
+                        }
                         ctx.next();
                     }
                 }
-                None => return self.lex_error(ctx, unfinished_error),
+                None => return self.lex_error(ctx, &unfinished_error()),
             }
         }
-        if self.config.use_origin_string {
-            bytes.push(ctx.get().unwrap());
-        }
-        if let Ok(string) = String::from_utf8(bytes) {
-            ctx.next();
-            success((TokenType::String, TokenValue::Str(string)))
+        self.check_token_length(ctx, ctx.current - content_start)?;
+        ctx.next();
+        let content = if self.config.use_origin_string {
+            &ctx.buffer[content_start - 1..ctx.current]
+        } else {
+            &ctx.buffer[content_start..ctx.current - 1]
+        };
+        let value = if escaped {
+            String::from_utf8(bytes)
+                .map(|string| self.str_value(string))
+                .ok()
+        } else if self.borrow {
+            // the value is taken from the input by `run_borrowed`
+            str::from_utf8(content).ok().map(|_| TokenValue::None)
         } else {
-            self.lex_error(ctx, "invalid utf8 string")
+            str::from_utf8(content).ok().map(|s| self.str_value(s))
+        };
+        match value {
+            Some(value) => success((TokenType::String, value)),
+            None => self.lex_error(ctx, "invalid utf8 string"),
         }
     }
 
-    // return count of '===' if a long string, otherwise return -1
-    fn try_read_long_string_boundary(&self, ctx: &mut Context, sep: u8) -> i8 {
-        if self.check_current(ctx, sep as char) {
-            let mut sep_count = 0;
-            loop {
-                if let Some(c) = ctx.get_ahead(sep_count + 1) {
-                    match c {
-                        b'=' => sep_count += 1,
-                        _ if c == sep => {
-                            ctx.skip(sep_count + 2);
-                            return sep_count as i8;
-                        }
-                        _ => break,
-                    };
+    // count '=' after current char, return the count and the char after them
+    fn count_long_string_sep(ctx: &Context) -> (usize, Option<u8>) {
+        let mut sep_count = 0;
+        while let Some(b'=') = ctx.get_ahead(sep_count + 1) {
+            sep_count += 1;
+        }
+        (sep_count, ctx.get_ahead(sep_count + 1))
+    }
+
+    // return count of '===' if current is the opening of a long string, otherwise return None
+    fn try_read_long_string_boundary(&self, ctx: &mut Context) -> Result<Option<usize>, LexError> {
+        if self.check_current(ctx, '[') {
+            let (sep_count, next) = Lexer::count_long_string_sep(ctx);
+            if next == Some(b'[') {
+                if let Some(max) = self.config.max_long_bracket_level {
+                    if sep_count > max {
+                        return self.lex_error(
+                            ctx,
+                            &format!("long bracket level too large (limit is {})", max),
+                        );
+                    }
                 }
+                ctx.skip(sep_count + 2);
+                return Ok(Some(sep_count));
             }
         }
-        -1
+        Ok(None)
     }
 
     // read long string
@@ -500,7 +971,7 @@ impl<'a> Lexer {
         ctx: &mut Context,
         sep_count: usize,
         sem: &str,
-    ) -> Result<String, LexError> {
+    ) -> Result<Range<usize>, LexError> {
         let line = ctx.line;
         let mut start = 0;
 
@@ -517,20 +988,23 @@ impl<'a> Lexer {
             start = ctx.current;
         }
 
+        let content_start = ctx.current;
         while let Some(c) = ctx.get() {
+            self.check_token_length(ctx, ctx.current - content_start)?;
             match c {
                 b']' => {
-                    if self.try_read_long_string_boundary(ctx, b']') == sep_count as i8 {
+                    let (count, next) = Lexer::count_long_string_sep(ctx);
+                    if count == sep_count && next == Some(b']') {
+                        ctx.skip(sep_count + 2);
                         let length = if self.config.use_origin_string {
                             ctx.current - start
                         } else {
                             ctx.current - 2 - sep_count - start
                         };
-                        if let Some(slice) = ctx.buffer.get(start..(start + length)) {
-                            return Ok(slice.to_string());
-                        }
+                        return Ok(start..(start + length));
                     } else {
-                        ctx.next();
+                        // a closing bracket may start right after a mismatched one, e.g. `]=]]`
+                        ctx.skip(count + 1);
                     }
                 }
                 _ if Lexer::is_line_break(c) => {
@@ -541,17 +1015,25 @@ impl<'a> Lexer {
         }
         self.lex_error(
             ctx,
-            &format!("unfinished long {} (starting at line {})", sem, line),
+            &format!(
+                "unfinished long {} (starting at line {}, expected ']{}]')",
+                sem,
+                line,
+                "=".repeat(sep_count)
+            ),
         )
     }
 
     fn read_long_string(&mut self, ctx: &mut Context) -> LexResult {
-        let sep_count = self.try_read_long_string_boundary(ctx, b'[');
-        if sep_count >= 0 {
-            let string = self.read_long_string_impl(ctx, sep_count as usize, "string")?;
-            return success((TokenType::String, TokenValue::Str(string)));
+        if let Some(sep_count) = self.try_read_long_string_boundary(ctx)? {
+            let string = self.read_long_string_impl(ctx, sep_count, "string")?;
+            return match str::from_utf8(&ctx.buffer[string]) {
+                Ok(_) if self.borrow => success((TokenType::String, TokenValue::None)),
+                Ok(string) => success((TokenType::String, self.str_value(string))),
+                Err(_) => self.lex_error(ctx, "invalid utf8 string"),
+            };
         }
-        unreachable!()
+        self.lex_error(ctx, "invalid long string delimiter")
     }
 
     fn read_other_tokens(&mut self, ctx: &mut Context) -> LexResult {
@@ -581,17 +1063,23 @@ impl<'a> Lexer {
                 ctx.next();
                 return success((t, TokenValue::None));
             } else if self.check_current_if(ctx, Lexer::is_valid_name_start) {
-                let mut word: Vec<u8> = Vec::new();
-                ctx.write_into(1, &mut word);
+                let start = ctx.current;
+                ctx.next();
                 while self.check_current_if(ctx, Lexer::is_valid_name) {
-                    ctx.write_into(1, &mut word);
+                    ctx.next();
+                    self.check_token_length(ctx, ctx.current - start)?;
                 }
-                if let Ok(s) = str::from_utf8(&word) {
-                    if let Some(t) = TokenType::from_keyword(s) {
-                        return success((t, TokenValue::None));
-                    } else {
-                        return success((TokenType::Name, TokenValue::Str(s.to_string())));
+                if let Ok(s) = str::from_utf8(&ctx.buffer[start..ctx.current]) {
+                    match TokenType::from_keyword(s) {
+                        // `goto` is not reserved in lua 5.1
+                        Some(TokenType::Goto) if self.config.lua_version == LuaVersion::Lua51 => (),
+                        Some(t) => return success((t, TokenValue::None)),
+                        None => (),
+                    }
+                    if self.borrow {
+                        return success((TokenType::Name, TokenValue::None));
                     }
+                    return success((TokenType::Name, self.str_value(s)));
                 }
             } else {
                 return self.lex_error(ctx, &format!("unknown token near {}", c as char));
@@ -600,6 +1088,8 @@ impl<'a> Lexer {
         unreachable!()
     }
 
+    // drop tokens left by the last run, e.g. the partial tokens of a failed run,
+    // the config and the allocation of tokens are kept
     fn reset(&mut self) {
         self.tokens.clear();
     }
@@ -693,7 +1183,10 @@ impl<'a> Lexer {
             }
         } else {
             while i < len && Lexer::is_digit(bytes[i]) {
-                r = r * 10 + (Lexer::to_digit(bytes[i]) as IntType);
+                // decimal integers which overflow are read as floats
+                r = r
+                    .checked_mul(10)?
+                    .checked_add(Lexer::to_digit(bytes[i]) as IntType)?;
                 i += 1;
                 empty = false;
             }
@@ -713,18 +1206,19 @@ impl<'a> Lexer {
         if Lexer::starts_with_0x(bytes, i) {
             Lexer::str_to_hex_float(&bytes[2..])
         } else {
-            match s.parse::<FloatType>() {
-                Ok(f) => Some(f),
-                Err(_e) => None,
-            }
+            s.parse::<FloatType>().ok()
         }
     }
 
+    // like lua_strx2number, digits after the first 30 significant ones only count
+    // for the exponent, so the mantissa never overflows
     pub fn str_to_hex_float(bytes: &[u8]) -> Option<FloatType> {
+        const MAX_SIG_DIGITS: usize = 30;
         let (sign, mut i) = Lexer::get_sign(bytes, 0);
         let mut has_dot = false;
         let mut e: IntType = 0;
         let mut r = 0.0;
+        let mut sig_digits = 0;
         let mut empty = true;
         while i < bytes.len() {
             match bytes[i] {
@@ -737,7 +1231,14 @@ impl<'a> Lexer {
                     }
                 }
                 _ if Lexer::is_hex_digit(bytes[i]) => {
-                    r = r * 16.0 + Lexer::to_hex_digit(bytes[i]) as FloatType;
+                    if sig_digits == 0 && bytes[i] == b'0' {
+                        // leading zeros are not significant
+                    } else if sig_digits < MAX_SIG_DIGITS {
+                        sig_digits += 1;
+                        r = r * 16.0 + Lexer::to_hex_digit(bytes[i]) as FloatType;
+                    } else {
+                        e += 1;
+                    }
                     if has_dot {
                         e -= 1;
                     }
@@ -751,12 +1252,14 @@ impl<'a> Lexer {
         if i < bytes.len() && (bytes[i] == b'p' || bytes[i] == b'P') {
             i += 1;
             let (esign, mut index) = Lexer::get_sign(bytes, i);
-            let mut exp_value = 0;
+            let mut exp_value: IntType = 0;
             let mut exp_empty = true;
             while index < bytes.len() {
                 if Lexer::is_digit(bytes[index]) {
                     exp_empty = false;
-                    exp_value = exp_value * 10 + Lexer::to_digit(bytes[index]) as IntType;
+                    exp_value = exp_value
+                        .saturating_mul(10)
+                        .saturating_add(Lexer::to_digit(bytes[index]) as IntType);
                 } else {
                     break;
                 }
@@ -765,19 +1268,38 @@ impl<'a> Lexer {
             if exp_empty {
                 return None;
             }
-            e += exp_value * esign;
+            e = e.saturating_add(exp_value * esign);
             i = index;
         }
-        r *= (2 as FloatType).powf(e as FloatType);
-        Lexer::skip_spaces(bytes, i);
+        i = Lexer::skip_spaces(bytes, i);
         if empty || i != bytes.len() {
             None
         } else {
-            Some(r * (sign as FloatType))
+            Some(Lexer::ldexp(r, e) * (sign as FloatType))
         }
     }
 
-    fn str_to_num(s: &str) -> Number {
+    // r * 2^e, scaled in steps so that 2^e itself never overflows or underflows
+    fn ldexp(mut r: FloatType, mut e: IntType) -> FloatType {
+        let two: FloatType = 2.0;
+        while e > FloatType::MAX_EXP as IntType {
+            if r == 0.0 || r.is_infinite() {
+                return r;
+            }
+            r *= two.powi(FloatType::MAX_EXP - 1);
+            e -= (FloatType::MAX_EXP - 1) as IntType;
+        }
+        while e < FloatType::MIN_EXP as IntType {
+            if r == 0.0 {
+                return r;
+            }
+            r *= two.powi(FloatType::MIN_EXP - 1);
+            e -= (FloatType::MIN_EXP - 1) as IntType;
+        }
+        r * two.powi(e as i32)
+    }
+
+    pub fn str_to_num(s: &str) -> Number {
         if let Some(i) = Lexer::str_to_int(s) {
             Number::Int(i)
         } else if let Some(f) = Lexer::str_to_float(s) {
@@ -817,32 +1339,103 @@ impl<'a> Lexer {
         self.check(ctx.get_next(), c)
     }
 
+    fn check_next_if(&self, ctx: &Context, f: impl Fn(u8) -> bool) -> bool {
+        self.check_if(ctx.get_next(), f)
+    }
+
     fn check_next2(&self, ctx: &Context, c1: char, c2: char) -> bool {
         self.check(ctx.get_next(), c1) || self.check(ctx.get_next(), c2)
     }
 
+    // comments are kept as tokens and also attached to the next non-comment token,
+    // trailing comments of the input are attached to the eos token.
+    // the context is created per run and `reset` clears tokens of previous runs,
+    // so comment_offset always indexes tokens of the current run
     fn add_token(&mut self, ctx: &mut Context, t: TokenType, value: TokenValue) {
         let source = ctx.get_saved_source();
-        let mut comments: Vec<Token> = Vec::new();
-
-        if !t.is_comment() {
-            for comment in &mut self.tokens[ctx.comment_offset..ctx.offset].iter() {
-                comments.push(comment.clone());
+        debug_assert!(ctx.comment_offset <= self.tokens.len());
+        let raw = match t {
+            TokenType::Int | TokenType::Flt if self.config.preserve_number_text => {
+                str::from_utf8(&ctx.buffer[source.offset..source.end()])
+                    .ok()
+                    .map(|s| s.to_string())
             }
-        }
-
-        self.tokens.push(Token {
+            _ => None,
+        };
+        let token = Token {
             t,
             value,
+            raw,
             source,
-            comments,
-        });
-        ctx.offset += 1;
-        if !t.is_comment() {
-            ctx.comment_offset = ctx.offset;
+            comments: Vec::new(),
+            trailing_comments: Vec::new(),
+        };
+
+        if t == TokenType::SComment
+            && self.config.attach_trailing_comments
+            && ctx.comment_offset == self.tokens.len()
+        {
+            if let Some(prev) = self.tokens.last_mut() {
+                if !prev.is_comment() && prev.source.line == source.line {
+                    prev.trailing_comments.push(token.clone());
+                    self.tokens.push(token);
+                    ctx.comment_offset = self.tokens.len();
+                    return;
+                }
+            }
+        }
+
+        let comments = if t.is_comment() {
+            Vec::new()
+        } else {
+            let comments = self.tokens[ctx.comment_offset..].to_vec();
+            ctx.comment_offset = self.tokens.len() + 1;
+            comments
+        };
+        self.tokens.push(Token { comments, ..token });
+    }
+
+    fn str_value<S: AsRef<str> + Into<String>>(&mut self, s: S) -> TokenValue {
+        if self.config.intern_strings {
+            let (symbol, s) = self.interner.intern(s.as_ref());
+            TokenValue::Interned(symbol, s)
+        } else {
+            TokenValue::Str(s.into())
         }
     }
 
+    fn check_token_length(&self, ctx: &Context, length: usize) -> Result<(), LexError> {
+        match self.config.max_token_length {
+            Some(max) if length > max => {
+                self.lex_error(ctx, &format!("token too long (limit is {})", max))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // tokens added after lua 5.1 are rejected in 5.1 mode
+    fn check_version(&self, ctx: &Context, t: TokenType) -> Result<(), LexError> {
+        if self.config.lua_version != LuaVersion::Lua51 {
+            return Ok(());
+        }
+        let op = match t {
+            TokenType::IDiv => {
+                return self.lex_error(ctx, "integer division not supported in Lua 5.1")
+            }
+            TokenType::DbColon => return self.lex_error(ctx, "labels not supported in Lua 5.1"),
+            TokenType::BAnd => "&",
+            TokenType::BOr => "|",
+            TokenType::BXor => "~",
+            TokenType::Shl => "<<",
+            TokenType::Shr => ">>",
+            _ => return Ok(()),
+        };
+        self.lex_error(
+            ctx,
+            &format!("bitwise operator '{}' not supported in Lua 5.1", op),
+        )
+    }
+
     fn lex_error<T>(&self, ctx: &Context, msg: &str) -> Result<T, LexError> {
         let error_msg = format!("[lex error] {} at line [{}:{}].", msg, ctx.line, ctx.col);
         Lexer::trace_error(LexError(error_msg))
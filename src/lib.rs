@@ -2,10 +2,101 @@ pub mod ast;
 pub mod ast_walker;
 pub mod compiler;
 pub mod consts;
+pub mod format;
 pub mod lexer;
 pub mod opcodes;
+pub mod opcodes_54;
 pub mod parser;
 pub mod proto;
 pub mod tokens;
 pub mod types;
 pub mod utils;
+
+use ast::Block;
+use compiler::{CompileError, Compiler};
+use lexer::{LexError, Lexer, LexerConfig};
+use parser::{Parser, SyntaxError};
+use proto::Proto;
+use rslua_traits::Error;
+
+#[derive(Debug)]
+pub enum ParseError {
+    Lex(LexError),
+    Syntax(SyntaxError),
+}
+
+impl Error for ParseError {
+    fn what(&self) -> &str {
+        match self {
+            ParseError::Lex(e) => e.what(),
+            ParseError::Syntax(e) => e.what(),
+        }
+    }
+}
+
+impl From<LexError> for ParseError {
+    fn from(e: LexError) -> Self {
+        ParseError::Lex(e)
+    }
+}
+
+impl From<SyntaxError> for ParseError {
+    fn from(e: SyntaxError) -> Self {
+        ParseError::Syntax(e)
+    }
+}
+
+// lex and parse lua source to the ast consumed by `Compiler::run`
+pub fn parse(input: &str) -> Result<Block, ParseError> {
+    parse_with(input, LexerConfig::default())
+}
+
+pub fn parse_with(input: &str, config: LexerConfig) -> Result<Block, ParseError> {
+    let mut lexer = Lexer::default();
+    lexer.set_config(config);
+    let tokens = lexer.run(input)?;
+    Ok(Parser::default().run(tokens)?)
+}
+
+// error of any stage of the pipeline from source text to proto
+#[derive(Debug)]
+pub enum LuaError {
+    Lex(LexError),
+    Syntax(SyntaxError),
+    Compile(CompileError),
+}
+
+impl Error for LuaError {
+    fn what(&self) -> &str {
+        match self {
+            LuaError::Lex(e) => e.what(),
+            LuaError::Syntax(e) => e.what(),
+            LuaError::Compile(e) => e.what(),
+        }
+    }
+}
+
+impl From<ParseError> for LuaError {
+    fn from(e: ParseError) -> Self {
+        match e {
+            ParseError::Lex(e) => LuaError::Lex(e),
+            ParseError::Syntax(e) => LuaError::Syntax(e),
+        }
+    }
+}
+
+impl From<CompileError> for LuaError {
+    fn from(e: CompileError) -> Self {
+        LuaError::Compile(e)
+    }
+}
+
+// compile lua source to the proto of its main function
+pub fn compile(input: &str) -> Result<Proto, LuaError> {
+    compile_with(input, LexerConfig::default())
+}
+
+pub fn compile_with(input: &str, config: LexerConfig) -> Result<Proto, LuaError> {
+    let block = parse_with(input, config)?;
+    Ok(Compiler::default().run(&block)?)
+}
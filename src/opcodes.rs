@@ -1,3 +1,5 @@
+use crate::proto::Proto;
+
 // Comments from lopcodes.h
 // We assume that instructions are unsigned numbers.
 // All instructions have an opcode in the first 6 bits.
@@ -15,7 +17,7 @@
 // represented by 2*max), which is half the maximum for the corresponding
 // unsigned argument.
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum OpMode {
     IA,
     IAB,
@@ -48,10 +50,37 @@ pub const MAXARG_BX: u32 = (1 << SIZE_BX) - 1;
 pub const MAXARG_SBX: i32 = (MAXARG_BX as i32) >> 1;
 
 pub const MASK_K: u32 = 1 << (SIZE_B - 1);
+// max index of const which can be used as RK
+pub const MAXINDEXRK: u32 = MASK_K - 1;
 
 pub const NO_JUMP: i32 = -1;
 pub const NO_REG: u32 = MAXARG_A;
 
+// option for multiple returns, B or C of CALL, RETURN and VARARG will be 0
+pub const LUA_MULTRET: u32 = u32::MAX;
+
+// number of list items to accumulate before a SETLIST instruction
+pub const LFIELDS_PER_FLUSH: u32 = 50;
+
+// converts an integer to a "floating point byte", represented as
+// (eeeeexxx), where the real value is (1xxx) * 2^(eeeee - 1) if
+// eeeee != 0 and (xxx) otherwise.
+pub fn int2fb(mut x: u32) -> u32 {
+    let mut e = 0;
+    if x < 8 {
+        return x;
+    }
+    while x >= (8 << 4) {
+        x = (x + 0xf) >> 4;
+        e += 4;
+    }
+    while x >= (8 << 1) {
+        x = (x + 1) >> 1;
+        e += 1;
+    }
+    ((e + 1) << 3) | (x - 8)
+}
+
 pub fn is_const(index: u32) -> bool {
     index & MASK_K != 0
 }
@@ -229,6 +258,78 @@ pub enum OpCode {
     ExtraArg,
 }
 
+// mode flags of an op, like luaP_opmodes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OpInfo {
+    pub format: OpMode,
+    // the op sets R(A)
+    pub sets_a: bool,
+    // the op is a test, the next instruction must be a jump
+    pub test: bool,
+    // the op jumps by sBx
+    pub jump: bool,
+}
+
+const fn op_info(test: bool, sets_a: bool, jump: bool, format: OpMode) -> OpInfo {
+    OpInfo {
+        format,
+        sets_a,
+        test,
+        jump,
+    }
+}
+
+// indexed by OpCode
+pub const OPCODE_INFO: [OpInfo; 47] = [
+    op_info(false, true, false, OpMode::IAB),   // Move
+    op_info(false, true, false, OpMode::IABx),  // LoadK
+    op_info(false, true, false, OpMode::IA),    // LoadKx
+    op_info(false, true, false, OpMode::IABC),  // LoadBool
+    op_info(false, true, false, OpMode::IAB),   // LoadNil
+    op_info(false, true, false, OpMode::IAB),   // GetUpVal
+    op_info(false, true, false, OpMode::IABC),  // GetTabUp
+    op_info(false, true, false, OpMode::IABC),  // GetTable
+    op_info(false, false, false, OpMode::IABC), // SetTabUp
+    op_info(false, false, false, OpMode::IAB),  // SetUpVal
+    op_info(false, false, false, OpMode::IABC), // SetTable
+    op_info(false, true, false, OpMode::IABC),  // NewTable
+    op_info(false, true, false, OpMode::IABC),  // Self_
+    op_info(false, true, false, OpMode::IABC),  // Add
+    op_info(false, true, false, OpMode::IABC),  // Sub
+    op_info(false, true, false, OpMode::IABC),  // Mul
+    op_info(false, true, false, OpMode::IABC),  // Mod
+    op_info(false, true, false, OpMode::IABC),  // Pow
+    op_info(false, true, false, OpMode::IABC),  // Div
+    op_info(false, true, false, OpMode::IABC),  // IDiv
+    op_info(false, true, false, OpMode::IABC),  // BAdd
+    op_info(false, true, false, OpMode::IABC),  // BOr
+    op_info(false, true, false, OpMode::IABC),  // BXor
+    op_info(false, true, false, OpMode::IABC),  // Shl
+    op_info(false, true, false, OpMode::IABC),  // Shr
+    op_info(false, true, false, OpMode::IAB),   // Unm
+    op_info(false, true, false, OpMode::IAB),   // BNot
+    op_info(false, true, false, OpMode::IAB),   // Not
+    op_info(false, true, false, OpMode::IAB),   // Len
+    op_info(false, true, false, OpMode::IABC),  // Concat
+    op_info(false, false, true, OpMode::IAsBx), // Jmp
+    op_info(true, false, false, OpMode::IABC),  // Eq
+    op_info(true, false, false, OpMode::IABC),  // Lt
+    op_info(true, false, false, OpMode::IABC),  // Le
+    op_info(true, false, false, OpMode::IAC),   // Test
+    op_info(true, true, false, OpMode::IABC),   // TestSet
+    op_info(false, true, false, OpMode::IABC),  // Call
+    op_info(false, true, false, OpMode::IABC),  // TailCall
+    op_info(false, false, false, OpMode::IAB),  // Return
+    op_info(false, true, true, OpMode::IAsBx),  // ForLoop
+    op_info(false, true, true, OpMode::IAsBx),  // ForPrep
+    op_info(false, false, false, OpMode::IAC),  // TForCall
+    op_info(false, true, true, OpMode::IAsBx),  // TForLoop
+    op_info(false, false, false, OpMode::IABC), // SetList
+    op_info(false, true, false, OpMode::IABx),  // Closure
+    op_info(false, true, false, OpMode::IAB),   // Vararg
+    op_info(false, false, false, OpMode::IAx),  // ExtraArg
+];
+
 impl OpCode {
     pub fn from_u32(u: u32) -> OpCode {
         match u {
@@ -291,8 +392,54 @@ impl OpCode {
         matches!(self, OpCode::Eq | OpCode::Lt | OpCode::Le)
     }
 
+    pub fn info(self) -> &'static OpInfo {
+        &OPCODE_INFO[self as usize]
+    }
+
+    pub fn format(self) -> OpMode {
+        self.info().format
+    }
+
+    pub fn sets_a(self) -> bool {
+        self.info().sets_a
+    }
+
+    pub fn has_jump(self) -> bool {
+        self.info().jump
+    }
+
     pub fn followed_by_jump(self) -> bool {
-        self.is_test() || self.is_comp()
+        self.info().test
+    }
+
+    // whether the only effect of the op is setting R(A) from other operands
+    pub fn only_sets_a(self) -> bool {
+        matches!(
+            self,
+            OpCode::LoadK
+                | OpCode::GetUpVal
+                | OpCode::GetTabUp
+                | OpCode::GetTable
+                | OpCode::NewTable
+                | OpCode::Add
+                | OpCode::Sub
+                | OpCode::Mul
+                | OpCode::Mod
+                | OpCode::Pow
+                | OpCode::Div
+                | OpCode::IDiv
+                | OpCode::BAdd
+                | OpCode::BOr
+                | OpCode::BXor
+                | OpCode::Shl
+                | OpCode::Shr
+                | OpCode::Unm
+                | OpCode::BNot
+                | OpCode::Not
+                | OpCode::Len
+                | OpCode::Concat
+                | OpCode::Closure
+        )
     }
 
     pub fn go_if_falsy_by_default(self) -> bool {
@@ -304,11 +451,27 @@ impl OpCode {
     }
 }
 
+// instruction with its operands decoded by the format of its op,
+// the IA, IAB and IAC formats decode as ABC with the unused operands 0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DecodedInsn {
+    ABC { op: OpCode, a: u32, b: u32, c: u32 },
+    ABx { op: OpCode, a: u32, bx: u32 },
+    AsBx { op: OpCode, a: u32, sbx: i32 },
+    Ax { op: OpCode, ax: u32 },
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instruction(u32);
 
 #[allow(dead_code)]
 #[allow(non_snake_case)]
 impl Instruction {
+    // encoded 32 bits of the instruction
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
     pub fn get_op(&self) -> OpCode {
         OpCode::from_u32(((self.0) >> POS_OP) & Instruction::mask1(SIZE_OP, 0))
     }
@@ -406,54 +569,148 @@ impl Instruction {
     }
 
     pub fn mode(&self) -> OpMode {
-        match self.get_op() {
-            OpCode::Move => OpMode::IAB,
-            OpCode::LoadK => OpMode::IABx,
-            OpCode::LoadKx => OpMode::IA,
-            OpCode::LoadBool => OpMode::IABC,
-            OpCode::LoadNil => OpMode::IAB,
-            OpCode::GetUpVal => OpMode::IAB,
-            OpCode::GetTabUp => OpMode::IABC,
-            OpCode::GetTable => OpMode::IABC,
-            OpCode::SetTabUp => OpMode::IABC,
-            OpCode::SetUpVal => OpMode::IAB,
-            OpCode::SetTable => OpMode::IABC,
-            OpCode::NewTable => OpMode::IABC,
-            OpCode::Self_ => OpMode::IABC,
-            OpCode::Add => OpMode::IABC,
-            OpCode::Sub => OpMode::IABC,
-            OpCode::Mul => OpMode::IABC,
-            OpCode::Mod => OpMode::IABC,
-            OpCode::Pow => OpMode::IABC,
-            OpCode::Div => OpMode::IABC,
-            OpCode::IDiv => OpMode::IABC,
-            OpCode::BAdd => OpMode::IABC,
-            OpCode::BOr => OpMode::IABC,
-            OpCode::BXor => OpMode::IABC,
-            OpCode::Shl => OpMode::IABC,
-            OpCode::Shr => OpMode::IABC,
-            OpCode::Unm => OpMode::IAB,
-            OpCode::BNot => OpMode::IAB,
-            OpCode::Not => OpMode::IAB,
-            OpCode::Len => OpMode::IAB,
-            OpCode::Concat => OpMode::IABC,
-            OpCode::Jmp => OpMode::IAsBx,
-            OpCode::Eq => OpMode::IABC,
-            OpCode::Lt => OpMode::IABC,
-            OpCode::Le => OpMode::IABC,
-            OpCode::Test => OpMode::IAC,
-            OpCode::TestSet => OpMode::IABC,
-            OpCode::Call => OpMode::IABC,
-            OpCode::TailCall => OpMode::IABC,
-            OpCode::Return => OpMode::IAB,
-            OpCode::ForLoop => OpMode::IAsBx,
-            OpCode::ForPrep => OpMode::IAsBx,
-            OpCode::TForCall => OpMode::IAC,
-            OpCode::TForLoop => OpMode::IAsBx,
-            OpCode::SetList => OpMode::IAsBx,
-            OpCode::Closure => OpMode::IABx,
-            OpCode::Vararg => OpMode::IAB,
-            OpCode::ExtraArg => OpMode::IAx,
+        self.get_op().format()
+    }
+
+    pub fn decode(&self) -> DecodedInsn {
+        let op = self.get_op();
+        match self.mode() {
+            OpMode::IA | OpMode::IAB | OpMode::IABC | OpMode::IAC => DecodedInsn::ABC {
+                op,
+                a: self.get_arg_A(),
+                b: self.get_arg_B(),
+                c: self.get_arg_C(),
+            },
+            OpMode::IABx => DecodedInsn::ABx {
+                op,
+                a: self.get_arg_A(),
+                bx: self.get_arg_Bx(),
+            },
+            OpMode::IAsBx => DecodedInsn::AsBx {
+                op,
+                a: self.get_arg_A(),
+                sbx: self.get_arg_sBx(),
+            },
+            OpMode::IAx => DecodedInsn::Ax {
+                op,
+                ax: self.get_arg_Ax(),
+            },
+        }
+    }
+}
+
+// rk operand in listings, consts are shown as negative numbers like luac
+fn rk_to_string(rk: u32) -> String {
+    if is_const(rk) {
+        (-1 - (rk & !MASK_K) as i64).to_string()
+    } else {
+        rk.to_string()
+    }
+}
+
+impl Instruction {
+    // render the instruction like `luac -l`, with constants, upvalue names
+    // and jump targets in comments
+    pub fn disassemble(&self, pc: usize, proto: &Proto) -> String {
+        let op = self.get_op();
+        let (a, b, c) = (self.get_arg_A(), self.get_arg_B(), self.get_arg_C());
+        let k = |index: u32| match proto.consts.get(index as usize) {
+            Some(k) => k.to_string(),
+            None => "?".to_string(),
+        };
+        let rk = |rk: u32| {
+            if is_const(rk) {
+                k(rk & !MASK_K)
+            } else {
+                "-".to_string()
+            }
+        };
+        let up_var = |index: u32| match proto.up_vars.get(index as usize) {
+            Some(_) => proto.up_var_name(index).to_string(),
+            None => "?".to_string(),
+        };
+        let b_is_rk = matches!(
+            op,
+            OpCode::SetTabUp
+                | OpCode::SetTable
+                | OpCode::Add
+                | OpCode::Sub
+                | OpCode::Mul
+                | OpCode::Mod
+                | OpCode::Pow
+                | OpCode::Div
+                | OpCode::IDiv
+                | OpCode::BAdd
+                | OpCode::BOr
+                | OpCode::BXor
+                | OpCode::Shl
+                | OpCode::Shr
+                | OpCode::Eq
+                | OpCode::Lt
+                | OpCode::Le
+        );
+        let c_is_rk = b_is_rk || matches!(op, OpCode::GetTabUp | OpCode::GetTable | OpCode::Self_);
+
+        let args = match self.mode() {
+            OpMode::IA => format!("{}", a),
+            OpMode::IAB if b_is_rk => format!("{} {}", a, rk_to_string(b)),
+            OpMode::IAB => format!("{} {}", a, b),
+            OpMode::IAC => format!("{} {}", a, c),
+            OpMode::IABC => format!(
+                "{} {} {}",
+                a,
+                if b_is_rk {
+                    rk_to_string(b)
+                } else {
+                    b.to_string()
+                },
+                if c_is_rk {
+                    rk_to_string(c)
+                } else {
+                    c.to_string()
+                }
+            ),
+            OpMode::IABx if op == OpCode::LoadK => {
+                format!("{} {}", a, -1 - self.get_arg_Bx() as i64)
+            }
+            OpMode::IABx => format!("{} {}", a, self.get_arg_Bx()),
+            OpMode::IAsBx => format!("{} {}", a, self.get_arg_sBx()),
+            OpMode::IAx => format!("{}", -1 - self.get_arg_Ax() as i64),
+        };
+
+        let comment = match op {
+            OpCode::LoadK => k(self.get_arg_Bx()),
+            OpCode::GetUpVal | OpCode::SetUpVal => up_var(b),
+            OpCode::GetTabUp if is_const(c) => format!("{} {}", up_var(b), rk(c)),
+            OpCode::GetTabUp => up_var(b),
+            OpCode::SetTabUp => {
+                let mut comment = up_var(a);
+                for operand in [b, c] {
+                    if is_const(operand) {
+                        comment = format!("{} {}", comment, rk(operand));
+                    }
+                }
+                comment
+            }
+            OpCode::GetTable | OpCode::Self_ if is_const(c) => rk(c),
+            _ if b_is_rk && (is_const(b) || is_const(c)) => format!("{} {}", rk(b), rk(c)),
+            OpCode::Jmp | OpCode::ForLoop | OpCode::ForPrep | OpCode::TForLoop => {
+                format!("to {}", pc as i32 + self.get_arg_sBx() + 2)
+            }
+            OpCode::SetList if c == 0 => match proto.code.get(pc + 1) {
+                Some(next) => next.get_arg_Ax().to_string(),
+                None => String::new(),
+            },
+            OpCode::SetList => c.to_string(),
+            OpCode::ExtraArg => k(self.get_arg_Ax()),
+            _ => String::new(),
+        };
+
+        let name = format!("{:?}", op).trim_end_matches('_').to_uppercase();
+        if comment.is_empty() {
+            format!("{:<9} {}", name, args)
+        } else {
+            format!("{:<9} {:<12}; {}", name, args, comment)
         }
     }
 }
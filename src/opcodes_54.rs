@@ -0,0 +1,581 @@
+use crate::opcodes::OpCode as OpCode53;
+
+// Comments from the Lua 5.4 lopcodes.h
+// All instructions are unsigned 32 bits numbers with an opcode in the first
+// 7 bits. Instructions can have the following formats:
+//
+//        3 3 2 2 2 2 2 2 2 2 2 2 1 1 1 1 1 1 1 1 1 1 0 0 0 0 0 0 0 0 0 0
+//        1 0 9 8 7 6 5 4 3 2 1 0 9 8 7 6 5 4 3 2 1 0 9 8 7 6 5 4 3 2 1 0
+// iABC         C(8)     |      B(8)     |k|     A(8)      |   Op(7)     |
+// iABx               Bx(17)               |     A(8)      |   Op(7)     |
+// iAsBx             sBx (signed)(17)      |     A(8)      |   Op(7)     |
+// iAx                           Ax(25)                    |   Op(7)     |
+// isJ                           sJ (signed)(25)           |   Op(7)     |
+//
+// Compared to 5.3, B and C lost one bit each since RK operands are gone,
+// constants are addressed by dedicated K variants of the ops and the `k`
+// bit takes over the role of the old flag arguments.
+
+// A signed argument is represented in excess K: the represented value is
+// the written unsigned value minus K, where K is half the maximum for the
+// corresponding unsigned argument.
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum OpMode {
+    IABC,
+    IABx,
+    IAsBx,
+    IAx,
+    IsJ,
+}
+
+pub const SIZE_OP: u32 = 7;
+pub const SIZE_A: u32 = 8;
+pub const SIZE_K: u32 = 1;
+pub const SIZE_B: u32 = 8;
+pub const SIZE_C: u32 = 8;
+pub const SIZE_BX: u32 = SIZE_C + SIZE_B + SIZE_K;
+pub const SIZE_AX: u32 = SIZE_BX + SIZE_A;
+pub const SIZE_SJ: u32 = SIZE_BX + SIZE_A;
+
+pub const POS_OP: u32 = 0;
+pub const POS_A: u32 = POS_OP + SIZE_OP;
+pub const POS_K: u32 = POS_A + SIZE_A;
+pub const POS_B: u32 = POS_K + SIZE_K;
+pub const POS_C: u32 = POS_B + SIZE_B;
+pub const POS_BX: u32 = POS_K;
+pub const POS_AX: u32 = POS_A;
+pub const POS_SJ: u32 = POS_A;
+
+pub const MAXARG_A: u32 = (1 << SIZE_A) - 1;
+pub const MAXARG_B: u32 = (1 << SIZE_B) - 1;
+pub const MAXARG_C: u32 = (1 << SIZE_C) - 1;
+pub const MAXARG_AX: u32 = (1 << SIZE_AX) - 1;
+pub const MAXARG_BX: u32 = (1 << SIZE_BX) - 1;
+pub const MAXARG_SJ: u32 = (1 << SIZE_SJ) - 1;
+
+pub const OFFSET_SBX: i32 = (MAXARG_BX >> 1) as i32;
+pub const OFFSET_SJ: i32 = (MAXARG_SJ >> 1) as i32;
+// sB and sC of the immediate ops, like ADDI or EQI
+pub const OFFSET_SC: i32 = (MAXARG_C >> 1) as i32;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OpCode {
+    // A B
+    // R[A] := R[B]
+    Move = 0,
+    // A sBx
+    // R[A] := sBx
+    LoadI,
+    // A sBx
+    // R[A] := (lua_Number)sBx
+    LoadF,
+    // A Bx
+    // R[A] := K[Bx]
+    LoadK,
+    // A
+    // R[A] := K[extra arg]
+    LoadKx,
+    // A
+    // R[A] := false
+    LoadFalse,
+    // A
+    // R[A] := false; pc++
+    LFalseSkip,
+    // A
+    // R[A] := true
+    LoadTrue,
+    // A B
+    // R[A], R[A+1], ..., R[A+B] := nil
+    LoadNil,
+    // A B
+    // R[A] := UpValue[B]
+    GetUpVal,
+    // A B
+    // UpValue[B] := R[A]
+    SetUpVal,
+
+    // A B C
+    // R[A] := UpValue[B][K[C]:string]
+    GetTabUp,
+    // A B C
+    // R[A] := R[B][R[C]]
+    GetTable,
+    // A B C
+    // R[A] := R[B][C]
+    GetI,
+    // A B C
+    // R[A] := R[B][K[C]:string]
+    GetField,
+
+    // A B C
+    // UpValue[A][K[B]:string] := RK(C)
+    SetTabUp,
+    // A B C
+    // R[A][R[B]] := RK(C)
+    SetTable,
+    // A B C
+    // R[A][B] := RK(C)
+    SetI,
+    // A B C
+    // R[A][K[B]:string] := RK(C)
+    SetField,
+
+    // A B C k
+    // R[A] := {}
+    NewTable,
+
+    // A B C
+    // R[A+1] := R[B]; R[A] := R[B][RK(C):string]
+    Self_,
+
+    // A B sC
+    // R[A] := R[B] + sC
+    AddI,
+
+    // A B C
+    // R[A] := R[B] op K[C]:number
+    AddK,
+    SubK,
+    MulK,
+    ModK,
+    PowK,
+    DivK,
+    IDivK,
+    // A B C
+    // R[A] := R[B] op K[C]:integer
+    BAndK,
+    BOrK,
+    BXorK,
+
+    // A B sC
+    // R[A] := R[B] >> sC
+    ShrI,
+    // A B sC
+    // R[A] := sC << R[B]
+    ShlI,
+
+    // A B C
+    // R[A] := R[B] op R[C]
+    Add,
+    Sub,
+    Mul,
+    Mod,
+    Pow,
+    Div,
+    IDiv,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+
+    // A B C
+    // call C metamethod over R[A] and R[B]
+    MmBin,
+    // A sB C k
+    // call C metamethod over R[A] and sB
+    MmBinI,
+    // A B C k
+    // call C metamethod over R[A] and K[B]
+    MmBinK,
+
+    // A B
+    // R[A] := -R[B]
+    Unm,
+    // A B
+    // R[A] := ~R[B]
+    BNot,
+    // A B
+    // R[A] := not R[B]
+    Not,
+    // A B
+    // R[A] := #R[B] (length operator)
+    Len,
+
+    // A B
+    // R[A] := R[A].. ... ..R[A + B - 1]
+    Concat,
+
+    // A
+    // close all upvalues >= R[A]
+    Close,
+    // A
+    // mark variable A "to be closed"
+    Tbc,
+    // sJ
+    // pc += sJ
+    Jmp,
+    // A B k
+    // if ((R[A] == R[B]) ~= k) then pc++
+    Eq,
+    // A B k
+    // if ((R[A] <  R[B]) ~= k) then pc++
+    Lt,
+    // A B k
+    // if ((R[A] <= R[B]) ~= k) then pc++
+    Le,
+
+    // A B k
+    // if ((R[A] == K[B]) ~= k) then pc++
+    EqK,
+    // A sB k
+    // if ((R[A] == sB) ~= k) then pc++
+    EqI,
+    // A sB k
+    // if ((R[A] < sB) ~= k) then pc++
+    LtI,
+    // A sB k
+    // if ((R[A] <= sB) ~= k) then pc++
+    LeI,
+    // A sB k
+    // if ((R[A] > sB) ~= k) then pc++
+    GtI,
+    // A sB k
+    // if ((R[A] >= sB) ~= k) then pc++
+    GeI,
+
+    // A k
+    // if (not R[A] == k) then pc++
+    Test,
+    // A B k
+    // if (not R[B] == k) then pc++ else R[A] := R[B]
+    TestSet,
+
+    // A B C
+    // R[A], ... ,R[A+C-2] := R[A](R[A+1], ... ,R[A+B-1])
+    Call,
+    // A B C k
+    // return R[A](R[A+1], ... ,R[A+B-1])
+    TailCall,
+
+    // A B C k
+    // return R[A], ... ,R[A+B-2]
+    Return,
+    // return
+    Return0,
+    // A
+    // return R[A]
+    Return1,
+
+    // A Bx
+    // update counters; if loop continues then pc-=Bx;
+    ForLoop,
+    // A Bx
+    // <check values and prepare counters>; if not to run then pc+=Bx+1;
+    ForPrep,
+
+    // A Bx
+    // create upvalue for R[A + 3]; pc+=Bx
+    TForPrep,
+    // A C
+    // R[A+4], ... ,R[A+3+C] := R[A](R[A+1], R[A+2]);
+    TForCall,
+    // A Bx
+    // if R[A+2] ~= nil then { R[A]=R[A+2]; pc -= Bx }
+    TForLoop,
+
+    // A B C k
+    // R[A][C+i] := R[A+i], 1 <= i <= B
+    SetList,
+
+    // A Bx
+    // R[A] := closure(KPROTO[Bx])
+    Closure,
+
+    // A C
+    // R[A], R[A+1], ..., R[A+C-2] = vararg
+    Vararg,
+
+    // A
+    // (adjust vararg parameters)
+    VarargPrep,
+
+    // Ax
+    // extra (larger) argument for previous opcode
+    ExtraArg,
+}
+
+// all ops in encoding order
+const OPCODES: [OpCode; 83] = [
+    OpCode::Move,
+    OpCode::LoadI,
+    OpCode::LoadF,
+    OpCode::LoadK,
+    OpCode::LoadKx,
+    OpCode::LoadFalse,
+    OpCode::LFalseSkip,
+    OpCode::LoadTrue,
+    OpCode::LoadNil,
+    OpCode::GetUpVal,
+    OpCode::SetUpVal,
+    OpCode::GetTabUp,
+    OpCode::GetTable,
+    OpCode::GetI,
+    OpCode::GetField,
+    OpCode::SetTabUp,
+    OpCode::SetTable,
+    OpCode::SetI,
+    OpCode::SetField,
+    OpCode::NewTable,
+    OpCode::Self_,
+    OpCode::AddI,
+    OpCode::AddK,
+    OpCode::SubK,
+    OpCode::MulK,
+    OpCode::ModK,
+    OpCode::PowK,
+    OpCode::DivK,
+    OpCode::IDivK,
+    OpCode::BAndK,
+    OpCode::BOrK,
+    OpCode::BXorK,
+    OpCode::ShrI,
+    OpCode::ShlI,
+    OpCode::Add,
+    OpCode::Sub,
+    OpCode::Mul,
+    OpCode::Mod,
+    OpCode::Pow,
+    OpCode::Div,
+    OpCode::IDiv,
+    OpCode::BAnd,
+    OpCode::BOr,
+    OpCode::BXor,
+    OpCode::Shl,
+    OpCode::Shr,
+    OpCode::MmBin,
+    OpCode::MmBinI,
+    OpCode::MmBinK,
+    OpCode::Unm,
+    OpCode::BNot,
+    OpCode::Not,
+    OpCode::Len,
+    OpCode::Concat,
+    OpCode::Close,
+    OpCode::Tbc,
+    OpCode::Jmp,
+    OpCode::Eq,
+    OpCode::Lt,
+    OpCode::Le,
+    OpCode::EqK,
+    OpCode::EqI,
+    OpCode::LtI,
+    OpCode::LeI,
+    OpCode::GtI,
+    OpCode::GeI,
+    OpCode::Test,
+    OpCode::TestSet,
+    OpCode::Call,
+    OpCode::TailCall,
+    OpCode::Return,
+    OpCode::Return0,
+    OpCode::Return1,
+    OpCode::ForLoop,
+    OpCode::ForPrep,
+    OpCode::TForPrep,
+    OpCode::TForCall,
+    OpCode::TForLoop,
+    OpCode::SetList,
+    OpCode::Closure,
+    OpCode::Vararg,
+    OpCode::VarargPrep,
+    OpCode::ExtraArg,
+];
+
+impl OpCode {
+    pub fn from_u32(u: u32) -> OpCode {
+        match OPCODES.get(u as usize) {
+            Some(op) => *op,
+            None => unreachable!("unknown op code : {}!", u),
+        }
+    }
+
+    // the 5.4 op doing the same as a 5.3 op, operands may still need to be
+    // rewritten, e.g. RK operands become K variants or a following MMBIN.
+    // LOADBOOL has no single counterpart, it is split into LOADFALSE,
+    // LOADTRUE and LFALSESKIP.
+    pub fn from_53(op: OpCode53) -> Option<OpCode> {
+        let op = match op {
+            OpCode53::Move => OpCode::Move,
+            OpCode53::LoadK => OpCode::LoadK,
+            OpCode53::LoadKx => OpCode::LoadKx,
+            OpCode53::LoadBool => return None,
+            OpCode53::LoadNil => OpCode::LoadNil,
+            OpCode53::GetUpVal => OpCode::GetUpVal,
+            OpCode53::GetTabUp => OpCode::GetTabUp,
+            OpCode53::GetTable => OpCode::GetTable,
+            OpCode53::SetTabUp => OpCode::SetTabUp,
+            OpCode53::SetUpVal => OpCode::SetUpVal,
+            OpCode53::SetTable => OpCode::SetTable,
+            OpCode53::NewTable => OpCode::NewTable,
+            OpCode53::Self_ => OpCode::Self_,
+            OpCode53::Add => OpCode::Add,
+            OpCode53::Sub => OpCode::Sub,
+            OpCode53::Mul => OpCode::Mul,
+            OpCode53::Mod => OpCode::Mod,
+            OpCode53::Pow => OpCode::Pow,
+            OpCode53::Div => OpCode::Div,
+            OpCode53::IDiv => OpCode::IDiv,
+            OpCode53::BAdd => OpCode::BAnd,
+            OpCode53::BOr => OpCode::BOr,
+            OpCode53::BXor => OpCode::BXor,
+            OpCode53::Shl => OpCode::Shl,
+            OpCode53::Shr => OpCode::Shr,
+            OpCode53::Unm => OpCode::Unm,
+            OpCode53::BNot => OpCode::BNot,
+            OpCode53::Not => OpCode::Not,
+            OpCode53::Len => OpCode::Len,
+            OpCode53::Concat => OpCode::Concat,
+            OpCode53::Jmp => OpCode::Jmp,
+            OpCode53::Eq => OpCode::Eq,
+            OpCode53::Lt => OpCode::Lt,
+            OpCode53::Le => OpCode::Le,
+            OpCode53::Test => OpCode::Test,
+            OpCode53::TestSet => OpCode::TestSet,
+            OpCode53::Call => OpCode::Call,
+            OpCode53::TailCall => OpCode::TailCall,
+            OpCode53::Return => OpCode::Return,
+            OpCode53::ForLoop => OpCode::ForLoop,
+            OpCode53::ForPrep => OpCode::ForPrep,
+            OpCode53::TForCall => OpCode::TForCall,
+            OpCode53::TForLoop => OpCode::TForLoop,
+            OpCode53::SetList => OpCode::SetList,
+            OpCode53::Closure => OpCode::Closure,
+            OpCode53::Vararg => OpCode::Vararg,
+            OpCode53::ExtraArg => OpCode::ExtraArg,
+        };
+        Some(op)
+    }
+
+    pub fn mode(self) -> OpMode {
+        match self {
+            OpCode::LoadI | OpCode::LoadF => OpMode::IAsBx,
+            OpCode::LoadK
+            | OpCode::LoadKx
+            | OpCode::ForLoop
+            | OpCode::ForPrep
+            | OpCode::TForPrep
+            | OpCode::TForLoop
+            | OpCode::Closure => OpMode::IABx,
+            OpCode::Jmp => OpMode::IsJ,
+            OpCode::ExtraArg => OpMode::IAx,
+            _ => OpMode::IABC,
+        }
+    }
+}
+
+pub struct Instruction(u32);
+
+#[allow(non_snake_case)]
+impl Instruction {
+    // encoded 32 bits of the instruction
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    pub fn get_op(&self) -> OpCode {
+        OpCode::from_u32(self.get_arg(POS_OP, SIZE_OP))
+    }
+
+    pub fn get_arg_A(&self) -> u32 {
+        self.get_arg(POS_A, SIZE_A)
+    }
+
+    pub fn get_arg_k(&self) -> bool {
+        self.get_arg(POS_K, SIZE_K) != 0
+    }
+
+    pub fn get_arg_B(&self) -> u32 {
+        self.get_arg(POS_B, SIZE_B)
+    }
+
+    pub fn get_arg_sB(&self) -> i32 {
+        self.get_arg_B() as i32 - OFFSET_SC
+    }
+
+    pub fn get_arg_C(&self) -> u32 {
+        self.get_arg(POS_C, SIZE_C)
+    }
+
+    pub fn get_arg_sC(&self) -> i32 {
+        self.get_arg_C() as i32 - OFFSET_SC
+    }
+
+    pub fn get_arg_Bx(&self) -> u32 {
+        self.get_arg(POS_BX, SIZE_BX)
+    }
+
+    pub fn get_arg_sBx(&self) -> i32 {
+        self.get_arg_Bx() as i32 - OFFSET_SBX
+    }
+
+    pub fn get_arg_Ax(&self) -> u32 {
+        self.get_arg(POS_AX, SIZE_AX)
+    }
+
+    pub fn get_arg_sJ(&self) -> i32 {
+        self.get_arg(POS_SJ, SIZE_SJ) as i32 - OFFSET_SJ
+    }
+
+    pub fn create_ABCk(op: OpCode, a: u32, b: u32, c: u32, k: bool) -> Self {
+        Instruction(
+            ((op as u32) << POS_OP)
+                | (a << POS_A)
+                | ((k as u32) << POS_K)
+                | (b << POS_B)
+                | (c << POS_C),
+        )
+    }
+
+    pub fn create_ABC(op: OpCode, a: u32, b: u32, c: u32) -> Self {
+        Instruction::create_ABCk(op, a, b, c, false)
+    }
+
+    pub fn create_ABx(op: OpCode, a: u32, bx: u32) -> Self {
+        Instruction(((op as u32) << POS_OP) | (a << POS_A) | (bx << POS_BX))
+    }
+
+    pub fn create_AsBx(op: OpCode, a: u32, sBx: i32) -> Self {
+        Instruction::create_ABx(op, a, (sBx + OFFSET_SBX) as u32)
+    }
+
+    pub fn create_Ax(op: OpCode, ax: u32) -> Self {
+        Instruction(((op as u32) << POS_OP) | (ax << POS_AX))
+    }
+
+    pub fn create_sJ(op: OpCode, sJ: i32) -> Self {
+        Instruction(((op as u32) << POS_OP) | (((sJ + OFFSET_SJ) as u32) << POS_SJ))
+    }
+
+    fn get_arg(&self, pos: u32, size: u32) -> u32 {
+        (self.0 >> pos) & !((!0u32) << size)
+    }
+
+    pub fn mode(&self) -> OpMode {
+        self.get_op().mode()
+    }
+}
+
+use std::fmt;
+impl fmt::Debug for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let op = format!("{:?}", self.get_op());
+        let a = self.get_arg_A().to_string();
+        let (a, b, c) = match self.mode() {
+            OpMode::IABC => {
+                let k = if self.get_arg_k() { "k" } else { "" };
+                (
+                    a,
+                    self.get_arg_B().to_string(),
+                    format!("{}{}", self.get_arg_C(), k),
+                )
+            }
+            OpMode::IABx => (a, self.get_arg_Bx().to_string(), String::new()),
+            OpMode::IAsBx => (a, self.get_arg_sBx().to_string(), String::new()),
+            OpMode::IAx => (self.get_arg_Ax().to_string(), String::new(), String::new()),
+            OpMode::IsJ => (self.get_arg_sJ().to_string(), String::new(), String::new()),
+        };
+        write!(f, "| {:<10} | {:<5} | {:<5} | {:<5} |", op, a, b, c)
+    }
+}
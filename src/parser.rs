@@ -5,10 +5,21 @@ use rslua_traits::Error;
 
 #[derive(Traceable, Default)]
 pub struct Parser {
+    tokens: TokenStream,
+}
+
+// tokens to parse, without comment tokens since the lexer attaches them to the tokens after them.
+// peeking or reading past the end keeps returning the Eos token.
+#[derive(Traceable)]
+pub struct TokenStream {
     tokens: Vec<Token>,
     current: usize,
 }
 
+// position in a token stream to rewind to, for backtracking without lexing again
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Mark(usize);
+
 #[derive(Debug)]
 pub struct SyntaxError(String);
 
@@ -20,10 +31,80 @@ impl Error for SyntaxError {
 
 type ParseResult<T> = Result<T, SyntaxError>;
 
+fn syntax_error_msg(token: &Token, msg: &str) -> String {
+    let ident = match token.value {
+        TokenValue::None => format!("{:?}", token.t),
+        _ => format!("{:?}", token.value),
+    };
+    format!(
+        "[syntax error] {} at line [{}:{}] near [{}]",
+        msg, token.source.line, token.source.col, ident
+    )
+}
+
+impl TokenStream {
+    pub fn new(mut tokens: Vec<Token>) -> Self {
+        tokens.retain(|token| !token.is_comment());
+        if !tokens.last().is_some_and(|token| token.is_eos()) {
+            let source = tokens.last().map(|token| token.source).unwrap_or_default();
+            tokens.push(Token {
+                t: TokenType::Eos,
+                value: TokenValue::None,
+                raw: None,
+                source,
+                comments: Vec::new(),
+                trailing_comments: Vec::new(),
+            });
+        }
+        TokenStream { tokens, current: 0 }
+    }
+
+    pub fn peek(&self) -> &Token {
+        &self.tokens[self.current]
+    }
+
+    // the n-th token after the current one, peek_nth(0) is peek()
+    pub fn peek_nth(&self, n: usize) -> &Token {
+        let index = (self.current + n).min(self.tokens.len() - 1);
+        &self.tokens[index]
+    }
+
+    #[allow(clippy::should_implement_trait)]
+    pub fn next(&mut self) -> Token {
+        let token = self.peek().clone();
+        if self.current + 1 < self.tokens.len() {
+            self.current += 1;
+        }
+        token
+    }
+
+    pub fn mark(&self) -> Mark {
+        Mark(self.current)
+    }
+
+    pub fn reset_to(&mut self, mark: Mark) {
+        debug_assert!(mark.0 < self.tokens.len());
+        self.current = mark.0;
+    }
+
+    pub fn expect(&mut self, expected: TokenType) -> Result<Token, SyntaxError> {
+        if self.peek().t != expected {
+            let msg = syntax_error_msg(self.peek(), &format!("{:?} expected", expected));
+            return TokenStream::trace_error(SyntaxError(msg));
+        }
+        Ok(self.next())
+    }
+}
+
+impl Default for TokenStream {
+    fn default() -> Self {
+        TokenStream::new(Vec::new())
+    }
+}
+
 impl Parser {
     pub fn run(&mut self, tokens: Vec<Token>) -> ParseResult<Block> {
-        self.reset();
-        self.tokens = tokens;
+        self.tokens = TokenStream::new(tokens);
         self.block()
     }
 
@@ -46,7 +127,7 @@ impl Parser {
     fn stat(&mut self) -> ParseResult<Option<Stat>> {
         let stat = match self.current_token_type() {
             // stat -> ';' (empty stat)
-            TokenType::Semi | TokenType::SComment | TokenType::MComment => {
+            TokenType::Semi => {
                 self.next();
                 return Ok(None);
             }
@@ -64,7 +145,7 @@ impl Parser {
             TokenType::Function => Stat::FuncStat(self.funcstat()?),
             // stat -> localstat | localfunc
             TokenType::Local => {
-                let local = self.next_and_skip_comment();
+                let local = self.next();
                 if self.test(TokenType::Function) {
                     Stat::FuncStat(self.localfunc(local)?)
                 } else {
@@ -118,7 +199,7 @@ impl Parser {
 
     //  [IF | ELSEIF] cond THEN block
     fn test_then_block(&mut self) -> ParseResult<CondBlock> {
-        let if_ = self.next_and_skip_comment();
+        let if_ = self.next();
         let cond = self.cond()?;
         let then = self.check_next(TokenType::Then)?;
         let block = self.block()?;
@@ -133,7 +214,7 @@ impl Parser {
     // whilestat -> WHILE cond DO block END
     fn whilestat(&mut self) -> ParseResult<WhileStat> {
         let line = self.current_line();
-        let while_ = self.next_and_skip_comment();
+        let while_ = self.next();
         let cond = self.cond()?;
         let do_ = self.check_next(TokenType::Do)?;
         let block = self.block()?;
@@ -154,7 +235,7 @@ impl Parser {
     // forstat -> FOR (fornum | forlist) END
     fn forstat(&mut self) -> ParseResult<ForStat> {
         let line = self.current_line();
-        let for_ = self.next_and_skip_comment();
+        let for_ = self.next();
         let var = self.check_name()?;
         match self.current_token_type() {
             TokenType::Assign => self.forenum(line, for_, var),
@@ -165,10 +246,9 @@ impl Parser {
 
     // fornum -> NAME = exp1,exp1[,exp1] forbody
     fn forenum(&mut self, line: usize, for_: Token, var: StringExpr) -> ParseResult<ForStat> {
-        let equal = self.next_and_skip_comment();
+        let equal = self.next();
         let init = self.expr()?;
         let init_commas = self.check_next(TokenType::Comma)?;
-        self.skip_comment();
         let limit = self.expr()?;
         let limit_commas = self.test_next(TokenType::Comma);
         let step = match limit_commas {
@@ -197,7 +277,6 @@ impl Parser {
     fn forlist(&mut self, line: usize, for_: Token, var: StringExpr) -> ParseResult<ForStat> {
         let vars = self.varlist(TokenType::Comma, Some(var))?;
         let in_ = self.check_next(TokenType::In)?;
-        self.skip_comment();
         let exprs = self.exprlist()?;
         let do_ = self.check_next(TokenType::Do)?;
         let body = self.block()?;
@@ -251,7 +330,7 @@ impl Parser {
 
     // funcstat -> FUNCTION funcname body
     fn funcstat(&mut self) -> ParseResult<FuncStat> {
-        let function = self.next_and_skip_comment();
+        let function = self.next();
         let func_name = self.funcname()?;
         let body = self.funcbody()?;
         Ok(FuncStat {
@@ -276,7 +355,6 @@ impl Parser {
     fn funcbody(&mut self) -> ParseResult<FuncBody> {
         let line = self.current_line();
         let lp = self.check_next(TokenType::Lp)?;
-        self.skip_comment();
         let mut params = ParamList {
             params: Vec::new(),
             commas: Vec::new(),
@@ -288,15 +366,13 @@ impl Parser {
             match self.current_token_type() {
                 TokenType::Dots => {
                     params.params.push(Param::VarArg(self.current_token()));
-                    self.next_and_skip_comment();
+                    self.next();
                 }
                 TokenType::Name => params.params.push(Param::Name(self.check_name()?)),
                 _ => self.syntax_error("<name> or '...' expected")?,
             };
-            self.skip_comment();
             if let Some(commas) = self.test_next(TokenType::Comma) {
                 params.commas.push(commas);
-                self.skip_comment();
             } else {
                 break;
             }
@@ -316,7 +392,7 @@ impl Parser {
     // funcstat -> local FUNCTION funcname body
     fn localfunc(&mut self, token: Token) -> ParseResult<FuncStat> {
         let function = self.current_token();
-        self.next_and_skip_comment();
+        self.next();
         let func_name = self.funcname()?;
         let body = self.funcbody()?;
         Ok(FuncStat {
@@ -327,9 +403,32 @@ impl Parser {
         })
     }
 
-    // stat -> LOCAL NAME {',' NAME} ['=' explist]
+    // stat -> LOCAL NAME attrib {',' NAME attrib} ['=' explist]
     fn localstat(&mut self, local: Token) -> ParseResult<LocalStat> {
-        let names = self.varlist(TokenType::Comma, None)?;
+        let mut names = VarList {
+            vars: Vec::new(),
+            delimiters: Vec::new(),
+        };
+        let mut attribs = Vec::new();
+        let mut has_close = false;
+        loop {
+            names.vars.push(self.check_name()?);
+            let attrib = self.attrib()?;
+            if attrib
+                .as_ref()
+                .is_some_and(|attrib| attrib.value() == "close")
+            {
+                if has_close {
+                    return self.syntax_error("multiple to-be-closed variables in local list");
+                }
+                has_close = true;
+            }
+            attribs.push(attrib);
+            match self.test_next(TokenType::Comma) {
+                Some(comma) => names.delimiters.push(comma),
+                None => break,
+            }
+        }
         let equal = self.test_next(TokenType::Assign);
         let exprs = if equal.is_some() {
             Some(self.exprlist()?)
@@ -339,24 +438,38 @@ impl Parser {
         Ok(LocalStat {
             local,
             names,
+            attribs,
             equal,
             exprs,
         })
     }
 
+    // attrib -> ['<' NAME '>']
+    fn attrib(&mut self) -> ParseResult<Option<Attrib>> {
+        let lt = match self.test_next(TokenType::Lt) {
+            Some(lt) => lt,
+            None => return Ok(None),
+        };
+        let name = self.check_name()?;
+        let value = name.value();
+        if value != "const" && value != "close" {
+            return self.syntax_error(&format!("unknown attribute '{}'", value));
+        }
+        let gt = self.check_next(TokenType::Gt)?;
+        Ok(Some(Attrib { lt, name, gt }))
+    }
+
     // label -> '::' NAME '::'
     fn labelstat(&mut self) -> ParseResult<LabelStat> {
         let ldc = self.next();
-        self.skip_comment();
         let label = self.check_name()?;
         let rdc = self.check_next(TokenType::DbColon)?;
-        self.skip_comment();
         Ok(LabelStat { ldc, label, rdc })
     }
 
     // stat -> RETURN [explist] [';']
     fn retstat(&mut self) -> ParseResult<RetStat> {
-        let return_ = self.next_and_skip_comment();
+        let return_ = self.next();
         let exprs = if !self.is_block_end() && self.current_token_type() != TokenType::Semi {
             Some(self.exprlist()?)
         } else {
@@ -371,12 +484,12 @@ impl Parser {
     }
 
     fn breakstat(&mut self) -> ParseResult<BreakStat> {
-        let token = self.next_and_skip_comment();
+        let token = self.next();
         Ok(BreakStat { token })
     }
 
     fn gotostat(&mut self) -> ParseResult<GotoStat> {
-        let goto = self.next_and_skip_comment();
+        let goto = self.next();
         let label = self.check_name()?;
         Ok(GotoStat { goto, label })
     }
@@ -406,7 +519,6 @@ impl Parser {
             left.assignables.push(self.suffixedexpr()?.to_assignable())
         }
         let equal = self.check_next(TokenType::Assign)?;
-        self.skip_comment();
         let right = self.exprlist()?;
         Ok(AssignStat { left, equal, right })
     }
@@ -414,14 +526,11 @@ impl Parser {
     // exprlist -> expr { ',' expr }
     fn exprlist(&mut self) -> ParseResult<ExprList> {
         let mut exprs = ExprList::default();
-        self.skip_comment();
         exprs.exprs.push(self.expr()?);
         while let Some(comma) = self.test_next(TokenType::Comma) {
             exprs.commas.push(comma);
-            self.skip_comment();
             exprs.exprs.push(self.expr()?)
         }
-        self.skip_comment();
         Ok(exprs)
     }
 
@@ -443,7 +552,7 @@ impl Parser {
         let mut left;
         let unop = self.get_unop();
         if unop != UnOp::None {
-            self.next_and_skip_comment();
+            self.next();
             let expr = Box::new(self.subexpr(unop.priority())?);
             left = Expr::UnExpr(UnExpr {
                 op: unop.clone(),
@@ -454,7 +563,7 @@ impl Parser {
         }
         let mut binop = self.get_binop();
         while binop != BinOp::None && binop.priority().left > limit {
-            self.next_and_skip_comment();
+            self.next();
             let right = self.subexpr(binop.priority().right)?;
             left = Expr::BinExpr(BinExpr {
                 left: Box::new(left),
@@ -479,12 +588,12 @@ impl Parser {
             TokenType::Dots => Expr::VarArg(token),
             TokenType::Lb => return Ok(Expr::Table(self.table()?)),
             TokenType::Function => {
-                self.next_and_skip_comment();
+                self.next();
                 return Ok(Expr::FuncBody(self.funcbody()?));
             }
             _ => return self.suffixedexpr(),
         };
-        self.next_and_skip_comment();
+        self.next();
         Ok(expr)
     }
 
@@ -495,12 +604,12 @@ impl Parser {
         loop {
             match self.current_token_type() {
                 TokenType::Attr => {
-                    let attr = self.next_and_skip_comment();
+                    let attr = self.next();
                     suffixes.push(Suffix::Attr(attr, self.check_name()?));
                 }
                 TokenType::Ls => {
                     let line = self.current_line();
-                    let ls = self.next_and_skip_comment();
+                    let ls = self.next();
                     suffixes.push(Suffix::Index(
                         ls,
                         self.expr()?,
@@ -508,7 +617,7 @@ impl Parser {
                     ));
                 }
                 TokenType::Colon => {
-                    let colon = self.next_and_skip_comment();
+                    let colon = self.next();
                     let name = self.check_name()?;
                     suffixes.push(Suffix::Method(colon, name));
                 }
@@ -535,7 +644,7 @@ impl Parser {
             TokenType::Name => Expr::Name(self.check_name()?),
             TokenType::Lp => {
                 let line = self.current_line();
-                self.next_and_skip_comment();
+                self.next();
                 let expr = self.expr()?;
                 self.check_match(TokenType::Rp, TokenType::Lp, line)?;
                 Expr::ParenExpr(Box::new(expr))
@@ -555,7 +664,6 @@ impl Parser {
     fn table(&mut self) -> ParseResult<Table> {
         let line = self.current_line();
         let lb = self.check_next(TokenType::Lb)?;
-        self.skip_comment();
         let mut fields: Vec<Field> = Vec::new();
         while !self.test(TokenType::Rb) {
             fields.push(self.field()?);
@@ -577,7 +685,6 @@ impl Parser {
             TokenType::Ls => self.recfield()?,
             _ => self.listfield()?,
         };
-        self.skip_comment();
         Ok(field)
     }
 
@@ -587,7 +694,7 @@ impl Parser {
             TokenType::Name => FieldKey::Name(self.check_name()?),
             TokenType::Ls => {
                 let line = self.current_line();
-                let ls = self.next_and_skip_comment();
+                let ls = self.next();
                 let expr = self.expr()?;
                 let rs = self.check_match(TokenType::Rs, TokenType::Ls, line)?;
                 FieldKey::Expr(ls, expr, rs)
@@ -595,9 +702,7 @@ impl Parser {
             _ => unreachable!(),
         };
         let equal = self.check_next(TokenType::Assign)?;
-        self.skip_comment();
         let value = self.expr()?;
-        self.skip_comment();
         let sep = self
             .test_next(TokenType::Comma)
             .or_else(|| self.test_next(TokenType::Semi));
@@ -623,7 +728,7 @@ impl Parser {
         let func_args = match self.current_token_type() {
             TokenType::Lp => {
                 let line = self.current_line();
-                let lp = self.next_and_skip_comment();
+                let lp = self.next();
 
                 // empty arg list
                 if let Some(rp) = self.test_next(TokenType::Rp) {
@@ -635,64 +740,30 @@ impl Parser {
                 FuncArgs::Exprs(lp, exprs, rp)
             }
             TokenType::Lb => FuncArgs::Table(self.table()?),
-            TokenType::String => FuncArgs::String(StringExpr {
-                token: self.next_and_skip_comment(),
-            }),
+            TokenType::String => FuncArgs::String(StringExpr { token: self.next() }),
             _ => return self.syntax_error("function arguments expected"),
         };
         Ok(func_args)
     }
 
-    fn reset(&mut self) {
-        self.current = 0;
-    }
-
     fn current_token(&self) -> Token {
-        self.tokens[self.current].clone()
-    }
-
-    fn next_token(&self) -> &Token {
-        let mut current = self.current + 1;
-        while self.tokens[current].is_comment() {
-            current += 1;
-        }
-        &self.tokens[current]
+        self.tokens.peek().clone()
     }
 
     fn current_token_type(&self) -> TokenType {
-        let token = self.current_token();
-        token.t
+        self.tokens.peek().t
     }
 
     fn current_line(&self) -> usize {
-        let token = self.current_token();
-        token.source.line
+        self.tokens.peek().source.line
     }
 
     fn next_token_type(&self) -> TokenType {
-        let token = self.next_token();
-        token.t
-    }
-
-    fn next_and_skip_comment(&mut self) -> Token {
-        let token = self.current_token();
-        self.current += 1;
-        self.skip_comment();
-        token
+        self.tokens.peek_nth(1).t
     }
 
     fn next(&mut self) -> Token {
-        let token = self.current_token();
-        self.current += 1;
-        token
-    }
-
-    fn skip_comment(&mut self) -> usize {
-        let old = self.current;
-        while self.current_token().is_comment() {
-            self.current += 1;
-        }
-        old
+        self.tokens.next()
     }
 
     // if reach a block end
@@ -709,7 +780,6 @@ impl Parser {
     }
 
     fn check_match(&mut self, end: TokenType, start: TokenType, line: usize) -> ParseResult<Token> {
-        self.skip_comment();
         if self.current_token_type() != end {
             if line == self.current_line() {
                 self.error_expected(end)?;
@@ -728,11 +798,9 @@ impl Parser {
     }
 
     fn test_next(&mut self, expected: TokenType) -> Option<Token> {
-        let origin = self.skip_comment();
         if self.test(expected) {
             Some(self.next())
         } else {
-            self.current = origin;
             None
         }
     }
@@ -746,27 +814,17 @@ impl Parser {
     }
 
     fn check_next(&mut self, expected: TokenType) -> ParseResult<Token> {
-        self.skip_comment();
         self.check(expected)?;
         Ok(self.next())
     }
 
     fn check_name(&mut self) -> ParseResult<StringExpr> {
-        self.skip_comment();
         self.check(TokenType::Name)?;
         Ok(StringExpr { token: self.next() })
     }
 
     fn syntax_error<T>(&self, msg: &str) -> ParseResult<T> {
-        let token = self.current_token();
-        let ident = match token.value {
-            TokenValue::None => format!("{:?}", token.t),
-            _ => format!("{:?}", token.value),
-        };
-        let error_msg = format!(
-            "[syntax error] {} at line [{}:{}] near [{}]",
-            msg, token.source.line, token.source.col, ident
-        );
+        let error_msg = syntax_error_msg(self.tokens.peek(), msg);
         Parser::trace_error(SyntaxError(error_msg))
     }
 
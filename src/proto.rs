@@ -1,24 +1,58 @@
 use std::collections::HashMap;
+use std::io::{self, Write};
 
 use crate::ast::{BinOp, UnOp};
+use crate::compiler::CompileError;
 use crate::consts::Const;
-use crate::opcodes::{Instruction, OpCode};
+use crate::opcodes::{
+    int2fb, is_const, Instruction, OpCode, LUA_MULTRET, MASK_K, MAXARG_BX, MAXARG_C, MAXINDEXRK,
+    NO_JUMP, NO_REG,
+};
+use crate::types::Source;
+use rslua_traits::Error;
 
+// registers are encoded in 8 bits, a few are kept for temporaries of calls
+pub const MAX_REGS: u32 = 250;
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct LocalVal {
     name: String,
+    // first pc where the local is visible
+    pub start_pc: u32,
+    // pc after the last instruction where the local is visible,
+    // none if the local is still in scope
+    pub end_pc: Option<u32>,
+    // `<const>` and `<close>` locals can't be assigned
+    pub read_only: bool,
 }
 
-pub struct UpVal {}
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UpVal {
+    name: String,
+    // whether the upvalue is a local of enclosing function
+    pub in_stack: bool,
+    // register or upvalue index in enclosing function
+    pub index: u32,
+}
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Proto {
     pub stack_size: u32,
     pub param_count: u32,
+    // whether the function accepts `...`
+    pub is_vararg: bool,
     pub code: Vec<Instruction>,
+    // source line of each instruction
+    pub line_info: Vec<u32>,
     pub consts: Vec<Const>,
+    // not serialized, `add_const` rebuilds it from consts
+    #[cfg_attr(feature = "serde", serde(skip))]
     pub const_map: HashMap<Const, u32>,
     pub local_vars: Vec<LocalVal>,
     pub up_vars: Vec<UpVal>,
     pub protos: Vec<Proto>,
+    // last pc that is a jump target, instructions there must not be merged
+    pub last_target: usize,
 }
 
 impl Default for Proto {
@@ -26,31 +60,191 @@ impl Default for Proto {
         Proto {
             stack_size: 2,
             param_count: 0,
+            is_vararg: false,
             code: Vec::new(),
+            line_info: Vec::new(),
             consts: Vec::new(),
             const_map: HashMap::new(),
             local_vars: Vec::new(),
             up_vars: Vec::new(),
             protos: Vec::new(),
+            last_target: 0,
         }
     }
 }
 
+// read only view of the compiled function, prefer these over the fields
+// since the representation may change
+impl Proto {
+    pub fn code(&self) -> &[Instruction] {
+        &self.code
+    }
+
+    pub fn consts(&self) -> &[Const] {
+        &self.consts
+    }
+
+    pub fn protos(&self) -> &[Proto] {
+        &self.protos
+    }
+
+    pub fn instruction_count(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn max_stack(&self) -> u32 {
+        self.stack_size
+    }
+}
+
 impl Proto {
     pub fn open(&mut self) {}
 
     pub fn close(&mut self) {
         self.code_return(0, 0);
+        self.peephole();
+    }
+
+    // remove redundant MOVEs, `MOVE a a` is dropped, and `MOVE a b` right after an
+    // instruction which only sets temporary register b is merged into it
+    pub fn peephole(&mut self) {
+        let len = self.code.len();
+        let targets = self.jump_targets();
+        let mut removed = vec![false; len];
+        for pc in 1..len {
+            let instruction = &self.code[pc];
+            // instruction conditionally skipped should be kept
+            if instruction.get_op() != OpCode::Move || self.skips_next(pc - 1) {
+                continue;
+            }
+            let (dst, src) = (instruction.get_arg_A(), instruction.get_arg_B());
+            if dst == src {
+                removed[pc] = true;
+                continue;
+            }
+            let prev = &self.code[pc - 1];
+            if targets[pc]
+                || removed[pc - 1]
+                || (pc > 1 && self.skips_next(pc - 2))
+                || !prev.get_op().only_sets_a()
+                || prev.get_arg_A() != src
+                || src < self.active_locals(pc).max(self.active_locals(pc + 1))
+            {
+                continue;
+            }
+            self.code[pc - 1].set_arg_A(dst);
+            removed[pc] = true;
+        }
+        if removed.iter().any(|r| *r) {
+            self.remove_instructions(&removed);
+        }
+    }
+
+    // whether each pc is the destination of a jump, or skipped by a conditional instruction
+    fn jump_targets(&self) -> Vec<bool> {
+        let mut targets = vec![false; self.code.len() + 1];
+        for (pc, instruction) in self.code.iter().enumerate() {
+            match instruction.get_op() {
+                op if op.has_jump() => {
+                    let target = pc as i32 + 1 + instruction.get_arg_sBx();
+                    targets[target as usize] = true;
+                }
+                _ if self.skips_next(pc) => targets[pc + 2] = true,
+                _ => (),
+            }
+        }
+        targets
+    }
+
+    // whether the next instruction may be skipped
+    fn skips_next(&self, pc: usize) -> bool {
+        let instruction = &self.code[pc];
+        let op = instruction.get_op();
+        op.followed_by_jump() || (op == OpCode::LoadBool && instruction.get_arg_C() != 0)
+    }
+
+    // number of locals in scope at pc, they take the lowest registers
+    fn active_locals(&self, pc: usize) -> u32 {
+        let pc = pc as u32;
+        self.local_vars
+            .iter()
+            .filter(|local| local.start_pc <= pc && local.end_pc.map_or(true, |end| pc < end))
+            .count() as u32
+    }
+
+    // remove instructions and fix jumps, line info and local ranges
+    fn remove_instructions(&mut self, removed: &[bool]) {
+        // new pc of each old pc, removed ones are mapped to the next instruction
+        let mut new_pc = Vec::with_capacity(removed.len() + 1);
+        let mut count = 0;
+        for r in removed.iter() {
+            new_pc.push(count);
+            if !r {
+                count += 1;
+            }
+        }
+        new_pc.push(count);
+
+        for (pc, instruction) in self.code.iter_mut().enumerate() {
+            if removed[pc] {
+                continue;
+            }
+            if let OpCode::Jmp | OpCode::ForPrep | OpCode::ForLoop | OpCode::TForLoop =
+                instruction.get_op()
+            {
+                let target = (pc as i32 + 1 + instruction.get_arg_sBx()) as usize;
+                instruction.set_arg_sBx(new_pc[target] as i32 - new_pc[pc] as i32 - 1);
+            }
+        }
+
+        let mut pc = 0;
+        self.code.retain(|_| {
+            pc += 1;
+            !removed[pc - 1]
+        });
+        // line info may not be synced for the last instructions
+        let mut pc = 0;
+        self.line_info.retain(|_| {
+            pc += 1;
+            !removed[pc - 1]
+        });
+        for local in self.local_vars.iter_mut() {
+            local.start_pc = new_pc[local.start_pc as usize];
+            local.end_pc = local.end_pc.map(|end| new_pc[end as usize]);
+        }
     }
 
     pub fn code_return(&mut self, first: u32, nret: u32) -> usize {
-        self.code
-            .push(Instruction::create_ABC(OpCode::Return, first, nret + 1, 0));
+        self.code.push(Instruction::create_ABC(
+            OpCode::Return,
+            first,
+            nret.wrapping_add(1),
+            0,
+        ));
         self.code.len() - 1
     }
 
     pub fn code_nil(&mut self, start_reg: u32, n: u32) -> usize {
-        // TODO : optimize for duplicate LoadNil
+        let mut from = start_reg;
+        let mut to = start_reg + n - 1;
+        let pc = self.code.len();
+        // merge into previous LoadNil if the ranges are connected
+        if pc > self.last_target && pc > 0 {
+            let previous = &mut self.code[pc - 1];
+            if previous.get_op() == OpCode::LoadNil {
+                let prev_from = previous.get_arg_A();
+                let prev_to = prev_from + previous.get_arg_B();
+                if (prev_from <= from && from <= prev_to + 1)
+                    || (from <= prev_from && prev_from <= to + 1)
+                {
+                    from = from.min(prev_from);
+                    to = to.max(prev_to);
+                    previous.set_arg_A(from);
+                    previous.set_arg_B(to - from);
+                    return pc - 1;
+                }
+            }
+        }
         self.code.push(Instruction::create_ABC(
             OpCode::LoadNil,
             start_reg,
@@ -71,12 +265,20 @@ impl Proto {
     }
 
     pub fn code_const(&mut self, reg_index: u32, const_index: u32) -> usize {
-        self.code.push(Instruction::create_ABx(
-            OpCode::LoadK,
-            reg_index,
-            const_index,
-        ));
-        self.code.len() - 1
+        if const_index <= MAXARG_BX {
+            self.code.push(Instruction::create_ABx(
+                OpCode::LoadK,
+                reg_index,
+                const_index,
+            ));
+            return self.code.len() - 1;
+        }
+        // index is too large for Bx, it's saved in the following EXTRAARG
+        self.code
+            .push(Instruction::create_ABx(OpCode::LoadKx, reg_index, 0));
+        self.code
+            .push(Instruction::create_Ax(OpCode::ExtraArg, const_index));
+        self.code.len() - 2
     }
 
     pub fn code_move(&mut self, reg: u32, src: u32) -> usize {
@@ -142,6 +344,15 @@ impl Proto {
         self.code.len() - 1
     }
 
+    // the jump following a test or comparison
+    pub fn code_cond_jmp(&mut self) -> usize {
+        debug_assert!(self
+            .code
+            .last()
+            .is_some_and(|last| last.get_op().followed_by_jump()));
+        self.code_jmp(NO_JUMP, 0)
+    }
+
     pub fn fix_cond_jump_pos(&mut self, true_pos: usize, false_pos: usize, pc: usize) {
         let instruction = self.get_instruction(pc);
         let pos = if instruction.get_arg_A() == 0 {
@@ -152,11 +363,269 @@ impl Proto {
         instruction.set_arg_sBx(pos as i32 - pc as i32 - 1);
     }
 
+    // patch jumps of a list, jumps after TESTSET go to `value_pos` with the tested value
+    // copied to `reg`, others go to `pos`
+    pub fn patch_list(&mut self, jumps: &[usize], reg: u32, value_pos: usize, pos: usize) {
+        for pc in jumps.iter() {
+            if self.patch_test_reg(*pc, reg) {
+                self.fix_jump_pos(value_pos, *pc);
+            } else {
+                self.fix_jump_pos(pos, *pc);
+            }
+        }
+    }
+
+    // make jumps close upvalues of locals from `level` on
+    pub fn patch_close(&mut self, jumps: &[usize], level: u32) {
+        for pc in jumps.iter() {
+            self.get_instruction(*pc).set_arg_A(level + 1);
+        }
+    }
+
+    // set target of TESTSET controlling the jump, it becomes TEST if
+    // the value is not needed or already in place
+    fn patch_test_reg(&mut self, pc: usize, reg: u32) -> bool {
+        if pc == 0 {
+            return false;
+        }
+        let instruction = self.get_instruction(pc - 1);
+        if instruction.get_op() != OpCode::TestSet {
+            return false;
+        }
+        let src = instruction.get_arg_B();
+        if reg != NO_REG && reg != src {
+            instruction.set_arg_A(reg);
+        } else {
+            *instruction = Instruction::create_ABC(OpCode::Test, src, 0, instruction.get_arg_C());
+        }
+        true
+    }
+
+    // jump lists can also be linked through sBx of the jumps like luac,
+    // a list is its first jump and `NO_JUMP` ends it
+
+    // next jump in the list
+    pub fn get_jump(&self, pc: usize) -> usize {
+        let offset = self.code[pc].get_arg_sBx();
+        if offset == NO_JUMP {
+            NO_JUMP as usize
+        } else {
+            (pc as i32 + 1 + offset) as usize
+        }
+    }
+
+    // append jump at `pc` to the list, return the new list
+    pub fn concat_jump(&mut self, list: usize, pc: usize) -> usize {
+        if pc == NO_JUMP as usize {
+            return list;
+        }
+        if list == NO_JUMP as usize {
+            return pc;
+        }
+        let mut last = list;
+        loop {
+            let next = self.get_jump(last);
+            if next == NO_JUMP as usize {
+                break;
+            }
+            last = next;
+        }
+        self.get_instruction(last)
+            .set_arg_sBx(pc as i32 - last as i32 - 1);
+        list
+    }
+
+    pub fn jump_list(&self, list: usize) -> Vec<usize> {
+        let mut jumps = Vec::new();
+        let mut pc = list;
+        while pc != NO_JUMP as usize {
+            jumps.push(pc);
+            pc = self.get_jump(pc);
+        }
+        jumps
+    }
+
+    // all jumps of the list go to `target`, tested values are not needed there
+    pub fn patch_jump_list(&mut self, list: usize, target: usize) {
+        let jumps = self.jump_list(list);
+        self.patch_list(&jumps, NO_REG, target, target);
+    }
+
+    // whether any jump produces a boolean instead of the value of an operand
+    pub fn need_value(&self, jumps: &[usize]) -> bool {
+        jumps
+            .iter()
+            .any(|pc| *pc == 0 || self.code[pc - 1].get_op() != OpCode::TestSet)
+    }
+
+    // mark current pc as a jump target
+    pub fn get_label(&mut self) -> usize {
+        self.last_target = self.code.len();
+        self.last_target
+    }
+
     pub fn fix_jump_pos(&mut self, pos: usize, pc: usize) {
+        self.last_target = self.last_target.max(pos);
         let instruction = self.get_instruction(pc);
         instruction.set_arg_sBx(pos as i32 - pc as i32 - 1);
     }
 
+    pub fn code_get_up_val(&mut self, reg: u32, up_var: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::GetUpVal, reg, up_var, 0));
+        self.code.len() - 1
+    }
+
+    pub fn code_set_up_val(&mut self, up_var: u32, reg: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::SetUpVal, reg, up_var, 0));
+        self.code.len() - 1
+    }
+
+    pub fn code_closure(&mut self, reg: u32, proto: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABx(OpCode::Closure, reg, proto));
+        self.code.len() - 1
+    }
+
+    pub fn code_new_table(&mut self, reg: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::NewTable, reg, 0, 0));
+        self.code.len() - 1
+    }
+
+    // set array and hash size of NEWTABLE at pc
+    pub fn fix_table_size(&mut self, pc: usize, array_size: u32, hash_size: u32) {
+        let instruction = self.get_instruction(pc);
+        instruction.set_arg_B(int2fb(array_size));
+        instruction.set_arg_C(int2fb(hash_size));
+    }
+
+    // batch which doesn't fit in C is saved in a following EXTRAARG
+    pub fn code_set_list(&mut self, table: u32, n: u32, batch: u32) -> usize {
+        let pc = self.code.len();
+        if batch <= MAXARG_C {
+            self.code
+                .push(Instruction::create_ABC(OpCode::SetList, table, n, batch));
+        } else {
+            self.code
+                .push(Instruction::create_ABC(OpCode::SetList, table, n, 0));
+            self.code
+                .push(Instruction::create_Ax(OpCode::ExtraArg, batch));
+        }
+        pc
+    }
+
+    // R(reg) := R(first) .. ... .. R(last)
+    pub fn code_concat(&mut self, reg: u32, first: u32, last: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::Concat, reg, first, last));
+        self.code.len() - 1
+    }
+
+    pub fn code_get_table(&mut self, reg: u32, table: u32, key: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::GetTable, reg, table, key));
+        self.code.len() - 1
+    }
+
+    // R(reg + 1) := R(obj), R(reg) := R(obj)[RK(key)]
+    pub fn code_self(&mut self, reg: u32, obj: u32, key: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::Self_, reg, obj, key));
+        self.code.len() - 1
+    }
+
+    pub fn code_set_table(&mut self, table: u32, key: u32, value: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::SetTable, table, key, value));
+        self.code.len() - 1
+    }
+
+    // call function in register `func`, `nargs` and `nret` could be `LUA_MULTRET`
+    pub fn code_call(&mut self, func: u32, nargs: u32, nret: u32) -> usize {
+        self.code.push(Instruction::create_ABC(
+            OpCode::Call,
+            func,
+            nargs.wrapping_add(1),
+            nret.wrapping_add(1),
+        ));
+        self.code.len() - 1
+    }
+
+    // set result count of last instruction, which should be a call or vararg
+    pub fn set_returns(&mut self, nret: u32) {
+        if let Some(instruction) = self.code.last_mut() {
+            match instruction.get_op() {
+                OpCode::Call => instruction.set_arg_C(nret.wrapping_add(1)),
+                OpCode::Vararg => instruction.set_arg_B(nret.wrapping_add(1)),
+                _ => unreachable!(),
+            }
+        }
+    }
+
+    // let last instruction return all of its results
+    pub fn set_multret(&mut self) {
+        self.set_returns(LUA_MULTRET);
+    }
+
+    // load `nret` varargs to registers starting from `reg`, `nret` could be `LUA_MULTRET`
+    pub fn code_vararg(&mut self, reg: u32, nret: u32) -> usize {
+        self.code.push(Instruction::create_ABC(
+            OpCode::Vararg,
+            reg,
+            nret.wrapping_add(1),
+            0,
+        ));
+        self.code.len() - 1
+    }
+
+    pub fn code_get_tab_up(&mut self, reg: u32, up_var: u32, key: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::GetTabUp, reg, up_var, key));
+        self.code.len() - 1
+    }
+
+    pub fn code_set_tab_up(&mut self, up_var: u32, key: u32, value: u32) -> usize {
+        self.code.push(Instruction::create_ABC(
+            OpCode::SetTabUp,
+            up_var,
+            key,
+            value,
+        ));
+        self.code.len() - 1
+    }
+
+    pub fn code_for_prep(&mut self, base: u32, offset: i32) -> usize {
+        self.code
+            .push(Instruction::create_AsBx(OpCode::ForPrep, base, offset));
+        self.code.len() - 1
+    }
+
+    pub fn code_for_loop(&mut self, base: u32, offset: i32) -> usize {
+        self.code
+            .push(Instruction::create_AsBx(OpCode::ForLoop, base, offset));
+        self.code.len() - 1
+    }
+
+    pub fn code_tfor_call(&mut self, base: u32, nvars: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::TForCall, base, 0, nvars));
+        self.code.len() - 1
+    }
+
+    pub fn code_tfor_loop(&mut self, base: u32, offset: i32) -> usize {
+        self.code
+            .push(Instruction::create_AsBx(OpCode::TForLoop, base, offset));
+        self.code.len() - 1
+    }
+
+    pub fn code_test(&mut self, reg: u32, c: u32) -> usize {
+        self.code
+            .push(Instruction::create_ABC(OpCode::Test, reg, 0, c));
+        self.code.len() - 1
+    }
+
     pub fn code_test_set(&mut self, set: u32, test: u32, to_test: u32) {
         self.code
             .push(Instruction::create_ABC(OpCode::TestSet, set, test, to_test));
@@ -165,17 +634,56 @@ impl Proto {
     pub fn add_local_var(&mut self, name: &str) {
         self.local_vars.push(LocalVal {
             name: name.to_string(),
+            start_pc: self.code.len() as u32,
+            end_pc: None,
+            read_only: false,
         });
     }
 
+    // index of the last local declared with the name
     pub fn get_local_var(&self, name: &str) -> Option<u32> {
         self.local_vars
+            .iter()
+            .rposition(|var| var.name == name)
+            .map(|i| i as u32)
+    }
+
+    pub fn add_up_var(&mut self, name: &str, in_stack: bool, index: u32) -> u32 {
+        self.up_vars.push(UpVal {
+            name: name.to_string(),
+            in_stack,
+            index,
+        });
+        (self.up_vars.len() - 1) as u32
+    }
+
+    pub fn up_var_name(&self, index: u32) -> &str {
+        &self.up_vars[index as usize].name
+    }
+
+    pub fn get_up_var(&self, name: &str) -> Option<u32> {
+        self.up_vars
             .iter()
             .position(|var| var.name == name)
             .map(|i| i as u32)
     }
 
+    // consts are shared by value and appended in order of first use, same as `addk` of luac.
+    // integers and floats are different consts even if they are equal, like luac.
     pub fn add_const(&mut self, k: Const) -> u32 {
+        // the map is empty for a deserialized proto
+        if self.const_map.is_empty() {
+            for (i, k) in self.consts.iter().enumerate() {
+                self.const_map.entry(k.clone()).or_insert(i as u32);
+            }
+        }
+        // NaN never equals itself, so it is never shared
+        if let Const::Float(f) = k {
+            if f.is_nan() {
+                self.consts.push(k);
+                return (self.consts.len() - 1) as u32;
+            }
+        }
         match self.const_map.get(&k) {
             Some(index) => *index,
             None => {
@@ -189,16 +697,391 @@ impl Proto {
 
     // save result to target reg
     pub fn save(&mut self, target: u32) -> usize {
-        let last = self.code.last_mut();
-        if let Some(code) = last {
-            code.save(target);
+        let mut pc = self.code.len() - 1;
+        // EXTRAARG belongs to the instruction before it
+        if pc > 0 && self.code[pc].get_op() == OpCode::ExtraArg {
+            pc -= 1;
         }
-        self.code.len() - 1
+        self.code[pc].save(target);
+        pc
     }
 
     pub fn get_instruction(&mut self, index: usize) -> &mut Instruction {
         &mut self.code[index]
     }
+
+    // remove debug info of this proto and its children,
+    // local vars are kept as anonymous ones so register layout is not changed
+    pub fn strip(&mut self) {
+        self.line_info.clear();
+        for local in self.local_vars.iter_mut() {
+            local.name.clear();
+        }
+        for up_var in self.up_vars.iter_mut() {
+            up_var.name.clear();
+        }
+        for proto in self.protos.iter_mut() {
+            proto.strip();
+        }
+    }
+}
+
+// header of lua 5.3 binary chunk
+const LUA_SIGNATURE: &[u8] = b"\x1bLua";
+const LUAC_VERSION: u8 = 0x53;
+const LUAC_FORMAT: u8 = 0;
+const LUAC_DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+const LUAC_INT: i64 = 0x5678;
+const LUAC_NUM: f64 = 370.5;
+
+// type tags of constants
+const LUA_TNIL: u8 = 0;
+const LUA_TBOOLEAN: u8 = 1;
+const LUA_TNUMFLT: u8 = 3;
+const LUA_TNUMINT: u8 = 3 | (1 << 4);
+const LUA_TSHRSTR: u8 = 4;
+const LUA_TLNGSTR: u8 = 4 | (1 << 4);
+const LUAI_MAXSHORTLEN: usize = 40;
+
+impl Proto {
+    // write this proto as main function of a lua 5.3 binary chunk
+    pub fn dump(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(LUA_SIGNATURE)?;
+        writer.write_all(&[LUAC_VERSION, LUAC_FORMAT])?;
+        writer.write_all(LUAC_DATA)?;
+        // sizeof int, size_t, Instruction, lua_Integer and lua_Number
+        writer.write_all(&[4, 8, 4, 8, 8])?;
+        writer.write_all(&LUAC_INT.to_le_bytes())?;
+        writer.write_all(&LUAC_NUM.to_le_bytes())?;
+        writer.write_all(&[self.up_vars.len() as u8])?;
+        self.dump_function(writer, Some("=?"))
+    }
+
+    fn dump_function(&self, writer: &mut impl Write, source: Option<&str>) -> io::Result<()> {
+        dump_string(writer, source)?;
+        // line defined and last line defined
+        dump_int(writer, 0)?;
+        dump_int(writer, 0)?;
+        writer.write_all(&[
+            self.param_count as u8,
+            self.is_vararg as u8,
+            self.stack_size as u8,
+        ])?;
+
+        dump_int(writer, self.code.len() as u32)?;
+        for instruction in self.code.iter() {
+            writer.write_all(&instruction.raw().to_le_bytes())?;
+        }
+
+        dump_int(writer, self.consts.len() as u32)?;
+        for k in self.consts.iter() {
+            match k {
+                Const::Int(i) => {
+                    writer.write_all(&[LUA_TNUMINT])?;
+                    writer.write_all(&i.to_le_bytes())?;
+                }
+                Const::Float(f) => {
+                    writer.write_all(&[LUA_TNUMFLT])?;
+                    writer.write_all(&f.to_le_bytes())?;
+                }
+                Const::Str(s) => {
+                    let t = if s.len() <= LUAI_MAXSHORTLEN {
+                        LUA_TSHRSTR
+                    } else {
+                        LUA_TLNGSTR
+                    };
+                    writer.write_all(&[t])?;
+                    dump_string(writer, Some(s))?;
+                }
+                Const::Bool(b) => writer.write_all(&[LUA_TBOOLEAN, *b as u8])?,
+                Const::Nil => writer.write_all(&[LUA_TNIL])?,
+            }
+        }
+
+        dump_int(writer, self.up_vars.len() as u32)?;
+        for up_var in self.up_vars.iter() {
+            writer.write_all(&[up_var.in_stack as u8, up_var.index as u8])?;
+        }
+
+        // source of children is the same as parent, so it's omitted
+        dump_int(writer, self.protos.len() as u32)?;
+        for proto in self.protos.iter() {
+            proto.dump_function(writer, None)?;
+        }
+
+        // debug info
+        dump_int(writer, self.line_info.len() as u32)?;
+        for line in self.line_info.iter() {
+            dump_int(writer, *line)?;
+        }
+        dump_int(writer, self.local_vars.len() as u32)?;
+        for local in self.local_vars.iter() {
+            dump_string(writer, Some(&local.name))?;
+            dump_int(writer, local.start_pc)?;
+            dump_int(writer, local.end_pc.unwrap_or(self.code.len() as u32))?;
+        }
+        dump_int(writer, self.up_vars.len() as u32)?;
+        for up_var in self.up_vars.iter() {
+            dump_string(writer, Some(&up_var.name))?;
+        }
+        Ok(())
+    }
+}
+
+fn dump_int(writer: &mut impl Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+// size is saved with the trailing '\0' of C string, 0 for NULL
+fn dump_string(writer: &mut impl Write, s: Option<&str>) -> io::Result<()> {
+    match s {
+        None => writer.write_all(&[0]),
+        Some(s) => {
+            let size = s.len() + 1;
+            if size < 0xFF {
+                writer.write_all(&[size as u8])?;
+            } else {
+                writer.write_all(&[0xFF])?;
+                writer.write_all(&(size as u64).to_le_bytes())?;
+            }
+            writer.write_all(s.as_bytes())
+        }
+    }
+}
+
+// first violation found by `Proto::verify`
+#[derive(Debug, PartialEq)]
+pub struct VerifyError {
+    // indexes of the nested protos leading to the invalid one, empty for the main proto
+    pub protos: Vec<usize>,
+    pub pc: usize,
+    pub message: String,
+}
+
+impl Error for VerifyError {
+    fn what(&self) -> &str {
+        &self.message
+    }
+}
+
+impl Proto {
+    // check operands of every instruction are in bounds, including nested protos
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        for pc in 0..self.code.len() {
+            if let Err(message) = self.verify_instruction(pc) {
+                return Err(VerifyError {
+                    protos: Vec::new(),
+                    pc,
+                    message,
+                });
+            }
+        }
+        for (i, proto) in self.protos.iter().enumerate() {
+            if let Err(mut e) = proto.verify() {
+                e.protos.insert(0, i);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    fn verify_instruction(&self, pc: usize) -> Result<(), String> {
+        let instruction = &self.code[pc];
+        let op = instruction.get_op();
+        let (a, b, c) = (
+            instruction.get_arg_A(),
+            instruction.get_arg_B(),
+            instruction.get_arg_C(),
+        );
+        // registers from `first` to `last` are all in the stack
+        let regs = |first: u32, last: u32| {
+            if last >= self.stack_size {
+                Err(format!(
+                    "register {} out of stack size {}",
+                    last, self.stack_size
+                ))
+            } else if first > last {
+                Err(format!("invalid register range {} to {}", first, last))
+            } else {
+                Ok(())
+            }
+        };
+        let reg = |reg: u32| regs(reg, reg);
+        let k = |index: u32| {
+            if (index as usize) < self.consts.len() {
+                Ok(())
+            } else {
+                Err(format!("constant {} out of range", index))
+            }
+        };
+        let rk = |operand: u32| {
+            if is_const(operand) {
+                k(operand & !MASK_K)
+            } else {
+                reg(operand)
+            }
+        };
+        let up_var = |index: u32| {
+            if (index as usize) < self.up_vars.len() {
+                Ok(())
+            } else {
+                Err(format!("upvalue {} out of range", index))
+            }
+        };
+        let next_op = || self.code.get(pc + 1).map(|next| next.get_op());
+        // the last instruction can't be skipped or jumped over
+        let target = |target: i64| {
+            if target < 0 || target >= self.code.len() as i64 {
+                Err(format!("jump target {} out of code", target))
+            } else {
+                Ok(())
+            }
+        };
+        let jump = || target(pc as i64 + 1 + instruction.get_arg_sBx() as i64);
+        let extra_arg = |check: &dyn Fn(u32) -> Result<(), String>| match self.code.get(pc + 1) {
+            Some(next) if next.get_op() == OpCode::ExtraArg => check(next.get_arg_Ax()),
+            _ => Err(format!("{:?} without EXTRAARG", op)),
+        };
+
+        if op.followed_by_jump() && next_op() != Some(OpCode::Jmp) {
+            return Err(format!("{:?} not followed by a jump", op));
+        }
+        match op {
+            OpCode::Move | OpCode::Unm | OpCode::BNot | OpCode::Not | OpCode::Len => {
+                reg(a)?;
+                reg(b)
+            }
+            OpCode::LoadK => {
+                reg(a)?;
+                k(instruction.get_arg_Bx())
+            }
+            OpCode::LoadKx => {
+                reg(a)?;
+                extra_arg(&k)
+            }
+            OpCode::LoadBool => {
+                reg(a)?;
+                if c != 0 {
+                    target(pc as i64 + 2)?;
+                }
+                Ok(())
+            }
+            OpCode::LoadNil => regs(a, a + b),
+            OpCode::GetUpVal | OpCode::SetUpVal => {
+                reg(a)?;
+                up_var(b)
+            }
+            OpCode::GetTabUp => {
+                reg(a)?;
+                up_var(b)?;
+                rk(c)
+            }
+            OpCode::SetTabUp => {
+                up_var(a)?;
+                rk(b)?;
+                rk(c)
+            }
+            OpCode::GetTable => {
+                reg(a)?;
+                reg(b)?;
+                rk(c)
+            }
+            OpCode::Self_ => {
+                regs(a, a + 1)?;
+                reg(b)?;
+                rk(c)
+            }
+            OpCode::NewTable => reg(a),
+            OpCode::SetTable => {
+                reg(a)?;
+                rk(b)?;
+                rk(c)
+            }
+            OpCode::Add
+            | OpCode::Sub
+            | OpCode::Mul
+            | OpCode::Mod
+            | OpCode::Pow
+            | OpCode::Div
+            | OpCode::IDiv
+            | OpCode::BAdd
+            | OpCode::BOr
+            | OpCode::BXor
+            | OpCode::Shl
+            | OpCode::Shr => {
+                reg(a)?;
+                rk(b)?;
+                rk(c)
+            }
+            OpCode::Concat => {
+                reg(a)?;
+                regs(b, c)
+            }
+            OpCode::Jmp => {
+                if a != 0 {
+                    reg(a - 1)?;
+                }
+                jump()
+            }
+            OpCode::Eq | OpCode::Lt | OpCode::Le => {
+                rk(b)?;
+                rk(c)
+            }
+            OpCode::Test => reg(a),
+            OpCode::TestSet => {
+                reg(a)?;
+                reg(b)
+            }
+            OpCode::Call | OpCode::TailCall => {
+                reg(a)?;
+                if b != 0 {
+                    regs(a, a + b - 1)?;
+                }
+                if c > 1 {
+                    regs(a, a + c - 2)?;
+                }
+                Ok(())
+            }
+            OpCode::Return => {
+                if b > 1 {
+                    regs(a, a + b - 2)?;
+                }
+                Ok(())
+            }
+            OpCode::ForLoop | OpCode::ForPrep => {
+                regs(a, a + 3)?;
+                jump()
+            }
+            OpCode::TForCall => regs(a, a + 2 + c.max(1)),
+            OpCode::TForLoop => {
+                regs(a, a + 1)?;
+                jump()
+            }
+            OpCode::SetList => {
+                regs(a, a + b)?;
+                if c == 0 {
+                    extra_arg(&|_| Ok(()))?;
+                }
+                Ok(())
+            }
+            OpCode::Closure => {
+                reg(a)?;
+                if (instruction.get_arg_Bx() as usize) < self.protos.len() {
+                    Ok(())
+                } else {
+                    Err(format!("proto {} out of range", instruction.get_arg_Bx()))
+                }
+            }
+            OpCode::Vararg => {
+                reg(a)?;
+                if b > 1 {
+                    regs(a, a + b - 2)?;
+                }
+                Ok(())
+            }
+            OpCode::ExtraArg => Ok(()),
+        }
+    }
 }
 
 use std::fmt;
@@ -210,16 +1093,7 @@ impl fmt::Debug for Proto {
 
         writeln!(f, "consts :")?;
         for (i, k) in self.consts.iter().enumerate() {
-            writeln!(
-                f,
-                "| {:<5} | {:<10} |",
-                i,
-                match k {
-                    Const::Int(i) => i.to_string(),
-                    Const::Float(f) => f.to_string(),
-                    Const::Str(s) => format!("\"{}\"", s.clone()),
-                }
-            )?;
+            writeln!(f, "| {:<5} | {:<10} |", i, k.to_string())?;
         }
 
         writeln!(f, "locals :")?;
@@ -227,39 +1101,227 @@ impl fmt::Debug for Proto {
             writeln!(f, "| {:<5} | {:<10} |", i, local.name)?;
         }
 
+        if !self.up_vars.is_empty() {
+            writeln!(f, "upvalues :")?;
+            for (i, up_var) in self.up_vars.iter().enumerate() {
+                writeln!(
+                    f,
+                    "| {:<5} | {:<10} | {:<5} | {:<5} |",
+                    i, up_var.name, up_var.in_stack as u32, up_var.index
+                )?;
+            }
+        }
+
+        // pc, source line and instruction, like `luac -l`
         writeln!(f, "instructions :")?;
-        writeln!(
-            f,
-            "| {:<5} | {:<10} | {:<5} | {:<5} | {:<5} |",
-            "line", "OP", "A", "B", "C"
-        )?;
-        for (i, instruction) in self.code.iter().enumerate() {
-            writeln!(f, "| {:<5} {:?}", i + 1, instruction)?;
+        for (pc, instruction) in self.code.iter().enumerate() {
+            let line = match self.line_info.get(pc) {
+                Some(line) => format!("[{}]", line),
+                None => "[-]".to_string(),
+            };
+            writeln!(
+                f,
+                "{:<5} {:<6} {}",
+                pc + 1,
+                line,
+                instruction.disassemble(pc, self)
+            )?;
         }
 
         Ok(())
     }
 }
 
+// a label, or a pending goto waiting for its label
+pub struct Label {
+    pub name: String,
+    pub pc: usize,
+    // number of active locals at this position
+    pub nactvar: usize,
+    pub source: Source,
+}
+
+pub struct BlockContext {
+    // number of active locals when entering this block
+    pub nactvar: usize,
+    // number of visible labels when entering this block
+    pub first_label: usize,
+    // labels followed only by other labels till the end of this block
+    pub tail_labels: Vec<String>,
+    // some local of this block is captured as an upvalue
+    pub upval: bool,
+}
+
+// sizes of a proto context, to drop what is generated after them
+pub struct ContextSnapshot {
+    reg_top: u32,
+    current_line: u32,
+    stack_size: u32,
+    last_target: usize,
+    code: usize,
+    consts: usize,
+    local_vars: usize,
+    up_vars: usize,
+    protos: usize,
+    active_vars: usize,
+    blocks: usize,
+    loops: usize,
+    labels: usize,
+    pending_gotos: usize,
+}
+
 #[derive(Default)]
 pub struct ProtoContext {
     pub reg_top: u32,
     pub proto: Proto,
+    // source line of instructions being generated
+    pub current_line: u32,
+    // indices of locals which are in scope
+    pub active_vars: Vec<u32>,
+    pub blocks: Vec<BlockContext>,
+    // pending break jumps of each enclosing loop
+    pub loops: Vec<Vec<Label>>,
+    // labels visible at current position
+    pub labels: Vec<Label>,
+    pub pending_gotos: Vec<Label>,
 }
 
 impl ProtoContext {
-    pub fn check_stack(&mut self, n: u32) {
+    pub fn add_local_var(&mut self, name: &str) {
+        self.active_vars.push(self.proto.local_vars.len() as u32);
+        self.proto.add_local_var(name);
+    }
+
+    // locals declared after the first `nactvar` ones go out of scope
+    pub fn remove_vars(&mut self, nactvar: usize) {
+        let pc = self.proto.code.len() as u32;
+        for index in self.active_vars.drain(nactvar..) {
+            self.proto.local_vars[index as usize].end_pc = Some(pc);
+        }
+    }
+
+    pub fn snapshot(&self) -> ContextSnapshot {
+        ContextSnapshot {
+            reg_top: self.reg_top,
+            current_line: self.current_line,
+            stack_size: self.proto.stack_size,
+            last_target: self.proto.last_target,
+            code: self.proto.code.len(),
+            consts: self.proto.consts.len(),
+            local_vars: self.proto.local_vars.len(),
+            up_vars: self.proto.up_vars.len(),
+            protos: self.proto.protos.len(),
+            active_vars: self.active_vars.len(),
+            blocks: self.blocks.len(),
+            loops: self.loops.len(),
+            labels: self.labels.len(),
+            pending_gotos: self.pending_gotos.len(),
+        }
+    }
+
+    // drop code, consts, locals and scopes added since the snapshot
+    pub fn restore(&mut self, snapshot: &ContextSnapshot) {
+        self.reg_top = snapshot.reg_top;
+        self.current_line = snapshot.current_line;
+        let proto = &mut self.proto;
+        proto.stack_size = snapshot.stack_size;
+        proto.last_target = snapshot.last_target;
+        proto.code.truncate(snapshot.code);
+        proto.line_info.truncate(snapshot.code);
+        proto.consts.truncate(snapshot.consts);
+        proto
+            .const_map
+            .retain(|_, index| (*index as usize) < snapshot.consts);
+        proto.local_vars.truncate(snapshot.local_vars);
+        proto.up_vars.truncate(snapshot.up_vars);
+        proto.protos.truncate(snapshot.protos);
+        self.active_vars.truncate(snapshot.active_vars);
+        self.blocks.truncate(snapshot.blocks);
+        self.loops.truncate(snapshot.loops);
+        self.labels.truncate(snapshot.labels);
+        self.pending_gotos.truncate(snapshot.pending_gotos);
+    }
+
+    // mark the block declaring local `reg` as having an upvalue, same as `markupval` of luac
+    pub fn mark_upval(&mut self, reg: u32) {
+        if let Some(block) = self
+            .blocks
+            .iter_mut()
+            .rev()
+            .find(|block| block.nactvar <= reg as usize)
+        {
+            block.upval = true;
+        }
+    }
+
+    // register of the innermost active local with the name
+    pub fn get_local_var(&self, name: &str) -> Option<u32> {
+        self.active_vars
+            .iter()
+            .rposition(|index| self.local_var_name(*index) == name)
+            .map(|reg| reg as u32)
+    }
+
+    pub fn is_read_only(&self, reg: u32) -> bool {
+        let index = self.active_vars[reg as usize];
+        self.proto.local_vars[index as usize].read_only
+    }
+
+    pub fn local_var_name(&self, index: u32) -> &str {
+        &self.proto.local_vars[index as usize].name
+    }
+
+    // record current line for instructions generated since last sync
+    pub fn fix_line_info(&mut self) {
+        let len = self.proto.code.len();
+        self.proto.line_info.resize(len, self.current_line);
+    }
+
+    pub fn set_line(&mut self, line: u32) {
+        self.fix_line_info();
+        self.current_line = line;
+    }
+
+    // emit a no-op jump for each line between current line and `line`,
+    // so that every source line is mapped to at least one instruction
+    pub fn fill_empty_lines(&mut self, line: u32) {
+        if self.current_line == 0 {
+            return;
+        }
+        for empty_line in (self.current_line + 1)..line {
+            self.set_line(empty_line);
+            self.proto.code_jmp(0, 0);
+        }
+    }
+
+    // rk of a const, it's loaded to a new register if the index doesn't fit in RK,
+    // which should be freed by caller
+    pub fn const_to_rk(&mut self, k: Const) -> Result<u32, CompileError> {
+        let index = self.proto.add_const(k);
+        if index <= MAXINDEXRK {
+            return Ok(MASK_K | index);
+        }
+        let reg = self.reserve_regs(1)?;
+        self.proto.code_const(reg, index);
+        Ok(reg)
+    }
+
+    pub fn check_stack(&mut self, n: u32) -> Result<(), CompileError> {
         let new_stack = self.reg_top + n;
+        if new_stack > MAX_REGS {
+            return Err(CompileError::new("too many local variables / registers"));
+        }
         if new_stack > self.proto.stack_size {
             self.proto.stack_size = new_stack;
         }
+        Ok(())
     }
 
-    pub fn reserve_regs(&mut self, n: u32) -> u32 {
-        self.check_stack(n);
+    pub fn reserve_regs(&mut self, n: u32) -> Result<u32, CompileError> {
+        self.check_stack(n)?;
         let index = self.reg_top;
         self.reg_top += n;
-        index
+        Ok(index)
     }
 
     pub fn get_reg_top(&self) -> u32 {
@@ -267,6 +1329,34 @@ impl ProtoContext {
     }
 
     pub fn free_reg(&mut self, n: u32) {
+        debug_assert!(
+            n <= self.reg_top,
+            "free {} registers of {}",
+            n,
+            self.reg_top
+        );
         self.reg_top -= n;
     }
+
+    pub fn checked_free_reg(&mut self, n: u32) -> Result<(), CompileError> {
+        match self.reg_top.checked_sub(n) {
+            Some(reg_top) => {
+                self.reg_top = reg_top;
+                Ok(())
+            }
+            None => Err(CompileError::new(&format!(
+                "free {} registers of {}",
+                n, self.reg_top
+            ))),
+        }
+    }
+
+    // registers above locals are all temporaries, which are freed at the end of a statement
+    pub fn check_reg_balance(&self) {
+        debug_assert_eq!(
+            self.reg_top,
+            self.active_vars.len() as u32,
+            "registers leaked"
+        );
+    }
 }
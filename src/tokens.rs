@@ -1,8 +1,13 @@
 use rslua_traits::Comments;
 
-use crate::types::{FloatType, IntType, Source};
+use crate::types::{FloatType, IntType, Number, Source};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
 
 #[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum TokenType {
     And,
     Break,
@@ -124,22 +129,132 @@ impl TokenType {
     pub fn is_comment(&self) -> bool {
         matches!(self, TokenType::SComment | TokenType::MComment)
     }
+
+    pub fn is_unop(&self) -> bool {
+        matches!(
+            self,
+            TokenType::Minus | TokenType::BXor | TokenType::Not | TokenType::Len
+        )
+    }
+
+    pub fn is_binop(&self) -> bool {
+        self.binop_priority().is_some()
+    }
+
+    // left and right priority of binary operators, from lparser.c,
+    // an operator is right associative if its right priority is lower
+    pub fn binop_priority(&self) -> Option<(u8, u8)> {
+        let priority = match self {
+            TokenType::Or => (1, 1),
+            TokenType::And => (2, 2),
+            TokenType::Eq
+            | TokenType::Ne
+            | TokenType::Lt
+            | TokenType::Gt
+            | TokenType::Le
+            | TokenType::Ge => (3, 3),
+            TokenType::BOr => (4, 4),
+            TokenType::BXor => (5, 5),
+            TokenType::BAnd => (6, 6),
+            TokenType::Shl | TokenType::Shr => (7, 7),
+            TokenType::Concat => (9, 8),
+            TokenType::Add | TokenType::Minus => (10, 10),
+            TokenType::Mul | TokenType::Mod | TokenType::Div | TokenType::IDiv => (11, 11),
+            TokenType::Pow => (14, 13),
+            _ => return None,
+        };
+        Some(priority)
+    }
 }
 
+// priority of unary operators, between `*` and `^`
+pub const UNARY_PRIORITY: u8 = 12;
+
+// serialized as `{ "type": "Int", "value": 1 }` with the `serde` feature
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "type", content = "value"))]
 pub enum TokenValue {
     None,
     Float(FloatType),
     Int(IntType),
     Str(String),
+    // value of names and strings shared in the intern table of the lexer
+    Interned(Symbol, Arc<str>),
+}
+
+// id of an interned string, ids are given in order of first use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Symbol(u32);
+
+impl Symbol {
+    pub fn id(&self) -> u32 {
+        self.0
+    }
+}
+
+// each distinct string is stored once, and shared by tokens with the same value
+#[derive(Default, Debug)]
+pub struct InternTable {
+    symbols: HashMap<Arc<str>, Symbol>,
+    strings: Vec<Arc<str>>,
+}
+
+impl InternTable {
+    pub fn intern(&mut self, s: &str) -> (Symbol, Arc<str>) {
+        if let Some((s, symbol)) = self.symbols.get_key_value(s) {
+            return (*symbol, s.clone());
+        }
+        let symbol = Symbol(self.strings.len() as u32);
+        let s: Arc<str> = Arc::from(s);
+        self.strings.push(s.clone());
+        self.symbols.insert(s.clone(), symbol);
+        (symbol, s)
+    }
+
+    pub fn resolve(&self, symbol: Symbol) -> Option<&str> {
+        self.strings.get(symbol.0 as usize).map(|s| s.as_ref())
+    }
+
+    pub fn len(&self) -> usize {
+        self.strings.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.strings.is_empty()
+    }
+}
+
+// numbers are rendered like lua
+impl fmt::Display for TokenValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenValue::None => Ok(()),
+            TokenValue::Float(n) => write!(f, "{}", Number::Float(*n)),
+            TokenValue::Int(i) => write!(f, "{}", Number::Int(*i)),
+            TokenValue::Str(s) => write!(f, "\"{}\"", s),
+            TokenValue::Interned(_, s) => write!(f, "\"{}\"", s),
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Token {
     pub t: TokenType,
     pub value: TokenValue,
+    // origin literal text of a number, only kept with `preserve_number_text`
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub raw: Option<String>,
     pub source: Source,
+    // comments before the token
     pub comments: Vec<Token>,
+    // single line comment after the token on the same line
+    pub trailing_comments: Vec<Token>,
 }
 
 impl Token {
@@ -155,25 +270,123 @@ impl Token {
             _ => unreachable!(),
         }
     }
+    pub fn get_number_text(&self) -> Option<&str> {
+        self.raw.as_deref()
+    }
     pub fn get_string(&self) -> String {
         match &self.value {
             TokenValue::Str(s) => s.clone(),
+            TokenValue::Interned(_, s) => s.to_string(),
             _ => unreachable!(),
         }
     }
     pub fn get_str(&self) -> &str {
         match &self.value {
             TokenValue::Str(s) => s,
+            TokenValue::Interned(_, s) => s,
             _ => unreachable!(),
         }
     }
     pub fn is_comment(&self) -> bool {
         self.t.is_comment()
     }
+    // payload accessors, None if the token doesn't carry such a value
+    pub fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            TokenValue::Str(s) => Some(s),
+            TokenValue::Interned(_, s) => Some(s),
+            _ => None,
+        }
+    }
+    pub fn as_symbol(&self) -> Option<Symbol> {
+        match self.value {
+            TokenValue::Interned(symbol, _) => Some(symbol),
+            _ => None,
+        }
+    }
+    pub fn as_int(&self) -> Option<IntType> {
+        match self.value {
+            TokenValue::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+    pub fn as_float(&self) -> Option<FloatType> {
+        match self.value {
+            TokenValue::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+    pub fn is_eos(&self) -> bool {
+        self.t == TokenType::Eos
+    }
+    pub fn get_trailing_comments(&self) -> Vec<&str> {
+        self.trailing_comments.iter().map(|t| t.get_str()).collect()
+    }
 }
 
 impl Comments for Token {
     fn get_comments(&self) -> Vec<&str> {
-        return self.comments.iter().map(|t| t.get_str()).collect();
+        self.comments.iter().map(|t| t.get_str()).collect()
+    }
+}
+
+// token of `Lexer::run_borrowed`, values of names, strings and comments are in `text`, which
+// borrows from the input unless escapes had to be rewritten. numbers are kept in `value`, and their
+// literal text in `raw` with `preserve_number_text`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BorrowedToken<'s> {
+    pub t: TokenType,
+    pub value: TokenValue,
+    pub text: Option<Cow<'s, str>>,
+    pub raw: Option<&'s str>,
+    pub source: Source,
+    pub comments: Vec<BorrowedToken<'s>>,
+    pub trailing_comments: Vec<BorrowedToken<'s>>,
+}
+
+impl<'s> BorrowedToken<'s> {
+    pub fn is_comment(&self) -> bool {
+        self.t.is_comment()
+    }
+    pub fn is_borrowed(&self) -> bool {
+        matches!(self.text, Some(Cow::Borrowed(_)))
+    }
+    pub fn as_str(&self) -> Option<&str> {
+        self.text.as_deref()
+    }
+    pub fn get_number_text(&self) -> Option<&str> {
+        self.raw
+    }
+    pub fn as_int(&self) -> Option<IntType> {
+        match self.value {
+            TokenValue::Int(i) => Some(i),
+            _ => None,
+        }
+    }
+    pub fn as_float(&self) -> Option<FloatType> {
+        match self.value {
+            TokenValue::Float(f) => Some(f),
+            _ => None,
+        }
+    }
+    pub fn is_eos(&self) -> bool {
+        self.t == TokenType::Eos
+    }
+}
+
+// line, column, type and value of each token, like the listing of `Proto`
+pub fn dump_tokens(tokens: &[Token]) -> String {
+    let mut output = String::from("\ntokens :\n");
+    for token in tokens {
+        let row = format!(
+            "| {:<5} | {:<5} | {:<10} | {}",
+            token.source.line,
+            token.source.col,
+            format!("{:?}", token.t),
+            token.value
+        );
+        output.push_str(row.trim_end());
+        output.push('\n');
     }
+    output
 }
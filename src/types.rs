@@ -1,3 +1,5 @@
+use std::fmt;
+
 pub type IntType = i64;
 pub type FloatType = f64;
 pub enum Number {
@@ -6,9 +8,89 @@ pub enum Number {
     None,
 }
 
+// like lua_Number2str, floats are formatted by `%.14g` with `.0` appended if
+// the result looks like an integer
+fn float_to_string(f: FloatType) -> String {
+    if f.is_nan() {
+        return if f.is_sign_negative() { "-nan" } else { "nan" }.to_string();
+    }
+    if f.is_infinite() {
+        return if f < 0.0 { "-inf" } else { "inf" }.to_string();
+    }
+    const PRECISION: i32 = 14;
+    // exponent after rounding to the precision
+    let sci = format!("{:.*e}", PRECISION as usize - 1, f);
+    let (mantissa, exp) = sci.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let trim = |s: &str| {
+        if s.contains('.') {
+            s.trim_end_matches('0').trim_end_matches('.').to_string()
+        } else {
+            s.to_string()
+        }
+    };
+    let s = if !(-4..PRECISION).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        format!("{}e{}{:02}", trim(mantissa), sign, exp.abs())
+    } else {
+        trim(&format!("{:.*}", (PRECISION - 1 - exp) as usize, f))
+    };
+    if s.chars().all(|c| c == '-' || c.is_ascii_digit()) {
+        s + ".0"
+    } else {
+        s
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(i) => write!(f, "{}", i),
+            Number::Float(n) => write!(f, "{}", float_to_string(*n)),
+            Number::None => write!(f, "none"),
+        }
+    }
+}
+
 #[derive(Default, Debug, Copy, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Source {
     pub line: usize,
     pub col: usize,
     pub length: usize,
+    // byte offset of the first char in the input
+    pub offset: usize,
+}
+
+impl Source {
+    // offset after the last char
+    pub fn end(&self) -> usize {
+        self.offset + self.length
+    }
+
+    // from the first char of `start` to the last char of `end`
+    pub fn span(start: &Source, end: &Source) -> Source {
+        Source {
+            length: end.end().max(start.end()) - start.offset,
+            ..*start
+        }
+    }
+
+    // the smallest source enclosing both sources
+    pub fn merge(&self, other: &Source) -> Source {
+        if self.offset <= other.offset {
+            Source::span(self, other)
+        } else {
+            Source::span(other, self)
+        }
+    }
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq)]
+pub enum LuaVersion {
+    // no integer division, bitwise operators or labels, `goto` is a name
+    Lua51,
+    #[default]
+    Lua53,
+    Lua54,
 }
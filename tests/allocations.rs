@@ -0,0 +1,60 @@
+use rslua::lexer::Lexer;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// count allocations of the whole test binary, so it only has one test
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+fn count_allocations<T>(f: impl FnOnce() -> T) -> (T, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let result = f();
+    (result, ALLOCATIONS.load(Ordering::Relaxed) - before)
+}
+
+// names and strings of an escape-free file borrow from the input, allocations are only
+// made for the vec of tokens
+#[test]
+fn borrowed_tokens() {
+    let mut input = String::new();
+    for i in 0..1000 {
+        input.push_str(&format!(
+            "local name{} = \"value{}\" .. [[long{}]] .. t.field{}\n",
+            i, i, i, i
+        ));
+    }
+    let mut lexer = Lexer::default();
+    let (tokens, owned) = count_allocations(|| lexer.run(&input).unwrap());
+    let (borrowed_tokens, borrowed) = count_allocations(|| lexer.run_borrowed(&input).unwrap());
+    assert_eq!(tokens.len(), borrowed_tokens.len());
+    assert!(borrowed_tokens
+        .iter()
+        .filter(|t| t.as_str().is_some())
+        .all(|t| t.is_borrowed()));
+
+    // 5000 names and strings are not allocated
+    assert!(owned >= 5000, "{} allocations of owned tokens", owned);
+    assert!(
+        borrowed < 100,
+        "{} allocations of borrowed tokens",
+        borrowed
+    );
+}
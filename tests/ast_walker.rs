@@ -0,0 +1,247 @@
+use rslua::ast::*;
+use rslua::ast_walker::{self, AstVisitor, AstVisitorMut, VisitAction, VisitFlow, VisitResult};
+use rslua::lexer::Lexer;
+use rslua::parser::Parser;
+use rslua::tokens::{Token, TokenType, TokenValue};
+use rslua::types::Source;
+
+fn try_parse(input: &str) -> Block {
+    let mut lexer = Lexer::default();
+    if let Ok(tokens) = lexer.run(input) {
+        let mut parser = Parser::default();
+        if let Ok(ast) = parser.run(tokens) {
+            return ast;
+        }
+    }
+    unreachable!()
+}
+
+// only overrides the hook it cares about
+#[derive(Default)]
+struct FuncCounter {
+    count: usize,
+}
+
+impl AstVisitor for FuncCounter {
+    fn begin_func_body(&mut self, _body: &FuncBody) -> VisitResult<()> {
+        self.count += 1;
+        Ok(VisitAction::Continue)
+    }
+}
+
+fn count_funcs(input: &str) -> usize {
+    let block = try_parse(input);
+    let mut counter = FuncCounter::default();
+    ast_walker::walk_block(&block, &mut counter).unwrap();
+    counter.count
+}
+
+#[test]
+fn count_func_stats() {
+    assert_eq!(count_funcs("function f() end local function g() end"), 2);
+    assert_eq!(count_funcs("function t.a.b:c() function d() end end"), 2);
+}
+
+#[test]
+fn count_anonymous_funcs() {
+    let input = r#"
+local a, b = function() end, 1
+t.x = { f = function() return function() end end }
+print(function() end)
+if x then return function() end end
+for i = 1, 2 do local c = (function() end)() end
+"#;
+    assert_eq!(count_funcs(input), 6);
+}
+
+// counts stats outside of function bodies, stops after `limit` stats
+struct StatCounter {
+    count: usize,
+    limit: usize,
+}
+
+impl AstVisitor for StatCounter {
+    fn begin_stat(&mut self, _stat: &Stat) -> VisitResult<()> {
+        if self.count == self.limit {
+            return Ok(VisitAction::Stop);
+        }
+        self.count += 1;
+        Ok(VisitAction::Continue)
+    }
+    fn begin_func_body(&mut self, _body: &FuncBody) -> VisitResult<()> {
+        Ok(VisitAction::SkipChildren)
+    }
+}
+
+fn count_stats(input: &str, limit: usize) -> (usize, VisitAction) {
+    let block = try_parse(input);
+    let mut counter = StatCounter { count: 0, limit };
+    let action = ast_walker::walk_block(&block, &mut counter).unwrap();
+    (counter.count, action)
+}
+
+#[test]
+fn skip_func_bodies() {
+    let input = r#"
+local a = function() local b = 1 return b end
+function f() print(1) print(2) end
+if a then
+    local c = { function() return 1 end }
+end
+"#;
+    assert_eq!(count_stats(input, usize::MAX), (4, VisitAction::Continue));
+}
+
+#[test]
+fn stop_walking() {
+    let input = "do local a = 1 local b = 2 end local c = 3";
+    assert_eq!(count_stats(input, 2), (2, VisitAction::Stop));
+    assert_eq!(count_stats(input, 4), (4, VisitAction::Continue));
+}
+
+// max nesting depth of blocks and functions, the main chunk is a block of depth 1
+#[derive(Default)]
+struct DepthCounter {
+    blocks: usize,
+    max_blocks: usize,
+    functions: usize,
+    max_functions: usize,
+}
+
+impl AstVisitor for DepthCounter {
+    fn enter_block(&mut self, _block: &Block) {
+        self.blocks += 1;
+        self.max_blocks = self.max_blocks.max(self.blocks);
+    }
+    fn leave_block(&mut self) {
+        self.blocks -= 1;
+    }
+    fn enter_function(&mut self, _body: &FuncBody) {
+        self.functions += 1;
+        self.max_functions = self.max_functions.max(self.functions);
+    }
+    fn leave_function(&mut self) {
+        self.functions -= 1;
+    }
+}
+
+fn nesting_depth(input: &str) -> (usize, usize) {
+    let block = try_parse(input);
+    let mut counter = DepthCounter::default();
+    ast_walker::walk_block(&block, &mut counter).unwrap();
+    assert_eq!((counter.blocks, counter.functions), (0, 0));
+    (counter.max_blocks, counter.max_functions)
+}
+
+#[test]
+fn nesting_depth_of_blocks() {
+    assert_eq!(nesting_depth(""), (1, 0));
+    assert_eq!(nesting_depth("do end do do end end"), (3, 0));
+    let input = r#"
+if a then
+    while b do
+        for i = 1, 2 do repeat local c until c end
+    end
+elseif d then
+else
+    for k, v in pairs(t) do end
+end
+"#;
+    assert_eq!(nesting_depth(input), (5, 0));
+    let input = r#"
+local function f()
+    return function() do end end
+end
+g = function() end
+"#;
+    assert_eq!(nesting_depth(input), (4, 2));
+}
+
+#[derive(Default)]
+struct StringCollector {
+    strings: Vec<(String, usize)>,
+}
+
+impl AstVisitor for StringCollector {
+    fn visit_expr(&mut self, expr: &Expr, source: &Source) {
+        if let Expr::String(s) = expr {
+            self.strings.push((s.value(), source.line));
+        }
+    }
+}
+
+#[test]
+fn collect_strings() {
+    let input = r#"
+local a = "a" .. ("b" .. "c")
+print(a, t["d"], { "e", f = "f", ["g"] = "h" })
+return function() return #"i" end
+"#;
+    let block = try_parse(input);
+    let mut collector = StringCollector::default();
+    ast_walker::walk_block(&block, &mut collector).unwrap();
+    let expected = [
+        ("a", 2),
+        ("b", 2),
+        ("c", 2),
+        ("d", 3),
+        ("e", 3),
+        ("f", 3),
+        ("g", 3),
+        ("h", 3),
+        ("i", 4),
+    ];
+    let expected: Vec<(String, usize)> = expected
+        .iter()
+        .map(|(s, line)| (s.to_string(), *line))
+        .collect();
+    assert_eq!(collector.strings, expected);
+}
+
+// folds additions of integer literals, after children are folded
+struct AddFolder;
+
+impl AstVisitorMut for AddFolder {
+    fn end_expr(&mut self, expr: &mut Expr) -> Result<VisitFlow<Expr>, ()> {
+        if let Expr::BinExpr(bin) = expr {
+            if let (BinOp::Add(op), Expr::Int(left), Expr::Int(right)) =
+                (&bin.op, bin.left.as_ref(), bin.right.as_ref())
+            {
+                let token = Token {
+                    t: TokenType::Int,
+                    value: TokenValue::Int(left.token.get_int() + right.token.get_int()),
+                    raw: None,
+                    source: op.source,
+                    comments: Vec::new(),
+                    trailing_comments: Vec::new(),
+                };
+                return Ok(VisitFlow::Replace(Expr::Int(IntExpr { token })));
+            }
+        }
+        Ok(VisitFlow::Continue)
+    }
+}
+
+#[test]
+fn fold_constant_add() {
+    let mut block = try_parse("local a = 1 + 2 + 3 print(1 + 2)");
+    ast_walker::walk_block_mut(&mut block, &mut AddFolder).unwrap();
+    let mut ints = Vec::new();
+    for stat in block.stats.iter() {
+        let exprs = match stat {
+            Stat::LocalStat(stat) => &stat.exprs.as_ref().unwrap().exprs,
+            Stat::CallStat(CallStat {
+                call: Assignable::SuffixedExpr(call),
+            }) => match &call.suffixes[0] {
+                Suffix::FuncArgs(FuncArgs::Exprs(_, exprs, _)) => &exprs.exprs,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        };
+        match &exprs[0] {
+            Expr::Int(int) => ints.push(int.token.get_int()),
+            _ => panic!("expression is not folded"),
+        }
+    }
+    assert_eq!(ints, vec![6, 3]);
+}
@@ -1,17 +1,27 @@
+use rslua::ast;
 use rslua::compiler::*;
+use rslua::consts::Const;
 use rslua::lexer::*;
+use rslua::opcodes::{is_const, OpCode, NO_JUMP};
 use rslua::parser::*;
-use rslua::proto::Proto;
+use rslua::proto::{BlockContext, Proto, ProtoContext};
+use rslua::types::Source;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
 
 fn try_compile(input: &str) -> Result<Proto, CompileError> {
+    try_compile_with(input, Compiler::default())
+}
+
+fn try_compile_with(input: &str, mut compiler: Compiler) -> Result<Proto, CompileError> {
     let mut lexer = Lexer::default();
     if let Ok(tokens) = lexer.run(input) {
         let mut parser = Parser::default();
         if let Ok(block) = parser.run(tokens) {
-            let mut compiler = Compiler::default();
             match compiler.run(&block) {
                 Ok(proto) => {
                     println!("{:?}", proto);
+                    assert_eq!(proto.verify(), Ok(()));
                     return Ok(proto);
                 }
                 Err(e) => {
@@ -26,7 +36,7 @@ fn try_compile(input: &str) -> Result<Proto, CompileError> {
 fn try_compile_and_print(input: &str) -> String {
     match try_compile(input) {
         Ok(proto) => format!("{:?}", proto),
-        Err(e) => e.0,
+        Err(e) => e.to_string(),
     }
 }
 
@@ -42,8 +52,7 @@ stack size : 2
 consts :
 locals :
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | Return     | 0     | 1     |       |
+1     [1]    RETURN    0 1
 "#
         );
     }
@@ -60,13 +69,32 @@ locals :
 | 1     | b          |
 | 2     | c          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    RETURN    0 1
 "#
         );
     }
 
+    // equal integers and floats are different consts, and are listed distinctly
+    #[test]
+    fn int_and_float_consts() {
+        let output = try_compile_and_print("local a, b = 1, 1.0");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 1          |
+| 1     | 1.0        |
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 1.0
+3     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
     #[test]
     fn local_stat_with_const() {
         assert_eq!(
@@ -75,18 +103,17 @@ instructions :
 stack size : 3
 consts :
 | 0     | 1          |
-| 1     | 2          |
+| 1     | 2.0        |
 | 2     | "123"      |
 locals :
 | 0     | a          |
 | 1     | b          |
 | 2     | c          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | LoadK      | 2     | 2     |       |
-| 4     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2.0
+3     [1]    LOADK     2 -3        ; "123"
+4     [1]    RETURN    0 1
 "#
         );
     }
@@ -99,7 +126,7 @@ instructions :
 stack size : 6
 consts :
 | 0     | 1          |
-| 1     | 2          |
+| 1     | 2.0        |
 | 2     | "123"      |
 locals :
 | 0     | a          |
@@ -109,14 +136,13 @@ locals :
 | 4     | e          |
 | 5     | f          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | LoadK      | 2     | 2     |       |
-| 4     | LoadK      | 3     | 0     |       |
-| 5     | LoadK      | 4     | 1     |       |
-| 6     | LoadK      | 5     | 2     |       |
-| 7     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2.0
+3     [1]    LOADK     2 -3        ; "123"
+4     [1]    LOADK     3 -1        ; 1
+5     [1]    LOADK     4 -2        ; 2.0
+6     [1]    LOADK     5 -3        ; "123"
+7     [1]    RETURN    0 1
 "#;
         assert_eq!(try_compile_and_print(stat1), output);
         assert_eq!(try_compile_and_print(stat2), output);
@@ -134,15 +160,51 @@ locals :
 | 1     | b          |
 | 2     | c          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadBool   | 0     | 1     | 0     |
-| 2     | LoadNil    | 1     | 0     |       |
-| 3     | LoadBool   | 2     | 0     | 0     |
-| 4     | Return     | 0     | 1     |       |
+1     [1]    LOADBOOL  0 1 0
+2     [1]    LOADNIL   1 0
+3     [1]    LOADBOOL  2 0 0
+4     [1]    RETURN    0 1
 "#
         )
     }
 
+    #[test]
+    fn local_stat_merge_nil() {
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+instructions :
+1     [1]    LOADNIL   0 2
+2     [1]    RETURN    0 1
+"#;
+        assert_eq!(try_compile_and_print("local a; local b; local c"), expected);
+    }
+
+    #[test]
+    fn local_stat_nil_jump_target() {
+        let expected = r#"
+stack size : 2
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    LOADNIL   1 0
+3     [1]    TEST      1 0
+4     [1]    JMP       0 -3        ; to 2
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(
+            try_compile_and_print("local a; repeat local b until b"),
+            expected
+        );
+    }
+
     #[test]
     fn local_stat_move() {
         assert_eq!(
@@ -162,14 +224,13 @@ locals :
 | 5     | f          |
 | 6     | g          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | Move       | 2     | 0     |       |
-| 4     | Move       | 3     | 1     |       |
-| 5     | LoadK      | 4     | 2     |       |
-| 6     | LoadNil    | 5     | 1     |       |
-| 7     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2
+3     [1]    MOVE      2 0
+4     [1]    MOVE      3 1
+5     [1]    LOADK     4 -3        ; 3
+6     [1]    LOADNIL   5 1
+7     [1]    RETURN    0 1
 "#
         )
     }
@@ -198,24 +259,23 @@ locals :
 | 5     | f          |
 | 6     | g          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | LoadK      | 2     | 2     |       |
-| 4     | LoadNil    | 3     | 3     |       |
-| 5     | Move       | 7     | 0     |       |
-| 6     | Move       | 8     | 1     |       |
-| 7     | LoadNil    | 9     | 1     |       |
-| 8     | Move       | 6     | 10    |       |
-| 9     | Move       | 5     | 9     |       |
-| 10    | Move       | 4     | 8     |       |
-| 11    | Move       | 3     | 7     |       |
-| 12    | LoadK      | 7     | 3     |       |
-| 13    | LoadK      | 8     | 4     |       |
-| 14    | LoadK      | 2     | 5     |       |
-| 15    | Move       | 1     | 8     |       |
-| 16    | Move       | 0     | 7     |       |
-| 17    | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2
+3     [1]    LOADK     2 -3        ; 3
+4     [1]    LOADNIL   3 3
+5     [1]    MOVE      7 0
+6     [1]    MOVE      8 1
+7     [1]    LOADNIL   9 1
+8     [1]    MOVE      6 10
+9     [1]    MOVE      5 9
+10    [1]    MOVE      4 8
+11    [1]    MOVE      3 7
+12    [1]    LOADK     7 -4        ; 4
+13    [1]    LOADK     8 -5        ; 5
+14    [1]    LOADK     2 -6        ; 6
+15    [1]    MOVE      1 8
+16    [1]    MOVE      0 7
+17    [1]    RETURN    0 1
 "#
         )
     }
@@ -233,17 +293,60 @@ locals :
 | 0     | a          |
 | 1     | b          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | Move       | 2     | 1     |       |
-| 4     | Move       | 1     | 0     |       |
-| 5     | Move       | 0     | 2     |       |
-| 6     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2
+3     [1]    MOVE      2 1
+4     [1]    MOVE      1 0
+5     [1]    MOVE      0 2
+6     [1]    RETURN    0 1
 "#
         )
     }
 
+    // targets are evaluated before values, `a[i]` uses a copy of `i` made before it changes
+    #[test]
+    fn assign_index_conflict() {
+        let output = try_compile_and_print("local a, i = {}, 3; a[i], i = 20, i + 1");
+        let expected = r#"
+stack size : 5
+consts :
+| 0     | 3          |
+| 1     | 20         |
+| 2     | 1          |
+locals :
+| 0     | a          |
+| 1     | i          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [1]    LOADK     1 -1        ; 3
+3     [1]    MOVE      2 1
+4     [1]    LOADK     3 -2        ; 20
+5     [1]    ADD       1 1 -3      ; - 1
+6     [1]    SETTABLE  0 2 3
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+
+        let output = try_compile_and_print("local t = {}; t.x, t = 1, 2");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | "x"        |
+| 1     | 1          |
+| 2     | 2          |
+locals :
+| 0     | t          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [1]    MOVE      1 0
+3     [1]    LOADK     2 -2        ; 1
+4     [1]    LOADK     0 -3        ; 2
+5     [1]    SETTABLE  1 -1 2      ; "x" -
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
     #[test]
     fn assign_free_extra_reg() {
         assert_eq!(
@@ -264,18 +367,17 @@ locals :
 | 4     | e          |
 | 5     | f          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | LoadK      | 3     | 0     |       |
-| 3     | LoadK      | 4     | 1     |       |
-| 4     | LoadK      | 5     | 2     |       |
-| 5     | LoadK      | 6     | 3     |       |
-| 6     | LoadK      | 7     | 4     |       |
-| 7     | Move       | 2     | 5     |       |
-| 8     | Move       | 1     | 4     |       |
-| 9     | Move       | 0     | 3     |       |
-| 10    | LoadNil    | 3     | 2     |       |
-| 11    | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    LOADK     3 -1        ; 1
+3     [1]    LOADK     4 -2        ; 2
+4     [1]    LOADK     5 -3        ; 3
+5     [1]    LOADK     6 -4        ; 4
+6     [1]    LOADK     7 -5        ; 5
+7     [1]    MOVE      2 5
+8     [1]    MOVE      1 4
+9     [1]    MOVE      0 3
+10    [1]    LOADNIL   3 2
+11    [1]    RETURN    0 1
 "#
         )
     }
@@ -293,15 +395,14 @@ local a, b = 1 + 2 - 3 * 4 / 6 % 7 ^ 8 & 9 | 10 ~ 11 << 1 >> 2,
 stack size : 2
 consts :
 | 0     | 15         |
-| 1     | 0.00000024104295037190596 |
+| 1     | 2.4104295037191e-07 |
 locals :
 | 0     | a          |
 | 1     | b          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | Return     | 0     | 1     |       |
+1     [2]    LOADK     0 -1        ; 15
+2     [2]    LOADK     1 -2        ; 2.4104295037191e-07
+3     [2]    RETURN    0 1
 "#
         )
     }
@@ -312,22 +413,21 @@ instructions :
         let expected = r#"
 stack size : 2
 consts :
-| 0     | -2         |
+| 0     | -2.0       |
 | 1     | 106704     |
 locals :
 | 0     | a          |
 | 1     | b          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | LoadK      | 1     | 1     |       |
-| 3     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; -2.0
+2     [1]    LOADK     1 -2        ; 106704
+3     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
 
     #[test]
-    #[should_panic]
+    #[should_panic(expected = "attempt to perform 'n//0' at line [5]")]
     fn divide_by_zero() {
         try_compile_and_print(
             r#"
@@ -338,6 +438,41 @@ local a = 1 // 0"#,
         );
     }
 
+    #[test]
+    #[should_panic(expected = "attempt to perform 'n%%0' at line [1]")]
+    fn modulo_by_zero() {
+        try_compile_and_print("local a = 1 % 0");
+    }
+
+    #[test]
+    fn float_divide_by_zero() {
+        // inf and nan are left to runtime, same as luac
+        let proto = try_compile("local a, b, c = 1.0 / 0, -1 / 0, 0 / 0.0")
+            .ok()
+            .unwrap();
+        let divs = proto
+            .code
+            .iter()
+            .filter(|i| i.get_op() == OpCode::Div)
+            .count();
+        assert_eq!(divs, 3);
+        assert!(proto
+            .consts
+            .iter()
+            .all(|k| !matches!(k, Const::Float(f) if !f.is_finite())));
+    }
+
+    #[test]
+    fn floor_division_and_modulo() {
+        let proto = try_compile("local a, b, c, d = -7 // 2, -7 % 3, 7 % -3, -7.5 // 2")
+            .ok()
+            .unwrap();
+        assert_eq!(proto.consts[0], Const::Int(-4));
+        assert_eq!(proto.consts[1], Const::Int(2));
+        assert_eq!(proto.consts[2], Const::Int(-2));
+        assert_eq!(proto.consts[3], Const::Float(-4.0));
+    }
+
     #[test]
     fn code_bin_op() {
         let output =
@@ -353,13 +488,12 @@ locals :
 | 3     | d          |
 | 4     | e          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | Add        | 3     | 256   | 0     |
-| 3     | Mul        | 4     | 1     | 2     |
-| 4     | Sub        | 3     | 3     | 4     |
-| 5     | Add        | 4     | 3     | 256   |
-| 6     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    ADD       3 -1 0      ; 1 -
+3     [1]    MUL       4 1 2
+4     [1]    SUB       3 3 4
+5     [1]    ADD       4 3 -1      ; - 1
+6     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -380,15 +514,14 @@ locals :
 | 4     | e          |
 | 5     | f          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | Mul        | 3     | 0     | 256   |
-| 3     | Sub        | 3     | 3     | 1     |
-| 4     | Div        | 4     | 0     | 1     |
-| 5     | Div        | 4     | 4     | 2     |
-| 6     | Add        | 5     | 1     | 0     |
-| 7     | Add        | 5     | 5     | 2     |
-| 8     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    MUL       3 0 -1      ; - 3
+3     [1]    SUB       3 3 1
+4     [1]    DIV       4 0 1
+5     [1]    DIV       4 4 2
+6     [1]    ADD       5 1 0
+7     [1]    ADD       5 5 2
+8     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -410,17 +543,16 @@ locals :
 | 4     | e          |
 | 5     | f          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 5     |       |
-| 2     | Mul        | 6     | 0     | 256   |
-| 3     | Sub        | 6     | 6     | 1     |
-| 4     | Div        | 7     | 0     | 1     |
-| 5     | Div        | 7     | 7     | 2     |
-| 6     | Add        | 8     | 1     | 0     |
-| 7     | Add        | 5     | 8     | 2     |
-| 8     | Move       | 4     | 7     |       |
-| 9     | Move       | 3     | 6     |       |
-| 10    | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 5
+2     [1]    MUL       6 0 -1      ; - 3
+3     [1]    SUB       6 6 1
+4     [1]    DIV       7 0 1
+5     [1]    DIV       7 7 2
+6     [1]    ADD       8 1 0
+7     [1]    ADD       5 8 2
+8     [1]    MOVE      4 7
+9     [1]    MOVE      3 6
+10    [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -437,12 +569,11 @@ locals :
 | 2     | c          |
 | 3     | d          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | Sub        | 3     | 0     | 1     |
-| 3     | Sub        | 4     | 1     | 2     |
-| 4     | Mul        | 3     | 3     | 4     |
-| 5     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    SUB       3 0 1
+3     [1]    SUB       4 1 2
+4     [1]    MUL       3 3 4
+5     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -459,11 +590,10 @@ locals :
 | 2     | c          |
 | 3     | d          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | Add        | 3     | 1     | 2     |
-| 3     | Add        | 3     | 0     | 3     |
-| 4     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    ADD       3 1 2
+3     [1]    ADD       3 0 3
+4     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -481,16 +611,15 @@ locals :
 | 2     | c          |
 | 3     | d          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 2     |       |
-| 2     | Unm        | 3     | 0     |       |
-| 3     | BNot       | 4     | 1     |       |
-| 4     | Add        | 3     | 3     | 4     |
-| 5     | Unm        | 4     | 2     |       |
-| 6     | Add        | 3     | 3     | 4     |
-| 7     | Mul        | 3     | 3     | 256   |
-| 8     | Unm        | 3     | 3     |       |
-| 9     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 2
+2     [1]    UNM       3 0
+3     [1]    BNOT      4 1
+4     [1]    ADD       3 3 4
+5     [1]    UNM       4 2
+6     [1]    ADD       3 3 4
+7     [1]    MUL       3 3 -1      ; - 4
+8     [1]    UNM       3 3
+9     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -511,14 +640,13 @@ locals :
 | 4     | e          |
 | 5     | f          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadBool   | 0     | 1     | 0     |
-| 2     | LoadBool   | 1     | 1     | 0     |
-| 3     | LoadBool   | 2     | 0     | 0     |
-| 4     | LoadBool   | 3     | 0     | 0     |
-| 5     | LoadBool   | 4     | 0     | 0     |
-| 6     | LoadNil    | 5     | 0     |       |
-| 7     | Return     | 0     | 1     |       |
+1     [1]    LOADBOOL  0 1 0
+2     [1]    LOADBOOL  1 1 0
+3     [1]    LOADBOOL  2 0 0
+4     [1]    LOADBOOL  3 0 0
+5     [1]    LOADBOOL  4 0 0
+6     [1]    LOADNIL   5 0
+7     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -534,12 +662,11 @@ locals :
 | 0     | a          |
 | 1     | b          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadBool   | 0     | 0     | 0     |
-| 2     | LoadNil    | 1     | 0     |       |
-| 3     | Not        | 2     | 0     |       |
-| 4     | Add        | 1     | 2     | 256   |
-| 5     | Return     | 0     | 1     |       |
+1     [1]    LOADBOOL  0 0 0
+2     [1]    LOADNIL   1 0
+3     [1]    NOT       2 0
+4     [1]    ADD       1 2 -1      ; - 1
+5     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -554,10 +681,9 @@ locals :
 | 0     | a          |
 | 1     | b          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 0     |       |
-| 2     | Len        | 1     | 0     |       |
-| 3     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 0
+2     [1]    LEN       1 0
+3     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected)
     }
@@ -573,13 +699,65 @@ locals :
 | 1     | b          |
 | 2     | c          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 1     |       |
-| 2     | Lt         | 1     | 0     | 1     |
-| 3     | Jmp        | 0     | 1     |       |
-| 4     | LoadBool   | 2     | 0     | 1     |
-| 5     | LoadBool   | 2     | 1     | 0     |
-| 6     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 1
+2     [1]    LT        1 0 1
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  2 0 1
+5     [1]    LOADBOOL  2 1 0
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn save_comp() {
+        let output = try_compile_and_print("local b = 1 < 2");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | b          |
+instructions :
+1     [1]    LT        1 -1 -2     ; 1 2
+2     [1]    JMP       0 1         ; to 4
+3     [1]    LOADBOOL  0 0 1
+4     [1]    LOADBOOL  0 1 0
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+
+        // result of comparison is saved to an existing local or a temp register
+        let output = try_compile_and_print("local a, b; a = a < b; b = a == 1; t.x = a ~= b");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | 1          |
+| 1     | "t"        |
+| 2     | "x"        |
+locals :
+| 0     | a          |
+| 1     | b          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    LT        1 0 1
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  0 0 1
+5     [1]    LOADBOOL  0 1 0
+6     [1]    EQ        1 0 -1      ; - 1
+7     [1]    JMP       0 1         ; to 9
+8     [1]    LOADBOOL  1 0 1
+9     [1]    LOADBOOL  1 1 0
+10    [1]    GETTABUP  2 0 -2      ; _ENV "t"
+11    [1]    EQ        0 0 1
+12    [1]    JMP       0 1         ; to 14
+13    [1]    LOADBOOL  3 0 1
+14    [1]    LOADBOOL  3 1 0
+15    [1]    SETTABLE  2 -3 3      ; "x" -
+16    [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -601,33 +779,32 @@ locals :
 | 3     | d          |
 | 4     | e          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 3     |       |
-| 2     | Lt         | 1     | 0     | 1     |
-| 3     | Jmp        | 0     | 1     |       |
-| 4     | LoadBool   | 4     | 0     | 1     |
-| 5     | LoadBool   | 4     | 1     | 0     |
-| 6     | Le         | 1     | 4     | 2     |
-| 7     | Jmp        | 0     | 1     |       |
-| 8     | LoadBool   | 4     | 0     | 1     |
-| 9     | LoadBool   | 4     | 1     | 0     |
-| 10    | Lt         | 1     | 3     | 4     |
-| 11    | Jmp        | 0     | 1     |       |
-| 12    | LoadBool   | 4     | 0     | 1     |
-| 13    | LoadBool   | 4     | 1     | 0     |
-| 14    | Le         | 1     | 256   | 4     |
-| 15    | Jmp        | 0     | 1     |       |
-| 16    | LoadBool   | 4     | 0     | 1     |
-| 17    | LoadBool   | 4     | 1     | 0     |
-| 18    | Eq         | 1     | 4     | 257   |
-| 19    | Jmp        | 0     | 1     |       |
-| 20    | LoadBool   | 4     | 0     | 1     |
-| 21    | LoadBool   | 4     | 1     | 0     |
-| 22    | Eq         | 0     | 4     | 258   |
-| 23    | Jmp        | 0     | 1     |       |
-| 24    | LoadBool   | 4     | 0     | 1     |
-| 25    | LoadBool   | 4     | 1     | 0     |
-| 26    | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 3
+2     [1]    LT        1 0 1
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  4 0 1
+5     [1]    LOADBOOL  4 1 0
+6     [1]    LE        1 4 2
+7     [1]    JMP       0 1         ; to 9
+8     [1]    LOADBOOL  4 0 1
+9     [1]    LOADBOOL  4 1 0
+10    [1]    LT        1 3 4
+11    [1]    JMP       0 1         ; to 13
+12    [1]    LOADBOOL  4 0 1
+13    [1]    LOADBOOL  4 1 0
+14    [1]    LE        1 -1 4      ; 1 -
+15    [1]    JMP       0 1         ; to 17
+16    [1]    LOADBOOL  4 0 1
+17    [1]    LOADBOOL  4 1 0
+18    [1]    EQ        1 4 -2      ; - 2
+19    [1]    JMP       0 1         ; to 21
+20    [1]    LOADBOOL  4 0 1
+21    [1]    LOADBOOL  4 1 0
+22    [1]    EQ        0 4 -3      ; - 3
+23    [1]    JMP       0 1         ; to 25
+24    [1]    LOADBOOL  4 0 1
+25    [1]    LOADBOOL  4 1 0
+26    [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -650,33 +827,32 @@ locals :
 | 3     | d          |
 | 4     | e          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 3     |       |
-| 2     | Le         | 1     | 256   | 3     |
-| 3     | Jmp        | 0     | 1     |       |
-| 4     | LoadBool   | 4     | 0     | 1     |
-| 5     | LoadBool   | 4     | 1     | 0     |
-| 6     | Lt         | 1     | 4     | 2     |
-| 7     | Jmp        | 0     | 1     |       |
-| 8     | LoadBool   | 4     | 0     | 1     |
-| 9     | LoadBool   | 4     | 1     | 0     |
-| 10    | Le         | 1     | 1     | 4     |
-| 11    | Jmp        | 0     | 1     |       |
-| 12    | LoadBool   | 4     | 0     | 1     |
-| 13    | LoadBool   | 4     | 1     | 0     |
-| 14    | Lt         | 1     | 0     | 4     |
-| 15    | Jmp        | 0     | 1     |       |
-| 16    | LoadBool   | 4     | 0     | 1     |
-| 17    | LoadBool   | 4     | 1     | 0     |
-| 18    | Eq         | 0     | 257   | 258   |
-| 19    | Jmp        | 0     | 1     |       |
-| 20    | LoadBool   | 5     | 0     | 1     |
-| 21    | LoadBool   | 5     | 1     | 0     |
-| 22    | Eq         | 1     | 4     | 5     |
-| 23    | Jmp        | 0     | 1     |       |
-| 24    | LoadBool   | 4     | 0     | 1     |
-| 25    | LoadBool   | 4     | 1     | 0     |
-| 26    | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 3
+2     [1]    LE        1 -1 3      ; 1 -
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  4 0 1
+5     [1]    LOADBOOL  4 1 0
+6     [1]    LT        1 4 2
+7     [1]    JMP       0 1         ; to 9
+8     [1]    LOADBOOL  4 0 1
+9     [1]    LOADBOOL  4 1 0
+10    [1]    LE        1 1 4
+11    [1]    JMP       0 1         ; to 13
+12    [1]    LOADBOOL  4 0 1
+13    [1]    LOADBOOL  4 1 0
+14    [1]    LT        1 0 4
+15    [1]    JMP       0 1         ; to 17
+16    [1]    LOADBOOL  4 0 1
+17    [1]    LOADBOOL  4 1 0
+18    [1]    EQ        0 -2 -3     ; 2 3
+19    [1]    JMP       0 1         ; to 21
+20    [1]    LOADBOOL  5 0 1
+21    [1]    LOADBOOL  5 1 0
+22    [1]    EQ        1 4 5
+23    [1]    JMP       0 1         ; to 25
+24    [1]    LOADBOOL  4 0 1
+25    [1]    LOADBOOL  4 1 0
+26    [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -692,15 +868,43 @@ locals :
 | 1     | b          |
 | 2     | c          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 1     |       |
-| 2     | Lt         | 0     | 1     | 0     |
-| 3     | Jmp        | 0     | 1     |       |
-| 4     | LoadBool   | 2     | 0     | 1     |
-| 5     | LoadBool   | 2     | 1     | 0     |
-| 6     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 1
+2     [1]    LT        0 1 0
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  2 0 1
+5     [1]    LOADBOOL  2 1 0
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn code_not_eq() {
+        let output = try_compile_and_print("local a, b; local c = not (a == b)");
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    EQ        0 0 1
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  2 0 1
+5     [1]    LOADBOOL  2 1 0
+6     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
+        assert_eq!(
+            output,
+            try_compile_and_print("local a, b; local c = a ~= b")
+        );
+        assert_eq!(
+            try_compile_and_print("local a, b; local c = not (a ~= b)"),
+            try_compile_and_print("local a, b; local c = a == b")
+        );
     }
 
     #[test]
@@ -713,9 +917,29 @@ consts :
 locals :
 | 0     | a          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 0     |       |
-| 2     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -1        ; 3
+2     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    // the value of `a` is copied to the result register by TESTSET, without a MOVE
+    #[test]
+    fn code_and_test_set() {
+        let output = try_compile_and_print("local a, b; local c = a and b");
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    TESTSET   2 0 0
+3     [1]    JMP       0 1         ; to 5
+4     [1]    MOVE      2 1
+5     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -731,9 +955,8 @@ consts :
 locals :
 | 0     | a          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadK      | 0     | 1     |       |
-| 2     | Return     | 0     | 1     |       |
+1     [1]    LOADK     0 -2        ; 3
+2     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -750,10 +973,9 @@ locals :
 | 0     | a          |
 | 1     | b          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 0     |       |
-| 2     | Move       | 1     | 0     |       |
-| 3     | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 0
+2     [1]    MOVE      1 0
+3     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -773,16 +995,15 @@ consts :
 locals :
 | 0     | a          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | Lt         | 0     | 256   | 257   |
-| 2     | Jmp        | 0     | 4     |       |
-| 3     | Lt         | 0     | 258   | 259   |
-| 4     | Jmp        | 0     | 2     |       |
-| 5     | Lt         | 1     | 260   | 261   |
-| 6     | Jmp        | 0     | 1     |       |
-| 7     | LoadBool   | 0     | 0     | 1     |
-| 8     | LoadBool   | 0     | 1     | 0     |
-| 9     | Return     | 0     | 1     |       |
+1     [1]    LT        0 -1 -2     ; 1 2
+2     [1]    JMP       0 4         ; to 7
+3     [1]    LT        0 -3 -4     ; 3 4
+4     [1]    JMP       0 2         ; to 7
+5     [1]    LT        1 -5 -6     ; 5 6
+6     [1]    JMP       0 1         ; to 8
+7     [1]    LOADBOOL  0 0 1
+8     [1]    LOADBOOL  0 1 0
+9     [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -806,19 +1027,18 @@ locals :
 | 5     | f          |
 | 6     | g          |
 instructions :
-| line  | OP         | A     | B     | C     |
-| 1     | LoadNil    | 0     | 5     |       |
-| 2     | Le         | 0     | 1     | 0     |
-| 3     | Jmp        | 0     | 6     |       |
-| 4     | Eq         | 0     | 2     | 3     |
-| 5     | Jmp        | 0     | 4     |       |
-| 6     | Add        | 6     | 4     | 256   |
-| 7     | Sub        | 7     | 5     | 257   |
-| 8     | Eq         | 0     | 6     | 7     |
-| 9     | Jmp        | 0     | 1     |       |
-| 10    | LoadBool   | 6     | 0     | 1     |
-| 11    | LoadBool   | 6     | 1     | 0     |
-| 12    | Return     | 0     | 1     |       |
+1     [1]    LOADNIL   0 5
+2     [1]    LE        0 1 0
+3     [1]    JMP       0 6         ; to 10
+4     [1]    EQ        0 2 3
+5     [1]    JMP       0 4         ; to 10
+6     [1]    ADD       6 4 -1      ; - 1
+7     [1]    SUB       7 5 -2      ; - 2
+8     [1]    EQ        0 6 7
+9     [1]    JMP       0 1         ; to 11
+10    [1]    LOADBOOL  6 0 1
+11    [1]    LOADBOOL  6 1 0
+12    [1]    RETURN    0 1
 "#;
         assert_eq!(output, expected);
     }
@@ -853,7 +1073,30 @@ instructions :
     fn test_short_circuit_test_set() {
         let output =
             try_compile_and_print("local a, b, c, d, e; local f = a and b and c and d and e;");
-        // TODO:
+        let expected = r#"
+stack size : 6
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+| 3     | d          |
+| 4     | e          |
+| 5     | f          |
+instructions :
+1     [1]    LOADNIL   0 4
+2     [1]    TESTSET   5 0 0
+3     [1]    JMP       0 7         ; to 11
+4     [1]    TESTSET   5 1 0
+5     [1]    JMP       0 5         ; to 11
+6     [1]    TESTSET   5 2 0
+7     [1]    JMP       0 3         ; to 11
+8     [1]    TESTSET   5 3 0
+9     [1]    JMP       0 1         ; to 11
+10    [1]    MOVE      5 4
+11    [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
     }
 
     #[test]
@@ -861,6 +1104,2438 @@ instructions :
         let output = try_compile_and_print(
             "local a, b, c, d, e; local f = not a and not b and not c and not d and not e;",
         );
-        // TODO:
+        let expected = r#"
+stack size : 6
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+| 3     | d          |
+| 4     | e          |
+| 5     | f          |
+instructions :
+1     [1]    LOADNIL   0 4
+2     [1]    TEST      0 1
+3     [1]    JMP       0 8         ; to 12
+4     [1]    TEST      1 1
+5     [1]    JMP       0 6         ; to 12
+6     [1]    TEST      2 1
+7     [1]    JMP       0 4         ; to 12
+8     [1]    TEST      3 1
+9     [1]    JMP       0 2         ; to 12
+10    [1]    NOT       5 4
+11    [1]    JMP       0 2         ; to 14
+12    [1]    LOADBOOL  5 0 1
+13    [1]    LOADBOOL  5 1 0
+14    [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn code_and_global() {
+        let output = try_compile_and_print("x = y and z");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "x"        |
+| 1     | "y"        |
+| 2     | "z"        |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -2      ; _ENV "y"
+2     [1]    TEST      0 0
+3     [1]    JMP       0 1         ; to 5
+4     [1]    GETTABUP  0 0 -3      ; _ENV "z"
+5     [1]    SETTABUP  0 -1 0      ; _ENV "x"
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn code_or() {
+        let output = try_compile_and_print("local x, y; local z = x or y");
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | x          |
+| 1     | y          |
+| 2     | z          |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    TESTSET   2 0 1
+3     [1]    JMP       0 1         ; to 5
+4     [1]    MOVE      2 1
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn code_and_or() {
+        let output = try_compile_and_print("local a, b, c; local d = a and b or c");
+        let expected = r#"
+stack size : 4
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+| 3     | d          |
+instructions :
+1     [1]    LOADNIL   0 2
+2     [1]    TEST      0 0
+3     [1]    JMP       0 2         ; to 6
+4     [1]    TESTSET   3 1 1
+5     [1]    JMP       0 1         ; to 7
+6     [1]    MOVE      3 2
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn code_or_comp() {
+        let output = try_compile_and_print("local a, b; local c = a or a < b");
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    TESTSET   2 0 1
+3     [1]    JMP       0 4         ; to 8
+4     [1]    LT        1 0 1
+5     [1]    JMP       0 1         ; to 7
+6     [1]    LOADBOOL  2 0 1
+7     [1]    LOADBOOL  2 1 0
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn cond_and_or() {
+        let output = try_compile_and_print("local a, b, c; if a and b or c then a = 1 end");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | 1          |
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+instructions :
+1     [1]    LOADNIL   0 2
+2     [1]    TEST      0 0
+3     [1]    JMP       0 2         ; to 6
+4     [1]    TEST      1 1
+5     [1]    JMP       0 2         ; to 8
+6     [1]    TEST      2 0
+7     [1]    JMP       0 1         ; to 9
+8     [1]    LOADK     0 -1        ; 1
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn line_info() {
+        let proto = try_compile("local a = 1\nprint(a)").ok().unwrap();
+        assert_eq!(proto.line_info, vec![1, 2, 2, 2, 2]);
+
+        // args on following lines, call stays at the line of function
+        let proto = try_compile("local a = 1\nprint(a,\n  a + 1)").ok().unwrap();
+        assert_eq!(proto.line_info, vec![1, 2, 2, 3, 2, 2]);
+
+        // implicit return of function is at the line of `end`
+        let proto = try_compile("local f = function()\n  return\nend")
+            .ok()
+            .unwrap();
+        assert_eq!(proto.protos[0].line_info, vec![2, 3]);
+    }
+
+    #[test]
+    fn local_scope() {
+        let proto = try_compile("local a = 1\ndo local b = a end\nlocal c = b")
+            .ok()
+            .unwrap();
+        let ranges: Vec<(u32, Option<u32>)> = proto
+            .local_vars
+            .iter()
+            .map(|local| (local.start_pc, local.end_pc))
+            .collect();
+        assert_eq!(ranges, vec![(1, Some(4)), (2, Some(2)), (3, Some(4))]);
+        // `b` is out of scope, so it's a global
+        assert_eq!(proto.code[2].get_op(), OpCode::GetTabUp);
+
+        // the innermost `a` shadows the outer one
+        let proto = try_compile("local a = 1; do local a = 2; local b = a end")
+            .ok()
+            .unwrap();
+        assert_eq!(proto.code[2].get_op(), OpCode::Move);
+        assert_eq!(proto.code[2].get_arg_B(), 1);
+    }
+
+    #[test]
+    fn local_shadowing() {
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | x          |
+| 1     | x          |
+| 2     | y          |
+| 3     | z          |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2
+3     [1]    MOVE      2 1
+4     [1]    MOVE      1 0
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(
+            try_compile_and_print("local x = 1; do local x = 2; local y = x end; local z = x"),
+            expected
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "too many local variables / registers at line [251]")]
+    fn too_many_registers() {
+        let input: Vec<String> = (0..300).map(|i| format!("local a{} = {}", i, i)).collect();
+        try_compile_and_print(&input.join("\n"));
+    }
+
+    #[test]
+    fn load_const_extra_arg() {
+        // 2^18 consts can't be addressed by Bx of LoadK
+        let items: Vec<String> = (0..=(1 << 18)).map(|i| i.to_string()).collect();
+        let proto = try_compile(&format!("local t = {{{}}}", items.join(",")))
+            .ok()
+            .unwrap();
+        let pc = proto
+            .code
+            .iter()
+            .position(|i| i.get_op() == OpCode::LoadKx)
+            .unwrap();
+        assert_eq!(proto.code[pc - 1].get_arg_Bx(), (1 << 18) - 1);
+        assert_eq!(proto.code[pc + 1].get_op(), OpCode::ExtraArg);
+        assert_eq!(proto.code[pc + 1].get_arg_Ax(), 1 << 18);
+        // so are batches of SETLIST over 511
+        let pc = proto
+            .code
+            .iter()
+            .position(|i| i.get_op() == OpCode::SetList && i.get_arg_C() == 0)
+            .unwrap();
+        assert_eq!(proto.code[pc - 1].get_arg_A(), 50);
+        assert_eq!(proto.code[pc + 1].get_op(), OpCode::ExtraArg);
+        assert_eq!(proto.code[pc + 1].get_arg_Ax(), 512);
+    }
+
+    // array items are flushed every 50 items, so registers in use are bounded
+    #[test]
+    fn set_list_batches() {
+        let set_lists = |proto: &Proto| -> Vec<(u32, u32, u32)> {
+            proto
+                .code
+                .iter()
+                .filter(|i| i.get_op() == OpCode::SetList)
+                .map(|i| (i.get_arg_A(), i.get_arg_B(), i.get_arg_C()))
+                .collect()
+        };
+        let items: Vec<String> = (0..120).map(|i| i.to_string()).collect();
+        let proto = try_compile(&format!("local t = {{{}}}", items.join(",")))
+            .ok()
+            .unwrap();
+        assert_eq!(set_lists(&proto), vec![(0, 50, 1), (0, 50, 2), (0, 20, 3)]);
+        assert_eq!(proto.stack_size, 51);
+
+        // hash items don't count, values of the last call are all stored
+        let input = format!(
+            "local t = {{x = 1, {}, y = 2, f()}}",
+            items[..100].join(",")
+        );
+        let proto = try_compile(&input).ok().unwrap();
+        assert_eq!(set_lists(&proto), vec![(0, 50, 1), (0, 50, 2), (0, 0, 3)]);
+        assert_eq!(proto.stack_size, 51);
+    }
+
+    #[test]
+    fn const_out_of_rk() {
+        // consts after the first 256 are loaded to registers before used as operands
+        let items: Vec<String> = (0..256).map(|i| i.to_string()).collect();
+        let input = format!(
+            "local t = {{{}}}\nlocal a = t + 256\nt.key = a",
+            items.join(",")
+        );
+        let output = try_compile_and_print(&input);
+        let lines: Vec<&str> = output.lines().collect();
+        let expected = r#"
+264   [2]    LOADK     2 -257      ; 256
+265   [2]    ADD       1 0 2
+266   [3]    LOADK     2 -258      ; "key"
+267   [3]    SETTABLE  0 2 1
+268   [3]    RETURN    0 1
+"#;
+        assert_eq!(lines[lines.len() - 5..].join("\n"), expected.trim());
+    }
+
+    #[test]
+    fn peephole_merge_move() {
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "x"        |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    MOVE      1 0
+3     [1]    LOADK     2 -1        ; "x"
+4     [1]    CONCAT    0 1 2
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(try_compile_and_print("local a; a = a .. 'x'"), expected);
+    }
+
+    #[test]
+    fn peephole_self_move() {
+        let mut proto = Proto::default();
+        let jmp = proto.code_jmp(0, 0);
+        proto.code_move(0, 0);
+        proto.code_const(0, 0);
+        proto.fix_jump_pos(3, jmp);
+        proto.close();
+        let ops: Vec<OpCode> = proto.code.iter().map(|i| i.get_op()).collect();
+        assert_eq!(ops, vec![OpCode::Jmp, OpCode::LoadK, OpCode::Return]);
+        // jump still goes to the instruction after LoadK
+        assert_eq!(proto.code[0].get_arg_sBx(), 1);
+    }
+
+    #[test]
+    fn disassemble_function() {
+        let proto = try_compile(
+            "local t = {}\nlocal function f(a)\n  if a > 0 then\n    t.n = a + 1.5\n  end\n  return g\nend",
+        )
+        .ok()
+        .unwrap();
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | 0          |
+| 1     | "n"        |
+| 2     | 1.5        |
+| 3     | "g"        |
+locals :
+| 0     | a          |
+upvalues :
+| 0     | t          | 1     | 0     |
+| 1     | _ENV       | 0     | 0     |
+instructions :
+1     [3]    LT        0 -1 0      ; 0 -
+2     [3]    JMP       0 3         ; to 6
+3     [4]    GETUPVAL  1 0         ; t
+4     [4]    ADD       2 0 -3      ; - 1.5
+5     [4]    SETTABLE  1 -2 2      ; "n" -
+6     [6]    GETTABUP  1 1 -4      ; _ENV "g"
+7     [6]    RETURN    1 2
+8     [7]    RETURN    0 1
+"#;
+        assert_eq!(format!("{:?}", proto.protos[0]), expected);
+    }
+
+    #[test]
+    fn dense_line_info() {
+        let input = "local a = 1\n\n-- comment\nlocal b = 2\n\n\nlocal c = a + b\n";
+        let tokens = Lexer::default().run(input).unwrap();
+        let block = Parser::default().run(tokens).unwrap();
+
+        let mut compiler = Compiler::default();
+        let proto = compiler.run(&block).ok().unwrap();
+        assert_eq!(proto.line_info.len(), proto.code.len());
+        assert!(!proto.line_info.contains(&2));
+
+        let mut compiler = Compiler::default();
+        compiler.dense_line_info = true;
+        let proto = compiler.run(&block).ok().unwrap();
+        assert_eq!(proto.line_info.len(), proto.code.len());
+        for line in 1..=7 {
+            assert!(
+                proto.line_info.contains(&line),
+                "line {} is not mapped",
+                line
+            );
+        }
+        assert!(proto.line_info.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn strip() {
+        let mut proto = try_compile("local a, b = 1, 'str'\nlocal c = a + b")
+            .ok()
+            .unwrap();
+        let code: Vec<String> = proto.code.iter().map(|i| format!("{:?}", i)).collect();
+        let consts = proto.consts.clone();
+        let local_count = proto.local_vars.len();
+        assert!(!proto.line_info.is_empty());
+
+        proto.strip();
+        assert!(proto.line_info.is_empty());
+        assert_eq!(proto.local_vars.len(), local_count);
+        assert_eq!(proto.get_local_var("a"), None);
+        assert_eq!(
+            proto
+                .code
+                .iter()
+                .map(|i| format!("{:?}", i))
+                .collect::<Vec<_>>(),
+            code
+        );
+        assert_eq!(proto.consts, consts);
+    }
+
+    #[test]
+    fn strip_verify_and_dump() {
+        let mut proto =
+            try_compile("local a = 1\nlocal function f(b) return a + b end\nprint(f(2))")
+                .ok()
+                .unwrap();
+        let dump = |proto: &Proto| {
+            let mut chunk = Vec::new();
+            proto.dump(&mut chunk).unwrap();
+            chunk
+        };
+        let code: Vec<u8> = proto
+            .code
+            .iter()
+            .flat_map(|i| i.raw().to_le_bytes())
+            .collect();
+        let find_code = |chunk: &[u8]| chunk.windows(code.len()).position(|w| w == code);
+        let chunk = dump(&proto);
+
+        proto.strip();
+        assert_eq!(proto.verify(), Ok(()));
+        assert!(proto.protos()[0].line_info.is_empty());
+        let stripped = dump(&proto);
+        assert!(stripped.len() < chunk.len());
+        // code is dumped before debug info, at the same offset
+        assert!(find_code(&chunk).is_some());
+        assert_eq!(find_code(&stripped), find_code(&chunk));
+    }
+
+    #[test]
+    fn run_append() {
+        let parse = |input: &str| {
+            let tokens = Lexer::default().run(input).unwrap();
+            Parser::default().run(tokens).unwrap()
+        };
+        let mut compiler = Compiler::default();
+        compiler.run_append(&parse("local a = 1")).ok().unwrap();
+        compiler.run_append(&parse("local b = a + 1")).ok().unwrap();
+        let proto = compiler.finish().ok().unwrap();
+        assert_eq!(
+            format!("{:?}", proto),
+            try_compile_and_print("local a = 1 local b = a + 1")
+        );
+    }
+
+    #[test]
+    fn run_append_after_error() {
+        let parse = |input: &str| {
+            let tokens = Lexer::default().run(input).unwrap();
+            Parser::default().run(tokens).unwrap()
+        };
+        let mut compiler = Compiler::default();
+        compiler.run_append(&parse("local a = 1")).ok().unwrap();
+        // compile errors panic in debug builds
+        let failed = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            compiler.run_append(&parse("local b = f(a, function() return 1 // 0 end)"))
+        }));
+        assert!(!matches!(failed, Ok(Ok(()))));
+        compiler.run_append(&parse("local d = a")).ok().unwrap();
+        compiler.run_append(&parse("print(d)")).ok().unwrap();
+        let proto = compiler.finish().ok().unwrap();
+        assert_eq!(proto.verify(), Ok(()));
+        assert_eq!(
+            format!("{:?}", proto),
+            try_compile_and_print("local a = 1 local d = a print(d)")
+        );
+    }
+
+    // shares written bytes with the test after the compiler takes it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn debug_output() {
+        let input = "local function f() return 1 end";
+        let buffer = SharedBuffer::default();
+        let mut compiler = Compiler::default();
+        compiler.set_debug_output(Box::new(buffer.clone()));
+        try_compile_with(input, compiler).ok().unwrap();
+        assert!(buffer.0.lock().unwrap().is_empty());
+
+        let mut compiler = Compiler::default();
+        compiler.set_debug(true);
+        compiler.set_debug_output(Box::new(buffer.clone()));
+        // compilers with a debug output can still be moved to other threads
+        let proto = std::thread::spawn(move || try_compile_with(input, compiler).ok().unwrap())
+            .join()
+            .unwrap();
+        // inner function is closed first
+        let expected = format!("{:?}{:?}", proto.protos[0], proto);
+        let output = std::mem::take(&mut *buffer.0.lock().unwrap());
+        assert_eq!(String::from_utf8(output).unwrap(), expected);
+    }
+
+    #[test]
+    fn max_fold_depth() {
+        let input = format!("local a = 1{}", " + 1".repeat(5));
+        let mut compiler = Compiler::default();
+        compiler.max_fold_depth = Some(5);
+        let proto = try_compile_with(&input, compiler).ok().unwrap();
+        assert_eq!(proto.consts, vec![Const::Int(6)]);
+
+        let proto = try_compile(&format!("local a = 1{}", " + 1".repeat(100)))
+            .ok()
+            .unwrap();
+        assert_eq!(proto.consts, vec![Const::Int(101)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "expression too complex at line [1]")]
+    fn max_fold_depth_exceeded() {
+        let input = format!("local a = 1{}", " + 1".repeat(6));
+        let mut compiler = Compiler::default();
+        compiler.max_fold_depth = Some(5);
+        try_compile_with(&input, compiler).ok().unwrap();
+    }
+
+    // runs on the default stack of tests, codegen of the chain must stop at the limit too
+    #[test]
+    #[should_panic(expected = "expression too complex at line [1]")]
+    fn max_fold_depth_deep_chain() {
+        let input = format!("local x = 1{}", " + 1".repeat(10_000));
+        let mut compiler = Compiler::default();
+        compiler.max_fold_depth = Some(200);
+        try_compile_with(&input, compiler).ok().unwrap();
+    }
+
+    // deep nesting shouldn't overflow stack of the compiler,
+    // dropping the ast still needs a large stack
+    #[test]
+    fn deep_paren_expr() {
+        let compile = || {
+            let tokens = Lexer::default().run("return -(1 + 2)").unwrap();
+            let mut block = Parser::default().run(tokens).unwrap();
+            if let Some(ast::Stat::RetStat(stat)) = block.stats.last_mut() {
+                let exprs = &mut stat.exprs.as_mut().unwrap().exprs;
+                let mut expr = exprs.pop().unwrap();
+                for _ in 0..100_000 {
+                    expr = ast::Expr::ParenExpr(Box::new(expr));
+                }
+                exprs.push(expr);
+            }
+            let mut compiler = Compiler::default();
+            compiler.max_fold_depth = Some(200);
+            let proto = compiler.run(&block).ok().unwrap();
+            assert_eq!(proto.consts, vec![Const::Int(-3)]);
+        };
+        std::thread::Builder::new()
+            .stack_size(64 << 20)
+            .spawn(compile)
+            .unwrap()
+            .join()
+            .unwrap();
+    }
+
+    #[test]
+    fn concat_folding() {
+        let input = "local a = 'a' .. 'b' .. 1 .. 2.5 local b = 1 .. 2 local c = 1 .. true";
+        let mut compiler = Compiler::default();
+        compiler.fold_flags.insert(FoldFlags::CONCAT);
+        let proto = try_compile_with(input, compiler).ok().unwrap();
+        assert_eq!(
+            &proto.consts[..2],
+            &[Const::Str("ab12.5".into()), Const::Str("12".into())]
+        );
+        let ops = proto.code.iter().map(|i| i.get_op());
+        assert_eq!(ops.filter(|op| *op == OpCode::Concat).count(), 1);
+    }
+
+    #[test]
+    fn fold_flags() {
+        let input = "local a = 1 + 2; local b = 'a' .. 'b'; local c = #'abc'; local d = 1 < 2";
+        let mut compiler = Compiler::default();
+        compiler.fold_flags = FoldFlags::ALL;
+        let output = format!("{:?}", try_compile_with(input, compiler).ok().unwrap());
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | 3          |
+| 1     | "ab"       |
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+| 3     | d          |
+instructions :
+1     [1]    LOADK     0 -1        ; 3
+2     [1]    LOADK     1 -2        ; "ab"
+3     [1]    LOADK     2 -1        ; 3
+4     [1]    LOADBOOL  3 1 0
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+
+        let mut compiler = Compiler::default();
+        compiler.fold_flags = FoldFlags::ALL;
+        compiler.fold_flags.remove(FoldFlags::CONCAT);
+        let proto = try_compile_with(input, compiler).ok().unwrap();
+        assert_eq!(proto.consts[0], Const::Int(3));
+        assert!(!proto.consts.contains(&Const::Str("ab".to_string())));
+        assert_eq!(proto.code[0].get_op(), OpCode::LoadK);
+        assert_eq!(proto.code[1].get_op(), OpCode::LoadK);
+        assert_eq!(proto.code[2].get_op(), OpCode::LoadK);
+        assert_eq!(proto.code[3].get_op(), OpCode::Concat);
+        assert_eq!(proto.code[4].get_op(), OpCode::LoadK);
+        assert_eq!(proto.code[5].get_op(), OpCode::LoadBool);
+    }
+
+    #[test]
+    fn un_op_folding() {
+        let input = "local t = {} local a, b, c = #'hello', #t, -(-5) local d = -(-9223372036854775807 - 1)";
+        let mut compiler = Compiler::default();
+        compiler.fold_flags.insert(FoldFlags::LEN);
+        let output = format!("{:?}", try_compile_with(input, compiler).ok().unwrap());
+        let expected = r#"
+stack size : 5
+consts :
+| 0     | 5          |
+| 1     | -9223372036854775808 |
+locals :
+| 0     | t          |
+| 1     | a          |
+| 2     | b          |
+| 3     | c          |
+| 4     | d          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [1]    LOADK     1 -1        ; 5
+3     [1]    LEN       2 0
+4     [1]    LOADK     3 -1        ; 5
+5     [1]    LOADK     4 -2        ; -9223372036854775808
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+
+        // `#` is only folded on demand like luac, consts not folded are loaded to registers
+        let output = try_compile_and_print("local a = #'hello' print(-nil, -'x')");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | "hello"    |
+| 1     | "print"    |
+| 2     | "x"        |
+locals :
+| 0     | a          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADK     0 -1        ; "hello"
+2     [1]    LEN       0 0
+3     [1]    GETTABUP  1 0 -2      ; _ENV "print"
+4     [1]    LOADNIL   2 0
+5     [1]    UNM       2 2
+6     [1]    LOADK     3 -3        ; "x"
+7     [1]    UNM       3 3
+8     [1]    CALL      1 3 1
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn global() {
+        let output = try_compile_and_print("x = 1; local a = x; y = a; x, y = y, x + 1");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "x"        |
+| 1     | 1          |
+| 2     | "y"        |
+locals :
+| 0     | a          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    SETTABUP  0 -1 -2     ; _ENV "x" 1
+2     [1]    GETTABUP  0 0 -1      ; _ENV "x"
+3     [1]    SETTABUP  0 -3 0      ; _ENV "y"
+4     [1]    GETTABUP  1 0 -3      ; _ENV "y"
+5     [1]    GETTABUP  2 0 -1      ; _ENV "x"
+6     [1]    ADD       2 2 -2      ; - 1
+7     [1]    SETTABUP  0 -3 2      ; _ENV "y"
+8     [1]    SETTABUP  0 -1 1      ; _ENV "x"
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn up_value() {
+        let proto = try_compile(
+            "local n = 1
+            local f = function(a)
+                local g = function() n = a + x end
+                local m = n
+            end",
+        )
+        .ok()
+        .unwrap();
+        let f = &proto.protos[0];
+        let g = &f.protos[0];
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | a          |
+| 1     | g          |
+| 2     | m          |
+upvalues :
+| 0     | n          | 1     | 0     |
+| 1     | _ENV       | 0     | 0     |
+instructions :
+1     [3]    CLOSURE   1 0
+2     [4]    GETUPVAL  2 0         ; n
+3     [5]    RETURN    0 1
+
+stack size : 2
+consts :
+| 0     | "x"        |
+locals :
+upvalues :
+| 0     | n          | 0     | 0     |
+| 1     | a          | 1     | 0     |
+| 2     | _ENV       | 0     | 1     |
+instructions :
+1     [3]    GETUPVAL  0 1         ; a
+2     [3]    GETTABUP  1 2 -1      ; _ENV "x"
+3     [3]    ADD       0 0 1
+4     [3]    SETUPVAL  0 0         ; n
+5     [3]    RETURN    0 1
+"#;
+        assert_eq!(format!("{:?}{:?}", f, g), expected);
+    }
+
+    #[test]
+    fn table_empty() {
+        let output = try_compile_and_print("local t = {}");
+        let expected = r#"
+stack size : 2
+consts :
+locals :
+| 0     | t          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn table_array() {
+        let output = try_compile_and_print("local a; local t = {1, 2, a}");
+        let expected = r#"
+stack size : 5
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | a          |
+| 1     | t          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    NEWTABLE  1 3 0
+3     [1]    LOADK     2 -1        ; 1
+4     [1]    LOADK     3 -2        ; 2
+5     [1]    MOVE      4 0
+6     [1]    SETLIST   1 3 1       ; 1
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn table_mixed() {
+        let output = try_compile_and_print("local a; a = {1, x = 2, [a] = 'b', 3}");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | 1          |
+| 1     | "x"        |
+| 2     | 2          |
+| 3     | "b"        |
+| 4     | 3          |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    NEWTABLE  1 2 2
+3     [1]    LOADK     2 -1        ; 1
+4     [1]    SETTABLE  1 -2 -3     ; "x" 2
+5     [1]    SETTABLE  1 0 -4      ; - "b"
+6     [1]    LOADK     3 -5        ; 3
+7     [1]    SETLIST   1 2 1       ; 1
+8     [1]    MOVE      0 1
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn table_keyed() {
+        let output = try_compile_and_print("t = {x = 1, y = {}}");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "t"        |
+| 1     | "x"        |
+| 2     | 1          |
+| 3     | "y"        |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    NEWTABLE  0 0 2
+2     [1]    SETTABLE  0 -2 -3     ; "x" 1
+3     [1]    NEWTABLE  1 0 0
+4     [1]    SETTABLE  0 -4 1      ; "y" -
+5     [1]    SETTABUP  0 -1 0      ; _ENV "t"
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn table_flush() {
+        let items = vec!["1"; 51].join(", ");
+        let proto = try_compile(&format!("local t = {{{}}}", items))
+            .ok()
+            .unwrap();
+        assert_eq!(proto.stack_size, 51);
+    }
+
+    #[test]
+    fn call() {
+        let output = try_compile_and_print("f()");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "f"        |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "f"
+2     [1]    CALL      0 1 1
+3     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn call_with_args() {
+        let output = try_compile_and_print("local a = 1; f(1, a)");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | 1          |
+| 1     | "f"        |
+locals :
+| 0     | a          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    GETTABUP  1 0 -2      ; _ENV "f"
+3     [1]    LOADK     2 -1        ; 1
+4     [1]    MOVE      3 0
+5     [1]    CALL      1 3 1
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn call_multret_arg() {
+        let output = try_compile_and_print("f(g())");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "f"        |
+| 1     | "g"        |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "f"
+2     [1]    GETTABUP  1 0 -2      ; _ENV "g"
+3     [1]    CALL      1 1 0
+4     [1]    CALL      0 0 1
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn call_result() {
+        let output = try_compile_and_print("local a = f{1}('s') + 1");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "f"        |
+| 1     | 1          |
+| 2     | "s"        |
+locals :
+| 0     | a          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "f"
+2     [1]    NEWTABLE  1 1 0
+3     [1]    LOADK     2 -2        ; 1
+4     [1]    SETLIST   1 1 1       ; 1
+5     [1]    CALL      0 2 2
+6     [1]    LOADK     1 -3        ; "s"
+7     [1]    CALL      0 2 2
+8     [1]    ADD       0 0 -2      ; - 1
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn if_else() {
+        let output = try_compile_and_print("local a, b; if a < b then a = 1 else b = 2 end");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    LT        0 0 1
+3     [1]    JMP       0 2         ; to 6
+4     [1]    LOADK     0 -1        ; 1
+5     [1]    JMP       0 1         ; to 7
+6     [1]    LOADK     1 -2        ; 2
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn if_elseif() {
+        let output = try_compile_and_print(
+            "local a; if a then a = 1 elseif a == 2 then a = 3 elseif false then a = 4 end",
+        );
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 1          |
+| 1     | 2          |
+| 2     | 3          |
+| 3     | 4          |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    TEST      0 0
+3     [1]    JMP       0 2         ; to 6
+4     [1]    LOADK     0 -1        ; 1
+5     [1]    JMP       0 6         ; to 12
+6     [1]    EQ        0 0 -2      ; - 2
+7     [1]    JMP       0 2         ; to 10
+8     [1]    LOADK     0 -3        ; 3
+9     [1]    JMP       0 2         ; to 12
+10    [1]    JMP       0 1         ; to 12
+11    [1]    LOADK     0 -4        ; 4
+12    [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn for_num() {
+        let output = try_compile_and_print("local a = 0; for i = 1, 10 do a = a + i end");
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | 0          |
+| 1     | 1          |
+| 2     | 10         |
+locals :
+| 0     | a          |
+| 1     | (for index) |
+| 2     | (for limit) |
+| 3     | (for step) |
+| 4     | i          |
+instructions :
+1     [1]    LOADK     0 -1        ; 0
+2     [1]    LOADK     1 -2        ; 1
+3     [1]    LOADK     2 -3        ; 10
+4     [1]    LOADK     3 -2        ; 1
+5     [1]    FORPREP   1 1         ; to 7
+6     [1]    ADD       0 0 4
+7     [1]    FORLOOP   1 -2        ; to 6
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn for_num_step() {
+        let output = try_compile_and_print("local a, b = 10, -1; for i = a, a * 2, b do end");
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | 10         |
+| 1     | -1         |
+| 2     | 2          |
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | (for index) |
+| 3     | (for limit) |
+| 4     | (for step) |
+| 5     | i          |
+instructions :
+1     [1]    LOADK     0 -1        ; 10
+2     [1]    LOADK     1 -2        ; -1
+3     [1]    MOVE      2 0
+4     [1]    MUL       3 0 -3      ; - 2
+5     [1]    MOVE      4 1
+6     [1]    FORPREP   2 0         ; to 7
+7     [1]    FORLOOP   2 -1        ; to 7
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn for_in() {
+        let output = try_compile_and_print("local t; for k, v in pairs(t) do t = v end");
+        let expected = r#"
+stack size : 7
+consts :
+| 0     | "pairs"    |
+locals :
+| 0     | t          |
+| 1     | (for generator) |
+| 2     | (for state) |
+| 3     | (for control) |
+| 4     | k          |
+| 5     | v          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    GETTABUP  1 0 -1      ; _ENV "pairs"
+3     [1]    MOVE      2 0
+4     [1]    CALL      1 2 4
+5     [1]    JMP       0 1         ; to 7
+6     [1]    MOVE      0 5
+7     [1]    TFORCALL  1 2
+8     [1]    TFORLOOP  3 -3        ; to 6
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn for_in_exprs() {
+        let output = try_compile_and_print("for k in next, {} do end");
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | "next"     |
+locals :
+| 0     | (for generator) |
+| 1     | (for state) |
+| 2     | (for control) |
+| 3     | k          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "next"
+2     [1]    NEWTABLE  1 0 0
+3     [1]    LOADNIL   2 0
+4     [1]    JMP       0 0         ; to 5
+5     [1]    TFORCALL  0 1
+6     [1]    TFORLOOP  2 -2        ; to 5
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn while_loop() {
+        let output = try_compile_and_print("local a = 0; while a < 10 do a = a + 1 end");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 0          |
+| 1     | 10         |
+| 2     | 1          |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADK     0 -1        ; 0
+2     [1]    LT        0 0 -2      ; - 10
+3     [1]    JMP       0 2         ; to 6
+4     [1]    ADD       0 0 -3      ; - 1
+5     [1]    JMP       0 -4        ; to 2
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn repeat_loop() {
+        let output =
+            try_compile_and_print("local a = 0; repeat local b = a; a = a + 1 until b > 10");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | 0          |
+| 1     | 1          |
+| 2     | 10         |
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    LOADK     0 -1        ; 0
+2     [1]    MOVE      1 0
+3     [1]    ADD       0 0 -2      ; - 1
+4     [1]    LT        0 -3 1      ; 10 -
+5     [1]    JMP       0 -4        ; to 2
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn break_loop() {
+        let output = try_compile_and_print(
+            "local a = 0; while true do a = a + 1; if a > 10 then break end end",
+        );
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 0          |
+| 1     | 1          |
+| 2     | 10         |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADK     0 -1        ; 0
+2     [1]    ADD       0 0 -2      ; - 1
+3     [1]    LT        1 -3 0      ; 10 -
+4     [1]    JMP       0 1         ; to 6
+5     [1]    JMP       0 -4        ; to 2
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    // jumps leaving the scope of captured locals close them
+    #[test]
+    fn break_close_upvalues() {
+        let output = try_compile_and_print(
+            "for i = 1, 3 do local x = i f = function() return x end if x then break end end",
+        );
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | 1          |
+| 1     | 3          |
+| 2     | "f"        |
+locals :
+| 0     | (for index) |
+| 1     | (for limit) |
+| 2     | (for step) |
+| 3     | i          |
+| 4     | x          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 3
+3     [1]    LOADK     2 -1        ; 1
+4     [1]    FORPREP   0 6         ; to 11
+5     [1]    MOVE      4 3
+6     [1]    CLOSURE   5 0
+7     [1]    SETTABUP  0 -3 5      ; _ENV "f"
+8     [1]    TEST      4 1
+9     [1]    JMP       5 2         ; to 12
+10    [1]    JMP       5 0         ; to 11
+11    [1]    FORLOOP   0 -7        ; to 5
+12    [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+
+        let output = try_compile_and_print("::top:: local x f = function() return x end goto top");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "f"        |
+locals :
+| 0     | x          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    CLOSURE   1 0
+3     [1]    SETTABUP  0 -1 1      ; _ENV "f"
+4     [1]    JMP       1 -4        ; to 1
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn goto_forward() {
+        let output = try_compile_and_print("local a = 1; if a then goto done end; a = 2; ::done::");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    TEST      0 1
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADK     0 -2        ; 2
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn goto_continue() {
+        let output = try_compile_and_print(
+            "for i = 1, 3 do local a = i; if a then goto continue end; a = 2; ::continue:: end",
+        );
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | 1          |
+| 1     | 3          |
+| 2     | 2          |
+locals :
+| 0     | (for index) |
+| 1     | (for limit) |
+| 2     | (for step) |
+| 3     | i          |
+| 4     | a          |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 3
+3     [1]    LOADK     2 -1        ; 1
+4     [1]    FORPREP   0 4         ; to 9
+5     [1]    MOVE      4 3
+6     [1]    TEST      4 1
+7     [1]    JMP       0 1         ; to 9
+8     [1]    LOADK     4 -3        ; 2
+9     [1]    FORLOOP   0 -5        ; to 5
+10    [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    // the cond jumps straight to the label before the back edge of the loop
+    #[test]
+    fn goto_continue_while() {
+        let proto = try_compile(
+            "local i = 0
+            while i < 10 do
+                i = i + 1
+                if i % 2 == 0 then goto continue end
+                print(i)
+                ::continue::
+            end",
+        )
+        .ok()
+        .unwrap();
+        let output = format!("{:?}", proto);
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | 0          |
+| 1     | 10         |
+| 2     | 1          |
+| 3     | 2          |
+| 4     | "print"    |
+locals :
+| 0     | i          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADK     0 -1        ; 0
+2     [2]    LT        0 0 -2      ; - 10
+3     [2]    JMP       0 8         ; to 12
+4     [3]    ADD       0 0 -3      ; - 1
+5     [4]    MOD       1 0 -4      ; - 2
+6     [4]    EQ        1 1 -1      ; - 0
+7     [4]    JMP       0 3         ; to 11
+8     [5]    GETTABUP  1 0 -5      ; _ENV "print"
+9     [5]    MOVE      2 0
+10    [5]    CALL      1 2 1
+11    [6]    JMP       0 -10       ; to 2
+12    [6]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+        let eq = proto
+            .code
+            .iter()
+            .position(|i| i.get_op() == OpCode::Eq)
+            .unwrap();
+        let target = (eq + 2) as i32 + proto.code[eq + 1].get_arg_sBx();
+        let back_edge = &proto.code[target as usize];
+        assert_eq!(back_edge.get_op(), OpCode::Jmp);
+        assert!(back_edge.get_arg_sBx() < 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "no visible label 'done' for goto")]
+    fn goto_undefined_label() {
+        try_compile_and_print("goto done");
+    }
+
+    #[test]
+    #[should_panic(expected = "jumps into the scope of local 'a'")]
+    fn goto_into_local_scope() {
+        try_compile_and_print("goto done; local a; ::done:: a = 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "label 'done' already defined on line 1")]
+    fn goto_duplicate_label() {
+        try_compile_and_print("::done:: local a = 1\n::done::");
+    }
+
+    // a label can shadow the one of an enclosing block, gotos jump to the innermost one
+    #[test]
+    fn goto_shadowed_label() {
+        let output = try_compile_and_print("::a:: print() do print() ::a:: goto a end goto a");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "print"    |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "print"
+2     [1]    CALL      0 1 1
+3     [1]    GETTABUP  0 0 -1      ; _ENV "print"
+4     [1]    CALL      0 1 1
+5     [1]    JMP       0 -1        ; to 5
+6     [1]    JMP       0 -6        ; to 1
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to assign to const variable 'x'")]
+    fn assign_const_local() {
+        try_compile_and_print("local x <const> = 1; x = 2");
+    }
+
+    #[test]
+    #[should_panic(expected = "attempt to assign to const variable 'x'")]
+    fn assign_const_upvalue() {
+        try_compile_and_print("local x <const> = nil; local function f() local y; y, x = 1, 2 end");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "to-be-closed variables are not supported by Lua 5.3 bytecode at line [1]"
+    )]
+    fn close_local() {
+        try_compile_and_print("local x <close> = nil");
+    }
+
+    #[test]
+    fn assign_shadowed_const_local() {
+        let proto = try_compile("local x <const> = 1; do local x = 2; x = 3 end")
+            .ok()
+            .unwrap();
+        assert!(proto.local_vars[0].read_only);
+        assert!(!proto.local_vars[1].read_only);
+    }
+
+    #[test]
+    fn function_stat() {
+        let output =
+            try_compile_and_print("function f() end local g function g() end function t.f() end");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "f"        |
+| 1     | "t"        |
+locals :
+| 0     | g          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    CLOSURE   0 0
+2     [1]    SETTABUP  0 -1 0      ; _ENV "f"
+3     [1]    LOADNIL   0 0
+4     [1]    CLOSURE   0 1
+5     [1]    GETTABUP  1 0 -2      ; _ENV "t"
+6     [1]    CLOSURE   2 2
+7     [1]    SETTABLE  1 -1 2      ; "f" -
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn method_stat() {
+        let output = try_compile_and_print("local t = {} function t:m(a) return self, a end");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "m"        |
+locals :
+| 0     | t          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [1]    CLOSURE   1 0
+3     [1]    SETTABLE  0 -1 1      ; "m" -
+4     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+        let proto = try_compile("local t = {} function t:m(a) return self, a end")
+            .ok()
+            .unwrap();
+        let method = &proto.protos[0];
+        assert_eq!(method.param_count, 2);
+        assert!(format!("{:?}", method)
+            .contains("locals :\n| 0     | self       |\n| 1     | a          |"));
+    }
+
+    #[test]
+    fn deep_function_name() {
+        let output = try_compile_and_print("local x function a.b.c.d() return x end");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "a"        |
+| 1     | "b"        |
+| 2     | "c"        |
+| 3     | "d"        |
+locals :
+| 0     | x          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    GETTABUP  1 0 -1      ; _ENV "a"
+3     [1]    GETTABLE  1 1 -2      ; "b"
+4     [1]    GETTABLE  1 1 -3      ; "c"
+5     [1]    CLOSURE   2 0
+6     [1]    SETTABLE  1 -4 2      ; "d" -
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    // errors are located at the expr causing them, not the stat
+    #[test]
+    #[should_panic(expected = "[compile error] number has no integer representation at line [3].")]
+    fn error_source_of_folding() {
+        try_compile_and_print("local a = 1\nlocal b = a +\n(1.5 | 1)");
+    }
+
+    #[test]
+    #[should_panic(
+        expected = "[compile error] cannot use '...' outside a vararg function at line [2]."
+    )]
+    fn error_source_of_expr() {
+        try_compile_and_print("local function f() return 1,\n... end");
+    }
+
+    #[test]
+    fn error_source() {
+        let e = CompileError::new("error");
+        assert_eq!(e.to_string(), "error");
+        let source = Source {
+            line: 2,
+            col: 3,
+            length: 4,
+            offset: 5,
+        };
+        let e = e.with_source(&source);
+        assert_eq!(e.source, Some(source));
+        assert_eq!(e.to_string(), "[compile error] error at line [2].");
+        // the innermost source is kept
+        let e = e.with_source(&Source::default());
+        assert_eq!(e.source, Some(source));
+    }
+
+    #[test]
+    fn return_none() {
+        let output = try_compile_and_print("return");
+        let expected = r#"
+stack size : 2
+consts :
+locals :
+instructions :
+1     [1]    RETURN    0 1
+2     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn return_values() {
+        let output = try_compile_and_print("local a = 1; return a, 2");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | a          |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    MOVE      1 0
+3     [1]    LOADK     2 -2        ; 2
+4     [1]    RETURN    1 3
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn return_local() {
+        let output = try_compile_and_print("local a, b = 1, 2; return b");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 1          |
+| 1     | 2          |
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    LOADK     1 -2        ; 2
+3     [1]    RETURN    1 2
+4     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn return_tail_call() {
+        let output = try_compile_and_print("return f(1)");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "f"        |
+| 1     | 1          |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "f"
+2     [1]    LOADK     1 -2        ; 1
+3     [1]    TAILCALL  0 2 0
+4     [1]    RETURN    0 0
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn return_multret() {
+        let output = try_compile_and_print("return 1, f()");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | 1          |
+| 1     | "f"        |
+locals :
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADK     0 -1        ; 1
+2     [1]    GETTABUP  1 0 -2      ; _ENV "f"
+3     [1]    CALL      1 1 0
+4     [1]    RETURN    0 0
+5     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn local_multret_expand() {
+        let proto = try_compile("local a, b, c = f()").ok().unwrap();
+        assert_eq!(proto.code[1].get_op(), OpCode::Call);
+        assert_eq!(proto.code[1].get_arg_C(), 4);
+        assert!(proto.code.iter().all(|i| i.get_op() != OpCode::LoadNil));
+        assert_eq!(proto.stack_size, 3);
+    }
+
+    #[test]
+    fn local_multret_truncate() {
+        let proto = try_compile("local a = f()").ok().unwrap();
+        assert_eq!(proto.code[1].get_op(), OpCode::Call);
+        assert_eq!(proto.code[1].get_arg_C(), 2);
+
+        let proto = try_compile("local a = 1, f()").ok().unwrap();
+        assert_eq!(proto.code[2].get_op(), OpCode::Call);
+        assert_eq!(proto.code[2].get_arg_C(), 1);
+    }
+
+    #[test]
+    fn assign_multret() {
+        let output = try_compile_and_print("local a, b, c; a, b, c = 1, f()");
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | 1          |
+| 1     | "f"        |
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 2
+2     [1]    LOADK     3 -1        ; 1
+3     [1]    GETTABUP  4 0 -2      ; _ENV "f"
+4     [1]    CALL      4 1 3
+5     [1]    MOVE      2 5
+6     [1]    MOVE      1 4
+7     [1]    MOVE      0 3
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn index_read() {
+        let output = try_compile_and_print("local t, k; local a = t.x; local b = t.x.y[k]");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | "x"        |
+| 1     | "y"        |
+locals :
+| 0     | t          |
+| 1     | k          |
+| 2     | a          |
+| 3     | b          |
+instructions :
+1     [1]    LOADNIL   0 1
+2     [1]    GETTABLE  2 0 -1      ; "x"
+3     [1]    GETTABLE  3 0 -1      ; "x"
+4     [1]    GETTABLE  3 3 -2      ; "y"
+5     [1]    GETTABLE  3 3 1
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn index_write() {
+        let output = try_compile_and_print("local t; t.x = 1; a.b[t] = t.x");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "x"        |
+| 1     | 1          |
+| 2     | "a"        |
+| 3     | "b"        |
+locals :
+| 0     | t          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    SETTABLE  0 -1 -2     ; "x" 1
+3     [1]    GETTABUP  1 0 -3      ; _ENV "a"
+4     [1]    GETTABLE  1 1 -4      ; "b"
+5     [1]    GETTABLE  2 0 -1      ; "x"
+6     [1]    SETTABLE  1 0 2
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn method_call() {
+        let output = try_compile_and_print("local obj; obj:m(1); local a = t.x:m()");
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | "m"        |
+| 1     | 1          |
+| 2     | "t"        |
+| 3     | "x"        |
+locals :
+| 0     | obj        |
+| 1     | a          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    SELF      1 0 -1      ; "m"
+3     [1]    LOADK     3 -2        ; 1
+4     [1]    CALL      1 3 1
+5     [1]    GETTABUP  1 0 -3      ; _ENV "t"
+6     [1]    GETTABLE  1 1 -4      ; "x"
+7     [1]    SELF      1 1 -1      ; "m"
+8     [1]    CALL      1 2 2
+9     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn paren_adjust_to_one() {
+        let output = try_compile_and_print("local a, b = (f()); return (f())");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | "f"        |
+locals :
+| 0     | a          |
+| 1     | b          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    GETTABUP  0 0 -1      ; _ENV "f"
+2     [1]    CALL      0 1 2
+3     [1]    LOADNIL   1 0
+4     [1]    GETTABUP  2 0 -1      ; _ENV "f"
+5     [1]    CALL      2 1 2
+6     [1]    RETURN    2 2
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot assign to a parenthesized expression")]
+    fn paren_assign() {
+        try_compile_and_print("local x; (x) = 1");
+    }
+
+    #[test]
+    fn local_function() {
+        let proto = try_compile(
+            "local function f(n) return f(n - 1) end
+            local g = function(n) return g(n - 1) end",
+        )
+        .ok()
+        .unwrap();
+        let output = format!("{:?}{:?}{:?}", proto, proto.protos[0], proto.protos[1]);
+        let expected = r#"
+stack size : 2
+consts :
+locals :
+| 0     | f          |
+| 1     | g          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    CLOSURE   0 0
+2     [2]    CLOSURE   1 1
+3     [2]    RETURN    0 1
+
+stack size : 3
+consts :
+| 0     | 1          |
+locals :
+| 0     | n          |
+upvalues :
+| 0     | f          | 1     | 0     |
+instructions :
+1     [1]    GETUPVAL  1 0         ; f
+2     [1]    SUB       2 0 -1      ; - 1
+3     [1]    TAILCALL  1 2 0
+4     [1]    RETURN    1 0
+5     [1]    RETURN    0 1
+
+stack size : 3
+consts :
+| 0     | "g"        |
+| 1     | 1          |
+locals :
+| 0     | n          |
+upvalues :
+| 0     | _ENV       | 0     | 0     |
+instructions :
+1     [2]    GETTABUP  1 0 -1      ; _ENV "g"
+2     [2]    SUB       2 0 -2      ; - 1
+3     [2]    TAILCALL  1 2 0
+4     [2]    RETURN    1 0
+5     [2]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn nested_function() {
+        let proto = try_compile(
+            "local x = 1
+            f = function(a) return a end
+            g = function() return function() return x end end",
+        )
+        .ok()
+        .unwrap();
+        assert_eq!(proto.protos.len(), 2);
+        let closures = proto
+            .code
+            .iter()
+            .filter(|i| i.get_op() == OpCode::Closure)
+            .count();
+        assert_eq!(closures, 2);
+
+        let f = &proto.protos[0];
+        assert_eq!(f.param_count, 1);
+        assert!(f.protos.is_empty());
+
+        // x is bound through the upvalue of g
+        let g = &proto.protos[1];
+        let inner = &g.protos[0];
+        let output = format!("{:?}{:?}", g, inner);
+        let expected = r#"
+stack size : 2
+consts :
+locals :
+upvalues :
+| 0     | x          | 1     | 0     |
+instructions :
+1     [3]    CLOSURE   0 0
+2     [3]    RETURN    0 2
+3     [3]    RETURN    0 1
+
+stack size : 2
+consts :
+locals :
+upvalues :
+| 0     | x          | 0     | 0     |
+instructions :
+1     [3]    GETUPVAL  0 0         ; x
+2     [3]    RETURN    0 2
+3     [3]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    // each iteration gets its own `j`, the loop body closes it before looping back
+    #[test]
+    fn close_upvalues_of_loop() {
+        let proto = try_compile(
+            "local fns = {}
+            for i = 1, 3 do local j = i fns[i] = function() return j end end",
+        )
+        .ok()
+        .unwrap();
+        let output = format!("{:?}", proto);
+        let expected = r#"
+stack size : 7
+consts :
+| 0     | 1          |
+| 1     | 3          |
+locals :
+| 0     | fns        |
+| 1     | (for index) |
+| 2     | (for limit) |
+| 3     | (for step) |
+| 4     | i          |
+| 5     | j          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [2]    LOADK     1 -1        ; 1
+3     [2]    LOADK     2 -2        ; 3
+4     [2]    LOADK     3 -1        ; 1
+5     [2]    FORPREP   1 4         ; to 10
+6     [2]    MOVE      5 4
+7     [2]    CLOSURE   6 0
+8     [2]    SETTABLE  0 4 6
+9     [2]    JMP       6 0         ; to 10
+10    [2]    FORLOOP   1 -5        ; to 6
+11    [2]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+        let forloop = proto
+            .code
+            .iter()
+            .position(|i| i.get_op() == OpCode::ForLoop)
+            .unwrap();
+        let close = &proto.code[forloop - 1];
+        assert_eq!(close.get_op(), OpCode::Jmp);
+        assert_eq!((close.get_arg_A(), close.get_arg_sBx()), (6, 0));
+
+        let proto = try_compile("repeat local x f = function() return x end until x")
+            .ok()
+            .unwrap();
+        let output = format!("{:?}", proto);
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "f"        |
+locals :
+| 0     | x          |
+upvalues :
+| 0     | _ENV       | 1     | 0     |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    CLOSURE   1 0
+3     [1]    SETTABUP  0 -1 1      ; _ENV "f"
+4     [1]    TEST      0 0
+5     [1]    JMP       1 -5        ; to 1
+6     [1]    JMP       1 0         ; to 7
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn vararg() {
+        let proto = try_compile("local a, b = ...; return ...").ok().unwrap();
+        assert!(proto.is_vararg);
+        let output = format!("{:?}", proto);
+        let expected = r#"
+stack size : 3
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    VARARG    0 3
+2     [1]    VARARG    2 0
+3     [1]    RETURN    2 0
+4     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn vararg_function() {
+        let proto =
+            try_compile("local f = function(a, ...) local b = ... end; local g = function() end")
+                .ok()
+                .unwrap();
+        assert!(proto.protos[0].is_vararg);
+        assert_eq!(proto.protos[0].param_count, 1);
+        assert!(!proto.protos[1].is_vararg);
+        let output = format!("{:?}", proto.protos[0]);
+        let expected = r#"
+stack size : 2
+consts :
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    VARARG    1 2
+2     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot use '...' outside a vararg function")]
+    fn vararg_outside_vararg_function() {
+        try_compile_and_print("local f = function(a) return ... end");
+    }
+
+    #[test]
+    fn concat() {
+        let input = "local x; local s = 'a' .. 'b' .. x .. 1 .. 2";
+        let output = try_compile_and_print(input);
+        let expected = r#"
+stack size : 6
+consts :
+| 0     | "a"        |
+| 1     | "b"        |
+| 2     | 1          |
+| 3     | 2          |
+locals :
+| 0     | x          |
+| 1     | s          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    LOADK     1 -1        ; "a"
+3     [1]    LOADK     2 -2        ; "b"
+4     [1]    MOVE      3 0
+5     [1]    LOADK     4 -3        ; 1
+6     [1]    LOADK     5 -4        ; 2
+7     [1]    CONCAT    1 1 5
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+
+        let mut compiler = Compiler::default();
+        compiler.fold_flags.insert(FoldFlags::CONCAT);
+        let output = format!("{:?}", try_compile_with(input, compiler).ok().unwrap());
+        let expected = r#"
+stack size : 4
+consts :
+| 0     | "ab"       |
+| 1     | "12"       |
+locals :
+| 0     | x          |
+| 1     | s          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    LOADK     1 -1        ; "ab"
+3     [1]    MOVE      2 0
+4     [1]    LOADK     3 -2        ; "12"
+5     [1]    CONCAT    1 1 3
+6     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn dump_header() {
+        let proto = try_compile("local a = 1; print(a, 'hello')").ok().unwrap();
+        let mut chunk = Vec::new();
+        proto.dump(&mut chunk).unwrap();
+        assert_eq!(&chunk[0..4], b"\x1bLua");
+        assert_eq!(chunk[4], 0x53);
+        assert_eq!(chunk[5], 0);
+        assert_eq!(&chunk[6..12], b"\x19\x93\r\n\x1a\n");
+        assert_eq!(&chunk[12..17], &[4, 8, 4, 8, 8]);
+        assert_eq!(
+            i64::from_le_bytes(chunk[17..25].try_into().unwrap()),
+            0x5678
+        );
+        assert_eq!(f64::from_le_bytes(chunk[25..33].try_into().unwrap()), 370.5);
+        assert_eq!(chunk[33] as usize, proto.up_vars.len());
+        // source "=?", line defined, last line defined
+        assert_eq!(&chunk[34..37], b"\x03=?");
+        let pos = 37 + 8;
+        assert_eq!(chunk[pos], proto.param_count as u8);
+        assert_eq!(chunk[pos + 1], 1);
+        assert_eq!(chunk[pos + 2], proto.stack_size as u8);
+        let code_size = u32::from_le_bytes(chunk[pos + 3..pos + 7].try_into().unwrap());
+        assert_eq!(code_size as usize, proto.code.len());
+        let first = u32::from_le_bytes(chunk[pos + 7..pos + 11].try_into().unwrap());
+        assert_eq!(first, proto.code[0].raw());
+    }
+
+    #[test]
+    fn bool_consts() {
+        let output =
+            try_compile_and_print("local t = {} t[true] = false t[1] = true local a = t == true");
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | true       |
+| 1     | false      |
+| 2     | 1          |
+locals :
+| 0     | t          |
+| 1     | a          |
+instructions :
+1     [1]    NEWTABLE  0 0 0
+2     [1]    SETTABLE  0 -1 -2     ; true false
+3     [1]    SETTABLE  0 -3 -1     ; 1 true
+4     [1]    EQ        1 0 -1      ; - true
+5     [1]    JMP       0 1         ; to 7
+6     [1]    LOADBOOL  1 0 1
+7     [1]    LOADBOOL  1 1 0
+8     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn not_const_folding() {
+        let output = try_compile_and_print("local a, b, c = not 1, not nil, (not false) == true");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | true       |
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+instructions :
+1     [1]    LOADBOOL  0 0 0
+2     [1]    LOADBOOL  1 1 0
+3     [1]    EQ        1 -1 -1     ; true true
+4     [1]    JMP       0 1         ; to 6
+5     [1]    LOADBOOL  2 0 1
+6     [1]    LOADBOOL  2 1 0
+7     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn compare_nil() {
+        let output = try_compile_and_print("local x; local a, b = x == nil, nil ~= x");
+        let expected = r#"
+stack size : 3
+consts :
+| 0     | nil        |
+locals :
+| 0     | x          |
+| 1     | a          |
+| 2     | b          |
+instructions :
+1     [1]    LOADNIL   0 0
+2     [1]    EQ        1 0 -1      ; - nil
+3     [1]    JMP       0 1         ; to 5
+4     [1]    LOADBOOL  1 0 1
+5     [1]    LOADBOOL  1 1 0
+6     [1]    EQ        0 -1 0      ; nil -
+7     [1]    JMP       0 1         ; to 9
+8     [1]    LOADBOOL  2 0 1
+9     [1]    LOADBOOL  2 1 0
+10    [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn float_const_dedup() {
+        let mut proto = Proto::default();
+        let zero = proto.add_const(Const::Float(0.0));
+        assert_eq!(proto.add_const(Const::Float(-0.0)), zero);
+        assert_ne!(proto.add_const(Const::Int(0)), zero);
+        let nan = proto.add_const(Const::Float(f64::NAN));
+        assert_ne!(proto.add_const(Const::Float(f64::NAN)), nan);
+        assert_eq!(proto.add_const(Const::Float(1.5)), 4);
+        assert_eq!(proto.add_const(Const::Float(1.5)), 4);
+        assert_eq!(proto.consts.len(), 5);
+    }
+
+    #[test]
+    fn string_arith_coercion() {
+        let output = try_compile_and_print(
+            r#"local a, b, c, d = "3" + 4, "0x10" * 2, -" 1.5 ", "1" .. 2
+local e, f, g, h = "10" // 3, "7" % 2, -"2", " 5 " * 2
+local i, j = "3" | 4, ~"0x10""#,
+        );
+        let expected = r#"
+stack size : 10
+consts :
+| 0     | 7.0        |
+| 1     | 32.0       |
+| 2     | -1.5       |
+| 3     | "1"        |
+| 4     | 2          |
+| 5     | 3.0        |
+| 6     | 1.0        |
+| 7     | -2.0       |
+| 8     | 10.0       |
+| 9     | 7          |
+| 10    | -17        |
+locals :
+| 0     | a          |
+| 1     | b          |
+| 2     | c          |
+| 3     | d          |
+| 4     | e          |
+| 5     | f          |
+| 6     | g          |
+| 7     | h          |
+| 8     | i          |
+| 9     | j          |
+instructions :
+1     [1]    LOADK     0 -1        ; 7.0
+2     [1]    LOADK     1 -2        ; 32.0
+3     [1]    LOADK     2 -3        ; -1.5
+4     [1]    LOADK     3 -4        ; "1"
+5     [1]    LOADK     4 -5        ; 2
+6     [1]    CONCAT    3 3 4
+7     [2]    LOADK     4 -6        ; 3.0
+8     [2]    LOADK     5 -7        ; 1.0
+9     [2]    LOADK     6 -8        ; -2.0
+10    [2]    LOADK     7 -9        ; 10.0
+11    [3]    LOADK     8 -10       ; 7
+12    [3]    LOADK     9 -11       ; -17
+13    [3]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn string_arith_no_coercion() {
+        let output = try_compile_and_print(r#"local a, b = "abc" + 1, "inf" & 1"#);
+        let expected = r#"
+stack size : 2
+consts :
+| 0     | "abc"      |
+| 1     | 1          |
+| 2     | "inf"      |
+locals :
+| 0     | a          |
+| 1     | b          |
+instructions :
+1     [1]    ADD       0 -1 -2     ; "abc" 1
+2     [1]    BADD      1 -3 -2     ; "inf" 1
+3     [1]    RETURN    0 1
+"#;
+        assert_eq!(output, expected);
+    }
+
+    fn verify_error(proto: &Proto) -> (Vec<usize>, usize, String) {
+        let e = proto.verify().err().unwrap();
+        (e.protos, e.pc, e.message)
+    }
+
+    #[test]
+    fn patch_jump_list() {
+        let mut proto = Proto::default();
+        proto.code_nil(0, 1);
+        let first = proto.code_jmp(NO_JUMP, 0);
+        proto.code_test_set(1, 0, 1);
+        let second = proto.code_jmp(NO_JUMP, 0);
+        proto.code_nil(0, 1);
+
+        let list = proto.concat_jump(NO_JUMP as usize, NO_JUMP as usize);
+        assert_eq!(list, NO_JUMP as usize);
+        let list = proto.concat_jump(list, first);
+        let list = proto.concat_jump(list, second);
+        assert_eq!(list, first);
+        assert_eq!(proto.jump_list(list), vec![first, second]);
+        assert_eq!(proto.get_jump(second), NO_JUMP as usize);
+
+        proto.patch_jump_list(list, 5);
+        assert_eq!(proto.code[first].get_arg_sBx(), 3);
+        assert_eq!(proto.code[second].get_arg_sBx(), 1);
+        // value of TESTSET is not needed at the target
+        assert_eq!(proto.code[second - 1].get_op(), OpCode::Test);
+        assert_eq!(proto.code[second - 1].get_arg_A(), 0);
+    }
+
+    #[test]
+    fn verify_corrupted_proto() {
+        let input = "local a = 'x' while a do a = f() end";
+        let proto = try_compile(input).ok().unwrap();
+        let jump = proto
+            .code
+            .iter()
+            .position(|i| i.get_op() == OpCode::Jmp)
+            .unwrap();
+
+        let mut corrupted = try_compile(input).ok().unwrap();
+        corrupted.code[jump].set_arg_sBx(100);
+        let target = jump + 101;
+        assert_eq!(
+            verify_error(&corrupted),
+            (vec![], jump, format!("jump target {} out of code", target))
+        );
+
+        let mut corrupted = try_compile(input).ok().unwrap();
+        corrupted.code[0].set_arg_Bx(5);
+        assert_eq!(
+            verify_error(&corrupted),
+            (vec![], 0, "constant 5 out of range".to_string())
+        );
+
+        let mut corrupted = try_compile(input).ok().unwrap();
+        corrupted.code[0].set_arg_A(corrupted.stack_size);
+        assert_eq!(
+            verify_error(&corrupted),
+            (vec![], 0, "register 2 out of stack size 2".to_string())
+        );
+
+        let mut corrupted = try_compile(input).ok().unwrap();
+        corrupted.code.remove(jump);
+        assert_eq!(
+            verify_error(&corrupted),
+            (vec![], jump - 1, "Test not followed by a jump".to_string())
+        );
+    }
+
+    #[test]
+    fn verify_nested_proto() {
+        let input = "local function f() return function(...) return ... end end";
+        let mut proto = try_compile(input).ok().unwrap();
+        proto.protos[0].protos[0].code[0].set_arg_A(10);
+        assert_eq!(
+            verify_error(&proto),
+            (vec![0, 0], 0, "register 10 out of stack size 2".to_string())
+        );
+    }
+
+    #[test]
+    fn free_registers() {
+        let mut context = ProtoContext::default();
+        context.add_local_var("a");
+        context.reserve_regs(3).ok().unwrap();
+        context.free_reg(2);
+        context.check_reg_balance();
+        let error = context.checked_free_reg(2).err().unwrap();
+        assert_eq!(error.message, "free 2 registers of 1");
+        assert_eq!(context.get_reg_top(), 1);
+        context.checked_free_reg(1).ok().unwrap();
+        assert_eq!(context.get_reg_top(), 0);
+    }
+
+    #[test]
+    #[should_panic(expected = "free 1 registers of 0")]
+    fn free_too_many_registers() {
+        ProtoContext::default().free_reg(1);
+    }
+
+    #[test]
+    #[should_panic(expected = "registers leaked")]
+    fn leaked_registers() {
+        let mut context = ProtoContext::default();
+        context.reserve_regs(1).ok().unwrap();
+        context.check_reg_balance();
+    }
+
+    // registers are checked to be balanced before every statement and at the end of each function
+    #[test]
+    fn balanced_registers() {
+        let input = r#"
+local a, b = 1, f()
+local t = { a, b, g(), x = a .. b, [a + b] = {} }
+t.x.y, t[a] = a and b or t, not a
+print(#t, -a, a < b, (f()))
+if a == b then local c = a else t = nil end
+for i = 1, #t do local d = t[i] end
+for k, v in pairs(t) do t[k] = { v } end
+while a do local e = a break end
+repeat local f = b until f
+local function h(...) local x, y = ... return x .. y, ... end
+return h(a, b), t:m(a)
+"#;
+        try_compile(input).ok().unwrap();
+    }
+
+    // constants of `f` listed by `luac -l -l` of lua 5.3:
+    // constants (6) for 0x...:
+    //     1    "x"
+    //     2    1
+    //     3    "y"
+    //     4    "g"
+    //     5    2.5
+    //     6    1.0
+    // captured locals mark the block declaring them
+    #[test]
+    fn mark_upval() {
+        let mut context = ProtoContext::default();
+        for nactvar in [0, 2, 3] {
+            context.blocks.push(BlockContext {
+                nactvar,
+                first_label: 0,
+                tail_labels: Vec::new(),
+                upval: false,
+            });
+        }
+        context.mark_upval(1);
+        context.mark_upval(3);
+        let upvals: Vec<bool> = context.blocks.iter().map(|block| block.upval).collect();
+        assert_eq!(upvals, [true, false, true]);
+    }
+
+    #[test]
+    fn const_order_of_luac() {
+        let input = r#"
+local function f(t)
+    t.x = 1
+    t.y = "x"
+    g = t.x + 2.5
+    return t[1], 1.0, "y"
+end
+local function h(t)
+    y, t.z, x = h(), "w"
+end
+"#;
+        let proto = try_compile(input).ok().unwrap();
+        let expected = [
+            Const::Str("x".to_string()),
+            Const::Int(1),
+            Const::Str("y".to_string()),
+            Const::Str("g".to_string()),
+            Const::Float(2.5),
+            Const::Float(1.0),
+        ];
+        assert_eq!(proto.protos()[0].consts(), &expected);
+        // names of targets are added before values, from left to right
+        let expected = [
+            Const::Str("y".to_string()),
+            Const::Str("z".to_string()),
+            Const::Str("x".to_string()),
+            Const::Str("w".to_string()),
+        ];
+        assert_eq!(proto.protos()[1].consts(), &expected);
+    }
+
+    // constant operands of arith ops are encoded as RK in B or C, without LOADK
+    #[test]
+    fn arith_const_operands() {
+        let ops = [
+            ("+", OpCode::Add),
+            ("-", OpCode::Sub),
+            ("*", OpCode::Mul),
+            ("%", OpCode::Mod),
+            ("^", OpCode::Pow),
+            ("/", OpCode::Div),
+            ("//", OpCode::IDiv),
+            ("&", OpCode::BAdd),
+            ("|", OpCode::BOr),
+            ("~", OpCode::BXor),
+            ("<<", OpCode::Shl),
+            (">>", OpCode::Shr),
+        ];
+        for (op, opcode) in ops.iter() {
+            let input = format!("local x; local y, z = x {} 1, 1.5 {} x", op, op);
+            let proto = try_compile(&input).ok().unwrap();
+            let code = proto.code();
+            assert!(
+                code.iter().all(|i| i.get_op() != OpCode::LoadK),
+                "{}",
+                input
+            );
+            assert_eq!(code[1].get_op(), *opcode);
+            assert!(!is_const(code[1].get_arg_B()) && is_const(code[1].get_arg_C()));
+            assert_eq!(code[2].get_op(), *opcode);
+            assert!(is_const(code[2].get_arg_B()) && !is_const(code[2].get_arg_C()));
+        }
     }
 }
@@ -0,0 +1,178 @@
+use rslua::ast::BinOp;
+use rslua::compiler::CompileError;
+use rslua::consts::Const::{self, Float, Int};
+use rslua::tokens::{Token, TokenType, TokenValue};
+use rslua::types::Source;
+use std::ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Rem, Shl, Shr, Sub};
+
+type ConstOp = fn(Const, Const) -> Result<Option<Const>, CompileError>;
+
+fn fold(op: ConstOp, a: Const, b: Const) -> Option<Const> {
+    match op(a, b) {
+        Ok(k) => k,
+        Err(e) => panic!("{}", e.message),
+    }
+}
+
+// results of `3 op 2`, `3 op 2.0` and `3.0 op 2.0`
+#[test]
+fn promotion() {
+    let cases: [(&str, ConstOp, [Const; 3]); 12] = [
+        ("+", Const::add, [Int(5), Float(5.0), Float(5.0)]),
+        ("-", Const::sub, [Int(1), Float(1.0), Float(1.0)]),
+        ("*", Const::mul, [Int(6), Float(6.0), Float(6.0)]),
+        ("/", Const::div, [Float(1.5), Float(1.5), Float(1.5)]),
+        ("//", Const::idiv, [Int(1), Float(1.0), Float(1.0)]),
+        ("%", Const::rem, [Int(1), Float(1.0), Float(1.0)]),
+        ("^", Const::pow, [Float(9.0), Float(9.0), Float(9.0)]),
+        ("&", Const::bitand, [Int(2), Int(2), Int(2)]),
+        ("|", Const::bitor, [Int(3), Int(3), Int(3)]),
+        ("~", Const::bitxor, [Int(1), Int(1), Int(1)]),
+        ("<<", Const::shl, [Int(12), Int(12), Int(12)]),
+        (">>", Const::shr, [Int(0), Int(0), Int(0)]),
+    ];
+    for (name, op, expected) in cases.iter() {
+        let operands = [
+            (Int(3), Int(2)),
+            (Int(3), Float(2.0)),
+            (Float(3.0), Float(2.0)),
+        ];
+        for ((a, b), k) in operands.into_iter().zip(expected.iter()) {
+            let message = format!("{:?} {} {:?}", a, name, b);
+            assert_eq!(fold(*op, a, b).as_ref(), Some(k), "{}", message);
+        }
+    }
+}
+
+#[test]
+fn int_wrap_around() {
+    assert_eq!(fold(Const::add, Int(i64::MAX), Int(1)), Some(Int(i64::MIN)));
+    assert_eq!(
+        fold(Const::mul, Int(i64::MIN), Int(-1)),
+        Some(Int(i64::MIN))
+    );
+    assert_eq!(
+        fold(Const::idiv, Int(i64::MIN), Int(-1)),
+        Some(Int(i64::MIN))
+    );
+    assert_eq!(Int(i64::MIN).minus().ok().unwrap(), Some(Int(i64::MIN)));
+}
+
+#[test]
+fn floor_rounding() {
+    assert_eq!(fold(Const::idiv, Int(-3), Int(2)), Some(Int(-2)));
+    assert_eq!(fold(Const::idiv, Float(-3.0), Int(2)), Some(Float(-2.0)));
+    assert_eq!(fold(Const::rem, Int(-3), Int(2)), Some(Int(1)));
+    assert_eq!(fold(Const::rem, Int(3), Float(-2.0)), Some(Float(-1.0)));
+}
+
+#[test]
+fn logical_shift() {
+    assert_eq!(fold(Const::shr, Int(-1), Int(60)), Some(Int(15)));
+    assert_eq!(fold(Const::shl, Int(1), Int(64)), Some(Int(0)));
+    assert_eq!(fold(Const::shl, Int(16), Int(-4)), Some(Int(1)));
+    assert_eq!(fold(Const::shr, Int(1), Float(-63.0)), Some(Int(i64::MIN)));
+}
+
+#[test]
+fn bitwise_without_integer_representation() {
+    let ops: [ConstOp; 5] = [
+        Const::bitand,
+        Const::bitor,
+        Const::bitxor,
+        Const::shl,
+        Const::shr,
+    ];
+    let operands = [
+        (Float(1.5), Int(1)),
+        (Int(1), Float(1.5)),
+        (Float(1e100), Float(1.0)),
+    ];
+    for op in ops.iter() {
+        for (a, b) in operands.iter() {
+            let error = op(a.clone(), b.clone()).err().unwrap();
+            assert_eq!(error.message, "number has no integer representation");
+        }
+    }
+    assert!(Float(0.5).bnot().is_err());
+    assert_eq!(Float(2.0).bnot().ok().unwrap(), Some(Int(-3)));
+}
+
+// numbers are converted to strings like `tostring`
+#[test]
+fn concat() {
+    let s = |s: &str| Some(Const::Str(s.to_string()));
+    let str = |s: &str| Const::Str(s.to_string());
+    assert_eq!(fold(Const::concat, str("a"), str("b")), s("ab"));
+    assert_eq!(fold(Const::concat, Int(1), Int(2)), s("12"));
+    assert_eq!(fold(Const::concat, Float(1.5), str("x")), s("1.5x"));
+    assert_eq!(fold(Const::concat, Float(2.0), Int(-3)), s("2.0-3"));
+    assert_eq!(fold(Const::concat, str(""), Float(1e100)), s("1e+100"));
+    assert_eq!(fold(Const::concat, str(""), Float(f64::INFINITY)), None);
+    assert_eq!(fold(Const::concat, str(""), Const::Bool(true)), None);
+}
+
+// floats are listed like `%.14g` of luac, always distinct from integers
+#[test]
+fn display() {
+    assert_eq!(Int(1).to_string(), "1");
+    assert_eq!(Float(1.0).to_string(), "1.0");
+    assert_eq!(Float(-0.0).to_string(), "-0.0");
+    assert_eq!(Float(0.1).to_string(), "0.1");
+    assert_eq!(Float(1e15).to_string(), "1e+15");
+    assert_eq!(Float(2.0f64.powi(63)).to_string(), "9.2233720368548e+18");
+    assert_ne!(Int(1).to_string(), Float(1.0).to_string());
+}
+
+fn compare(a: Const, op: &str, b: Const) -> Option<bool> {
+    let token = Token {
+        t: TokenType::Eq,
+        value: TokenValue::None,
+        raw: None,
+        source: Source::default(),
+        comments: Vec::new(),
+        trailing_comments: Vec::new(),
+    };
+    let op = match op {
+        "==" => BinOp::Eq(token),
+        "~=" => BinOp::Ne(token),
+        "<" => BinOp::Lt(token),
+        "<=" => BinOp::Le(token),
+        ">" => BinOp::Gt(token),
+        ">=" => BinOp::Ge(token),
+        _ => unreachable!(),
+    };
+    a.compare(&b, &op)
+}
+
+// ints above 2^53 are not rounded to floats when compared with floats
+#[test]
+fn compare_int_float() {
+    let p53 = 2.0f64.powi(53);
+    let i = (1 << 53) + 1;
+    assert_eq!(compare(Int(i), "==", Float(p53)), Some(false));
+    assert_eq!(compare(Int(i), "~=", Float(p53)), Some(true));
+    assert_eq!(compare(Int(i), "<", Float(p53)), Some(false));
+    assert_eq!(compare(Int(i), "<=", Float(p53)), Some(false));
+    assert_eq!(compare(Int(i), ">", Float(p53)), Some(true));
+    assert_eq!(compare(Float(p53), "<", Int(i)), Some(true));
+    assert_eq!(compare(Float(p53), ">=", Int(i)), Some(false));
+    assert_eq!(compare(Int(1 << 53), "==", Float(p53)), Some(true));
+    assert_eq!(compare(Int(1 << 53), "<=", Float(p53)), Some(true));
+
+    // floats without integer values and out of int range
+    assert_eq!(compare(Int(1), "<", Float(1.5)), Some(true));
+    assert_eq!(compare(Int(-2), "<", Float(-1.5)), Some(true));
+    assert_eq!(compare(Int(-1), "<=", Float(-1.5)), Some(false));
+    assert_eq!(
+        compare(Int(i64::MAX), "<", Float(2.0f64.powi(63))),
+        Some(true)
+    );
+    assert_eq!(
+        compare(Int(i64::MIN), "==", Float(-(2.0f64.powi(63)))),
+        Some(true)
+    );
+    assert_eq!(compare(Int(i64::MIN), ">", Float(-1e300)), Some(true));
+    assert_eq!(compare(Int(0), "<", Float(f64::NAN)), None);
+    assert_eq!(compare(Int(0), "==", Float(f64::NAN)), Some(false));
+}
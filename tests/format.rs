@@ -0,0 +1,81 @@
+use rslua::ast::Block;
+use rslua::format::{format_block, FormatConfig, QuoteStyle};
+use rslua::lexer::Lexer;
+use rslua::parser::Parser;
+
+fn try_parse(input: &str) -> Block {
+    let mut lexer = Lexer::default();
+    if let Ok(tokens) = lexer.run(input) {
+        let mut parser = Parser::default();
+        if let Ok(ast) = parser.run(tokens) {
+            return ast;
+        }
+    }
+    unreachable!()
+}
+
+// debug output of the ast without sources, which always change after formatting
+fn ast_without_sources(block: &Block) -> String {
+    let mut ast = format!("{:?}", block);
+    while let Some(start) = ast.find("Source {") {
+        let end = start + ast[start..].find('}').unwrap();
+        ast.replace_range(start..=end, "");
+    }
+    ast
+}
+
+fn check_round_trip(input: &str, config: &FormatConfig) -> String {
+    let block = try_parse(input);
+    let output = format_block(&block, config);
+    let formatted = try_parse(&output);
+    assert_eq!(ast_without_sources(&block), ast_without_sources(&formatted));
+    output
+}
+
+#[test]
+fn round_trip() {
+    let input = r#"
+local a, b = 1, 2.5 local c local d <const>, e, f <close> = 1
+local t = { 1, 2; x = "x", ["y\n"] = 'y', [1 + 2] = {}; }
+function t.f(x, ...) return x, ... end
+function t.m:g() return self end
+local function h(a) if a then return a elseif not a then return - -a else return end end
+while a < b do a = a + 1 break end
+for i = 10, 1, -1 do print(i) end
+for k, v in pairs(t) do print(k, v) end
+repeat local x = a until x
+do goto done end
+::done::
+a = b;
+(print)(0xffffffffffffffff, 1e300, 2^53, "\0\1\\\"")
+print "s" print { 1 } t.m:g(#t, ~a, a // b .. c)
+return (a or b) and c;
+"#;
+    check_round_trip(input, &FormatConfig::default());
+}
+
+#[test]
+fn format_config() {
+    let input = r#"if a then b = "it's" else return 1; end"#;
+    let config = FormatConfig {
+        indent: 2,
+        keep_semicolons: false,
+        quote_style: QuoteStyle::Single,
+    };
+    let block = try_parse(input);
+    let expected = r#"if a then
+  b = 'it\'s'
+else
+  return 1
+end
+"#;
+    assert_eq!(format_block(&block, &config), expected);
+
+    let expected = r#"if a then
+    b = "it's"
+else
+    return 1;
+end
+"#;
+    assert_eq!(check_round_trip(input, &FormatConfig::default()), expected);
+}
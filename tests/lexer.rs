@@ -1,8 +1,9 @@
 #[allow(unused_must_use)]
 mod lexer_tests {
-    use rslua::lexer::{LexError, Lexer};
+    use rslua::lexer::{LexError, Lexer, LexerConfig};
     use rslua::tokens::*;
-    use rslua::types::Source;
+    use rslua::types::{LuaVersion, Number, Source};
+    use rslua_traits::Comments;
     use std::fs::File;
     use std::io::prelude::*;
 
@@ -11,6 +12,18 @@ mod lexer_tests {
         lexer.run(input)
     }
 
+    fn try_lexer_with_version(
+        input: &str,
+        lua_version: LuaVersion,
+    ) -> Result<Vec<Token>, LexError> {
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            lua_version,
+            ..Default::default()
+        });
+        lexer.run(input)
+    }
+
     #[test]
     fn lexer_practical() -> std::io::Result<()> {
         let mut file = File::open(r"lua/json.lua")?;
@@ -107,6 +120,178 @@ mod lexer_tests {
     fn short_string_panic2() {
         try_lexer(r#""\256\256""#);
     }
+    // escapes are decimal like lua, not octal
+    #[test]
+    fn dec_esc() {
+        let tokens = try_lexer(r#""\101" "\8\9" "\0659" "\97\98" "\0" "\195\191""#).unwrap();
+        assert_eq!(tokens[0].as_str(), Some("e"));
+        assert_eq!(tokens[1].as_str(), Some("\u{8}\t"));
+        assert_eq!(tokens[2].as_str(), Some("A9"));
+        assert_eq!(tokens[3].as_str(), Some("ab"));
+        assert_eq!(tokens[4].as_str(), Some("\0"));
+        assert_eq!(tokens[5].as_str(), Some("ÿ"));
+    }
+    // `\255` is a valid escape, the string is rejected only because it isn't utf8
+    #[test]
+    #[should_panic(expected = "invalid utf8 string")]
+    fn dec_esc_max() {
+        try_lexer(r#""\255""#);
+    }
+    #[test]
+    #[should_panic(expected = "decimal escape too large at line [1:6]")]
+    fn dec_esc_too_large() {
+        try_lexer(r#""\256""#);
+    }
+    #[test]
+    #[should_panic(expected = "unfinished string (starting at line 2) at line [4:")]
+    fn unfinished_short_string() {
+        try_lexer("local a = 1\nlocal b = 'abc\\\n\\z\n   def\nlocal c = 2");
+    }
+    #[test]
+    #[should_panic(expected = "unfinished string (starting at line 1) at line [1:7]")]
+    fn unfinished_short_string_eof() {
+        try_lexer("a = \"b");
+    }
+    #[test]
+    fn escaped_line_breaks() {
+        for line_break in ["\n", "\r", "\r\n", "\n\r"] {
+            let input = format!("'a\\{}b' c", line_break);
+            let tokens = try_lexer(&input).unwrap();
+            assert_eq!(tokens[0].get_str(), "a\nb");
+            assert_eq!(tokens[1].get_str(), "c");
+            assert_eq!(tokens[1].source.line, 2);
+            assert_eq!(tokens[1].source.col, 4);
+
+            let mut lexer = Lexer::default();
+            lexer.set_config(LexerConfig {
+                use_origin_string: true,
+                ..Default::default()
+            });
+            let tokens = lexer.run(&input).unwrap();
+            assert_eq!(tokens[0].get_string(), format!("'a\\{}b'", line_break));
+            assert_eq!(tokens[1].source.line, 2);
+        }
+    }
+    #[test]
+    #[should_panic(expected = "UTF-8 value too large")]
+    fn utf8_esc_too_large_53() {
+        try_lexer_with_version(r#""\u{7FFFFFFF}""#, LuaVersion::Lua53);
+    }
+    #[test]
+    #[should_panic(expected = "invalid utf8 string")]
+    fn utf8_esc_extended_54() {
+        // the escape itself is accepted in 5.4, but the extended sequence is not valid utf8
+        try_lexer_with_version(r#""\u{7FFFFFFF}""#, LuaVersion::Lua54);
+    }
+    #[test]
+    #[should_panic(expected = "UTF-8 value too large")]
+    fn utf8_esc_too_large_54() {
+        try_lexer_with_version(r#""\u{80000000}""#, LuaVersion::Lua54);
+    }
+    #[test]
+    fn lua53_operators() {
+        let tokens = try_lexer_with_version("a // b ~ c ~= d", LuaVersion::Lua53).unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.t).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Name,
+                TokenType::IDiv,
+                TokenType::Name,
+                TokenType::BXor,
+                TokenType::Name,
+                TokenType::Ne,
+                TokenType::Name,
+                TokenType::Eos
+            ]
+        );
+    }
+    #[test]
+    #[should_panic(expected = "integer division not supported in Lua 5.1 at line [1:5]")]
+    fn lua51_idiv() {
+        try_lexer_with_version("a // b", LuaVersion::Lua51);
+    }
+    #[test]
+    #[should_panic(expected = "bitwise operator '~' not supported in Lua 5.1")]
+    fn lua51_bxor() {
+        try_lexer_with_version("a ~ b", LuaVersion::Lua51);
+    }
+    #[test]
+    #[should_panic(expected = "labels not supported in Lua 5.1")]
+    fn lua51_label() {
+        try_lexer_with_version("::done::", LuaVersion::Lua51);
+    }
+    // `goto` is a name, and `..`, `...` and `~=` are still lexed
+    #[test]
+    fn lua51_tokens() {
+        let tokens = try_lexer_with_version("goto .. ... ~= / <= >=", LuaVersion::Lua51).unwrap();
+        let types: Vec<TokenType> = tokens.iter().map(|t| t.t).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Name,
+                TokenType::Concat,
+                TokenType::Dots,
+                TokenType::Ne,
+                TokenType::Div,
+                TokenType::Le,
+                TokenType::Ge,
+                TokenType::Eos
+            ]
+        );
+    }
+    #[test]
+    #[should_panic(expected = "hexadecimal digit expected near 'G' at line [1:4]")]
+    fn hex_esc_no_digit() {
+        try_lexer(r#""\xG0""#);
+    }
+    #[test]
+    #[should_panic(expected = "hexadecimal digit expected near '\"' at line [1:5]")]
+    fn hex_esc_one_digit() {
+        try_lexer(r#""\x1""#);
+    }
+    #[test]
+    fn hex_esc() {
+        let tokens = try_lexer(r#""\x41\x7a\xC3\xBF""#).unwrap();
+        assert_eq!(tokens[0].get_str(), "Azÿ");
+
+        // kept verbatim in origin strings
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            use_origin_string: true,
+            ..Default::default()
+        });
+        let tokens = lexer.run(r#"'\xFF\x1'"#).unwrap();
+        assert_eq!(tokens[0].get_str(), r#"'\xFF\x1'"#);
+    }
+    #[test]
+    #[should_panic(expected = "hexadecimal digit expected near '}'")]
+    fn utf8_esc_empty() {
+        try_lexer(r#""\u{}""#);
+    }
+    #[test]
+    #[should_panic(expected = "invalid utf8 codepoint 0xD800")]
+    fn utf8_esc_surrogate() {
+        try_lexer(r#""\u{D800}""#);
+    }
+    #[test]
+    #[should_panic(expected = "missing '{'")]
+    fn utf8_esc_eof() {
+        try_lexer(r#""\u"#);
+    }
+    #[test]
+    fn utf8_esc() {
+        let tokens = try_lexer_with_version(r#""\u{10FFFF}""#, LuaVersion::Lua54).unwrap();
+        assert_eq!(tokens[0].get_str(), "\u{10FFFF}");
+        let tokens = try_lexer(r#""\u{1F600}\u{0}""#).unwrap();
+        assert_eq!(tokens[0].get_str(), "\u{1F600}\0");
+        // first and last codepoints of each length of encoding
+        let tokens = try_lexer(r#""\u{7F}\u{80}\u{7FF}\u{800}\u{5B57}\u{FFFF}\u{10000}""#).unwrap();
+        assert_eq!(
+            tokens[0].get_str(),
+            "\u{7F}\u{80}\u{7FF}\u{800}字\u{FFFF}\u{10000}"
+        );
+    }
     #[test]
     fn long_string() -> Result<(), LexError> {
         try_lexer(
@@ -130,6 +315,47 @@ mod lexer_tests {
         Ok(())
     }
     #[test]
+    #[should_panic(expected = "unfinished long string (starting at line 1, expected ']==]')")]
+    fn long_string_mismatched_level() {
+        try_lexer("[==[ foo ]=]");
+    }
+    #[test]
+    fn long_string_close_after_mismatch() {
+        let tokens = try_lexer("[[ a]=]]").unwrap();
+        assert_eq!(tokens[0].get_str(), " a]=");
+        let tokens = try_lexer("[=[ a]]]==]]=]").unwrap();
+        assert_eq!(tokens[0].get_str(), " a]]]==]");
+    }
+    #[test]
+    #[should_panic(expected = "invalid long string delimiter")]
+    fn long_string_invalid_delimiter() {
+        try_lexer("a = [==");
+    }
+    #[test]
+    #[should_panic(expected = "long bracket level too large (limit is 2)")]
+    fn long_string_level_limit() {
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            max_long_bracket_level: Some(2),
+            ..Default::default()
+        });
+        lexer.run("[==[ ok ]==] --[===[ too deep ]===]");
+    }
+    #[test]
+    fn run_bytes() {
+        let mut lexer = Lexer::default();
+        let tokens = lexer.run_bytes(b"--[[ \xFF ]] a = 1 -- \xFE").unwrap();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[0].get_str(), "a");
+        assert_eq!(tokens[2].get_int(), 1);
+    }
+    #[test]
+    #[should_panic(expected = "invalid utf8 string at line [1:12]")]
+    fn run_bytes_invalid_long_string() {
+        let mut lexer = Lexer::default();
+        lexer.run_bytes(b"a = [[ \xFF ]]");
+    }
+    #[test]
     fn str_to_int() {
         assert_eq!(Some(0x12345678), Lexer::str_to_int("0x12345678"));
         assert_eq!(Some(0x6789ABCD), Lexer::str_to_int("0x6789aBcD"));
@@ -148,6 +374,7 @@ mod lexer_tests {
         assert_eq!(None, Lexer::str_to_int("hello world"));
         assert_eq!(None, Lexer::str_to_int("12345s"));
         assert_eq!(None, Lexer::str_to_int("0x12345s"));
+        assert_eq!(None, Lexer::str_to_int("9223372036854775808"));
     }
     #[test]
     fn str_to_float() {
@@ -168,6 +395,34 @@ mod lexer_tests {
         assert_eq!(None, Lexer::str_to_float("3.14.1"));
         assert_eq!(None, Lexer::str_to_float("3..14"));
         assert_eq!(None, Lexer::str_to_float("3..14ss"));
+        // trailing spaces are allowed after hex floats too
+        assert_eq!(Some(16.0), Lexer::str_to_float("0x1p4 "));
+        assert_eq!(Some(10.5), Lexer::str_to_float("0xA.8  "));
+        assert_eq!(None, Lexer::str_to_float("0x1p4 x"));
+        assert_eq!(Some(0.5), Lexer::str_to_float("0x.8p0"));
+        assert_eq!(Some(16.0), Lexer::str_to_float("0x10"));
+        assert_eq!(None, Lexer::str_to_float("0x.p1"));
+        assert_eq!(None, Lexer::str_to_float("0x1p"));
+        // overflow, subnormals and underflow
+        assert_eq!(Some(f64::INFINITY), Lexer::str_to_float("0x1p1024"));
+        assert_eq!(
+            Some(f64::MAX),
+            Lexer::str_to_float("0x1.fffffffffffffp1023")
+        );
+        assert_eq!(Some(5e-324), Lexer::str_to_float("0x1p-1074"));
+        assert_eq!(Some(5e-324), Lexer::str_to_float("0x1000p-1086"));
+        assert_eq!(Some(0.0), Lexer::str_to_float("0x1p-1080"));
+        assert_eq!(
+            Some(f64::INFINITY),
+            Lexer::str_to_float("0x1p99999999999999999999")
+        );
+        assert_eq!(Some(0.0), Lexer::str_to_float("0x1p-99999999999999999999"));
+        assert_eq!(Some(0.0), Lexer::str_to_float("0x0p99999999999999999999"));
+        let digits = "1".to_string() + &"0".repeat(300);
+        assert_eq!(
+            Some(1.0),
+            Lexer::str_to_float(&format!("0x{}p-1200", digits))
+        );
     }
 
     #[test]
@@ -179,27 +434,64 @@ mod lexer_tests {
                 Token {
                     t: TokenType::Flt,
                     value: TokenValue::Float(0.13),
+                    raw: None,
                     source: Source {
                         length: 5,
                         line: 1,
-                        col: 1
+                        col: 1,
+                        offset: 0
                     },
                     comments: Vec::new(),
+                    trailing_comments: Vec::new(),
                 },
                 Token {
                     t: TokenType::Eos,
                     value: TokenValue::None,
+                    raw: None,
                     source: Source {
                         length: 0,
                         line: 1,
-                        col: 6
+                        col: 6,
+                        offset: 5
                     },
                     comments: Vec::new(),
+                    trailing_comments: Vec::new(),
                 }
             ]
         )
     }
 
+    #[test]
+    fn preserve_number_text() {
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            preserve_number_text: true,
+            ..Default::default()
+        });
+        let tokens = lexer.run("0x1p4 1e3 0xA 42 3.0").unwrap();
+        let values: Vec<(&TokenValue, Option<&str>)> = tokens
+            .iter()
+            .map(|t| (&t.value, t.get_number_text()))
+            .collect();
+        assert_eq!(
+            values,
+            vec![
+                (&TokenValue::Float(16.0), Some("0x1p4")),
+                (&TokenValue::Float(1000.0), Some("1e3")),
+                (&TokenValue::Int(10), Some("0xA")),
+                (&TokenValue::Int(42), Some("42")),
+                (&TokenValue::Float(3.0), Some("3.0")),
+                (&TokenValue::None, None),
+            ]
+        );
+        assert_eq!(tokens[0].get_float(), 16.0);
+        assert_eq!(tokens[2].get_int(), 10);
+
+        let tokens = try_lexer("0x1p4 1e3").unwrap();
+        assert_eq!(tokens[0].value, TokenValue::Float(16.0));
+        assert_eq!(tokens[1].get_number_text(), None);
+    }
+
     #[test]
     fn name() {
         let tokens = try_lexer("codepoint_to_utf8").unwrap();
@@ -209,22 +501,28 @@ mod lexer_tests {
                 Token {
                     t: TokenType::Name,
                     value: TokenValue::Str("codepoint_to_utf8".to_string()),
+                    raw: None,
                     source: Source {
                         length: 17,
                         line: 1,
                         col: 1,
+                        offset: 0
                     },
                     comments: Vec::new(),
+                    trailing_comments: Vec::new(),
                 },
                 Token {
                     t: TokenType::Eos,
                     value: TokenValue::None,
+                    raw: None,
                     source: Source {
                         length: 0,
                         line: 1,
-                        col: 18
+                        col: 18,
+                        offset: 17
                     },
                     comments: Vec::new(),
+                    trailing_comments: Vec::new(),
                 }
             ]
         )
@@ -239,24 +537,537 @@ mod lexer_tests {
                 Token {
                     t: TokenType::IDiv,
                     value: TokenValue::None,
+                    raw: None,
                     source: Source {
                         length: 2,
                         line: 1,
                         col: 1,
+                        offset: 0
                     },
                     comments: Vec::new(),
+                    trailing_comments: Vec::new(),
                 },
                 Token {
                     t: TokenType::Eos,
                     value: TokenValue::None,
+                    raw: None,
                     source: Source {
                         length: 0,
                         line: 1,
                         col: 3,
+                        offset: 2
                     },
                     comments: Vec::new(),
+                    trailing_comments: Vec::new(),
                 },
             ],
         )
     }
+
+    #[test]
+    fn merge_sources() {
+        let tokens = try_lexer("a + bc").unwrap();
+        let (a, bc) = (tokens[0].source, tokens[2].source);
+        let merged = Source {
+            line: 1,
+            col: 1,
+            length: 6,
+            offset: 0,
+        };
+        assert_eq!(a.merge(&bc), merged);
+        assert_eq!(bc.merge(&a), merged);
+        assert_eq!(Source::span(&a, &bc), merged);
+        assert_eq!(a.merge(&a), a);
+        assert_eq!(merged.merge(&tokens[1].source), merged);
+
+        // a long string spanning lines
+        let tokens = try_lexer("x\n  .. [[s\nt]] y").unwrap();
+        let merged = tokens[1].source.merge(&tokens[2].source);
+        assert_eq!(
+            merged,
+            Source {
+                line: 2,
+                col: 3,
+                length: 10,
+                offset: 4,
+            }
+        );
+        assert_eq!(
+            &"x\n  .. [[s\nt]] y"[merged.offset..merged.end()],
+            ".. [[s\nt]]"
+        );
+    }
+
+    #[test]
+    fn display_numbers() {
+        let cases = [
+            (Number::Float(1.0), "1.0"),
+            (Number::Float(-0.0), "-0.0"),
+            (Number::Float(0.1), "0.1"),
+            (Number::Float(12.3456789012344), "12.345678901234"),
+            (Number::Float(1e14), "1e+14"),
+            (Number::Float(123456789012.0), "123456789012.0"),
+            (Number::Float(2.5e-5), "2.5e-05"),
+            (Number::Float(f64::INFINITY), "inf"),
+            (Number::Float(f64::NEG_INFINITY), "-inf"),
+            (Number::Int(1), "1"),
+            (Number::Int(i64::MIN), "-9223372036854775808"),
+        ];
+        for (n, s) in cases.iter() {
+            assert_eq!(n.to_string(), *s);
+        }
+
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            preserve_number_text: true,
+            ..Default::default()
+        });
+        let tokens = lexer.run("0x10 'a' + 2.50").unwrap();
+        let values: Vec<String> = tokens.iter().map(|t| t.value.to_string()).collect();
+        assert_eq!(values, vec!["16", "\"a\"", "", "2.5", ""]);
+        let tokens = try_lexer("0x10 2.50 1e2").unwrap();
+        let values: Vec<String> = tokens.iter().map(|t| t.value.to_string()).collect();
+        assert_eq!(values, vec!["16", "2.5", "100.0", ""]);
+    }
+
+    #[test]
+    fn operator_priority() {
+        let right_assoc = |t: TokenType| {
+            let (left, right) = t.binop_priority().unwrap();
+            right < left
+        };
+        assert!(right_assoc(TokenType::Pow));
+        assert!(right_assoc(TokenType::Concat));
+        assert!(!right_assoc(TokenType::Add));
+        assert!(!right_assoc(TokenType::Lt));
+
+        let left = |t: TokenType| t.binop_priority().unwrap().0;
+        assert!(left(TokenType::Pow) > UNARY_PRIORITY);
+        assert!(left(TokenType::Mul) < UNARY_PRIORITY);
+        assert!(left(TokenType::Concat) > left(TokenType::Shl));
+        assert!(left(TokenType::Concat) < left(TokenType::Add));
+        assert!(left(TokenType::And) > left(TokenType::Or));
+
+        assert!(TokenType::Minus.is_unop() && TokenType::Minus.is_binop());
+        assert!(TokenType::BXor.is_unop() && TokenType::BXor.is_binop());
+        assert!(TokenType::Len.is_unop() && !TokenType::Len.is_binop());
+        assert!(!TokenType::Assign.is_unop() && !TokenType::Assign.is_binop());
+    }
+
+    #[test]
+    fn reuse_lexer() {
+        let mut lexer = Lexer::with_capacity(256);
+        lexer.set_config(LexerConfig {
+            reserve_comments: true,
+            ..Default::default()
+        });
+        let mut tokens = lexer.run("local a = 1 -- one").unwrap();
+        assert!(tokens.capacity() >= 256);
+        let ptr = tokens.as_ptr();
+        for _ in 0..100 {
+            lexer.recycle(tokens);
+            tokens = lexer.run("local b = 2 -- two").unwrap();
+            assert_eq!(tokens.as_ptr(), ptr);
+        }
+        lexer.recycle(tokens);
+
+        // config is kept
+        let tokens = lexer.run("x -- comment").unwrap();
+        assert_eq!(tokens.as_ptr(), ptr);
+        assert_eq!(tokens[1].t, TokenType::SComment);
+    }
+
+    #[test]
+    fn attach_comments() {
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            reserve_comments: true,
+            ..Default::default()
+        });
+        let input = "--[[a]] x -- b\n-- c\ny --[[d]]\n-- e";
+        for _ in 0..2 {
+            let tokens = lexer.run(input).unwrap();
+            let attached: Vec<(TokenType, Vec<&str>)> = tokens
+                .iter()
+                .filter(|t| !t.t.is_comment())
+                .map(|t| (t.t, t.get_comments()))
+                .collect();
+            assert_eq!(
+                attached,
+                vec![
+                    (TokenType::Name, vec!["a"]),
+                    (TokenType::Name, vec![" b", " c"]),
+                    (TokenType::Eos, vec!["d", " e"]),
+                ]
+            );
+        }
+
+        // an input with only a comment
+        let tokens = lexer.run("-- only").unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert_eq!(tokens[1].get_comments(), vec![" only"]);
+    }
+
+    #[test]
+    fn attach_trailing_comments() {
+        let input = "x = 1 -- set x\n-- y\ny = 2 --[[ z ]] -- set y";
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            reserve_comments: true,
+            attach_trailing_comments: true,
+            ..Default::default()
+        });
+        let tokens = lexer.run(input).unwrap();
+        let tokens: Vec<&Token> = tokens.iter().filter(|t| !t.is_comment()).collect();
+        assert_eq!(tokens[2].get_trailing_comments(), vec![" set x"]);
+        assert_eq!(tokens[3].get_comments(), vec![" y"]);
+        // only a single line comment right after the token is trailing
+        assert!(tokens[5].trailing_comments.is_empty());
+        assert_eq!(tokens[6].get_comments(), vec![" z ", " set y"]);
+
+        // comments are leading by default
+        lexer.set_config(LexerConfig {
+            reserve_comments: true,
+            ..Default::default()
+        });
+        let tokens = lexer.run(input).unwrap();
+        let tokens: Vec<&Token> = tokens.iter().filter(|t| !t.is_comment()).collect();
+        assert!(tokens[2].trailing_comments.is_empty());
+        assert_eq!(tokens[3].get_comments(), vec![" set x", " y"]);
+    }
+
+    fn try_lexer_with_limit(input: &str) -> Result<Vec<Token>, LexError> {
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            max_token_length: Some(10),
+            ..Default::default()
+        });
+        lexer.run(input)
+    }
+
+    #[test]
+    fn max_token_length() {
+        let tokens = try_lexer_with_limit("abcdefghij = 'abcdefghij' .. [[\nabcdefghij]]").unwrap();
+        assert_eq!(tokens.len(), 6);
+    }
+
+    #[test]
+    #[should_panic(expected = "token too long (limit is 10)")]
+    fn name_too_long() {
+        try_lexer_with_limit("abcdefghijk = 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "token too long (limit is 10)")]
+    fn string_too_long() {
+        try_lexer_with_limit("a = 'abcdefghijk'");
+    }
+
+    #[test]
+    #[should_panic(expected = "token too long (limit is 10)")]
+    fn long_string_too_long() {
+        try_lexer_with_limit("a = [==[abcdefghijk]==]");
+    }
+
+    #[test]
+    fn token_accessors() {
+        let tokens = try_lexer("name 42 4.5 'str'").unwrap();
+        assert_eq!(tokens[0].as_str(), Some("name"));
+        assert_eq!(tokens[0].as_int(), None);
+        assert_eq!(tokens[1].as_int(), Some(42));
+        assert_eq!(tokens[1].as_float(), None);
+        assert_eq!(tokens[2].as_float(), Some(4.5));
+        assert_eq!(tokens[2].as_str(), None);
+        assert_eq!(tokens[3].as_str(), Some("str"));
+        assert!(!tokens[3].is_eos());
+        assert!(tokens[4].is_eos());
+        assert_eq!(tokens[4].as_str(), None);
+    }
+
+    #[test]
+    fn skip_bom() {
+        let tokens = try_lexer("\u{FEFF}return 1").unwrap();
+        assert_eq!(tokens[0].t, TokenType::Return);
+        assert_eq!((tokens[0].source.line, tokens[0].source.col), (1, 1));
+        assert_eq!(tokens[0].source.offset, 3);
+        let mut lexer = Lexer::default();
+        let tokens = lexer.run_bytes(b"\xEF\xBB\xBFx").unwrap();
+        assert_eq!(tokens[0].as_str(), Some("x"));
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown token")]
+    fn bom_in_the_middle() {
+        try_lexer("return \u{FEFF}1");
+    }
+
+    #[test]
+    fn empty_input() {
+        for input in ["", "   \n\t", "\r\n\n", "\u{FEFF}"] {
+            let tokens = try_lexer(input).unwrap();
+            assert_eq!(tokens.len(), 1, "{:?}", input);
+            assert!(tokens[0].is_eos());
+            assert_eq!(tokens[0].source.offset, input.len());
+        }
+    }
+
+    #[test]
+    fn dump_tokens() {
+        let tokens = try_lexer("local a = 'x'\nprint(a, 1.5)").unwrap();
+        let expected = r#"
+tokens :
+| 1     | 1     | Local      |
+| 1     | 7     | Name       | "a"
+| 1     | 9     | Assign     |
+| 1     | 11    | String     | "x"
+| 2     | 1     | Name       | "print"
+| 2     | 6     | Lp         |
+| 2     | 7     | Name       | "a"
+| 2     | 8     | Comma      |
+| 2     | 10    | Flt        | 1.5
+| 2     | 13    | Rp         |
+| 2     | 14    | Eos        |
+"#;
+        assert_eq!(rslua::tokens::dump_tokens(&tokens), expected);
+    }
+
+    fn relex_config() -> LexerConfig {
+        LexerConfig {
+            reserve_comments: true,
+            attach_trailing_comments: true,
+            ..Default::default()
+        }
+    }
+
+    // replace `edit` of `input` with `text`, tokens of relex must be the same as a full run
+    fn check_relex(
+        lexer: &mut Lexer,
+        input: &str,
+        edit: std::ops::Range<usize>,
+        text: &str,
+    ) -> String {
+        let output = format!("{}{}{}", &input[..edit.start], text, &input[edit.end..]);
+        lexer.relex(&output, edit).unwrap();
+        let mut full = Lexer::default();
+        full.set_config(relex_config());
+        assert_eq!(lexer.tokens(), &full.run(&output).unwrap(), "{:?}", output);
+        output
+    }
+
+    #[test]
+    fn relex_one_char() -> std::io::Result<()> {
+        let mut content = String::new();
+        File::open(r"lua/json.lua")?.read_to_string(&mut content)?;
+        let mut lexer = Lexer::default();
+        lexer.set_config(relex_config());
+        lexer.relex(&content, 0..0).unwrap();
+        let old = lexer.tokens().clone();
+
+        let name = old[old.len() / 2..]
+            .iter()
+            .find(|t| t.t == TokenType::Name && t.source.length > 1)
+            .unwrap();
+        let offset = name.source.offset;
+        check_relex(&mut lexer, &content, offset..offset + 1, "z");
+        let changed: Vec<&Token> = old
+            .iter()
+            .zip(lexer.tokens().iter())
+            .filter(|(a, b)| a != b)
+            .map(|(a, _)| a)
+            .collect();
+        assert_eq!(lexer.tokens().len(), old.len());
+        assert_eq!(changed, vec![name]);
+        Ok(())
+    }
+
+    #[test]
+    fn relex_edits() {
+        let mut lexer = Lexer::default();
+        lexer.set_config(relex_config());
+        let mut input =
+            "local a = 1 -- one\nlocal b = a\n--[[ long ]] print(a == b, 'x')\n".to_string();
+        lexer.relex(&input, 0..0).unwrap();
+        // replace the first occurrence of each pattern
+        let edits = [
+            // a name grows and a number becomes a float
+            ("local a", "local abc"),
+            ("= 1 ", "= 1.5 "),
+            // a trailing comment is removed
+            ("-- one\n", "\n"),
+            // lines are inserted before the rest
+            ("", "x = 1\n\n"),
+            // `==` becomes `=`
+            ("==", "="),
+            // a long comment swallows a statement
+            ("local b = a", "--[==[ local b = a ]==]"),
+            // a string is inserted into another one
+            ("'x'", "'\"y\" .. x'"),
+            // edits at the start
+            ("x", "yy"),
+            ("yy = 1", ""),
+        ];
+        for (pattern, text) in edits.iter() {
+            let start = input.find(pattern).unwrap();
+            input = check_relex(&mut lexer, &input, start..start + pattern.len(), text);
+        }
+        // edits at the end, then everything is removed
+        let end = input.len();
+        input = check_relex(&mut lexer, &input, end..end, " -- end");
+        let end = input.len() - 4;
+        input = check_relex(&mut lexer, &input, end..end, "=");
+        check_relex(&mut lexer, &input, 0..input.len(), "");
+        assert_eq!(lexer.tokens().len(), 1);
+    }
+
+    fn check_parallel(input: &str, config: fn() -> LexerConfig) {
+        let mut lexer = Lexer::default();
+        lexer.set_config(config());
+        let expected = lexer.run(input).unwrap();
+        for threads in 1..8 {
+            let tokens = lexer.run_parallel(input, threads).unwrap();
+            assert_eq!(tokens, expected, "{} threads", threads);
+        }
+    }
+
+    #[test]
+    fn run_parallel() -> std::io::Result<()> {
+        let mut content = String::new();
+        File::open(r"lua/json.lua")?.read_to_string(&mut content)?;
+        check_parallel(&content, LexerConfig::default);
+        check_parallel(&content, relex_config);
+
+        // line breaks in strings and comments are not split
+        let input = r#"local a = [==[
+]]
+]==] -- x
+--[[ long
+comment ]] local b = "a\
+b\z
+   c" .. `d\
+`
+-- last
+"#;
+        let input = input
+            .repeat(20)
+            .replace("]]\n", "]]\r\n")
+            .replace("x\n", "x\n\r");
+        check_parallel(&input, LexerConfig::default);
+        check_parallel(&input, relex_config);
+        check_parallel("", relex_config);
+        check_parallel("\u{FEFF}a\n\n\n\nb\n\n\n", relex_config);
+        Ok(())
+    }
+
+    fn intern_config() -> LexerConfig {
+        LexerConfig {
+            intern_strings: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn intern_strings() {
+        let mut lexer = Lexer::default();
+        lexer.set_config(intern_config());
+        let tokens = lexer.run("local abc = abc .. 'abc' .. x .. [[x]]").unwrap();
+        let symbols: Vec<u32> = tokens
+            .iter()
+            .filter_map(|t| t.as_symbol())
+            .map(|s| s.id())
+            .collect();
+        assert_eq!(symbols, vec![0, 0, 0, 1, 1]);
+        match (&tokens[1].value, &tokens[3].value) {
+            (TokenValue::Interned(_, a), TokenValue::Interned(_, b)) => {
+                assert!(std::sync::Arc::ptr_eq(a, b))
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(tokens[3].get_str(), "abc");
+        let table = lexer.intern_table();
+        assert_eq!(table.len(), 2);
+        assert_eq!(table.resolve(tokens[9].as_symbol().unwrap()), Some("x"));
+
+        // symbols are kept across runs
+        let tokens = lexer.run("x = abc").unwrap();
+        assert_eq!(tokens[0].as_symbol().unwrap().id(), 1);
+        assert_eq!(lexer.intern_table().len(), 2);
+
+        let mut content = String::new();
+        File::open(r"lua/json.lua")
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        check_parallel(&content, intern_config);
+    }
+
+    fn check_borrowed(expected: &[Token], tokens: &[BorrowedToken]) {
+        assert_eq!(expected.len(), tokens.len());
+        for (expected, token) in expected.iter().zip(tokens) {
+            assert_eq!(expected.t, token.t);
+            assert_eq!(expected.source, token.source);
+            assert_eq!(expected.as_str(), token.as_str());
+            assert_eq!(expected.get_number_text(), token.get_number_text());
+            assert_eq!(expected.as_int(), token.as_int());
+            check_borrowed(&expected.comments, &token.comments);
+            check_borrowed(&expected.trailing_comments, &token.trailing_comments);
+        }
+    }
+
+    #[test]
+    fn run_borrowed() -> std::io::Result<()> {
+        let mut content = String::new();
+        File::open(r"lua/json.lua")?.read_to_string(&mut content)?;
+        let input = r#"local a = [==[
+]]
+]==] -- x
+local b, c = "a\nb", 'c' .. [[
+
+d]] .. [=[]=] .. "" .. 0x10
+"#;
+        let crlf = input.replace('\n', "\r\n");
+        for input in [content.as_str(), input, &crlf] {
+            let origin_config = || LexerConfig {
+                use_origin_string: true,
+                preserve_number_text: true,
+                ..Default::default()
+            };
+            let configs: [fn() -> LexerConfig; 4] = [
+                LexerConfig::default,
+                relex_config,
+                intern_config,
+                origin_config,
+            ];
+            for config in configs {
+                let mut lexer = Lexer::default();
+                lexer.set_config(config());
+                let expected = lexer.run(input).unwrap();
+                let tokens = lexer.run_borrowed(input).unwrap();
+                check_borrowed(&expected, &tokens);
+            }
+        }
+
+        let mut lexer = Lexer::default();
+        let tokens = lexer.run_borrowed(input).unwrap();
+        let strings: Vec<(&str, bool)> = tokens
+            .iter()
+            .filter(|t| t.t == TokenType::String)
+            .map(|t| (t.as_str().unwrap(), t.is_borrowed()))
+            .collect();
+        assert_eq!(
+            strings,
+            [
+                ("]]\n", true),
+                ("a\nb", false),
+                ("c", true),
+                ("\nd", true),
+                ("", true),
+                ("", true)
+            ]
+        );
+        assert!(tokens
+            .iter()
+            .filter(|t| t.t == TokenType::Name)
+            .all(|t| t.is_borrowed()));
+        Ok(())
+    }
 }
@@ -1,6 +1,6 @@
 use rslua::ast::*;
 use rslua::ast_walker;
-use rslua::ast_walker::AstVisitor;
+use rslua::ast_walker::{AstVisitor, VisitAction};
 use rslua::lexer::{Lexer, LexerConfig};
 use rslua::parser::Parser;
 use rslua_traits::Comments;
@@ -116,103 +116,103 @@ impl LuaWriter {
 }
 
 type WriteResult<T> = Result<T, ()>;
-type WriteSuccess = WriteResult<()>;
+type WriteSuccess = WriteResult<VisitAction>;
 
 impl AstVisitor for LuaWriter {
     fn stat_sep(&mut self) {
         self.incline();
     }
 
-    fn begin_if(&mut self, _cond: &Expr) -> WriteResult<bool> {
+    fn begin_if(&mut self, _cond: &Expr) -> WriteResult<VisitAction> {
         self.append_space("if");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
-    fn then(&mut self, _block: &Block) -> WriteResult<bool> {
+    fn then(&mut self, _block: &Block) -> WriteResult<VisitAction> {
         self.space();
         self.enter_scope();
         self.append_inc("then");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
-    fn begin_else_if(&mut self, _cond: &Expr) -> WriteResult<bool> {
+    fn begin_else_if(&mut self, _cond: &Expr) -> WriteResult<VisitAction> {
         self.leave_scope();
         self.append_space("elseif");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
-    fn begin_else(&mut self, _block: &Block) -> WriteResult<bool> {
+    fn begin_else(&mut self, _block: &Block) -> WriteResult<VisitAction> {
         self.leave_scope();
         self.append("else");
         self.enter_scope();
         self.incline();
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_if(&mut self) {
         self.end();
     }
 
-    fn begin_while(&mut self, _cond: &Expr) -> WriteResult<bool> {
+    fn begin_while(&mut self, _cond: &Expr) -> WriteResult<VisitAction> {
         self.append_space("while");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
-    fn begin_while_block(&mut self, _block: &Block) -> WriteResult<bool> {
+    fn begin_while_block(&mut self, _block: &Block) -> WriteResult<VisitAction> {
         self.enter_scope();
         self.space();
         self.append_inc("do");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_while(&mut self) {
         self.end();
     }
 
-    fn begin_do_block(&mut self, _block: &Block) -> WriteResult<bool> {
+    fn begin_do_block(&mut self, _block: &Block) -> WriteResult<VisitAction> {
         self.enter_scope();
         self.space();
         self.append_inc("do");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_do_block(&mut self) {
         self.end();
     }
 
-    fn begin_for_num(&mut self, _for_enum: &ForNum) -> WriteResult<bool> {
+    fn begin_for_num(&mut self, _for_enum: &ForNum) -> WriteResult<VisitAction> {
         self.append_space("for");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn for_enum_equal(&mut self) {
         self.space_append_space("=");
     }
 
-    fn begin_for_list(&mut self, _: &ForList) -> WriteResult<bool> {
+    fn begin_for_list(&mut self, _: &ForList) -> WriteResult<VisitAction> {
         self.append_space("for");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn for_list_in(&mut self) {
         self.space_append_space("in");
     }
 
-    fn begin_for_block(&mut self, _block: &Block) -> WriteResult<bool> {
+    fn begin_for_block(&mut self, _block: &Block) -> WriteResult<VisitAction> {
         self.enter_scope();
         self.space();
         self.append_inc("do");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_for(&mut self) {
         self.end();
     }
 
-    fn begin_repeat(&mut self, _block: &Block) -> WriteResult<bool> {
+    fn begin_repeat(&mut self, _block: &Block) -> WriteResult<VisitAction> {
         self.enter_scope();
         self.append_inc("repeat");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn until(&mut self) {
@@ -271,28 +271,28 @@ impl AstVisitor for LuaWriter {
             self.append_space("=");
             self.exprlist(stat.exprs.as_ref().unwrap())?;
         }
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn label_stat(&mut self, stat: &LabelStat) -> WriteSuccess {
         self.append(&format!("::{}::", stat.label.value()));
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn ret_stat(&mut self, stat: &RetStat) -> WriteSuccess {
         self.append_space("return");
         self.exprlist(stat.exprs.as_ref().unwrap())?;
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn break_stat(&mut self, _stat: &BreakStat) -> WriteSuccess {
         self.append("break");
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn goto_stat(&mut self, stat: &GotoStat) -> WriteSuccess {
         self.append(&format!("goto {}", stat.label.value()));
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn assign_stat(&mut self, stat: &AssignStat) -> WriteSuccess {
@@ -304,12 +304,12 @@ impl AstVisitor for LuaWriter {
         }
         self.space_append_space("=");
         self.exprlist(&stat.right)?;
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn call_stat(&mut self, stat: &CallStat) -> WriteSuccess {
         ast_walker::walk_assinable(&stat.call, self)?;
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn exprlist(&mut self, exprs: &ExprList) -> WriteSuccess {
@@ -325,7 +325,7 @@ impl AstVisitor for LuaWriter {
                 self.append(",");
             }
         }
-        Ok(())
+        Ok(VisitAction::Continue)
     }
 
     fn nil(&mut self) {
@@ -341,6 +341,9 @@ impl AstVisitor for LuaWriter {
     }
 
     fn float(&mut self, f: &FloatExpr) {
+        if let Some(text) = f.text() {
+            return self.append(text);
+        }
         let string = if f.value().fract() == 0.0 {
             format!("{}.0", f.value())
         } else {
@@ -350,6 +353,9 @@ impl AstVisitor for LuaWriter {
     }
 
     fn int(&mut self, i: &IntExpr) {
+        if let Some(text) = i.text() {
+            return self.append(text);
+        }
         self.append(&i.value().to_string());
     }
 
@@ -365,7 +371,7 @@ impl AstVisitor for LuaWriter {
         self.append_space("function");
     }
 
-    fn begin_func_body(&mut self, body: &FuncBody) -> WriteResult<bool> {
+    fn begin_func_body(&mut self, body: &FuncBody) -> WriteResult<VisitAction> {
         self.append("(");
         let has_comments = body.params.params.iter().any(|param| param.has_comments())
             || body.params.commas.iter().any(|comma| comma.has_comments());
@@ -401,21 +407,21 @@ impl AstVisitor for LuaWriter {
         };
         self.append_inc(")");
         self.enter_scope();
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_func_body(&mut self) {
         self.end();
     }
 
-    fn begin_table(&mut self, t: &Table) -> WriteResult<bool> {
+    fn begin_table(&mut self, t: &Table) -> WriteResult<VisitAction> {
         if !t.fields.is_empty() {
             self.enter_scope();
             self.append_inc("{");
         } else {
             self.append("{}");
         }
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_table(&mut self, t: &Table) {
@@ -433,11 +439,11 @@ impl AstVisitor for LuaWriter {
         self.space_append_space("=");
     }
 
-    fn begin_field_key(&mut self, key: &FieldKey) -> WriteResult<bool> {
+    fn begin_field_key(&mut self, key: &FieldKey) -> WriteResult<VisitAction> {
         if let FieldKey::Expr(..) = key {
             self.append_space("[");
         }
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_field_key(&mut self, key: &FieldKey) {
@@ -498,27 +504,27 @@ impl AstVisitor for LuaWriter {
         self.append(&method.value());
     }
 
-    fn begin_index(&mut self, _expr: &Expr) -> WriteResult<bool> {
+    fn begin_index(&mut self, _expr: &Expr) -> WriteResult<VisitAction> {
         self.append("[");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_index(&mut self) {
         self.append("]");
     }
 
-    fn begin_func_args(&mut self, args: &FuncArgs) -> WriteResult<bool> {
+    fn begin_func_args(&mut self, args: &FuncArgs) -> WriteResult<VisitAction> {
         self.append("(");
         if let FuncArgs::Exprs(_, exprs, _) = args {
             if exprs.has_comments() {
                 self.func_call_stack.push(FuncCallType::WithComments);
                 self.incline();
                 self.enter_scope();
-                return Ok(false);
+                return Ok(VisitAction::Continue);
             }
         }
         self.func_call_stack.push(FuncCallType::WithOutComments);
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_func_args(&mut self) {
@@ -531,9 +537,9 @@ impl AstVisitor for LuaWriter {
         self.append(")");
     }
 
-    fn begin_paren_expr(&mut self, _expr: &Expr) -> WriteResult<bool> {
+    fn begin_paren_expr(&mut self, _expr: &Expr) -> WriteResult<VisitAction> {
         self.append("(");
-        Ok(false)
+        Ok(VisitAction::Continue)
     }
 
     fn end_paren_expr(&mut self) {
@@ -566,6 +572,8 @@ fn try_convert(input: &str) -> String {
     lexer.set_config(LexerConfig {
         use_origin_string: true,
         reserve_comments: true,
+        preserve_number_text: true,
+        ..Default::default()
     });
     if let Ok(tokens) = lexer.run(input) {
         let mut parser = Parser::default();
@@ -619,6 +627,12 @@ fn write_method_call() {
     assert_eq!("str:sub(i, i)\n".to_string(), try_convert("str:sub(i,i)"));
 }
 
+#[test]
+fn write_number_text() {
+    let code = "local a = 0x1p4\nlocal b = 1e3\nlocal c = 0xFF\nlocal d = 3.50\n";
+    assert_eq!(try_convert(code), code);
+}
+
 #[test]
 fn parse_comments_simple() {
     let code = "--Hello
@@ -0,0 +1,74 @@
+use rslua::opcodes::OpCode as OpCode53;
+use rslua::opcodes_54::*;
+use std::io::Write;
+
+#[test]
+fn encode_ops() {
+    assert_eq!(OpCode::ExtraArg as u32, 82);
+
+    let i = Instruction::create_AsBx(OpCode::LoadI, 1, -5);
+    assert_eq!(i.raw(), 1 | (1 << 7) | (((-5 + 65535) as u32) << 15));
+    assert_eq!(i.get_arg_sBx(), -5);
+
+    let i = Instruction::create_AsBx(OpCode::LoadF, 2, 3);
+    assert_eq!(i.raw(), 2 | (2 << 7) | ((3 + 65535) << 15));
+
+    let i = Instruction::create_ABC(OpCode::AddK, 0, 1, 2);
+    assert_eq!(i.raw(), 22 | (1 << 16) | (2 << 24));
+
+    let i = Instruction::create_ABC(OpCode::AddI, 0, 1, (-1 + OFFSET_SC) as u32);
+    assert_eq!(i.get_arg_sC(), -1);
+
+    let i = Instruction::create_ABCk(OpCode::MmBinK, 0, 2, 6, true);
+    assert_eq!(i.raw(), 48 | (1 << 15) | (2 << 16) | (6 << 24));
+    assert!(i.get_arg_k());
+
+    let i = Instruction::create_sJ(OpCode::Jmp, -2);
+    assert_eq!(i.raw(), 56 | (((-2 + OFFSET_SJ) as u32) << 7));
+    assert_eq!(i.get_arg_sJ(), -2);
+
+    let i = Instruction::create_Ax(OpCode::ExtraArg, MAXARG_AX);
+    assert_eq!(i.raw(), 0xffff_ff80 | 82);
+}
+
+#[test]
+fn print_instruction() {
+    let mut s = Vec::<u8>::new();
+    let instructions = [
+        Instruction::create_ABC(OpCode::Move, 1, 0, 0),
+        Instruction::create_AsBx(OpCode::LoadI, 1, -5),
+        Instruction::create_ABx(OpCode::LoadK, 2, 300),
+        Instruction::create_ABCk(OpCode::EqK, 0, 1, 0, true),
+        Instruction::create_sJ(OpCode::Jmp, 3),
+        Instruction::create_Ax(OpCode::ExtraArg, 70000),
+    ];
+    for i in instructions.iter() {
+        writeln!(&mut s, "{:?}", i).unwrap();
+    }
+    let string = String::from_utf8(s).ok().unwrap();
+    assert_eq!(
+        string,
+        r#"| Move       | 1     | 0     | 0     |
+| LoadI      | 1     | -5    |       |
+| LoadK      | 2     | 300   |       |
+| EqK        | 0     | 1     | 0k    |
+| Jmp        | 3     |       |       |
+| ExtraArg   | 70000 |       |       |
+"#
+    )
+}
+
+#[test]
+fn map_53_ops() {
+    assert_eq!(OpCode::from_53(OpCode53::Move), Some(OpCode::Move));
+    assert_eq!(OpCode::from_53(OpCode53::BAdd), Some(OpCode::BAnd));
+    assert_eq!(OpCode::from_53(OpCode53::TForLoop), Some(OpCode::TForLoop));
+    assert_eq!(OpCode::from_53(OpCode53::LoadBool), None);
+    for u in 0..=OpCode53::ExtraArg as u32 {
+        let op = OpCode53::from_u32(u);
+        if let Some(op54) = OpCode::from_53(op) {
+            let name = format!("{:?}", op).to_lowercase().replace("badd", "band");
+            assert_eq!(format!("{:?}", op54).to_lowercase(), name);
+        }
+    }
+}
@@ -2,7 +2,10 @@
 mod parser_tests {
     use rslua::ast::*;
     use rslua::lexer::Lexer;
-    use rslua::parser::Parser;
+    use rslua::lexer::LexerConfig;
+    use rslua::parser::{Parser, TokenStream};
+    use rslua::tokens::TokenType;
+    use rslua_traits::Comments;
     use std::fs::File;
     use std::io::prelude::*;
 
@@ -519,4 +522,108 @@ mod parser_tests {
             _ => unreachable!(),
         }
     }
+
+    fn token_stream(input: &str) -> TokenStream {
+        let mut lexer = Lexer::default();
+        lexer.set_config(LexerConfig {
+            reserve_comments: true,
+            ..LexerConfig::default()
+        });
+        TokenStream::new(lexer.run(input).unwrap())
+    }
+
+    #[test]
+    fn token_stream_lookahead() {
+        let mut stream = token_stream("local --[[ c ]] a = 1 -- d");
+        assert_eq!(stream.peek().t, TokenType::Local);
+        assert_eq!(stream.peek_nth(1).t, TokenType::Name);
+        assert_eq!(stream.peek_nth(3).t, TokenType::Int);
+        assert_eq!(stream.next().t, TokenType::Local);
+        let name = stream.expect(TokenType::Name).unwrap();
+        assert_eq!(name.get_comments(), vec![" c "]);
+        assert_eq!(
+            stream.expect(TokenType::Assign).unwrap().t,
+            TokenType::Assign
+        );
+        assert_eq!(stream.next().get_int(), 1);
+        assert!(stream.peek().is_eos());
+    }
+
+    #[test]
+    fn token_stream_past_eos() {
+        let mut stream = token_stream("a");
+        assert_eq!(stream.peek_nth(1).t, TokenType::Eos);
+        assert_eq!(stream.peek_nth(100).t, TokenType::Eos);
+        assert_eq!(stream.next().t, TokenType::Name);
+        for _ in 0..3 {
+            assert!(stream.peek().is_eos());
+            assert!(stream.next().is_eos());
+        }
+        assert!(stream.expect(TokenType::Eos).unwrap().is_eos());
+
+        // a missing Eos is added
+        let mut stream = TokenStream::new(Vec::new());
+        assert!(stream.next().is_eos());
+        assert!(stream.peek().is_eos());
+    }
+
+    #[test]
+    fn token_stream_mark() {
+        let mut stream = token_stream("a.b = c");
+        stream.next();
+        let mark = stream.mark();
+        let read: Vec<TokenType> = (0..3).map(|_| stream.next().t).collect();
+        assert_eq!(
+            read,
+            vec![TokenType::Attr, TokenType::Name, TokenType::Assign]
+        );
+        stream.reset_to(mark);
+        let reread: Vec<TokenType> = (0..3).map(|_| stream.next().t).collect();
+        assert_eq!(reread, read);
+        assert_eq!(stream.next().as_str(), Some("c"));
+
+        // marks at the end stay there
+        let end = stream.mark();
+        stream.next();
+        assert_eq!(stream.mark(), end);
+    }
+
+    #[test]
+    #[should_panic(expected = "[syntax error] Name expected at line [1:7] near [Assign]")]
+    fn token_stream_expect() {
+        let mut stream = token_stream("local = 1");
+        stream.next();
+        stream.expect(TokenType::Name);
+    }
+
+    #[test]
+    fn local_attribs() {
+        let ast = try_parse("local a <const>, b, c <close> = 1");
+        match &ast.stats[0] {
+            Stat::LocalStat(stat) => {
+                let attribs: Vec<Option<String>> = stat
+                    .attribs
+                    .iter()
+                    .map(|attrib| attrib.as_ref().map(|attrib| attrib.value()))
+                    .collect();
+                assert_eq!(
+                    attribs,
+                    vec![Some("const".into()), None, Some("close".into())]
+                );
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "unknown attribute 'static'")]
+    fn unknown_attrib() {
+        try_parse("local a <static> = 1");
+    }
+
+    #[test]
+    #[should_panic(expected = "multiple to-be-closed variables in local list")]
+    fn multiple_close_attribs() {
+        try_parse("local a <close>, b <close> = f()");
+    }
 }
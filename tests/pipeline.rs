@@ -0,0 +1,115 @@
+use rslua::ast::{Expr, Stat};
+use rslua::compiler::Compiler;
+use rslua::consts::Const;
+use rslua::lexer::LexerConfig;
+use rslua::opcodes::OpCode;
+use rslua::tokens::TokenValue;
+use rslua::{compile, compile_with, parse, parse_with};
+
+#[test]
+fn parse_and_compile() {
+    let block = parse("local a = 1 print(a + 1)").ok().unwrap();
+    assert_eq!(block.stats.len(), 2);
+    let proto = Compiler::default().run(&block).ok().unwrap();
+    assert_eq!(proto.consts.len(), 2);
+    assert_eq!(proto.verify(), Ok(()));
+}
+
+#[test]
+fn parse_with_config() {
+    let config = LexerConfig {
+        preserve_number_text: true,
+        ..Default::default()
+    };
+    let block = parse_with("local a = 0x10", config).ok().unwrap();
+    match &block.stats[0] {
+        Stat::LocalStat(stat) => match &stat.exprs.as_ref().unwrap().exprs[0] {
+            Expr::Int(int) => {
+                assert_eq!(int.token.value, TokenValue::Int(16));
+                assert_eq!(int.text(), Some("0x10"));
+            }
+            _ => unreachable!(),
+        },
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn compile_empty_input() {
+    for input in ["", "   \n\t", ";"] {
+        let proto = compile(input).ok().unwrap();
+        assert_eq!(proto.code.len(), 1);
+        assert_eq!(proto.code[0].get_op(), OpCode::Return);
+        assert_eq!(proto.line_info, vec![1]);
+        assert_eq!(proto.verify(), Ok(()));
+    }
+}
+
+#[test]
+fn proto_accessors() {
+    let proto = compile("local a = 'x' local function f() return a end")
+        .ok()
+        .unwrap();
+    let ops: Vec<OpCode> = proto.code().iter().map(|i| i.get_op()).collect();
+    assert_eq!(ops, vec![OpCode::LoadK, OpCode::Closure, OpCode::Return]);
+    assert_eq!(proto.instruction_count(), 3);
+    assert_eq!(proto.consts(), &[Const::Str("x".into())]);
+    assert_eq!(proto.max_stack(), 2);
+    assert_eq!(proto.protos().len(), 1);
+    assert_eq!(proto.protos()[0].instruction_count(), 3);
+}
+
+#[test]
+#[should_panic(expected = "unexpected symbol")]
+fn parse_error() {
+    let _ = parse("x = = 1");
+}
+
+#[test]
+fn compile_source() {
+    let proto = compile("local x = 1 + 2").ok().unwrap();
+    assert_eq!(proto.consts, vec![Const::Int(3)]);
+    assert_eq!(proto.code[0].get_op(), OpCode::LoadK);
+    assert_eq!(proto.code.len(), 2);
+
+    let config = LexerConfig {
+        preserve_number_text: true,
+        ..Default::default()
+    };
+    let proto = compile_with("return 0x10 | 1", config).ok().unwrap();
+    assert_eq!(proto.consts, vec![Const::Int(17)]);
+}
+
+// interned names and strings are compiled the same
+#[test]
+fn compile_interned_strings() {
+    let input = "local a = 'x' local function f(x) return a .. x end return f(a)";
+    let config = LexerConfig {
+        intern_strings: true,
+        ..Default::default()
+    };
+    let proto = compile_with(input, config).ok().unwrap();
+    let expected = compile(input).ok().unwrap();
+    assert_eq!(format!("{:?}", proto), format!("{:?}", expected));
+    assert_eq!(
+        format!("{:?}", proto.protos()[0]),
+        format!("{:?}", expected.protos()[0])
+    );
+}
+
+// every sample script compiles to code passing verification
+#[test]
+fn compile_lua_files() -> std::io::Result<()> {
+    for entry in std::fs::read_dir("lua")? {
+        let path = entry?.path();
+        let proto = compile(&std::fs::read_to_string(&path)?).ok().unwrap();
+        assert_eq!(proto.verify(), Ok(()), "{:?}", path);
+    }
+    Ok(())
+}
+
+#[test]
+#[should_panic(expected = "no visible label")]
+fn compile_error() {
+    let _ = compile("goto done");
+}
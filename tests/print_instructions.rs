@@ -58,3 +58,80 @@ fn print_instruction() {
 "#
     )
 }
+
+#[test]
+fn decode_instruction() {
+    let decoded = Instruction::create_ABC(OpCode::Add, 1, 2, 300).decode();
+    assert_eq!(
+        decoded,
+        DecodedInsn::ABC {
+            op: OpCode::Add,
+            a: 1,
+            b: 2,
+            c: 300
+        }
+    );
+    let decoded = Instruction::create_ABC(OpCode::Move, 3, 4, 0).decode();
+    assert_eq!(
+        decoded,
+        DecodedInsn::ABC {
+            op: OpCode::Move,
+            a: 3,
+            b: 4,
+            c: 0
+        }
+    );
+    let decoded = Instruction::create_ABx(OpCode::LoadK, 5, MAXARG_BX).decode();
+    assert_eq!(
+        decoded,
+        DecodedInsn::ABx {
+            op: OpCode::LoadK,
+            a: 5,
+            bx: MAXARG_BX
+        }
+    );
+    for sbx in [-MAXARG_SBX, -1, 0, 1, MAXARG_SBX] {
+        let decoded = Instruction::create_AsBx(OpCode::Jmp, 0, sbx).decode();
+        assert_eq!(
+            decoded,
+            DecodedInsn::AsBx {
+                op: OpCode::Jmp,
+                a: 0,
+                sbx
+            }
+        );
+    }
+    let decoded = Instruction::create_Ax(OpCode::ExtraArg, MAXARG_AX).decode();
+    assert_eq!(
+        decoded,
+        DecodedInsn::Ax {
+            op: OpCode::ExtraArg,
+            ax: MAXARG_AX
+        }
+    );
+}
+
+#[test]
+fn opcode_info() {
+    assert_eq!(OPCODE_INFO.len(), OpCode::ExtraArg as usize + 1);
+
+    assert_eq!(OpCode::LoadK.format(), OpMode::IABx);
+    assert!(OpCode::LoadK.sets_a());
+    assert!(!OpCode::LoadK.followed_by_jump());
+
+    assert_eq!(OpCode::Jmp.format(), OpMode::IAsBx);
+    assert!(OpCode::Jmp.has_jump());
+    assert!(!OpCode::Jmp.sets_a());
+
+    for op in [OpCode::Eq, OpCode::Lt, OpCode::Le, OpCode::Test] {
+        assert!(op.followed_by_jump());
+        assert!(!op.sets_a());
+    }
+    assert!(OpCode::TestSet.followed_by_jump());
+    assert!(OpCode::TestSet.sets_a());
+
+    assert_eq!(OpCode::TForCall.format(), OpMode::IAC);
+    assert!(!OpCode::TForCall.sets_a());
+    assert!(OpCode::TForLoop.has_jump());
+    assert_eq!(OpCode::ExtraArg.format(), OpMode::IAx);
+}
@@ -0,0 +1,114 @@
+use rslua::ast::scopes::*;
+use rslua::ast::Block;
+use rslua::lexer::Lexer;
+use rslua::parser::Parser;
+
+fn try_parse(input: &str) -> Block {
+    let mut lexer = Lexer::default();
+    if let Ok(tokens) = lexer.run(input) {
+        let mut parser = Parser::default();
+        if let Ok(ast) = parser.run(tokens) {
+            return ast;
+        }
+    }
+    unreachable!()
+}
+
+// (name, decl line, decl col, end line and col of scope)
+type ScopeSummary = (String, usize, usize, Option<(usize, usize)>);
+
+fn summary(scopes: &[LocalScope]) -> Vec<ScopeSummary> {
+    scopes
+        .iter()
+        .map(|s| {
+            (
+                s.name.clone(),
+                s.decl_source.line,
+                s.decl_source.col,
+                s.scope_span.end.map(|e| (e.line, e.col)),
+            )
+        })
+        .collect()
+}
+
+mod scopes_tests {
+    use super::*;
+
+    #[test]
+    fn nested_scopes() {
+        let block = try_parse(
+            r#"local x = 1
+local function f(a, b)
+  local x = a
+  for i = 1, 10 do
+    local y = i
+  end
+  for k, v in pairs(b) do
+    do local x = v end
+  end
+  return function(c) return c end
+end"#,
+        );
+        let scopes = local_scopes(&block);
+        let s = |name: &str, line, col, end| (name.to_string(), line, col, end);
+        assert_eq!(
+            summary(&scopes),
+            vec![
+                s("x", 1, 7, None),
+                s("f", 2, 16, None),
+                s("a", 2, 18, Some((11, 1))),
+                s("b", 2, 21, Some((11, 1))),
+                s("x", 3, 9, Some((11, 1))),
+                s("i", 4, 7, Some((6, 3))),
+                s("y", 5, 11, Some((6, 3))),
+                s("k", 7, 7, Some((9, 3))),
+                s("v", 7, 10, Some((9, 3))),
+                s("x", 8, 14, Some((8, 20))),
+                s("c", 10, 19, Some((10, 31))),
+            ]
+        );
+        assert_eq!(scopes[0].scope_span.start, scopes[0].decl_source);
+    }
+
+    #[test]
+    fn if_and_repeat_scopes() {
+        let block = try_parse(
+            r#"if a then local x elseif b then local y else local z end
+repeat local w until w"#,
+        );
+        let scopes = local_scopes(&block);
+        let ends: Vec<_> = scopes
+            .iter()
+            .map(|s| (s.name.as_str(), s.scope_span.end.map(|e| e.col)))
+            .collect();
+        assert_eq!(
+            ends,
+            vec![
+                ("x", Some(19)),
+                ("y", Some(41)),
+                ("z", Some(54)),
+                ("w", Some(16))
+            ]
+        );
+    }
+
+    #[test]
+    fn method_self() {
+        let block = try_parse(
+            r#"function t:m(a)
+  local b = self
+end
+function t.f() end"#,
+        );
+        let scopes = local_scopes(&block);
+        let s = |name: &str, line, col, end| (name.to_string(), line, col, end);
+        assert_eq!(
+            summary(&scopes),
+            vec![
+                s("self", 1, 12, Some((3, 1))),
+                s("a", 1, 14, Some((3, 1))),
+                s("b", 2, 9, Some((3, 1))),
+            ]
+        );
+    }
+}
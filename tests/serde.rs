@@ -0,0 +1,93 @@
+#![cfg(feature = "serde")]
+
+use rslua::ast::{Block, Stat};
+use rslua::consts::Const;
+use rslua::lexer::{Lexer, LexerConfig};
+use rslua::parser::Parser;
+use rslua::proto::Proto;
+use serde_json::json;
+
+#[test]
+fn serialize_tokens() {
+    let tokens = Lexer::default().run("local a = 1.5 .. 'x'").unwrap();
+    let value = serde_json::to_value(&tokens).unwrap();
+    assert_eq!(value.as_array().unwrap().len(), 7);
+    assert_eq!(
+        value[0],
+        json!({
+            "t": "Local",
+            "value": { "type": "None" },
+            "source": { "line": 1, "col": 1, "length": 5, "offset": 0 },
+            "comments": [],
+            "trailing_comments": []
+        })
+    );
+    assert_eq!(value[1]["value"], json!({ "type": "Str", "value": "a" }));
+    assert_eq!(value[3]["t"], "Flt");
+    assert_eq!(value[3]["value"], json!({ "type": "Float", "value": 1.5 }));
+    assert_eq!(value[3]["source"]["col"], 11);
+    assert_eq!(value[5]["value"], json!({ "type": "Str", "value": "x" }));
+    assert_eq!(value[6]["t"], "Eos");
+}
+
+#[test]
+fn ast_round_trip() {
+    let input = r#"
+local a <const>, b = 1, 0x10 -- comment
+local t = { 1, x = "x", [a + b] = { ... } }
+function t.f(self, ...) return -a, not b, #t .. "s" end
+for i = 1, 10, 2 do t[i] = t:f(i)(a) end
+while a < b do goto continue ::continue:: end
+return (a or b) and 2.5
+"#;
+    let mut lexer = Lexer::default();
+    lexer.set_config(LexerConfig {
+        reserve_comments: true,
+        preserve_number_text: true,
+        ..Default::default()
+    });
+    let tokens = lexer.run(input).unwrap();
+    let block = Parser::default().run(tokens).unwrap();
+    let serialized = serde_json::to_string(&block).unwrap();
+    let deserialized: Block = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(deserialized, block);
+
+    let value = serde_json::to_value(&block).unwrap();
+    let local = &value["stats"][0]["LocalStat"];
+    assert_eq!(
+        local["names"]["vars"][0]["token"]["source"],
+        json!({ "line": 2, "col": 7, "length": 1, "offset": 7 })
+    );
+    assert!(matches!(deserialized.stats[0], Stat::LocalStat(_)));
+}
+
+#[test]
+fn proto_round_trip() {
+    let input = r#"
+local a, b = 1, "s"
+local function f(x, ...)
+    local t = { x, b, 2.5, ... }
+    return function() return a + #t end
+end
+print(f(a, true, false, nil))
+"#;
+    let proto = rslua::compile(input).unwrap();
+    let serialized = serde_json::to_string(&proto).unwrap();
+    let mut deserialized: Proto = serde_json::from_str(&serialized).unwrap();
+    assert_eq!(format!("{:?}", deserialized), format!("{:?}", proto));
+    assert_eq!(
+        format!("{:?}", deserialized.protos()[0].protos()[0]),
+        format!("{:?}", proto.protos()[0].protos()[0])
+    );
+    // dump covers everything of nested protos, including line info and pcs of locals
+    let mut expected = Vec::new();
+    proto.dump(&mut expected).unwrap();
+    let mut dumped = Vec::new();
+    deserialized.dump(&mut dumped).unwrap();
+    assert_eq!(dumped, expected);
+
+    // consts are still shared after loading
+    assert_eq!(deserialized.add_const(Const::Str("s".to_string())), 1);
+    let count = deserialized.consts().len() as u32;
+    assert_eq!(deserialized.add_const(Const::Int(2)), count);
+}